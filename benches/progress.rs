@@ -0,0 +1,117 @@
+//! Cost of following the progress the Docker daemon streams during a pull:
+//! time and heap allocations per line through the progress log and the
+//! layer spans, with INFO logging on as in production.
+//!
+//! Run with `cargo bench --bench progress`.
+
+#![allow(dead_code)]
+
+use bollard::models::CreateImageInfo;
+use bollard::models::ProgressDetail;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Instant;
+
+#[path = "../src/progresslog.rs"]
+mod progresslog;
+#[path = "../src/spans.rs"]
+mod spans;
+
+// the part of the service's settings the progress log reads
+mod config {
+    pub struct LogConfig {
+        pub progress_interval: std::time::Duration,
+        pub progress_step: u64,
+    }
+}
+
+struct Counting;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+const LAYERS: usize = 40;
+const LINES_PER_LAYER: usize = 2_000;
+
+// what the daemon sends for a pull of LAYERS layers of 1GB each
+fn lines() -> Vec<CreateImageInfo> {
+    let total = 1_000_000_000i64;
+    let mut lines = Vec::new();
+    for step in 0..LINES_PER_LAYER {
+        for layer in 0..LAYERS {
+            let current = total * step as i64 / LINES_PER_LAYER as i64;
+            lines.push(CreateImageInfo {
+                id: Some(format!("{:012x}", layer)),
+                status: Some("Downloading".to_owned()),
+                progress: Some(format!("[=>   ] {}/{}", current, total)),
+                progress_detail: Some(ProgressDetail {
+                    current: Some(current),
+                    total: Some(total),
+                }),
+                error: None,
+            });
+        }
+    }
+    for layer in 0..LAYERS {
+        lines.push(CreateImageInfo {
+            id: Some(format!("{:012x}", layer)),
+            status: Some("Pull complete".to_owned()),
+            ..Default::default()
+        });
+    }
+    lines
+}
+
+fn main() {
+    tracing_subscriber::fmt()
+        .with_max_level(tracing::Level::INFO)
+        .with_writer(std::io::sink)
+        .init();
+    let lines = lines();
+    let span = tracing::info_span!("pull", bytes = tracing::field::Empty);
+
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed);
+    let started = Instant::now();
+    let mut layers = spans::Layers::new(&span);
+    let mut log = progresslog::ProgressLog::new("docker.io/library/bench:latest");
+    for info in &lines {
+        if let (Some(layer), Some(status)) = (&info.id, &info.status) {
+            let total = info.progress_detail.as_ref().and_then(|d| d.total);
+            layers.update(layer, status, total);
+        }
+        let detail = info.progress_detail.as_ref();
+        log.record(
+            info,
+            info.id.as_deref(),
+            info.status.as_deref(),
+            detail.and_then(|d| Some((d.current?, d.total?))),
+        );
+    }
+    layers.finish();
+    let elapsed = started.elapsed();
+    let allocations = ALLOCATIONS.load(Ordering::Relaxed) - allocations;
+
+    println!(
+        "{} lines in {:?}: {:.0} ns and {:.3} allocations per line",
+        lines.len(),
+        elapsed,
+        elapsed.as_nanos() as f64 / lines.len() as f64,
+        allocations as f64 / lines.len() as f64
+    );
+}
@@ -0,0 +1,59 @@
+// Build metadata reported by `GET /version`: the commit built from, when,
+// and with which compiler. `GIT_COMMIT` and `SOURCE_DATE_EPOCH` override
+// the first two for builds outside a Git checkout or reproducible ones.
+use std::process::Command;
+use std::time::SystemTime;
+
+fn main() {
+    let commit = std::env::var("GIT_COMMIT")
+        .ok()
+        .filter(|c| !c.is_empty())
+        .or_else(git_commit)
+        .unwrap_or_else(|| "unknown".to_owned());
+    let built = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|s| s.parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    let rustc = std::env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let rustc = output(Command::new(rustc).arg("--version")).unwrap_or_default();
+
+    println!("cargo:rustc-env=IMAGESYNC_COMMIT={}", commit);
+    println!("cargo:rustc-env=IMAGESYNC_BUILT={}", built);
+    println!("cargo:rustc-env=IMAGESYNC_RUSTC={}", rustc);
+    println!(
+        "cargo:rustc-env=IMAGESYNC_TARGET={}",
+        std::env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rerun-if-env-changed=GIT_COMMIT");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    // a new commit or changed sources make a new build
+    println!("cargo:rerun-if-changed=src");
+    for path in [".git/HEAD", ".git/index"] {
+        if std::path::Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+}
+
+fn git_commit() -> Option<String> {
+    let commit = output(Command::new("git").args(["rev-parse", "--short=12", "HEAD"]))?;
+    let dirty = output(Command::new("git").args(["status", "--porcelain", "--untracked-files=no"]))
+        .is_some_and(|status| !status.is_empty());
+    Some(match dirty {
+        true => format!("{}-dirty", commit),
+        false => commit,
+    })
+}
+
+fn output(command: &mut Command) -> Option<String> {
+    let output = command.output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim().to_owned())
+}
@@ -0,0 +1,31 @@
+use crate::error::Error;
+use warp::Filter;
+use warp::Rejection;
+
+/// Passes requests carrying `Authorization: Bearer <token>`. Without a
+/// configured token (`ADMIN_TOKEN`) the admin endpoints are turned off and
+/// answer 404.
+pub fn authorized(token: Option<String>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::header::optional::<String>("authorization")
+        .and_then(move |header: Option<String>| {
+            let token = token.clone();
+            async move {
+                let token = match token {
+                    Some(token) => token,
+                    None => return Err(warp::reject::not_found()),
+                };
+                let given = header.as_deref().and_then(|h| h.strip_prefix("Bearer "));
+                if given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes())) {
+                    Ok(())
+                } else {
+                    Err(warp::reject::custom(Error::Unauthorized))
+                }
+            }
+        })
+        .untuple_one()
+}
+
+// compare without leaking through timing how much of the token matched
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
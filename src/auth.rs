@@ -0,0 +1,141 @@
+use crate::config::Config;
+use crate::config::PULL_ANONYMOUS;
+use crate::config::PULL_DEFAULT;
+use crate::error::Error;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+/// Lifetime the distribution spec tells clients to assume when a token
+/// response carries no `expires_in`.
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60);
+
+/// An `Authorization` header value and when it stops being accepted.
+#[derive(Debug, Clone)]
+pub struct Token {
+    pub value: String,
+    /// `None` for credentials that never expire, such as basic auth.
+    pub expires: Option<Instant>,
+    pub lifetime: Duration,
+}
+
+impl Token {
+    pub fn basic(value: String) -> Token {
+        Token {
+            value,
+            expires: None,
+            lifetime: Duration::MAX,
+        }
+    }
+
+    pub fn bearer(value: String, lifetime: Duration) -> Token {
+        Token {
+            value,
+            expires: Some(Instant::now() + lifetime),
+            lifetime,
+        }
+    }
+
+    /// Whether the token is close enough to expiry that it should be
+    /// replaced before the next request: within a quarter of its
+    /// lifetime, and never less than 10 seconds ahead.
+    pub fn needs_refresh(&self) -> bool {
+        match self.expires {
+            Some(expires) => {
+                let margin = (self.lifetime / 4).max(Duration::from_secs(10));
+                Instant::now() + margin >= expires
+            }
+            None => false,
+        }
+    }
+
+    fn expired(&self) -> bool {
+        matches!(self.expires, Some(e) if Instant::now() >= e)
+    }
+}
+
+// whether refused pull credentials fall back to anonymous pulls, set by
+// `init`
+static ANONYMOUS_FALLBACK: OnceCell<bool> = OnceCell::new();
+
+// our own login (`USERNAME`/`PASSWORD`), set by `init`
+static LOGIN: OnceCell<(String, String)> = OnceCell::new();
+
+/// Set whether pulls go on anonymously when a registry refuses our
+/// credentials, and our own login, which `pull_auth: default` pulls with.
+/// Without this refused pulls fall back and there is no login to pull with.
+pub fn init(anonymous_fallback: bool, login: (String, String)) {
+    let _ = ANONYMOUS_FALLBACK.set(anonymous_fallback);
+    let _ = LOGIN.set(login);
+}
+
+/// Credentials to pull from `host` with, as its `pull_auth` says:
+/// none for `anonymous` or without the setting, our login for `default`,
+/// otherwise the `credentials:` entry it names.
+pub fn pull_credentials(config: &Config, host: &str) -> Result<Option<(String, String)>, Error> {
+    let pull_auth = config
+        .network
+        .registries
+        .get(host)
+        .and_then(|r| r.pull_auth.as_deref());
+    match pull_auth {
+        None | Some(PULL_ANONYMOUS) => Ok(None),
+        Some(PULL_DEFAULT) => Ok(LOGIN.get().cloned()),
+        Some(name) => {
+            let resolved = match config.credentials.get(name) {
+                Some(credential) => credential.resolve(),
+                None => Err(anyhow::anyhow!("not configured")),
+            };
+            resolved.map(Some).map_err(|source| Error::Credentials {
+                name: name.to_owned(),
+                source,
+            })
+        }
+    }
+}
+
+/// Whether a request for `scopes`, refused with our credentials, is tried
+/// again without them: only ever for pulls.
+pub fn falls_back(scopes: &[String]) -> bool {
+    *ANONYMOUS_FALLBACK.get_or_init(|| true) && scopes.iter().all(|s| s.ends_with(":pull"))
+}
+
+// tokens by "registry user scopes", shared by every client
+static TOKENS: Lazy<Mutex<HashMap<String, Token>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// last WWW-Authenticate challenge by registry, so tokens can be fetched
+// without first being refused
+static CHALLENGES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub fn key(registry: &str, username: Option<&str>, scopes: &[String]) -> String {
+    format!(
+        "{} {} {}",
+        registry,
+        username.unwrap_or("-"),
+        scopes.join(" ")
+    )
+}
+
+pub fn get(key: &str) -> Option<Token> {
+    TOKENS.lock().unwrap().get(key).cloned()
+}
+
+pub fn put(key: String, token: Token) {
+    let mut tokens = TOKENS.lock().unwrap();
+    tokens.retain(|_, t| !t.expired());
+    tokens.insert(key, token);
+}
+
+pub fn challenge(registry: &str) -> Option<String> {
+    CHALLENGES.lock().unwrap().get(registry).cloned()
+}
+
+pub fn set_challenge(registry: &str, challenge: &str) {
+    CHALLENGES
+        .lock()
+        .unwrap()
+        .insert(registry.to_owned(), challenge.to_owned());
+}
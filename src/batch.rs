@@ -0,0 +1,356 @@
+use crate::jobs::Job;
+use crate::jobs::Jobs;
+use crate::jobs::State;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use tracing::event;
+use tracing::Level;
+
+/// Version of the batch result schema. It goes up when a field changes
+/// meaning or goes away; fields may be added within a version.
+pub const SCHEMA_VERSION: u32 = 1;
+
+// batches kept, the oldest are dropped beyond this
+const MAX_BATCHES: usize = 1000;
+
+/// What submitted the batch.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// A CI delivery to `/webhooks/generic`.
+    Webhook,
+    /// `POST /jobs/retry`.
+    Retry,
+    /// A round of the mirror list, over every image it names.
+    MirrorList,
+}
+
+/// Where an image of a batch stands.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Queued,
+    Running,
+    Synced,
+    /// Left out with a reason, such as being in sync already.
+    Skipped,
+    Failed,
+}
+
+/// An image of a batch as recorded: the job syncing it, or the outcome of
+/// an image no job was started for.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Entry {
+    pub image: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_of: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    status: Option<Status>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    source_digest: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    reason: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error_code: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Entry {
+    /// `image`, synced by job `id`.
+    pub fn job(image: &str, id: &str) -> Entry {
+        Entry::new(image, Some(id.to_owned()), None)
+    }
+
+    /// `image`, left out for `reason`.
+    pub fn skipped(image: &str, source_digest: Option<String>, reason: &str) -> Entry {
+        Entry {
+            source_digest,
+            reason: Some(reason.to_owned()),
+            ..Entry::new(image, None, Some(Status::Skipped))
+        }
+    }
+
+    /// `image`, failed before a job was started for it.
+    pub fn failed(image: &str, error_code: &str, error: String) -> Entry {
+        Entry {
+            error_code: Some(error_code.to_owned()),
+            error: Some(error),
+            ..Entry::new(image, None, Some(Status::Failed))
+        }
+    }
+
+    /// The same, running the failed job `original` again.
+    pub fn retrying(mut self, original: &str) -> Entry {
+        self.retry_of = Some(original.to_owned());
+        self
+    }
+
+    fn new(image: &str, job_id: Option<String>, status: Option<Status>) -> Entry {
+        Entry {
+            image: image.to_owned(),
+            job_id,
+            retry_of: None,
+            status,
+            source_digest: None,
+            reason: None,
+            error_code: None,
+            error: None,
+        }
+    }
+}
+
+// what is kept of a batch; outcomes of its jobs are read from the jobs
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Record {
+    id: String,
+    kind: Kind,
+    created_at: DateTime<Utc>,
+    items: Vec<Entry>,
+}
+
+/// A batch as `GET /batches/{id}` answers, in the versioned schema. Every
+/// field is always there, `null` when it does not apply or is not known.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    pub schema_version: u32,
+    pub batch_id: String,
+    pub kind: Kind,
+    pub created_at: DateTime<Utc>,
+    pub summary: Summary,
+    pub items: Vec<Item>,
+}
+
+/// Counts of the items of a batch by status.
+#[derive(Serialize, Debug, Default)]
+pub struct Summary {
+    pub total: usize,
+    pub queued: usize,
+    pub running: usize,
+    pub synced: usize,
+    pub skipped: usize,
+    pub failed: usize,
+    /// Whether no item is queued or running any more.
+    pub done: bool,
+    /// When the last item finished, once done.
+    pub finished_at: Option<DateTime<Utc>>,
+    /// Milliseconds from the batch being submitted to `finished_at`.
+    pub duration_ms: Option<u64>,
+}
+
+/// One image of a batch.
+#[derive(Serialize, Debug)]
+pub struct Item {
+    pub image: String,
+    pub status: Status,
+    pub job_id: Option<String>,
+    /// The failed job this item runs again, in retry batches.
+    pub retry_of: Option<String>,
+    pub source_digest: Option<String>,
+    /// Digest of the manifest pushed.
+    pub digest: Option<String>,
+    pub dest_image: Option<String>,
+    /// Why a skipped item was left out.
+    pub reason: Option<String>,
+    /// Kind of failure, as in `error_code` of jobs.
+    pub error_code: Option<String>,
+    pub error: Option<String>,
+    pub started_at: Option<DateTime<Utc>>,
+    pub finished_at: Option<DateTime<Utc>>,
+    pub duration_ms: Option<u64>,
+    /// Milliseconds spent in each step, as kept with the job.
+    pub timings: BTreeMap<String, u64>,
+}
+
+impl Item {
+    fn new(entry: Entry, job: Option<Job>) -> Item {
+        let mut item = Item {
+            image: entry.image,
+            status: entry.status.unwrap_or(Status::Failed),
+            job_id: entry.job_id,
+            retry_of: entry.retry_of,
+            source_digest: entry.source_digest,
+            digest: None,
+            dest_image: None,
+            reason: entry.reason,
+            error_code: entry.error_code,
+            error: entry.error,
+            started_at: None,
+            finished_at: None,
+            duration_ms: None,
+            timings: BTreeMap::new(),
+        };
+        let Some(job) = job else {
+            // dropped from the job history since
+            if item.job_id.is_some() {
+                item.error_code = Some("job_not_found".to_owned());
+            }
+            return item;
+        };
+        item.status = match job.state {
+            State::Queued => Status::Queued,
+            State::Running => Status::Running,
+            State::Succeeded => Status::Synced,
+            State::Failed => Status::Failed,
+        };
+        let result = job.result.as_ref();
+        let field = |name: &str| {
+            result
+                .and_then(|r| r.get(name))
+                .and_then(|v| v.as_str())
+                .map(str::to_owned)
+        };
+        item.source_digest = field("source_digest").or(job.digest.clone());
+        item.digest = field("digest");
+        item.dest_image = field("dest_image");
+        item.error_code = job.error_code;
+        item.error = job.error;
+        item.started_at = job.started_at;
+        item.finished_at = job.finished_at;
+        item.duration_ms = job
+            .started_at
+            .zip(job.finished_at)
+            .map(|(started, finished)| (finished - started).num_milliseconds().max(0) as u64);
+        item.timings = job.timings;
+        item
+    }
+}
+
+/// Syncs submitted together, kept in `batches.json` of the state directory
+/// so their results can be read back as one.
+pub struct Batches {
+    path: PathBuf,
+    batches: Mutex<BTreeMap<String, Record>>,
+    next: AtomicU64,
+}
+
+impl Batches {
+    pub fn open(state_dir: &Path) -> Batches {
+        let path = state_dir.join("batches.json");
+        let batches = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                event!(Level::WARN, "ignoring unreadable {}: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(_) => BTreeMap::new(),
+        };
+        Batches {
+            path,
+            batches: Mutex::new(batches),
+            next: AtomicU64::new(1),
+        }
+    }
+
+    /// Keep a batch of `items`, returning its id.
+    pub fn record(&self, kind: Kind, items: Vec<Entry>) -> String {
+        let now = Utc::now();
+        let mut batches = self.batches.lock().unwrap();
+        let id = loop {
+            let id = format!(
+                "batch-{}-{}",
+                now.format("%Y%m%d%H%M%S"),
+                self.next.fetch_add(1, Ordering::Relaxed)
+            );
+            if !batches.contains_key(&id) {
+                break id;
+            }
+        };
+        batches.insert(
+            id.clone(),
+            Record {
+                id: id.clone(),
+                kind,
+                created_at: now,
+                items,
+            },
+        );
+        while batches.len() > MAX_BATCHES {
+            let oldest = batches
+                .values()
+                .min_by_key(|b| b.created_at)
+                .map(|b| b.id.clone());
+            match oldest {
+                Some(oldest) => batches.remove(&oldest),
+                None => break,
+            };
+        }
+        self.save(&batches);
+        id
+    }
+
+    /// The batch `id` with the current outcome of each of its images.
+    pub fn report(&self, id: &str, jobs: &Jobs) -> Option<Report> {
+        let record = self.batches.lock().unwrap().get(id).cloned()?;
+        let items: Vec<Item> = record
+            .items
+            .into_iter()
+            .map(|entry| {
+                let job = entry.job_id.as_deref().and_then(|id| jobs.get(id));
+                Item::new(entry, job)
+            })
+            .collect();
+
+        let mut summary = Summary {
+            total: items.len(),
+            ..Summary::default()
+        };
+        for item in &items {
+            *match item.status {
+                Status::Queued => &mut summary.queued,
+                Status::Running => &mut summary.running,
+                Status::Synced => &mut summary.synced,
+                Status::Skipped => &mut summary.skipped,
+                Status::Failed => &mut summary.failed,
+            } += 1;
+        }
+        summary.done = summary.queued == 0 && summary.running == 0;
+        if summary.done {
+            let finished_at = items
+                .iter()
+                .filter_map(|i| i.finished_at)
+                .max()
+                .unwrap_or(record.created_at);
+            summary.finished_at = Some(finished_at);
+            summary.duration_ms =
+                Some((finished_at - record.created_at).num_milliseconds().max(0) as u64);
+        }
+        Some(Report {
+            schema_version: SCHEMA_VERSION,
+            batch_id: record.id,
+            kind: record.kind,
+            created_at: record.created_at,
+            summary,
+            items,
+        })
+    }
+
+    // Write the batches, replacing the file atomically.
+    fn save(&self, batches: &BTreeMap<String, Record>) {
+        let written = (|| -> anyhow::Result<()> {
+            let dir = self.path.parent().unwrap_or(Path::new("."));
+            std::fs::create_dir_all(dir)?;
+            let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+            serde_json::to_writer(&mut tmp, batches)?;
+            tmp.persist(&self.path)?;
+            Ok(())
+        })();
+        if let Err(e) = written {
+            event!(
+                Level::ERROR,
+                "failed to save {}: {:?}",
+                self.path.display(),
+                e
+            );
+        }
+    }
+}
@@ -0,0 +1,194 @@
+use crate::progresslog::ProgressLog;
+use anyhow::Context;
+use bollard::image::CreateImageOptions;
+use bollard::image::ImportImageOptions;
+use bollard::Docker;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use futures::stream::StreamExt;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tokio_util::io::ReaderStream;
+use tracing::event;
+use tracing::Level;
+
+/// Table of contents stored as `manifest.json` at the root of a bundle.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BundleManifest {
+    pub created: String,
+    pub images: Vec<BundleImage>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BundleImage {
+    pub reference: String,
+    pub id: String,
+    pub repo_digests: Vec<String>,
+    /// Path of the `docker save` archive inside the bundle.
+    pub archive: String,
+    pub sha256: String,
+}
+
+/// Pull every image and write them, plus a manifest of their digests, to a
+/// single gzip-compressed tarball at `path`.
+pub async fn create_bundle(
+    docker: &Docker,
+    images: &[String],
+    path: &Path,
+) -> anyhow::Result<BundleManifest> {
+    let tmp = tempfile::tempdir()?;
+    fs::create_dir(tmp.path().join("images"))?;
+
+    let mut manifest = BundleManifest {
+        created: chrono::Utc::now().to_rfc3339(),
+        images: Vec::new(),
+    };
+
+    for (i, image) in images.iter().enumerate() {
+        let reference = with_default_tag(image);
+        pull_image(docker, &reference).await?;
+
+        let inspect = docker.inspect_image(&reference).await?;
+        let archive = format!("images/{:04}.tar", i);
+
+        // save the image while hashing it
+        let mut hasher = Sha256::new();
+        let mut file = tokio::fs::File::create(tmp.path().join(&archive)).await?;
+        let mut stream = docker.export_image(&reference);
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            hasher.update(&chunk);
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+        event!(Level::INFO, "image {} saved...", reference);
+
+        manifest.images.push(BundleImage {
+            reference,
+            id: inspect.id.unwrap_or_default(),
+            repo_digests: inspect.repo_digests.unwrap_or_default(),
+            archive,
+            sha256: hex::encode(hasher.finalize()),
+        });
+    }
+
+    fs::write(
+        tmp.path().join("manifest.json"),
+        serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    let (src, dst) = (tmp.path().to_owned(), path.to_owned());
+    tokio::task::spawn_blocking(move || write_bundle(&src, &dst)).await??;
+    event!(Level::INFO, "bundle written to {}", path.display());
+
+    Ok(manifest)
+}
+
+/// Verify and load every image of a bundle into the local daemon.
+pub async fn import_bundle(docker: &Docker, path: &Path) -> anyhow::Result<BundleManifest> {
+    let tmp = tempfile::tempdir()?;
+
+    let (src, dst) = (path.to_owned(), tmp.path().to_owned());
+    tokio::task::spawn_blocking(move || -> io::Result<()> {
+        tar::Archive::new(GzDecoder::new(fs::File::open(src)?)).unpack(dst)
+    })
+    .await??;
+
+    let manifest = fs::read(tmp.path().join("manifest.json")).context("bundle has no manifest")?;
+    let manifest: BundleManifest = serde_json::from_slice(&manifest)?;
+
+    for image in &manifest.images {
+        let archive = tmp.path().join(&image.archive);
+
+        // refuse to load anything that changed in transit
+        let (file, expected) = (archive.clone(), image.sha256.clone());
+        let actual = tokio::task::spawn_blocking(move || -> io::Result<String> {
+            let mut hasher = Sha256::new();
+            io::copy(&mut fs::File::open(file)?, &mut hasher)?;
+            Ok(hex::encode(hasher.finalize()))
+        })
+        .await??;
+        if actual != expected {
+            anyhow::bail!(
+                "checksum mismatch for {}: expected {}, got {}",
+                image.reference,
+                expected,
+                actual
+            );
+        }
+
+        let body = warp::hyper::Body::wrap_stream(ReaderStream::new(
+            tokio::fs::File::open(&archive).await?,
+        ));
+        let mut stream = docker.import_image(ImportImageOptions::default(), body, None);
+        let mut log = ProgressLog::new(&image.reference);
+        while let Some(info) = stream.next().await {
+            let info = info?;
+            let detail = info.progress_detail.as_ref();
+            log.record(
+                &info,
+                info.id.as_deref(),
+                info.status.as_deref(),
+                detail.and_then(|d| Some((d.current?, d.total?))),
+            );
+        }
+        event!(Level::INFO, "image {} loaded...", image.reference);
+    }
+
+    Ok(manifest)
+}
+
+async fn pull_image(docker: &Docker, image: &str) -> anyhow::Result<()> {
+    let options = Some(CreateImageOptions {
+        from_image: image,
+        ..Default::default()
+    });
+
+    let mut stream = docker.create_image(options, None, None);
+    let mut log = ProgressLog::new(image);
+    while let Some(info) = stream.next().await {
+        let info = info.with_context(|| format!("failed to pull {}", image))?;
+        let detail = info.progress_detail.as_ref();
+        log.record(
+            &info,
+            info.id.as_deref(),
+            info.status.as_deref(),
+            detail.and_then(|d| Some((d.current?, d.total?))),
+        );
+    }
+    event!(Level::INFO, "image {} pulled...", image);
+
+    Ok(())
+}
+
+// append `latest` to references with neither tag nor digest
+fn with_default_tag(image: &str) -> String {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    if name.contains(':') || name.contains('@') {
+        image.to_owned()
+    } else {
+        format!("{}:latest", image)
+    }
+}
+
+fn write_bundle(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // write beside the target so a half-written bundle is never picked up
+    let partial = dst.with_extension("partial");
+    let encoder = GzEncoder::new(fs::File::create(&partial)?, flate2::Compression::default());
+    let mut builder = tar::Builder::new(encoder);
+    builder.append_path_with_name(src.join("manifest.json"), "manifest.json")?;
+    builder.append_dir_all("images", src.join("images"))?;
+    builder.into_inner()?.finish()?;
+    fs::rename(partial, dst)?;
+
+    Ok(())
+}
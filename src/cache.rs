@@ -0,0 +1,314 @@
+use crate::metrics;
+use crate::registry::Registry;
+use futures::stream::StreamExt;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::sync::Weak;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncWriteExt;
+use tracing::event;
+use tracing::Level;
+
+/// Blobs stored on disk by digest, shared by every job that moves layers
+/// between registries so common base layers are only downloaded once.
+pub struct BlobCache {
+    dir: PathBuf,
+    // digest -> (registry, repository) pairs known to hold the blob
+    locations: Mutex<HashMap<String, Vec<(String, String)>>>,
+    // blob transfers in flight, by direction, registry and digest
+    transfers: Mutex<HashMap<String, Weak<tokio::sync::Mutex<()>>>>,
+}
+
+/// Claim on moving a blob, held until the transfer is done. Jobs claiming
+/// the same transfer wait for each other.
+pub struct Transfer {
+    _guard: tokio::sync::OwnedMutexGuard<()>,
+    /// Whether another job held the claim first.
+    pub waited: bool,
+}
+
+impl BlobCache {
+    pub fn new(dir: PathBuf) -> BlobCache {
+        // one "digest registry repository" line per known location
+        let mut locations: HashMap<String, Vec<(String, String)>> = HashMap::new();
+        if let Ok(index) = std::fs::read_to_string(dir.join("locations")) {
+            for line in index.lines() {
+                let fields: Vec<&str> = line.split(' ').collect();
+                if let [digest, registry, repo] = fields.as_slice() {
+                    locations
+                        .entry(digest.to_string())
+                        .or_default()
+                        .push((registry.to_string(), repo.to_string()));
+                }
+            }
+        }
+
+        BlobCache {
+            dir,
+            locations: Mutex::new(locations),
+            transfers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn dir(&self) -> &Path {
+        &self.dir
+    }
+
+    pub fn path(&self, digest: &str) -> anyhow::Result<PathBuf> {
+        Ok(self.dir.join("blobs").join(hex_digest(digest)?))
+    }
+
+    /// Repositories of `registry` known to hold the blob.
+    pub fn locate(&self, registry: &str, digest: &str) -> Vec<String> {
+        let locations = self.locations.lock().unwrap();
+        locations
+            .get(digest)
+            .map(|l| {
+                l.iter()
+                    .filter(|(r, _)| r == registry)
+                    .map(|(_, repo)| repo.clone())
+                    .collect()
+            })
+            .unwrap_or_default()
+    }
+
+    /// Remember that `registry` holds the blob under `repo`.
+    pub fn record(&self, registry: &str, repo: &str, digest: &str) {
+        let mut locations = self.locations.lock().unwrap();
+        let known = locations.entry(digest.to_owned()).or_default();
+        if known.iter().any(|(r, p)| r == registry && p == repo) {
+            return;
+        }
+        known.push((registry.to_owned(), repo.to_owned()));
+
+        let appended = std::fs::create_dir_all(&self.dir).and_then(|_| {
+            std::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.dir.join("locations"))
+                .and_then(|mut f| writeln!(f, "{} {} {}", digest, registry, repo))
+        });
+        if let Err(e) = appended {
+            event!(Level::WARN, "failed to record blob location: {:?}", e);
+        }
+    }
+
+    /// Claim pushing `digest` to `registry`, once any other job pushing it
+    /// there is done. After waiting the blob should be looked for again, the
+    /// other job may have put it where it is needed.
+    pub async fn claim_push(&self, registry: &str, digest: &str) -> Transfer {
+        self.claim("push", &format!("push {} {}", registry, digest))
+            .await
+    }
+
+    async fn claim(&self, direction: &str, key: &str) -> Transfer {
+        let lock = {
+            let mut transfers = self.transfers.lock().unwrap();
+            transfers.retain(|_, t| t.strong_count() > 0);
+            match transfers.get(key).and_then(Weak::upgrade) {
+                Some(lock) => lock,
+                None => {
+                    let lock = Arc::new(tokio::sync::Mutex::new(()));
+                    transfers.insert(key.to_owned(), Arc::downgrade(&lock));
+                    lock
+                }
+            }
+        };
+        let (guard, waited) = match lock.clone().try_lock_owned() {
+            Ok(guard) => (guard, false),
+            Err(_) => {
+                event!(Level::DEBUG, "waiting for the {} in flight", key);
+                metrics::SHARED_TRANSFERS
+                    .with_label_values(&[direction])
+                    .inc();
+                (lock.lock_owned().await, true)
+            }
+        };
+        Transfer {
+            _guard: guard,
+            waited,
+        }
+    }
+
+    /// Store a blob we made ourselves, returning its digest.
+    pub async fn put(&self, bytes: &[u8]) -> anyhow::Result<String> {
+        let digest = format!("sha256:{}", hex::encode(Sha256::digest(bytes)));
+        let path = self.path(&digest)?;
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(&path, bytes).await?;
+        Ok(digest)
+    }
+
+    /// Path of a cached blob, downloading and verifying it on a cache miss.
+    pub async fn fetch(
+        &self,
+        registry: &Registry,
+        repo: &str,
+        digest: &str,
+    ) -> anyhow::Result<PathBuf> {
+        self.fetch_checked(registry, repo, digest, None).await
+    }
+
+    /// Like `fetch` for a blob a manifest says is `size` bytes, stopping the
+    /// download as soon as it is longer. A cached copy is hashed again before
+    /// it is used, and dropped when it no longer matches.
+    pub async fn fetch_verified(
+        &self,
+        registry: &Registry,
+        repo: &str,
+        digest: &str,
+        size: u64,
+    ) -> anyhow::Result<PathBuf> {
+        let path = self.path(digest)?;
+        if tokio::fs::metadata(&path).await.is_ok() {
+            match verify_file(&path, digest, size).await? {
+                None => return Ok(path),
+                Some((actual, actual_size)) => {
+                    event!(
+                        Level::WARN,
+                        "cached blob {} is {} with {} bytes, downloading it again",
+                        digest,
+                        actual,
+                        actual_size
+                    );
+                    tokio::fs::remove_file(&path).await?;
+                }
+            }
+        }
+        self.fetch_checked(registry, repo, digest, Some(size)).await
+    }
+
+    async fn fetch_checked(
+        &self,
+        registry: &Registry,
+        repo: &str,
+        digest: &str,
+        size: Option<u64>,
+    ) -> anyhow::Result<PathBuf> {
+        let path = self.path(digest)?;
+        if tokio::fs::metadata(&path).await.is_ok() {
+            event!(Level::DEBUG, "blob {} served from cache", digest);
+            return Ok(path);
+        }
+
+        // another job fetching the blob leaves it in the cache
+        let transfer = self.claim("pull", &format!("pull {}", digest)).await;
+        if transfer.waited && tokio::fs::metadata(&path).await.is_ok() {
+            event!(Level::DEBUG, "blob {} fetched by another job", digest);
+            return Ok(path);
+        }
+
+        let tmp_dir = self.dir.join("tmp");
+        tokio::fs::create_dir_all(&tmp_dir).await?;
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        let tmp = tempfile::NamedTempFile::new_in(&tmp_dir)?;
+
+        let mut file = tokio::fs::File::create(tmp.path()).await?;
+        let mut hasher = Sha256::new();
+        let mut received = 0;
+        let mut stream = registry.get_blob(repo, digest).await?.bytes_stream();
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            received += chunk.len() as u64;
+            hasher.update(&chunk);
+            if size.is_some_and(|size| received > size) {
+                return Err(Corrupt {
+                    digest: digest.to_owned(),
+                    from: format!("{}/{}", registry.base(), repo),
+                    size,
+                    actual: None,
+                    actual_size: received,
+                }
+                .into());
+            }
+            file.write_all(&chunk).await?;
+        }
+        file.flush().await?;
+
+        let actual = format!("sha256:{}", hex::encode(hasher.finalize()));
+        if actual != digest || size.is_some_and(|size| received != size) {
+            return Err(Corrupt {
+                digest: digest.to_owned(),
+                from: format!("{}/{}", registry.base(), repo),
+                size,
+                actual: Some(actual),
+                actual_size: received,
+            }
+            .into());
+        }
+        tmp.persist(&path)?;
+        event!(Level::INFO, "cached blob {}", digest);
+
+        Ok(path)
+    }
+}
+
+/// A blob that did not arrive as its digest and size say: the layer is
+/// corrupt at the source or was damaged on the way.
+#[derive(Debug, thiserror::Error)]
+#[error("blob {digest} from {from} is corrupt: {}", detail(.actual, .actual_size, .size))]
+pub struct Corrupt {
+    pub digest: String,
+    /// Registry and repository the blob was downloaded from.
+    pub from: String,
+    /// Size the manifest gives, when known.
+    pub size: Option<u64>,
+    /// Digest of what was received, `None` when the download was stopped
+    /// for being longer than `size`.
+    pub actual: Option<String>,
+    pub actual_size: u64,
+}
+
+// what was wrong with a corrupt blob
+fn detail(actual: &Option<String>, actual_size: &u64, size: &Option<u64>) -> String {
+    match (actual, *size) {
+        (None, Some(size)) => format!("more than the expected {} bytes were sent", size),
+        (Some(actual), Some(size)) => format!(
+            "received {} bytes with digest {}, expected {} bytes",
+            actual_size, actual, size
+        ),
+        (actual, None) => format!(
+            "received {} bytes with digest {}",
+            actual_size,
+            actual.as_deref().unwrap_or("unknown")
+        ),
+    }
+}
+
+// `None` when the file at `path` has `digest` and `size`, its digest and
+// size otherwise.
+async fn verify_file(
+    path: &Path,
+    digest: &str,
+    size: u64,
+) -> anyhow::Result<Option<(String, u64)>> {
+    let mut file = tokio::fs::File::open(path).await?;
+    let mut hasher = Sha256::new();
+    let mut read = 0;
+    let mut buf = vec![0; 64 * 1024];
+    loop {
+        let n = file.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        read += n as u64;
+        hasher.update(&buf[..n]);
+    }
+    let actual = format!("sha256:{}", hex::encode(hasher.finalize()));
+    match actual == digest && read == size {
+        true => Ok(None),
+        false => Ok(Some((actual, read))),
+    }
+}
+
+pub fn hex_digest(digest: &str) -> anyhow::Result<&str> {
+    match digest.strip_prefix("sha256:") {
+        Some(hex) if hex.len() == 64 && hex.bytes().all(|b| b.is_ascii_hexdigit()) => Ok(hex),
+        _ => anyhow::bail!("unsupported digest {}", digest),
+    }
+}
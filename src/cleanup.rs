@@ -0,0 +1,103 @@
+use crate::config::Config;
+use crate::config::Naming;
+use crate::jobs::Job;
+use crate::labels;
+use bollard::container::ListContainersOptions;
+use bollard::container::RemoveContainerOptions;
+use bollard::image::ListImagesOptions;
+use bollard::image::RemoveImageOptions;
+use bollard::Docker;
+use std::collections::HashMap;
+use tracing::event;
+use tracing::Level;
+
+/// Remove what syncs cut short by a crash left in the daemon: containers
+/// used for labeling, images tagged for the destination repository, and the
+/// source images of interrupted jobs. Runs at startup before any job does.
+pub async fn remove_orphans(docker: &Docker, config: &Config, interrupted: &[Job]) {
+    let mut removed = 0;
+
+    let filters = HashMap::from([("name", vec![labels::CONTAINER_PREFIX])]);
+    let containers = docker
+        .list_containers(Some(ListContainersOptions {
+            all: true,
+            filters,
+            ..Default::default()
+        }))
+        .await;
+    match containers {
+        Ok(containers) => {
+            for id in containers.into_iter().filter_map(|c| c.id) {
+                let options = Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                });
+                match docker.remove_container(&id, options).await {
+                    Ok(()) => removed += 1,
+                    Err(e) => event!(Level::WARN, "could not remove container {}: {:?}", id, e),
+                }
+            }
+        }
+        Err(e) => {
+            event!(Level::WARN, "skipping orphan cleanup: {:?}", e);
+            return;
+        }
+    }
+
+    // every sync removes its destination tags once pushed
+    let mut images = Vec::new();
+    match docker
+        .list_images(Some(ListImagesOptions::<String>::default()))
+        .await
+    {
+        Ok(list) => {
+            let prefix = format!("{}:", config.dest_image_repo(&config.dest_repository));
+            for image in list {
+                images.extend(
+                    image
+                        .repo_tags
+                        .into_iter()
+                        .filter(|t| t.starts_with(&prefix)),
+                );
+            }
+        }
+        Err(e) => event!(Level::WARN, "could not list images: {:?}", e),
+    }
+    // nested repositories are not ours alone, only the interrupted jobs'
+    // own tags are removed from them
+    for job in interrupted {
+        let naming = match job.request.get("naming") {
+            Some(naming) => naming.parse().unwrap_or(config.dest_naming),
+            None => config.dest_naming,
+        };
+        if naming == Naming::Nested {
+            let (repository, tag) = config.nested_destination(&job.image);
+            images.push(format!("{}:{}", config.dest_image_repo(&repository), tag));
+        }
+    }
+    for job in interrupted {
+        images.push(job.image.clone());
+    }
+
+    for image in images {
+        let options = Some(RemoveImageOptions {
+            force: true,
+            ..Default::default()
+        });
+        match docker.remove_image(&image, options, None).await {
+            Ok(_) => removed += 1,
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404, ..
+            }) => {}
+            Err(e) => event!(Level::WARN, "could not remove image {}: {:?}", image, e),
+        }
+    }
+
+    if removed > 0 {
+        event!(
+            Level::INFO,
+            "removed {} images and containers left by earlier runs",
+            removed
+        );
+    }
+}
@@ -0,0 +1,55 @@
+use crate::bundle;
+use std::path::Path;
+
+const USAGE: &str = "usage:
+    image-sync                                  run the HTTP server
+    image-sync bundle create <output> <image>...
+    image-sync bundle import <bundle>
+    image-sync top [--url <url>] [--interval <seconds>]
+                                                live view of a server's queue";
+
+/// Run a command-line subcommand and return the process exit code.
+pub async fn run(args: &[String]) -> i32 {
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+
+    if let ["top", rest @ ..] = args.as_slice() {
+        return match crate::top::run(rest).await {
+            Ok(()) => 0,
+            Err(e) => {
+                eprintln!("{:#}", e);
+                1
+            }
+        };
+    }
+
+    let result = match args.as_slice() {
+        ["bundle", "create", output, images @ ..] if !images.is_empty() => {
+            let images: Vec<String> = images.iter().map(|s| s.to_string()).collect();
+            match crate::connect_docker() {
+                Ok(docker) => bundle::create_bundle(&docker, &images, Path::new(output)).await,
+                Err(e) => Err(e.into()),
+            }
+        }
+        ["bundle", "import", input] => match crate::connect_docker() {
+            Ok(docker) => bundle::import_bundle(&docker, Path::new(input)).await,
+            Err(e) => Err(e.into()),
+        },
+        _ => {
+            eprintln!("{}", USAGE);
+            return 2;
+        }
+    };
+
+    match result {
+        Ok(manifest) => {
+            for image in manifest.images {
+                println!("{}\t{}", image.reference, image.id);
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("{:#}", e);
+            1
+        }
+    }
+}
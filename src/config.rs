@@ -0,0 +1,1737 @@
+use crate::jobs::Priority;
+use crate::registry;
+use anyhow::Context;
+use serde::Deserialize;
+use serde::Serialize;
+use serde::Serializer;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::env;
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::Duration;
+
+/// Settings read from the environment, and for structured settings from the
+/// YAML file named by `CONFIG_FILE`, at startup.
+#[derive(Serialize, Debug, Clone)]
+pub struct Config {
+    pub export: ExportConfig,
+    /// Directory air-gap bundles are written to (`BUNDLE_DIR`).
+    pub bundle_dir: PathBuf,
+    /// Registry synced images are pushed to (`DEST_REGISTRY`).
+    pub dest_registry: String,
+    /// Repository synced images are pushed to (`DEST_REPOSITORY`).
+    pub dest_repository: String,
+    pub dest_naming: Naming,
+    /// Leading path of the repositories in nested naming (`DEST_PREFIX`).
+    pub dest_prefix: Option<String>,
+    /// How often the destination is checked for readiness
+    /// (`DEST_CHECK_INTERVAL`, seconds, default 30, 0 off).
+    #[serde(serialize_with = "optional_seconds")]
+    pub dest_check_interval: Option<Duration>,
+    /// Directory of the blob cache shared by the proxy and daemonless syncs (`CACHE_DIR`).
+    pub cache_dir: PathBuf,
+    pub sync_mode: SyncMode,
+    /// Report Docker daemon image events about synced images
+    /// (`DAEMON_EVENTS`, on in daemon mode).
+    pub daemon_events: bool,
+    /// How often the daemon's disk usage is sampled for the metrics
+    /// (`DAEMON_DF_INTERVAL`, seconds, default 60 in daemon mode, 0 off).
+    #[serde(serialize_with = "optional_seconds")]
+    pub daemon_df_interval: Option<Duration>,
+    /// Blobs larger than this are pushed in resumable chunks of this size
+    /// (`UPLOAD_CHUNK_SIZE`, bytes, 0 disables chunking).
+    pub upload_chunk_size: Option<u64>,
+    /// How many times a blob upload is started over in a new session when
+    /// the registry lost its session (`UPLOAD_SESSION_RETRIES`, default 3).
+    pub upload_session_retries: u32,
+    pub proxy: ProxyConfig,
+    pub ratelimit: RateLimitConfig,
+    pub network: NetworkConfig,
+    /// Ask the source for the manifest with a HEAD request before a daemon
+    /// pull, failing fast when no source has the image (`PULL_PREFLIGHT`,
+    /// default true).
+    pub pull_preflight: bool,
+    /// Pull anonymously when a registry refuses the credentials we pull
+    /// with (`PULL_ANONYMOUS_FALLBACK`, default true).
+    pub pull_anonymous_fallback: bool,
+    /// Label synced images with their origin (`PROVENANCE`, default true).
+    pub provenance: bool,
+    /// Labels added to every synced image (`labels:` in the config file).
+    pub labels: BTreeMap<String, String>,
+    pub strip: StripConfig,
+    pub signatures: SignatureConfig,
+    pub retention: RetentionConfig,
+    pub gc: GcConfig,
+    pub mirror_list: MirrorListConfig,
+    pub discovery: DiscoveryConfig,
+    pub webhook: WebhookConfig,
+    pub sync_links: SyncLinkConfig,
+    /// Refuse to overwrite a destination tag holding a different image
+    /// (`IMMUTABLE_TAGS`, `force=true` on the request overrides).
+    pub immutable_tags: bool,
+    /// Source tags that move, refused unless the request says
+    /// `allow_mutable=true` (`MUTABLE_TAGS`, comma-separated, `*` matches any
+    /// characters, default `latest`, empty allows every tag).
+    pub mutable_tags: Vec<String>,
+    /// Only sync images referenced by digest, refusing tags
+    /// (`DIGEST_ONLY`).
+    pub digest_only: bool,
+    /// Tag of images requested without one (`DEFAULT_TAG`, default
+    /// `latest`); set empty they are refused.
+    pub default_tag: Option<String>,
+    /// Read pushed images back from the destination and check them
+    /// (`READ_BACK`: `manifest`, `sample` or `full`, off when unset,
+    /// `read_back=` on the request overrides).
+    pub read_back: Option<ReadBack>,
+    /// Resolve source tags to digests and push a digest-derived tag as well
+    /// (`PIN_DIGEST`, `pin=` on the request overrides).
+    pub pin_digest: bool,
+    /// Syncs running at once (`MAX_CONCURRENT_SYNCS`, default 2).
+    pub max_concurrent_syncs: usize,
+    /// Blobs each daemonless sync moves at once (`LAYER_PARALLELISM`,
+    /// default 3).
+    pub layer_parallelism: usize,
+    /// Start `priority=high` syncs right away even when every slot is busy
+    /// (`PRIORITY_PREEMPT`).
+    pub priority_preempt: bool,
+    /// What waiting syncs of one priority take turns by (`FAIR_SHARE`).
+    pub fair_share: FairShare,
+    /// Weight of each tenant or repository in taking turns
+    /// (`FAIR_SHARE_WEIGHTS`, comma separated `name=weight`, 1 otherwise).
+    pub fair_share_weights: HashMap<String, u32>,
+    pub maintenance: MaintenanceConfig,
+    pub retry: RetryConfig,
+    /// Directory job state is kept in across restarts (`STATE_DIR`).
+    pub state_dir: PathBuf,
+    pub history: HistoryConfig,
+    pub log: LogConfig,
+    pub statsd: StatsdConfig,
+    pub metric_labels: MetricLabelsConfig,
+    /// Bearer token for the admin endpoints, which are off without one
+    /// (`ADMIN_TOKEN`).
+    #[serde(serialize_with = "redacted_optional")]
+    pub admin_token: Option<String>,
+    pub listen: ListenConfig,
+    pub limits: LimitsConfig,
+    pub policy: PolicyConfig,
+    pub local_cache: LocalCacheConfig,
+    /// Registry credentials requests refer to by name (`credentials:` in the
+    /// config file).
+    pub credentials: HashMap<String, Credential>,
+}
+
+/// Limits images must stay within to be pushed, for registries that
+/// enforce their own.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct PolicyConfig {
+    /// Most layers per image (`POLICY_MAX_LAYERS`).
+    pub max_layers: Option<usize>,
+    /// Largest single layer in bytes (`POLICY_MAX_LAYER_SIZE`).
+    pub max_layer_size: Option<u64>,
+    /// Oldest an image may be by its `created` time (`POLICY_MAX_AGE`, days).
+    #[serde(serialize_with = "optional_seconds")]
+    pub max_age: Option<Duration>,
+    /// Only log images older than `max_age` instead of refusing them
+    /// (`POLICY_MAX_AGE_WARN`).
+    pub max_age_warn: bool,
+}
+
+impl PolicyConfig {
+    fn from_env() -> anyhow::Result<PolicyConfig> {
+        Ok(PolicyConfig {
+            max_layers: match env::var("POLICY_MAX_LAYERS") {
+                Ok(n) => Some(n.parse()?),
+                Err(_) => None,
+            },
+            max_layer_size: match env::var("POLICY_MAX_LAYER_SIZE") {
+                Ok(n) => Some(n.parse()?),
+                Err(_) => None,
+            },
+            max_age: match env::var("POLICY_MAX_AGE") {
+                Ok(days) => Some(Duration::from_secs(days.parse::<u64>()? * 24 * 60 * 60)),
+                Err(_) => None,
+            },
+            max_age_warn: env::var("POLICY_MAX_AGE_WARN")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+        })
+    }
+}
+
+/// A registry login kept out of requests and the config file: the password
+/// is read from the environment or a file when used.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Credential {
+    /// Left out for registries that only look at a token, such as GHCR.
+    #[serde(default)]
+    pub username: String,
+    /// Environment variable holding the password.
+    pub password_env: Option<String>,
+    /// File holding the password, re-read at every use so it can rotate.
+    pub password_file: Option<PathBuf>,
+}
+
+impl Credential {
+    /// Username and password.
+    pub fn resolve(&self) -> anyhow::Result<(String, String)> {
+        let password = match (&self.password_env, &self.password_file) {
+            (Some(var), _) => env::var(var).with_context(|| format!("{} is not set", var))?,
+            (None, Some(path)) => std::fs::read_to_string(path)
+                .with_context(|| format!("failed to read {}", path.display()))?
+                .trim_end()
+                .to_owned(),
+            (None, None) => anyhow::bail!("no password_env or password_file"),
+        };
+        let username = match self.username.as_str() {
+            "" => crate::ghcr::TOKEN_USERNAME.to_owned(),
+            username => username.to_owned(),
+        };
+        Ok((username, password))
+    }
+}
+
+/// Source images kept on the daemon after syncing, so later syncs of the
+/// same digest skip the pull.
+#[derive(Serialize, Debug, Clone)]
+pub struct LocalCacheConfig {
+    /// Total image size kept before the least recently used images go
+    /// (`LOCAL_CACHE_SIZE`, bytes); unset keeps nothing.
+    pub max_size: Option<u64>,
+    /// Repository the images are kept under (`LOCAL_CACHE_REPOSITORY`,
+    /// default `imagesync-cache`).
+    pub repository: String,
+}
+
+impl LocalCacheConfig {
+    fn from_env() -> anyhow::Result<LocalCacheConfig> {
+        Ok(LocalCacheConfig {
+            max_size: match env::var("LOCAL_CACHE_SIZE") {
+                Ok(n) => Some(n.parse()?),
+                Err(_) => None,
+            },
+            repository: env::var("LOCAL_CACHE_REPOSITORY").unwrap_or("imagesync-cache".to_owned()),
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_size.is_some()
+    }
+}
+
+/// Caps on what a single request can make us hold in memory.
+#[derive(Serialize, Debug, Clone)]
+pub struct LimitsConfig {
+    /// Largest request body (`MAX_BODY_BYTES`, default 1 MiB).
+    pub body_bytes: u64,
+    /// Most images in one batch request (`MAX_BATCH_SIZE`, default 1000).
+    pub batch_size: usize,
+    /// Longest query string (`MAX_QUERY_BYTES`, default 8 KiB).
+    pub query_bytes: usize,
+}
+
+impl LimitsConfig {
+    fn from_env() -> anyhow::Result<LimitsConfig> {
+        Ok(LimitsConfig {
+            body_bytes: match env::var("MAX_BODY_BYTES") {
+                Ok(n) => n.parse()?,
+                Err(_) => 1024 * 1024,
+            },
+            batch_size: match env::var("MAX_BATCH_SIZE") {
+                Ok(n) => n.parse()?,
+                Err(_) => 1000,
+            },
+            query_bytes: match env::var("MAX_QUERY_BYTES") {
+                Ok(n) => n.parse()?,
+                Err(_) => 8 * 1024,
+            },
+        })
+    }
+}
+
+/// Where the API is served.
+#[derive(Serialize, Debug, Clone)]
+pub struct ListenConfig {
+    /// TCP address (`LISTEN_ADDR`, default `127.0.0.1:3030`, empty to serve
+    /// only on the Unix socket).
+    pub tcp: Option<std::net::SocketAddr>,
+    /// Unix socket path (`LISTEN_UNIX`).
+    pub unix: Option<PathBuf>,
+    /// Permissions of the Unix socket (`LISTEN_UNIX_MODE`, octal, default
+    /// 660).
+    pub unix_mode: u32,
+}
+
+impl ListenConfig {
+    fn from_env() -> anyhow::Result<ListenConfig> {
+        Ok(ListenConfig {
+            tcp: match env::var("LISTEN_ADDR") {
+                Ok(addr) if addr.is_empty() => None,
+                Ok(addr) => Some(
+                    addr.parse()
+                        .with_context(|| format!("invalid LISTEN_ADDR {}", addr))?,
+                ),
+                Err(_) => Some(([127, 0, 0, 1], 3030).into()),
+            },
+            unix: env::var("LISTEN_UNIX")
+                .ok()
+                .filter(|p| !p.is_empty())
+                .map(PathBuf::from),
+            unix_mode: match env::var("LISTEN_UNIX_MODE") {
+                Ok(mode) => u32::from_str_radix(&mode, 8)
+                    .with_context(|| format!("invalid LISTEN_UNIX_MODE {}", mode))?,
+                Err(_) => 0o660,
+            },
+        })
+    }
+}
+
+/// Where and how logs are written.
+#[derive(Serialize, Debug, Clone)]
+pub struct LogConfig {
+    pub format: LogFormat,
+    /// Also write logs to a rotated file (`log:` in the config file).
+    pub file: Option<LogFileConfig>,
+    /// Longest a layer goes without a progress line while its status stays
+    /// the same (`LOG_PROGRESS_INTERVAL`, seconds, default 10).
+    #[serde(serialize_with = "seconds")]
+    pub progress_interval: Duration,
+    /// Percent of a layer between progress lines (`LOG_PROGRESS_STEP`,
+    /// default 10).
+    pub progress_step: u64,
+}
+
+/// Log file settings, e.g.
+///
+/// ```yaml
+/// log:
+///   path: /var/log/image-sync/image-sync.log
+///   rotation: daily
+///   max_size: 104857600
+///   keep: 7
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct LogFileConfig {
+    pub path: PathBuf,
+    /// Start a new file every hour or day.
+    #[serde(default)]
+    pub rotation: Rotation,
+    /// Start a new file once the current one reaches this many bytes.
+    pub max_size: Option<u64>,
+    /// Rotated files kept next to the current one (`<path>.1` is the newest).
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+}
+
+fn default_log_keep() -> usize {
+    7
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Rotation {
+    Never,
+    Hourly,
+    #[default]
+    Daily,
+}
+
+/// Log line format (`LOG_FORMAT`).
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Text,
+    /// One JSON object per line, for log collectors.
+    Json,
+}
+
+impl std::str::FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<LogFormat> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => anyhow::bail!("unknown log format {}", s),
+        }
+    }
+}
+
+/// Metrics sent to a StatsD agent, next to `/metrics`.
+#[derive(Serialize, Debug, Clone)]
+pub struct StatsdConfig {
+    /// `host:port` of the agent, nothing is sent without one (`STATSD_ADDR`).
+    pub addr: Option<String>,
+    /// Put before every metric name (`STATSD_PREFIX`).
+    pub prefix: String,
+    /// `key:value` tags added to every metric (`STATSD_TAGS`, comma
+    /// separated).
+    pub tags: Vec<String>,
+    /// Send labels as DogStatsD tags, or append their values to the metric
+    /// name for plain StatsD (`STATSD_FLAVOR`, `dogstatsd` or `statsd`,
+    /// default `dogstatsd`).
+    pub dogstatsd: bool,
+    /// How often metrics are sent (`STATSD_INTERVAL`, seconds, default 10).
+    #[serde(serialize_with = "seconds")]
+    pub interval: Duration,
+}
+
+impl StatsdConfig {
+    fn from_env() -> anyhow::Result<StatsdConfig> {
+        Ok(StatsdConfig {
+            addr: env::var("STATSD_ADDR").ok().filter(|a| !a.is_empty()),
+            prefix: env::var("STATSD_PREFIX").unwrap_or_default(),
+            tags: env::var("STATSD_TAGS")
+                .map(|tags| {
+                    tags.split(',')
+                        .map(str::trim)
+                        .filter(|t| !t.is_empty())
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            dogstatsd: match env::var("STATSD_FLAVOR").as_deref() {
+                Ok("dogstatsd") | Err(_) => true,
+                Ok("statsd") => false,
+                Ok(flavor) => anyhow::bail!("unknown StatsD flavor {}", flavor),
+            },
+            interval: match env::var("STATSD_INTERVAL") {
+                Ok(secs) => Duration::from_secs(secs.parse::<u64>()?.max(1)),
+                Err(_) => Duration::from_secs(10),
+            },
+        })
+    }
+}
+
+/// Labels of the per-sync metrics, chosen to keep the number of series
+/// bounded on busy mirrors.
+#[derive(Serialize, Debug, Clone)]
+pub struct MetricLabelsConfig {
+    /// Label syncs with the source repository (`image` in `METRIC_LABELS`,
+    /// comma separated among `image`, `registry` and `tenant`, default
+    /// `registry`).
+    pub image: bool,
+    /// Label syncs with the registry pulled from (`registry`).
+    pub registry: bool,
+    /// Label syncs with the `tenant=` of their request (`tenant`).
+    pub tenant: bool,
+    /// Repositories the `image` label names, others are reported as `other`
+    /// (`METRIC_IMAGES`, comma separated patterns, `*` matches any run of
+    /// characters; all when unset).
+    pub images: Vec<String>,
+    /// Report repositories not in `images` as one of this many buckets by
+    /// a hash of their name instead (`METRIC_IMAGE_BUCKETS`).
+    pub image_buckets: Option<u32>,
+}
+
+impl MetricLabelsConfig {
+    fn from_env() -> anyhow::Result<MetricLabelsConfig> {
+        let list = |var: &str| -> Option<Vec<String>> {
+            env::var(var).ok().map(|v| {
+                v.split(',')
+                    .map(str::trim)
+                    .filter(|s| !s.is_empty())
+                    .map(str::to_owned)
+                    .collect()
+            })
+        };
+        let labels = list("METRIC_LABELS").unwrap_or_else(|| vec!["registry".to_owned()]);
+        if let Some(label) = labels
+            .iter()
+            .find(|l| !["image", "registry", "tenant"].contains(&l.as_str()))
+        {
+            anyhow::bail!("unknown metric label {}", label);
+        }
+        Ok(MetricLabelsConfig {
+            image: labels.iter().any(|l| l == "image"),
+            registry: labels.iter().any(|l| l == "registry"),
+            tenant: labels.iter().any(|l| l == "tenant"),
+            images: list("METRIC_IMAGES").unwrap_or_default(),
+            image_buckets: match env::var("METRIC_IMAGE_BUCKETS") {
+                Ok(n) if n.parse::<u32>()? > 0 => Some(n.parse()?),
+                Ok(_) => anyhow::bail!("METRIC_IMAGE_BUCKETS must be at least 1"),
+                Err(_) => None,
+            },
+        })
+    }
+}
+
+/// How long finished jobs are kept.
+#[derive(Serialize, Debug, Clone)]
+pub struct HistoryConfig {
+    /// Drop jobs finished longer ago than this (`HISTORY_MAX_AGE`, days,
+    /// default 30, 0 keeps them forever).
+    #[serde(serialize_with = "optional_seconds")]
+    pub max_age: Option<Duration>,
+    /// Keep at most this many jobs (`HISTORY_MAX_JOBS`, default 100000, 0
+    /// for no limit).
+    pub max_jobs: Option<usize>,
+    /// How often old jobs are dropped (`HISTORY_GC_INTERVAL`, seconds,
+    /// default one hour).
+    #[serde(serialize_with = "seconds")]
+    pub gc_interval: Duration,
+}
+
+impl HistoryConfig {
+    fn from_env() -> anyhow::Result<HistoryConfig> {
+        let max_age: u64 = match env::var("HISTORY_MAX_AGE") {
+            Ok(days) => days.parse()?,
+            Err(_) => 30,
+        };
+        let max_jobs: usize = match env::var("HISTORY_MAX_JOBS") {
+            Ok(n) => n.parse()?,
+            Err(_) => 100_000,
+        };
+        Ok(HistoryConfig {
+            max_age: (max_age > 0).then(|| Duration::from_secs(max_age * 24 * 60 * 60)),
+            max_jobs: (max_jobs > 0).then_some(max_jobs),
+            gc_interval: match env::var("HISTORY_GC_INTERVAL") {
+                Ok(secs) => Duration::from_secs(secs.parse()?),
+                Err(_) => Duration::from_secs(60 * 60),
+            },
+        })
+    }
+}
+
+/// Cleanup of old tags in the destination repository.
+#[derive(Serialize, Debug, Clone)]
+pub struct RetentionConfig {
+    /// Keep only the newest N tags (`RETENTION_KEEP`).
+    pub keep: Option<usize>,
+    /// Delete tags synced longer ago than this (`RETENTION_MAX_AGE`, days).
+    #[serde(serialize_with = "optional_seconds")]
+    pub max_age: Option<Duration>,
+    /// How often the policy runs (`RETENTION_INTERVAL`, seconds, default one day).
+    #[serde(serialize_with = "seconds")]
+    pub interval: Duration,
+}
+
+impl RetentionConfig {
+    fn from_env() -> anyhow::Result<RetentionConfig> {
+        Ok(RetentionConfig {
+            keep: match env::var("RETENTION_KEEP") {
+                Ok(n) => Some(n.parse()?),
+                Err(_) => None,
+            },
+            max_age: match env::var("RETENTION_MAX_AGE") {
+                Ok(days) => Some(Duration::from_secs(days.parse::<u64>()? * 24 * 60 * 60)),
+                Err(_) => None,
+            },
+            interval: match env::var("RETENTION_INTERVAL") {
+                Ok(secs) => Duration::from_secs(secs.parse()?),
+                Err(_) => Duration::from_secs(24 * 60 * 60),
+            },
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.keep.is_some() || self.max_age.is_some()
+    }
+}
+
+/// Removal of untagged manifests through the destination registry's own
+/// API, which the registry API has no call for.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GcConfig {
+    /// API of the destination registry (`DEST_GC`: `harbor` or `ecr`), off
+    /// when unset.
+    pub registry: Option<GcRegistry>,
+    /// How often it runs (`DEST_GC_INTERVAL`, seconds, default one day),
+    /// and after every retention run that deleted manifests.
+    #[serde(serialize_with = "seconds")]
+    pub interval: Duration,
+    /// AWS credentials for the ECR API (`AWS_ACCESS_KEY_ID`,
+    /// `AWS_SECRET_ACCESS_KEY`, `AWS_SESSION_TOKEN`).
+    #[serde(serialize_with = "redacted_optional")]
+    pub access_key: Option<String>,
+    #[serde(serialize_with = "redacted_optional")]
+    pub secret_key: Option<String>,
+    #[serde(serialize_with = "redacted_optional")]
+    pub session_token: Option<String>,
+}
+
+impl GcConfig {
+    fn from_env() -> anyhow::Result<GcConfig> {
+        Ok(GcConfig {
+            registry: match env::var("DEST_GC") {
+                Ok(registry) if !registry.is_empty() => Some(registry.parse()?),
+                _ => None,
+            },
+            interval: match env::var("DEST_GC_INTERVAL") {
+                Ok(secs) => Duration::from_secs(secs.parse()?),
+                Err(_) => Duration::from_secs(24 * 60 * 60),
+            },
+            access_key: env::var("AWS_ACCESS_KEY_ID").ok(),
+            secret_key: env::var("AWS_SECRET_ACCESS_KEY").ok(),
+            session_token: env::var("AWS_SESSION_TOKEN").ok(),
+        })
+    }
+}
+
+/// Registry whose API `DEST_GC` calls.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum GcRegistry {
+    /// Start Harbor's garbage collection, deleting untagged artifacts.
+    Harbor,
+    /// Batch-delete the untagged images of the ECR repository.
+    Ecr,
+}
+
+impl std::str::FromStr for GcRegistry {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<GcRegistry> {
+        match s {
+            "harbor" => Ok(GcRegistry::Harbor),
+            "ecr" => Ok(GcRegistry::Ecr),
+            _ => anyhow::bail!("unknown DEST_GC registry {}", s),
+        }
+    }
+}
+
+/// Declarative list of images kept mirrored.
+#[derive(Serialize, Debug, Clone)]
+pub struct MirrorListConfig {
+    /// YAML file listing what to mirror (`MIRRORS_FILE`).
+    pub file: Option<PathBuf>,
+    /// How often every listed image is checked against its source
+    /// (`MIRRORS_INTERVAL`, seconds, default 300). Changes to the file are
+    /// picked up within seconds.
+    #[serde(serialize_with = "seconds")]
+    pub interval: Duration,
+    /// Git repository the file is read from instead.
+    pub git: Option<GitSource>,
+}
+
+/// Mirror list kept in a Git repository, so changes to it are reviewed and
+/// versioned.
+#[derive(Serialize, Debug, Clone)]
+pub struct GitSource {
+    /// Repository to clone (`MIRRORS_GIT_URL`), with the credentials git is
+    /// set up with.
+    #[serde(serialize_with = "url_redacted")]
+    pub url: String,
+    /// Branch followed (`MIRRORS_GIT_BRANCH`, default `main`).
+    pub branch: String,
+    /// File in the repository (`MIRRORS_GIT_PATH`, default `mirrors.yaml`).
+    pub path: PathBuf,
+    /// How often the branch is fetched (`MIRRORS_GIT_INTERVAL`, seconds,
+    /// default 60).
+    #[serde(serialize_with = "seconds")]
+    pub interval: Duration,
+}
+
+impl MirrorListConfig {
+    fn from_env() -> anyhow::Result<MirrorListConfig> {
+        let git = match env::var("MIRRORS_GIT_URL") {
+            Ok(url) => {
+                let path = PathBuf::from(
+                    env::var("MIRRORS_GIT_PATH").unwrap_or("mirrors.yaml".to_owned()),
+                );
+                if path.is_absolute()
+                    || path
+                        .components()
+                        .any(|c| c == std::path::Component::ParentDir)
+                {
+                    anyhow::bail!("MIRRORS_GIT_PATH must be relative to the repository");
+                }
+                Some(GitSource {
+                    url,
+                    branch: env::var("MIRRORS_GIT_BRANCH").unwrap_or("main".to_owned()),
+                    path,
+                    interval: match env::var("MIRRORS_GIT_INTERVAL") {
+                        Ok(secs) => Duration::from_secs(secs.parse()?),
+                        Err(_) => Duration::from_secs(60),
+                    },
+                })
+            }
+            Err(_) => None,
+        };
+        let file = env::var("MIRRORS_FILE").ok().map(PathBuf::from);
+        if file.is_some() && git.is_some() {
+            anyhow::bail!("set one of MIRRORS_FILE and MIRRORS_GIT_URL");
+        }
+        Ok(MirrorListConfig {
+            file,
+            interval: match env::var("MIRRORS_INTERVAL") {
+                Ok(secs) => Duration::from_secs(secs.parse()?),
+                Err(_) => Duration::from_secs(300),
+            },
+            git,
+        })
+    }
+
+    /// The mirror list file, in the clone of `git` under `state_dir` when
+    /// it is kept in Git.
+    pub fn path(&self, state_dir: &Path) -> Option<PathBuf> {
+        match &self.git {
+            Some(git) => Some(state_dir.join("mirrors-git").join(&git.path)),
+            None => self.file.clone(),
+        }
+    }
+}
+
+/// Mirroring the images of Kubernetes workloads that opt in with an
+/// annotation.
+#[derive(Serialize, Debug, Clone)]
+pub struct DiscoveryConfig {
+    /// Watch the cluster for annotated workloads (`K8S_DISCOVERY`).
+    pub enabled: bool,
+    /// Annotation opting a Deployment or Pod in when `"true"`
+    /// (`K8S_ANNOTATION`, default `imagesync.io/mirror`).
+    pub annotation: String,
+    /// Namespaces watched (`K8S_NAMESPACES`, comma separated, all when
+    /// unset).
+    pub namespaces: Vec<String>,
+    /// How often the workloads are listed (`K8S_DISCOVERY_INTERVAL`,
+    /// seconds, default 60).
+    #[serde(serialize_with = "seconds")]
+    pub interval: Duration,
+    /// API server (`K8S_API_SERVER`), the cluster the service runs in when
+    /// unset.
+    pub api_server: Option<String>,
+    /// Service account token (`K8S_TOKEN_FILE`).
+    pub token_file: PathBuf,
+    /// CA of the API server (`K8S_CA_FILE`).
+    pub ca_file: PathBuf,
+}
+
+impl DiscoveryConfig {
+    fn from_env() -> anyhow::Result<DiscoveryConfig> {
+        const SERVICE_ACCOUNT: &str = "/var/run/secrets/kubernetes.io/serviceaccount";
+        Ok(DiscoveryConfig {
+            enabled: env::var("K8S_DISCOVERY")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            annotation: env::var("K8S_ANNOTATION").unwrap_or("imagesync.io/mirror".to_owned()),
+            namespaces: env::var("K8S_NAMESPACES")
+                .map(|v| {
+                    v.split(',')
+                        .map(str::trim)
+                        .filter(|ns| !ns.is_empty())
+                        .map(str::to_owned)
+                        .collect()
+                })
+                .unwrap_or_default(),
+            interval: match env::var("K8S_DISCOVERY_INTERVAL") {
+                Ok(secs) => Duration::from_secs(secs.parse()?),
+                Err(_) => Duration::from_secs(60),
+            },
+            api_server: env::var("K8S_API_SERVER").ok(),
+            token_file: env::var("K8S_TOKEN_FILE")
+                .map(PathBuf::from)
+                .unwrap_or(Path::new(SERVICE_ACCOUNT).join("token")),
+            ca_file: env::var("K8S_CA_FILE")
+                .map(PathBuf::from)
+                .unwrap_or(Path::new(SERVICE_ACCOUNT).join("ca.crt")),
+        })
+    }
+}
+
+/// Signed sync requests from CI (`/webhooks/generic`).
+#[derive(Serialize, Debug, Clone)]
+pub struct WebhookConfig {
+    /// Secret deliveries are signed with, the endpoint is off without one
+    /// (`WEBHOOK_SECRET`).
+    #[serde(serialize_with = "redacted_optional")]
+    pub secret: Option<String>,
+    /// How far the timestamp of a delivery may be off
+    /// (`WEBHOOK_TOLERANCE`, seconds, default 300).
+    #[serde(serialize_with = "seconds")]
+    pub tolerance: Duration,
+}
+
+impl WebhookConfig {
+    fn from_env() -> anyhow::Result<WebhookConfig> {
+        Ok(WebhookConfig {
+            secret: env::var("WEBHOOK_SECRET").ok().filter(|s| !s.is_empty()),
+            tolerance: match env::var("WEBHOOK_TOLERANCE") {
+                Ok(secs) => Duration::from_secs(secs.parse()?),
+                Err(_) => Duration::from_secs(300),
+            },
+        })
+    }
+}
+
+/// Signed links running one predefined sync (`/sync-links`).
+#[derive(Serialize, Debug, Clone)]
+pub struct SyncLinkConfig {
+    /// Secret links are signed with, they are off without one
+    /// (`SYNC_LINK_SECRET`).
+    #[serde(serialize_with = "redacted_optional")]
+    pub secret: Option<String>,
+    /// Longest a link may stay valid, and how long it does by default
+    /// (`SYNC_LINK_MAX_TTL`, seconds, default 86400).
+    #[serde(serialize_with = "seconds")]
+    pub max_ttl: Duration,
+}
+
+impl SyncLinkConfig {
+    fn from_env() -> anyhow::Result<SyncLinkConfig> {
+        Ok(SyncLinkConfig {
+            secret: env::var("SYNC_LINK_SECRET").ok().filter(|s| !s.is_empty()),
+            max_ttl: match env::var("SYNC_LINK_MAX_TTL") {
+                Ok(secs) => Duration::from_secs(secs.parse()?),
+                Err(_) => Duration::from_secs(86400),
+            },
+        })
+    }
+}
+
+/// Settings for direct registry traffic (daemonless mode, the pull-through
+/// proxy, quota probes). The Docker daemon uses its own proxy settings.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct NetworkConfig {
+    /// Proxy for all registry requests (`OUTBOUND_PROXY`). When unset
+    /// `HTTP_PROXY`/`HTTPS_PROXY`/`NO_PROXY` are honored.
+    #[serde(serialize_with = "url_redacted_optional")]
+    pub proxy: Option<String>,
+    /// Hosts bypassing `proxy` (`OUTBOUND_NO_PROXY`, same syntax as `NO_PROXY`).
+    pub no_proxy: Option<String>,
+    /// Per registry host settings (`registries:` in the config file).
+    pub registries: HashMap<String, RegistryConfig>,
+}
+
+/// Settings for one registry host, e.g. `harbor.lab:5000`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct RegistryConfig {
+    /// Accept self-signed or otherwise unverifiable TLS certificates.
+    pub insecure: bool,
+    /// Talk plain HTTP instead of HTTPS.
+    pub http: bool,
+    /// PEM file with CA certificates trusted in addition to the system ones.
+    pub ca_file: Option<PathBuf>,
+    /// Registries mirroring this one, pulled from in order. The registry
+    /// itself is tried last unless it is listed.
+    pub mirrors: Vec<String>,
+    /// How this registry is pulled from: `anonymous` (the default),
+    /// `default` with `USERNAME`/`PASSWORD`, or with the `credentials:`
+    /// entry of this name. Credentials a request names take precedence.
+    pub pull_auth: Option<String>,
+    /// Send our authorization along when the registry redirects us to
+    /// another host, as for blobs served from storage that checks it too.
+    /// Otherwise it is dropped on redirects leaving the host.
+    pub credentials_on_redirect: bool,
+}
+
+/// `pull_auth` pulling without credentials.
+pub const PULL_ANONYMOUS: &str = "anonymous";
+/// `pull_auth` pulling with `USERNAME`/`PASSWORD`.
+pub const PULL_DEFAULT: &str = "default";
+
+impl RegistryConfig {
+    /// Whether the Docker daemon must treat this registry as insecure too.
+    pub fn is_insecure(&self) -> bool {
+        self.insecure || self.http
+    }
+}
+
+/// Layout of the config file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    registries: HashMap<String, RegistryConfig>,
+    credentials: HashMap<String, Credential>,
+    labels: BTreeMap<String, String>,
+    strip: StripConfig,
+    signatures: SignatureConfig,
+    maintenance: MaintenanceConfig,
+    retry: RetryConfig,
+    log: Option<LogFileConfig>,
+}
+
+/// Build metadata removed from images before they are pushed (`strip:` in
+/// the config file). Patterns may use `*` for any run of characters.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StripConfig {
+    /// Drop the build history, along with the legacy `container_config`
+    /// that repeats the last build step.
+    pub history: bool,
+    /// Environment variables to drop from image configs.
+    pub env: Vec<String>,
+    /// Labels to drop from image configs.
+    pub labels: Vec<String>,
+    /// Annotations to drop from manifests.
+    pub annotations: Vec<String>,
+}
+
+impl StripConfig {
+    pub fn is_empty(&self) -> bool {
+        !self.history
+            && self.env.is_empty()
+            && self.labels.is_empty()
+            && self.annotations.is_empty()
+    }
+}
+
+/// Whether `name` matches `pattern`, where `*` stands for any run of
+/// characters.
+pub fn glob_match(pattern: &str, name: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let mut rest = match name.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let parts: Vec<&str> = parts.collect();
+    let last = match parts.split_last() {
+        Some((last, middle)) => {
+            for part in middle {
+                match rest.find(part) {
+                    Some(i) => rest = &rest[i + part.len()..],
+                    None => return false,
+                }
+            }
+            last
+        }
+        // no `*` at all
+        None => return rest.is_empty(),
+    };
+    rest.ends_with(last)
+}
+
+/// Signature checks on source images before they are pushed (`signatures:`
+/// in the config file).
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct SignatureConfig {
+    /// Refuse source images without a valid cosign signature, where no
+    /// rule says otherwise.
+    pub verify: bool,
+    /// Which sources must be signed, the first matching rule applies.
+    pub rules: Vec<SignatureRule>,
+    /// PEM public keys (ECDSA P-256 or P-384) signatures may be made with.
+    pub keys: Vec<PathBuf>,
+    /// Who keyless signatures may be made by.
+    pub identities: Vec<SignerIdentity>,
+    /// PEM file with the Fulcio CA certificates keyless signing
+    /// certificates must chain to.
+    pub fulcio_roots: Option<PathBuf>,
+    /// PEM public key of the Rekor log, which vouches for when a keyless
+    /// signature was made.
+    pub rekor_key: Option<PathBuf>,
+}
+
+impl SignatureConfig {
+    pub fn is_enabled(&self) -> bool {
+        self.verify || !self.rules.is_empty()
+    }
+}
+
+/// Whether images from sources matching `source` must be signed. The
+/// pattern is matched against `host/repository`, such as
+/// `docker.io/library/nginx`, and may use `*` for any run of characters.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SignatureRule {
+    pub source: String,
+    pub require: bool,
+}
+
+/// Signer of a keyless signature, as the OIDC issuer and the email or URI
+/// its certificate was issued to. Both may use `*` for any run of
+/// characters.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct SignerIdentity {
+    pub issuer: String,
+    pub subject: String,
+}
+
+/// Which failed syncs are run again, and how often (`retry:` in the config
+/// file). Each rule is an HTTP status such as `502`, matched against the
+/// status registries and the daemon answered with, or text matched without
+/// regard to case against the whole error, such as `MANIFEST_UNKNOWN` or
+/// `timeout`. `permanent` rules are checked first; errors no rule matches
+/// are not retried. Giving either list replaces its defaults.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryConfig {
+    /// Runs of a sync in all, 1 never retries.
+    pub attempts: u32,
+    /// Seconds before the first retry, doubled for each one after it.
+    pub backoff: u64,
+    pub retryable: Vec<String>,
+    pub permanent: Vec<String>,
+}
+
+impl Default for RetryConfig {
+    fn default() -> RetryConfig {
+        let rules = |rules: &[&str]| rules.iter().map(|r| r.to_string()).collect();
+        RetryConfig {
+            attempts: 3,
+            backoff: 5,
+            retryable: rules(&[
+                "408",
+                "429",
+                "500",
+                "502",
+                "503",
+                "504",
+                "TOOMANYREQUESTS",
+                "UNAVAILABLE",
+                "timeout",
+                "timed out",
+                "connection reset",
+                "connection refused",
+                "connection closed",
+                "broken pipe",
+                "error sending request",
+                "bad gateway",
+                "service unavailable",
+                "Docker daemon is unavailable",
+            ]),
+            permanent: rules(&[
+                "400",
+                "401",
+                "403",
+                "404",
+                "405",
+                "MANIFEST_UNKNOWN",
+                "NAME_UNKNOWN",
+                "NAME_INVALID",
+                "MANIFEST_INVALID",
+                "UNAUTHORIZED",
+                "DENIED",
+                "manifest unknown",
+                "not found",
+                "unauthorized",
+                "access denied",
+                "invalid reference format",
+            ]),
+        }
+    }
+}
+
+/// When bulk syncs may run (`maintenance:` in the config file).
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub struct MaintenanceConfig {
+    /// Bulk syncs only run inside one of these; none means any time.
+    pub windows: Vec<Window>,
+    /// Bulk syncs never run inside these.
+    pub blackouts: Vec<Window>,
+    /// Priorities that count as bulk syncs.
+    pub priorities: Vec<Priority>,
+}
+
+impl Default for MaintenanceConfig {
+    fn default() -> MaintenanceConfig {
+        MaintenanceConfig {
+            windows: Vec::new(),
+            blackouts: Vec::new(),
+            priorities: vec![Priority::Low],
+        }
+    }
+}
+
+/// Daily local time range such as `01:00-05:00`, wrapping past midnight
+/// when it ends before it starts.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct Window {
+    pub start: chrono::NaiveTime,
+    pub end: chrono::NaiveTime,
+}
+
+impl TryFrom<String> for Window {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> anyhow::Result<Window> {
+        let (start, end) = s
+            .split_once('-')
+            .with_context(|| format!("invalid window {}, expected HH:MM-HH:MM", s))?;
+        let time = |t: &str| {
+            chrono::NaiveTime::parse_from_str(t.trim(), "%H:%M")
+                .with_context(|| format!("invalid time {} in window {}", t, s))
+        };
+        Ok(Window {
+            start: time(start)?,
+            end: time(end)?,
+        })
+    }
+}
+
+impl From<Window> for String {
+    fn from(window: Window) -> String {
+        format!(
+            "{}-{}",
+            window.start.format("%H:%M"),
+            window.end.format("%H:%M")
+        )
+    }
+}
+
+impl Window {
+    pub fn contains(&self, t: chrono::NaiveTime) -> bool {
+        if self.start <= self.end {
+            self.start <= t && t < self.end
+        } else {
+            t >= self.start || t < self.end
+        }
+    }
+}
+
+impl FileConfig {
+    fn load() -> anyhow::Result<FileConfig> {
+        match env::var("CONFIG_FILE") {
+            Ok(path) => {
+                let content = std::fs::read_to_string(&path)
+                    .with_context(|| format!("failed to read {}", path))?;
+                let file: FileConfig = serde_yaml::from_str(&content)
+                    .with_context(|| format!("invalid config file {}", path))?;
+
+                for (name, credential) in &file.credentials {
+                    if credential.password_env.is_some() == credential.password_file.is_some() {
+                        anyhow::bail!(
+                            "credentials {} need one of password_env and password_file",
+                            name
+                        );
+                    }
+                }
+                for (host, registry) in &file.registries {
+                    match registry.pull_auth.as_deref() {
+                        None | Some(PULL_ANONYMOUS) | Some(PULL_DEFAULT) => {}
+                        Some(name) if file.credentials.contains_key(name) => {}
+                        Some(name) => anyhow::bail!(
+                            "pull_auth of {} is {}, which is not anonymous, default or a credentials entry",
+                            host,
+                            name
+                        ),
+                    }
+                    if let Some(ca_file) = &registry.ca_file {
+                        std::fs::metadata(ca_file).with_context(|| {
+                            format!("CA file {} for {} not found", ca_file.display(), host)
+                        })?;
+                    }
+                }
+                let signatures = &file.signatures;
+                let required = signatures.verify || signatures.rules.iter().any(|r| r.require);
+                if required && signatures.keys.is_empty() && signatures.identities.is_empty() {
+                    anyhow::bail!("required signatures need keys or identities");
+                }
+                if !signatures.identities.is_empty()
+                    && (signatures.fulcio_roots.is_none() || signatures.rekor_key.is_none())
+                {
+                    anyhow::bail!("keyless signature identities need fulcio_roots and rekor_key");
+                }
+                for path in signatures
+                    .keys
+                    .iter()
+                    .chain(&signatures.fulcio_roots)
+                    .chain(&signatures.rekor_key)
+                {
+                    std::fs::metadata(path)
+                        .with_context(|| format!("signature key {} not found", path.display()))?;
+                }
+                Ok(file)
+            }
+            Err(_) => Ok(FileConfig::default()),
+        }
+    }
+}
+
+/// Docker Hub pull quota handling.
+#[derive(Serialize, Debug, Clone)]
+pub struct RateLimitConfig {
+    /// Pulls are held while at most this many remain (`RATELIMIT_MIN_REMAINING`).
+    pub min_remaining: u64,
+    /// How often a held pull re-checks the quota (`RATELIMIT_RETRY_INTERVAL`, seconds).
+    #[serde(serialize_with = "seconds")]
+    pub retry_interval: Duration,
+    /// Longest a pull is held before going ahead anyway (`RATELIMIT_MAX_WAIT`, seconds).
+    #[serde(serialize_with = "seconds")]
+    pub max_wait: Duration,
+}
+
+/// How `/imagesync` moves images (`SYNC_MODE`).
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyncMode {
+    /// Pull, tag and push through the local Docker daemon.
+    #[default]
+    Daemon,
+    /// Copy manifests and blobs directly between registries.
+    Daemonless,
+}
+
+impl std::str::FromStr for SyncMode {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<SyncMode> {
+        match s {
+            "daemon" => Ok(SyncMode::Daemon),
+            "daemonless" => Ok(SyncMode::Daemonless),
+            _ => anyhow::bail!("unknown sync mode {}", s),
+        }
+    }
+}
+
+/// What waiting syncs take turns by, so one large batch does not hold up
+/// everyone else's syncs.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum FairShare {
+    /// The `tenant=` of the request, syncs without one sharing a turn.
+    Tenant,
+    /// The source repository.
+    Repository,
+    /// Strictly in the order they were queued.
+    Off,
+}
+
+impl std::str::FromStr for FairShare {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<FairShare> {
+        match s {
+            "tenant" => Ok(FairShare::Tenant),
+            "repository" => Ok(FairShare::Repository),
+            "off" => Ok(FairShare::Off),
+            _ => anyhow::bail!("unknown fair share {}", s),
+        }
+    }
+}
+
+// `name=weight,...`, with weights of at least 1
+fn parse_weights(weights: &str) -> anyhow::Result<HashMap<String, u32>> {
+    let mut parsed = HashMap::new();
+    for entry in weights.split(',').map(str::trim).filter(|e| !e.is_empty()) {
+        let (name, weight) = entry
+            .split_once('=')
+            .with_context(|| format!("fair share weight {} is not name=weight", entry))?;
+        let weight: u32 = weight
+            .trim()
+            .parse()
+            .with_context(|| format!("invalid fair share weight {}", entry))?;
+        if weight == 0 {
+            anyhow::bail!("fair share weight of {} must be at least 1", name);
+        }
+        parsed.insert(name.trim().to_owned(), weight);
+    }
+    Ok(parsed)
+}
+
+/// How much of a pushed image is read back from the destination.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum ReadBack {
+    /// The manifest, and those an index lists.
+    Manifest,
+    /// The manifests, every config and the smallest layer of each image.
+    Sample,
+    /// The manifests and every blob.
+    Full,
+}
+
+impl std::str::FromStr for ReadBack {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<ReadBack> {
+        match s {
+            "manifest" => Ok(ReadBack::Manifest),
+            "sample" => Ok(ReadBack::Sample),
+            "full" => Ok(ReadBack::Full),
+            _ => anyhow::bail!("unknown read-back depth {}", s),
+        }
+    }
+}
+
+/// How synced images are named in the destination registry (`DEST_NAMING`).
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Naming {
+    /// Every image in `DEST_REPOSITORY`, the source name flattened into the
+    /// tag: `dierbei/csi_demo:quay.io_argoproj_argocd_v2.9`.
+    #[default]
+    Flat,
+    /// One repository per source repository, under its registry's host:
+    /// `quay.io/argoproj/argocd:v2.9`.
+    Nested,
+}
+
+impl std::str::FromStr for Naming {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Naming> {
+        match s {
+            "flat" => Ok(Naming::Flat),
+            "nested" => Ok(Naming::Nested),
+            _ => anyhow::bail!("unknown naming {}", s),
+        }
+    }
+}
+
+/// Pull-through cache registry mode.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ProxyConfig {
+    /// Registry proxied under `/v2/`; the mode is off when unset (`PROXY_UPSTREAM`).
+    pub upstream: Option<String>,
+    /// How long a tag is served from cache before asking upstream again.
+    #[serde(serialize_with = "seconds")]
+    pub tag_ttl: Duration,
+    /// Also push pulled-through images to the destination repository.
+    pub mirror: bool,
+}
+
+/// Where synced images are exported as OCI image layouts.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct ExportConfig {
+    /// Local directory, one layout per synced image (`EXPORT_DIR`).
+    pub dir: Option<PathBuf>,
+    /// S3/MinIO bucket receiving a tarred layout per synced image.
+    pub s3: Option<S3Config>,
+}
+
+#[derive(Serialize, Clone)]
+pub struct S3Config {
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub prefix: String,
+    #[serde(serialize_with = "redacted")]
+    pub access_key: String,
+    #[serde(serialize_with = "redacted")]
+    pub secret_key: String,
+}
+
+impl std::fmt::Debug for S3Config {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("S3Config")
+            .field("endpoint", &self.endpoint)
+            .field("region", &self.region)
+            .field("bucket", &self.bucket)
+            .field("prefix", &self.prefix)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Config {
+    pub fn from_env() -> anyhow::Result<Config> {
+        let file = FileConfig::load()?;
+
+        Ok(Config {
+            export: ExportConfig::from_env()?,
+            bundle_dir: env::var("BUNDLE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(PathBuf::from("bundles")),
+            dest_registry: env::var("DEST_REGISTRY").unwrap_or("docker.io".to_owned()),
+            dest_repository: env::var("DEST_REPOSITORY").unwrap_or("dierbei/csi_demo".to_owned()),
+            dest_naming: match env::var("DEST_NAMING") {
+                Ok(naming) => naming.parse()?,
+                Err(_) => Naming::Flat,
+            },
+            dest_prefix: env::var("DEST_PREFIX")
+                .ok()
+                .map(|p| p.trim_matches('/').to_owned())
+                .filter(|p| !p.is_empty()),
+            dest_check_interval: match env::var("DEST_CHECK_INTERVAL") {
+                Ok(secs) => Some(Duration::from_secs(secs.parse()?)).filter(|d| !d.is_zero()),
+                Err(_) => Some(Duration::from_secs(30)),
+            },
+            cache_dir: env::var("CACHE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(PathBuf::from("cache")),
+            sync_mode: match env::var("SYNC_MODE") {
+                Ok(mode) => mode.parse()?,
+                Err(_) => SyncMode::Daemon,
+            },
+            daemon_events: match env::var("DAEMON_EVENTS") {
+                Ok(v) => v == "true",
+                Err(_) => env::var("SYNC_MODE").map_or(true, |mode| mode == "daemon"),
+            },
+            daemon_df_interval: match env::var("DAEMON_DF_INTERVAL") {
+                Ok(secs) => Some(Duration::from_secs(secs.parse()?)).filter(|d| !d.is_zero()),
+                Err(_) => env::var("SYNC_MODE")
+                    .map_or(true, |mode| mode == "daemon")
+                    .then(|| Duration::from_secs(60)),
+            },
+            upload_chunk_size: match env::var("UPLOAD_CHUNK_SIZE") {
+                Ok(size) => Some(size.parse()?),
+                Err(_) => Some(32 * 1024 * 1024),
+            },
+            upload_session_retries: match env::var("UPLOAD_SESSION_RETRIES") {
+                Ok(retries) => retries.parse()?,
+                Err(_) => 3,
+            },
+            proxy: ProxyConfig::from_env()?,
+            ratelimit: RateLimitConfig::from_env()?,
+            network: NetworkConfig {
+                proxy: env::var("OUTBOUND_PROXY").ok(),
+                no_proxy: env::var("OUTBOUND_NO_PROXY").ok(),
+                registries: file.registries,
+            },
+            pull_preflight: env::var("PULL_PREFLIGHT")
+                .map(|v| v != "false")
+                .unwrap_or(true),
+            pull_anonymous_fallback: env::var("PULL_ANONYMOUS_FALLBACK")
+                .map(|v| v != "false")
+                .unwrap_or(true),
+            provenance: env::var("PROVENANCE").map(|v| v != "false").unwrap_or(true),
+            labels: file.labels,
+            strip: file.strip,
+            signatures: file.signatures,
+            retention: RetentionConfig::from_env()?,
+            gc: GcConfig::from_env()?,
+            mirror_list: MirrorListConfig::from_env()?,
+            discovery: DiscoveryConfig::from_env()?,
+            webhook: WebhookConfig::from_env()?,
+            sync_links: SyncLinkConfig::from_env()?,
+            immutable_tags: env::var("IMMUTABLE_TAGS")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            mutable_tags: env::var("MUTABLE_TAGS")
+                .unwrap_or_else(|_| "latest".to_owned())
+                .split(',')
+                .map(str::trim)
+                .filter(|s| !s.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            digest_only: env::var("DIGEST_ONLY")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            default_tag: match env::var("DEFAULT_TAG") {
+                Ok(tag) if tag.is_empty() => None,
+                Ok(tag) => Some(tag),
+                Err(_) => Some("latest".to_owned()),
+            },
+            read_back: match env::var("READ_BACK") {
+                Ok(depth) if !depth.is_empty() => depth.parse::<ReadBack>()?.into(),
+                _ => None,
+            },
+            pin_digest: env::var("PIN_DIGEST").map(|v| v == "true").unwrap_or(false),
+            maintenance: file.maintenance,
+            retry: file.retry,
+            state_dir: env::var("STATE_DIR")
+                .map(PathBuf::from)
+                .unwrap_or(PathBuf::from("state")),
+            history: HistoryConfig::from_env()?,
+            log: LogConfig {
+                format: match env::var("LOG_FORMAT") {
+                    Ok(format) => format.parse()?,
+                    Err(_) => LogFormat::Text,
+                },
+                file: file.log,
+                progress_interval: match env::var("LOG_PROGRESS_INTERVAL") {
+                    Ok(secs) => Duration::from_secs(secs.parse()?),
+                    Err(_) => Duration::from_secs(10),
+                },
+                progress_step: match env::var("LOG_PROGRESS_STEP") {
+                    Ok(step) => step.parse()?,
+                    Err(_) => 10,
+                },
+            },
+            statsd: StatsdConfig::from_env()?,
+            metric_labels: MetricLabelsConfig::from_env()?,
+            admin_token: env::var("ADMIN_TOKEN").ok().filter(|t| !t.is_empty()),
+            listen: ListenConfig::from_env()?,
+            limits: LimitsConfig::from_env()?,
+            policy: PolicyConfig::from_env()?,
+            local_cache: LocalCacheConfig::from_env()?,
+            credentials: file.credentials,
+            max_concurrent_syncs: match env::var("MAX_CONCURRENT_SYNCS") {
+                Ok(n) => n.parse()?,
+                Err(_) => 2,
+            },
+            layer_parallelism: match env::var("LAYER_PARALLELISM") {
+                Ok(n) => n.parse()?,
+                Err(_) => 3,
+            },
+            priority_preempt: env::var("PRIORITY_PREEMPT")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+            fair_share: match env::var("FAIR_SHARE") {
+                Ok(share) => share.parse()?,
+                Err(_) => FairShare::Tenant,
+            },
+            fair_share_weights: match env::var("FAIR_SHARE_WEIGHTS") {
+                Ok(weights) => parse_weights(&weights)?,
+                Err(_) => HashMap::new(),
+            },
+        })
+    }
+
+    /// Registries to pull images of `host` from, in order.
+    pub fn sources(&self, host: &str) -> Vec<String> {
+        let mut sources = self
+            .network
+            .registries
+            .get(host)
+            .map(|r| r.mirrors.clone())
+            .unwrap_or_default();
+        if !sources.iter().any(|s| s == host) {
+            sources.push(host.to_owned());
+        }
+        sources
+    }
+
+    /// Destination `repository` as the Docker daemon refers to it.
+    pub fn dest_image_repo(&self, repository: &str) -> String {
+        if self.dest_registry == "docker.io" {
+            repository.to_owned()
+        } else {
+            format!("{}/{}", self.dest_registry, repository)
+        }
+    }
+
+    /// Repository and tag `image` is pushed to in nested naming. References
+    /// by digest get the digest as their tag.
+    pub fn nested_destination(&self, image: &str) -> (String, String) {
+        let (host, name, reference) = registry::parse_reference(image);
+        // spelled out like mirrors of Docker Hub do
+        let name = if host == "docker.io" && !name.contains('/') {
+            format!("library/{}", name)
+        } else {
+            name
+        };
+        let repository = format!("{}/{}", self.nested_root(&host), name);
+        (repository, reference.replace(':', "_"))
+    }
+
+    /// Path the repositories of source registry `host` are nested under.
+    pub fn nested_root(&self, host: &str) -> String {
+        match &self.dest_prefix {
+            Some(prefix) => format!("{}/{}", prefix, host),
+            None => host.to_owned(),
+        }
+    }
+
+    /// Whether `tag` is one of the tags that move and must not be synced
+    /// without `allow_mutable=true`.
+    pub fn is_mutable_tag(&self, tag: &str) -> bool {
+        self.mutable_tags
+            .iter()
+            .any(|pattern| glob_match(pattern, tag))
+    }
+}
+
+/// Tag `image` is pushed as in flat naming: its registry host (left out
+/// for Docker Hub), repository and tag or digest joined by `_`, so images of
+/// different registries never share a tag.
+pub fn flat_tag(image: &str) -> String {
+    // the most the distribution spec allows
+    const MAX_LEN: usize = 128;
+
+    let (host, name, reference) = registry::parse_reference(image);
+    let docker_hub = host == "docker.io" || host == "index.docker.io";
+    let name = match name.strip_prefix("library/") {
+        Some(name) if docker_hub => name.to_owned(),
+        _ => name,
+    };
+    let mut parts = Vec::new();
+    if !docker_hub {
+        parts.push(host);
+    }
+    parts.push(name);
+    parts.push(reference);
+    let tag = parts.join("_").replace(['/', '@', ':'], "_");
+    if tag.len() <= MAX_LEN {
+        return tag;
+    }
+
+    // long names keep their start and get a hash of the whole for uniqueness
+    let hash = hex::encode(Sha256::digest(tag.as_bytes()));
+    let mut end = MAX_LEN - 13;
+    while !tag.is_char_boundary(end) {
+        end -= 1;
+    }
+    format!("{}_{}", &tag[..end], &hash[..12])
+}
+
+impl RateLimitConfig {
+    fn from_env() -> anyhow::Result<RateLimitConfig> {
+        let secs = |name: &str, default: u64| -> anyhow::Result<Duration> {
+            match env::var(name) {
+                Ok(v) => Ok(Duration::from_secs(v.parse()?)),
+                Err(_) => Ok(Duration::from_secs(default)),
+            }
+        };
+
+        Ok(RateLimitConfig {
+            min_remaining: match env::var("RATELIMIT_MIN_REMAINING") {
+                Ok(v) => v.parse()?,
+                Err(_) => 5,
+            },
+            retry_interval: secs("RATELIMIT_RETRY_INTERVAL", 60)?,
+            max_wait: secs("RATELIMIT_MAX_WAIT", 3600)?,
+        })
+    }
+}
+
+impl ProxyConfig {
+    fn from_env() -> anyhow::Result<ProxyConfig> {
+        let tag_ttl = match env::var("PROXY_TAG_TTL") {
+            Ok(secs) => Duration::from_secs(secs.parse()?),
+            Err(_) => Duration::from_secs(300),
+        };
+
+        Ok(ProxyConfig {
+            upstream: env::var("PROXY_UPSTREAM").ok(),
+            tag_ttl,
+            mirror: env::var("PROXY_MIRROR")
+                .map(|v| v == "true")
+                .unwrap_or(false),
+        })
+    }
+}
+
+impl ExportConfig {
+    fn from_env() -> anyhow::Result<ExportConfig> {
+        let dir = env::var("EXPORT_DIR").ok().map(PathBuf::from);
+
+        // S3 export is enabled by setting a bucket
+        let s3 = match env::var("EXPORT_S3_BUCKET") {
+            Ok(bucket) => {
+                let region = env::var("EXPORT_S3_REGION").unwrap_or("us-east-1".to_owned());
+                let endpoint = env::var("EXPORT_S3_ENDPOINT")
+                    .unwrap_or(format!("https://s3.{}.amazonaws.com", region));
+                let access_key = env::var("EXPORT_S3_ACCESS_KEY")
+                    .or_else(|_| env::var("AWS_ACCESS_KEY_ID"))
+                    .map_err(|_| anyhow::anyhow!("EXPORT_S3_ACCESS_KEY is not set"))?;
+                let secret_key = env::var("EXPORT_S3_SECRET_KEY")
+                    .or_else(|_| env::var("AWS_SECRET_ACCESS_KEY"))
+                    .map_err(|_| anyhow::anyhow!("EXPORT_S3_SECRET_KEY is not set"))?;
+
+                Some(S3Config {
+                    endpoint: endpoint.trim_end_matches('/').to_owned(),
+                    region,
+                    bucket,
+                    prefix: env::var("EXPORT_S3_PREFIX").unwrap_or_default(),
+                    access_key,
+                    secret_key,
+                })
+            }
+            Err(_) => None,
+        };
+
+        Ok(ExportConfig { dir, s3 })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.dir.is_some() || self.s3.is_some()
+    }
+}
+
+// durations are shown in seconds, as they are configured
+fn seconds<S: Serializer>(duration: &Duration, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_u64(duration.as_secs())
+}
+
+fn optional_seconds<S: Serializer>(duration: &Option<Duration>, s: S) -> Result<S::Ok, S::Error> {
+    duration.map(|d| d.as_secs()).serialize(s)
+}
+
+// secrets are shown as set or not, never as they are
+const REDACTED: &str = "<redacted>";
+
+fn redacted<S: Serializer>(_: &str, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(REDACTED)
+}
+
+fn redacted_optional<S: Serializer>(secret: &Option<String>, s: S) -> Result<S::Ok, S::Error> {
+    secret.as_ref().map(|_| REDACTED).serialize(s)
+}
+
+fn url_redacted<S: Serializer>(url: &str, s: S) -> Result<S::Ok, S::Error> {
+    s.serialize_str(&without_userinfo(url))
+}
+
+fn url_redacted_optional<S: Serializer>(url: &Option<String>, s: S) -> Result<S::Ok, S::Error> {
+    url.as_deref().map(without_userinfo).serialize(s)
+}
+
+// `url` with the `user:password@` it may carry redacted
+fn without_userinfo(url: &str) -> String {
+    let (scheme, rest) = url.split_once("://").unwrap_or(("", url));
+    let authority = rest.split('/').next().unwrap_or_default();
+    match authority.rsplit_once('@') {
+        Some((_, host)) => {
+            let rest = &rest[authority.len()..];
+            match scheme {
+                "" => format!("{}@{}{}", REDACTED, host, rest),
+                scheme => format!("{}://{}@{}{}", scheme, REDACTED, host, rest),
+            }
+        }
+        None => url.to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveTime;
+
+    fn at(hour: u32, minute: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(hour, minute, 0).unwrap()
+    }
+
+    fn window(s: &str) -> Window {
+        Window::try_from(s.to_owned()).unwrap()
+    }
+
+    #[test]
+    fn window_within_day() {
+        let window = window("01:00-05:00");
+        assert!(window.contains(at(1, 0)));
+        assert!(window.contains(at(4, 59)));
+        assert!(!window.contains(at(5, 0)));
+        assert!(!window.contains(at(0, 59)));
+        assert!(!window.contains(at(23, 0)));
+    }
+
+    #[test]
+    fn window_across_midnight() {
+        let window = window("22:00-02:00");
+        assert!(window.contains(at(22, 0)));
+        assert!(window.contains(at(23, 59)));
+        assert!(window.contains(at(0, 0)));
+        assert!(window.contains(at(1, 59)));
+        assert!(!window.contains(at(2, 0)));
+        assert!(!window.contains(at(12, 0)));
+        assert!(!window.contains(at(21, 59)));
+    }
+
+    #[test]
+    fn window_parsing() {
+        assert_eq!(String::from(window(" 22:00 - 02:30 ")), "22:00-02:30");
+        assert!(Window::try_from("22:00".to_owned()).is_err());
+        assert!(Window::try_from("25:00-02:00".to_owned()).is_err());
+    }
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn flat_tags() {
+        assert_eq!(flat_tag("nginx"), "nginx_latest");
+        assert_eq!(flat_tag("library/nginx:1.25"), "nginx_1.25");
+        assert_eq!(flat_tag("bitnami/redis:7"), "bitnami_redis_7");
+        assert_eq!(
+            flat_tag("localhost:5000/team/app:1.0"),
+            "localhost_5000_team_app_1.0"
+        );
+        assert_eq!(
+            flat_tag(&format!("registry:5000/app@{}", DIGEST)),
+            format!("registry_5000_app_{}", DIGEST.replace(':', "_"))
+        );
+        // images of different registries stay apart
+        assert_ne!(flat_tag("ghcr.io/org/app:1"), flat_tag("quay.io/org/app:1"));
+    }
+
+    #[test]
+    fn long_flat_tags_are_hashed() {
+        let long = format!("ghcr.io/{}/app:1", "a".repeat(200));
+        let tag = flat_tag(&long);
+        assert_eq!(tag.len(), 128);
+        assert!(tag.starts_with("ghcr.io_aaaa"));
+        assert_ne!(tag, flat_tag(&format!("ghcr.io/{}/app:2", "a".repeat(200))));
+    }
+}
@@ -0,0 +1,313 @@
+use crate::cache::BlobCache;
+use crate::config::PolicyConfig;
+use crate::config::StripConfig;
+use crate::jobs::Phase;
+use crate::jobs::Progress;
+use crate::policy;
+use crate::registry::Descriptor;
+use crate::registry::Manifest;
+use crate::registry::Platform;
+use crate::registry::Registry;
+use crate::squash;
+use crate::strip;
+use anyhow::Context;
+use futures::StreamExt;
+use futures::TryStreamExt;
+use std::collections::BTreeMap;
+use tracing::event;
+use tracing::Level;
+
+/// Changes made to every image copied.
+#[derive(Debug, Clone, Default)]
+pub struct Rewrite {
+    /// Labels added to the image config.
+    pub labels: BTreeMap<String, String>,
+    /// Merge all layers into one.
+    pub squash: bool,
+    /// Build metadata removed before pushing.
+    pub strip: StripConfig,
+}
+
+impl Rewrite {
+    fn is_empty(&self) -> bool {
+        self.labels.is_empty() && !self.squash && self.strip.is_empty()
+    }
+}
+
+/// Copy `src_repo:reference` to `dst_repo:dst_tag` directly between
+/// registries, without a Docker daemon. Every platform of a multi-arch image
+/// is copied, or with `platforms` only those, under a trimmed index. Blobs go
+/// through the shared cache, so layers already on disk are
+/// not downloaded again; layers already in the destination, or in another of
+/// its repositories we know of, are not uploaded again. Up to `parallelism`
+/// blobs are moved at once.
+///
+/// Images that do not follow the OCI image spec or exceed the layer limits of
+/// `policy` are refused before anything is pushed. Images are changed on the
+/// way as `rewrite` says, which gives the copy new digests.
+#[allow(clippy::too_many_arguments)]
+pub async fn copy_image(
+    cache: &BlobCache,
+    src: &Registry,
+    src_repo: &str,
+    reference: &str,
+    dst: &Registry,
+    dst_repo: &str,
+    dst_tag: &str,
+    platforms: &[Platform],
+    policy: &PolicyConfig,
+    rewrite: &Rewrite,
+    parallelism: usize,
+    progress: &Progress,
+) -> anyhow::Result<Manifest> {
+    let mut manifest = src.get_manifest(src_repo, reference).await?;
+    // a trimmed index has a digest of its own
+    policy::check_digest(reference, &manifest)?;
+    if manifest.is_index() && !platforms.is_empty() {
+        manifest = select_platforms(&manifest, platforms)?;
+    }
+
+    let mut children = Vec::new();
+    if manifest.is_index() {
+        for child in manifest.parse()?.manifests {
+            children.push(src.get_manifest(src_repo, &child.digest).await?);
+        }
+    }
+    policy::validate(policy, cache, src, src_repo, &manifest, &children).await?;
+
+    if !rewrite.is_empty() {
+        if manifest.is_index() {
+            let mut index: serde_json::Value = serde_json::from_slice(&manifest.bytes)?;
+            strip::annotations(&mut index, &rewrite.strip);
+            for child in children.iter_mut() {
+                let mut entries = index["manifests"].as_array_mut().into_iter().flatten();
+                let entry = entries.find(|e| e["digest"] == child.digest.as_str());
+                // attestations hold documents, not file systems
+                let attestation = entry.as_ref().is_some_and(|e| {
+                    e["annotations"]["vnd.docker.reference.type"] == "attestation-manifest"
+                });
+                let rewritten =
+                    rewrite_image(cache, src, src_repo, child, rewrite, attestation, progress)
+                        .await?;
+                if let Some(entry) = entry {
+                    entry["digest"] = rewritten.digest.clone().into();
+                    entry["size"] = rewritten.bytes.len().into();
+                }
+                // keep attestations pointing at the image they describe
+                for entry in index["manifests"].as_array_mut().into_iter().flatten() {
+                    let described = entry
+                        .get_mut("annotations")
+                        .and_then(|a| a.get_mut("vnd.docker.reference.digest"));
+                    if let Some(described) = described.filter(|d| **d == child.digest.as_str()) {
+                        *described = rewritten.digest.clone().into();
+                    }
+                }
+                *child = rewritten;
+            }
+            manifest = Manifest::new(manifest.media_type, serde_json::to_vec(&index)?);
+        } else {
+            manifest =
+                rewrite_image(cache, src, src_repo, &manifest, rewrite, false, progress).await?;
+        }
+    }
+
+    let images = if manifest.is_index() {
+        children.iter().collect::<Vec<_>>()
+    } else {
+        vec![&manifest]
+    };
+
+    let mut blobs: Vec<(String, u64)> = Vec::new();
+    for image in &images {
+        let body = image.parse()?;
+        for blob in body.config.into_iter().chain(body.layers) {
+            if !blobs.iter().any(|(digest, _)| *digest == blob.digest) {
+                blobs.push((blob.digest, blob.size));
+            }
+        }
+    }
+
+    // skip blobs the destination has, mounting them from another of its
+    // repositories where possible
+    let mut missing = Vec::new();
+    for (digest, size) in &blobs {
+        if !has_or_mount(cache, src, src_repo, dst, dst_repo, digest).await? {
+            missing.push((digest, *size));
+        }
+    }
+
+    // each blob is pushed as soon as it is pulled; manifests only go up once
+    // every blob is in, so a failed pull leaves no image behind
+    progress.phase(Phase::Transferring);
+    for (digest, size) in &missing {
+        progress.update(digest, 0, size * 2);
+    }
+    let transfers: Vec<_> = missing
+        .iter()
+        .map(|(digest, size)| async move {
+            let path = cache.fetch_verified(src, src_repo, digest, *size).await?;
+            progress.update(digest, *size, size * 2);
+
+            // a job pushing the same blob at the same time leaves it in the
+            // destination to find or mount
+            let transfer = cache.claim_push(dst.base(), digest).await;
+            if !transfer.waited
+                || !has_or_mount(cache, src, src_repo, dst, dst_repo, digest).await?
+            {
+                dst.push_blob(dst_repo, digest, &path).await?;
+                cache.record(dst.base(), dst_repo, digest);
+            }
+            progress.update(digest, size * 2, size * 2);
+            anyhow::Ok(())
+        })
+        .collect();
+    futures::stream::iter(transfers)
+        .buffer_unordered(parallelism.max(1))
+        .try_collect::<()>()
+        .await?;
+
+    for child in &children {
+        dst.put_manifest(dst_repo, &child.digest, child).await?;
+    }
+    dst.put_manifest(dst_repo, dst_tag, &manifest).await?;
+    event!(Level::INFO, "image pushed...");
+
+    Ok(manifest)
+}
+
+// Whether the destination has the blob, possibly after mounting it from
+// another of its repositories.
+async fn has_or_mount(
+    cache: &BlobCache,
+    src: &Registry,
+    src_repo: &str,
+    dst: &Registry,
+    dst_repo: &str,
+    digest: &str,
+) -> anyhow::Result<bool> {
+    if dst.has_blob(dst_repo, digest).await? {
+        event!(Level::DEBUG, "blob {} already in destination", digest);
+        cache.record(dst.base(), dst_repo, digest);
+        return Ok(true);
+    }
+
+    let mut candidates = cache.locate(dst.base(), digest);
+    if src.base() == dst.base() {
+        candidates.insert(0, src_repo.to_owned());
+    }
+    for from in candidates.iter().filter(|r| r.as_str() != dst_repo) {
+        if dst.mount_blob(dst_repo, digest, from).await? {
+            cache.record(dst.base(), dst_repo, digest);
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+// `index` with only the images of `platforms`, all of which must be in it.
+fn select_platforms(index: &Manifest, platforms: &[Platform]) -> anyhow::Result<Manifest> {
+    let listed = index.parse()?.manifests;
+    let has = |wanted: &Platform, entry: &Descriptor| {
+        entry.platform.as_ref().is_some_and(|p| wanted.matches(p))
+    };
+    if let Some(missing) = platforms
+        .iter()
+        .find(|wanted| !listed.iter().any(|e| has(wanted, e)))
+    {
+        let available: Vec<String> = listed
+            .iter()
+            .filter_map(|e| e.platform.as_ref().map(|p| p.to_string()))
+            .collect();
+        anyhow::bail!(
+            "platform {} is not in the image, it has {}",
+            missing,
+            available.join(", ")
+        );
+    }
+    let keep: Vec<&str> = listed
+        .iter()
+        .filter(|e| platforms.iter().any(|wanted| has(wanted, e)))
+        .map(|e| e.digest.as_str())
+        .collect();
+
+    let mut body: serde_json::Value = serde_json::from_slice(&index.bytes)?;
+    if let Some(entries) = body["manifests"].as_array_mut() {
+        entries.retain(|e| e["digest"].as_str().is_some_and(|d| keep.contains(&d)));
+    }
+    Ok(Manifest::new(
+        index.media_type.clone(),
+        serde_json::to_vec(&body)?,
+    ))
+}
+
+// `image` changed as `rewrite` says.
+async fn rewrite_image(
+    cache: &BlobCache,
+    src: &Registry,
+    src_repo: &str,
+    image: &Manifest,
+    rewrite: &Rewrite,
+    attestation: bool,
+    progress: &Progress,
+) -> anyhow::Result<Manifest> {
+    let mut image = image.clone();
+    if rewrite.squash && !attestation {
+        progress.phase(Phase::Squashing);
+        image = squash::squash(cache, src, src_repo, &image).await?;
+    }
+    let strip = &rewrite.strip;
+    if !rewrite.labels.is_empty()
+        || strip.history
+        || !strip.env.is_empty()
+        || !strip.labels.is_empty()
+    {
+        image = edit_config(cache, src, src_repo, &image, rewrite).await?;
+    }
+    if !strip.annotations.is_empty() {
+        let mut body: serde_json::Value = serde_json::from_slice(&image.bytes)?;
+        strip::annotations(&mut body, strip);
+        image = Manifest::new(image.media_type, serde_json::to_vec(&body)?);
+    }
+    Ok(image)
+}
+
+// Image manifest pointing at a copy of its config with build metadata
+// stripped and labels added. The new config blob is only in the cache, so it
+// is pushed like a missing blob.
+async fn edit_config(
+    cache: &BlobCache,
+    src: &Registry,
+    src_repo: &str,
+    image: &Manifest,
+    rewrite: &Rewrite,
+) -> anyhow::Result<Manifest> {
+    let config = image.parse()?.config.context("manifest has no config")?;
+    let path = cache.fetch(src, src_repo, &config.digest).await?;
+    let mut blob: serde_json::Value = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+
+    // our own labels go on after stripping, so they always stay
+    strip::config(&mut blob, &rewrite.strip);
+    let image_config = blob
+        .as_object_mut()
+        .context("invalid image config")?
+        .entry("config")
+        .or_insert_with(|| serde_json::json!({}));
+    if !rewrite.labels.is_empty() && !image_config["Labels"].is_object() {
+        image_config["Labels"] = serde_json::json!({});
+    }
+    for (k, v) in &rewrite.labels {
+        image_config["Labels"][k] = v.clone().into();
+    }
+
+    let bytes = serde_json::to_vec(&blob)?;
+    let digest = cache.put(&bytes).await?;
+
+    let mut body: serde_json::Value = serde_json::from_slice(&image.bytes)?;
+    body["config"]["digest"] = digest.into();
+    body["config"]["size"] = bytes.len().into();
+
+    Ok(Manifest::new(
+        image.media_type.clone(),
+        serde_json::to_vec(&body)?,
+    ))
+}
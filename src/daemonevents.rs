@@ -0,0 +1,184 @@
+use crate::config::flat_tag;
+use crate::config::Config;
+use crate::config::Naming;
+use crate::events;
+use crate::jobs::Job;
+use crate::jobs::Jobs;
+use crate::jobs::State;
+use crate::metrics;
+use bollard::system::EventsOptions;
+use futures::StreamExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::event;
+use tracing::Level;
+
+// image actions of other processes that can undo or race a sync
+const ACTIONS: &[&str] = &["pull", "push", "tag", "untag", "delete"];
+
+/// Follow the image events of the Docker daemon and report those about
+/// images the service syncs: on `/events` as `daemon_event`, with the job
+/// they belong to, and, when no job of ours was running on the image, as a
+/// warning, as another process on the host is then at work on it. The
+/// stream is opened again whenever the daemon goes away.
+pub async fn watch(config: Arc<Config>, jobs: Arc<Jobs>) {
+    // image ids by the names they were seen under, as deletes only name ids
+    let mut names: HashMap<String, Vec<String>> = HashMap::new();
+    loop {
+        let docker = match crate::connect_docker() {
+            Ok(docker) => docker,
+            Err(e) => {
+                event!(Level::DEBUG, "daemon events unavailable: {:?}", e);
+                tokio::time::sleep(Duration::from_secs(30)).await;
+                continue;
+            }
+        };
+        let options = EventsOptions {
+            since: None,
+            until: None,
+            filters: HashMap::from([("type", vec!["image"]), ("event", ACTIONS.to_vec())]),
+        };
+        let mut stream = docker.events(Some(options));
+        while let Some(message) = stream.next().await {
+            let message = match message {
+                Ok(message) => message,
+                Err(e) => {
+                    event!(Level::DEBUG, "daemon event stream ended: {:?}", e);
+                    break;
+                }
+            };
+            let (Some(action), Some(actor)) = (message.action, message.actor) else {
+                continue;
+            };
+            let id = actor.id.unwrap_or_default();
+            let name = actor
+                .attributes
+                .and_then(|mut a| a.remove("name"))
+                .unwrap_or_else(|| id.clone());
+            // a name that is an image id stands for every name it had
+            let images = match name.starts_with("sha256:") {
+                true => names.get(&name).cloned().unwrap_or_default(),
+                false => vec![name.clone()],
+            };
+            for image in images {
+                report(&config, &jobs, &action, &image);
+                if action == "tag" || action == "pull" {
+                    let seen = names.entry(id.clone()).or_default();
+                    if !seen.contains(&image) {
+                        seen.push(image);
+                    }
+                }
+            }
+            if action == "delete" {
+                names.remove(&id);
+            }
+        }
+        tokio::time::sleep(Duration::from_secs(5)).await;
+    }
+}
+
+fn report(config: &Config, jobs: &Jobs, action: &str, image: &str) {
+    let image = normalize(image);
+    let list = jobs.list();
+    let mine: Vec<_> = list
+        .iter()
+        .filter(|job| {
+            normalize(&job.image) == image || destination(config, job).as_deref() == Some(&image)
+        })
+        .collect();
+    // flat naming tags the shared repository, nested naming repositories
+    // under it
+    let dest_repo = normalize_name(&config.dest_image_repo(&config.dest_repository));
+    let is_dest = image.starts_with(&format!("{}:", dest_repo))
+        || image.starts_with(&format!("{}/", dest_repo));
+    if mine.is_empty() && !is_dest {
+        return;
+    }
+    let running = mine.iter().find(|job| job.state == State::Running);
+    let job = running.or_else(|| mine.iter().max_by_key(|job| job.created_at));
+    let job_id = job.map(|job| job.id.clone());
+    // extra tags such as the digest-derived one of pinned syncs are not
+    // known up front, any running sync may have made them
+    let during_job = running.is_some()
+        || (is_dest && job.is_none() && list.iter().any(|job| job.state == State::Running));
+
+    metrics::DAEMON_EVENTS
+        .with_label_values(&[action, if during_job { "true" } else { "false" }])
+        .inc();
+    if !during_job {
+        event!(
+            Level::WARN,
+            "{} was {} by the Docker daemon outside of any sync, another process may be using it",
+            image,
+            past(action)
+        );
+    }
+    events::publish(
+        "daemon_event",
+        serde_json::json!({
+            "action": action,
+            "image": image,
+            "job_id": job_id,
+            "during_job": during_job,
+        }),
+    );
+}
+
+// The local name the daemon gives the destination of `job`: the one it
+// reported once finished, as the naming of its request says before.
+fn destination(config: &Config, job: &Job) -> Option<String> {
+    let (repository, tag) = match job.result.as_ref() {
+        Some(result) => {
+            let dest_image = result["dest_image"].as_str()?;
+            // flat naming reports the tag alone
+            match dest_image.rsplit_once(':') {
+                Some((repository, tag)) => (repository.to_owned(), tag.to_owned()),
+                None => (config.dest_repository.clone(), dest_image.to_owned()),
+            }
+        }
+        None => {
+            let naming = match job.request.get("naming") {
+                Some(naming) => naming.parse().ok()?,
+                None => config.dest_naming,
+            };
+            match naming {
+                Naming::Flat => (config.dest_repository.clone(), flat_tag(&job.image)),
+                Naming::Nested => config.nested_destination(&job.image),
+            }
+        }
+    };
+    Some(normalize(&format!(
+        "{}:{}",
+        config.dest_image_repo(&repository),
+        tag
+    )))
+}
+
+// `name:tag` as the daemon reports it: Docker Hub names without their
+// registry and `library/`, and with `latest` when no tag is given.
+fn normalize(image: &str) -> String {
+    if image.contains('@') {
+        return normalize_name(image);
+    }
+    let last = image.rsplit('/').next().unwrap_or(image);
+    match last.contains(':') {
+        true => normalize_name(image),
+        false => format!("{}:latest", normalize_name(image)),
+    }
+}
+
+fn normalize_name(image: &str) -> String {
+    let image = image.strip_prefix("docker.io/").unwrap_or(image);
+    image.strip_prefix("library/").unwrap_or(image).to_owned()
+}
+
+fn past(action: &str) -> &str {
+    match action {
+        "pull" => "pulled",
+        "push" => "pushed",
+        "tag" => "tagged",
+        "untag" => "untagged",
+        _ => "deleted",
+    }
+}
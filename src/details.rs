@@ -0,0 +1,134 @@
+use crate::cache::BlobCache;
+use crate::registry::Manifest;
+use crate::registry::Registry;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use tracing::event;
+use tracing::Level;
+
+// where builders record the image a build started from
+const BASE_NAME: &str = "org.opencontainers.image.base.name";
+const BASE_DIGEST: &str = "org.opencontainers.image.base.digest";
+
+/// How a synced image runs, from its config, so the result of a sync can
+/// be checked without inspecting the image.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImageDetails {
+    pub platform: String,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub entrypoint: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub cmd: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub exposed_ports: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub working_dir: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub created: Option<String>,
+    /// Image the build started from, when the builder recorded it.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub base_image: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub base_digest: Option<String>,
+}
+
+/// Details of an image config blob, or of anything shaped like one such as
+/// the Docker daemon's inspect output. Base image hints come from the
+/// manifest `annotations` or else the config labels.
+pub fn from_config(config: &Value, annotations: &Value) -> ImageDetails {
+    let strings = |v: &Value| -> Vec<String> {
+        v.as_array()
+            .map(|a| {
+                a.iter()
+                    .filter_map(|s| s.as_str().map(str::to_owned))
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+    let text = |v: &Value| v.as_str().filter(|s| !s.is_empty()).map(str::to_owned);
+    let run = &config["config"];
+    let hint = |key: &str| text(&annotations[key]).or_else(|| text(&run["Labels"][key]));
+
+    let mut platform = format!(
+        "{}/{}",
+        config["os"].as_str().unwrap_or_default(),
+        config["architecture"].as_str().unwrap_or_default()
+    );
+    if let Some(variant) = text(&config["variant"]) {
+        platform = format!("{}/{}", platform, variant);
+    }
+    let mut exposed_ports: Vec<String> = run["ExposedPorts"]
+        .as_object()
+        .map(|p| p.keys().cloned().collect())
+        .unwrap_or_default();
+    exposed_ports.sort();
+
+    ImageDetails {
+        platform,
+        entrypoint: strings(&run["Entrypoint"]),
+        cmd: strings(&run["Cmd"]),
+        exposed_ports,
+        user: text(&run["User"]),
+        working_dir: text(&run["WorkingDir"]),
+        created: text(&config["created"]),
+        base_image: hint(BASE_NAME),
+        base_digest: hint(BASE_DIGEST),
+    }
+}
+
+/// Details of every platform of `manifest`, just pushed to `repo` of `dst`
+/// with its configs still in `cache`. Missing details are only logged, as
+/// the image itself was synced.
+pub async fn collect(
+    cache: &BlobCache,
+    dst: &Registry,
+    repo: &str,
+    manifest: &Manifest,
+) -> Vec<ImageDetails> {
+    match try_collect(cache, dst, repo, manifest).await {
+        Ok(details) => details,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "could not read the config of {}: {:?}",
+                manifest.digest,
+                e
+            );
+            Vec::new()
+        }
+    }
+}
+
+async fn try_collect(
+    cache: &BlobCache,
+    dst: &Registry,
+    repo: &str,
+    manifest: &Manifest,
+) -> anyhow::Result<Vec<ImageDetails>> {
+    let mut images = Vec::new();
+    if manifest.is_index() {
+        let index: Value = serde_json::from_slice(&manifest.bytes)?;
+        for entry in index["manifests"].as_array().into_iter().flatten() {
+            if entry["annotations"]["vnd.docker.reference.type"] == "attestation-manifest" {
+                continue;
+            }
+            let digest = entry["digest"].as_str().unwrap_or_default();
+            images.push(dst.get_manifest(repo, digest).await?);
+        }
+    } else {
+        images.push(manifest.clone());
+    }
+
+    let mut details = Vec::new();
+    for image in images {
+        let body: Value = serde_json::from_slice(&image.bytes)?;
+        let digest = body["config"]["digest"].as_str().unwrap_or_default();
+        let config: Value =
+            serde_json::from_slice(&tokio::fs::read(cache.fetch(dst, repo, digest).await?).await?)?;
+        details.push(from_config(&config, &body["annotations"]));
+    }
+    Ok(details)
+}
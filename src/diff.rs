@@ -0,0 +1,221 @@
+use crate::cache::BlobCache;
+use crate::registry::Platform;
+use crate::registry::Registry;
+use anyhow::Context;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::collections::HashSet;
+
+/// What changed between two images, such as two syncs of a tag upstream
+/// moved.
+#[derive(Serialize, Debug)]
+pub struct ImageDiff {
+    pub old: Side,
+    pub new: Side,
+    pub identical: bool,
+    /// Bytes the new image's layers add up to more than the old one's.
+    pub size_change: i64,
+    pub layers: LayerDiff,
+    pub labels: MapDiff,
+    pub env: MapDiff,
+}
+
+/// One of the images compared.
+#[derive(Serialize, Debug)]
+pub struct Side {
+    pub image: String,
+    pub digest: String,
+    pub platform: String,
+    pub layers: usize,
+    pub size: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub created: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct Layer {
+    pub digest: String,
+    pub size: u64,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct LayerDiff {
+    pub added: Vec<Layer>,
+    pub removed: Vec<Layer>,
+    pub unchanged: usize,
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct MapDiff {
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub added: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub removed: BTreeMap<String, String>,
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub changed: BTreeMap<String, Change>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Change {
+    pub old: String,
+    pub new: String,
+}
+
+/// An image of one platform as read from its registry.
+pub struct Image {
+    pub side: Side,
+    layers: Vec<Layer>,
+    config: Value,
+}
+
+/// Read `reference` in `repo` of `registry`, picking `platform` out of an
+/// index, or its first image when none is given.
+pub async fn read(
+    cache: &BlobCache,
+    registry: &Registry,
+    image: &str,
+    repo: &str,
+    reference: &str,
+    platform: Option<&Platform>,
+) -> anyhow::Result<Image> {
+    let mut manifest = registry.get_manifest(repo, reference).await?;
+    if manifest.is_index() {
+        let index = manifest.parse()?;
+        let entry = index
+            .manifests
+            .iter()
+            .filter(|m| m.platform.as_ref().is_some_and(|p| p.os != "unknown"))
+            .find(|m| match (platform, &m.platform) {
+                (Some(wanted), Some(p)) => wanted.matches(p),
+                (None, _) => true,
+                _ => false,
+            })
+            .with_context(|| match platform {
+                Some(platform) => format!("{} has no image for {}", image, platform),
+                None => format!("{} lists no images", image),
+            })?;
+        manifest = registry.get_manifest(repo, &entry.digest).await?;
+    }
+
+    let body = manifest.parse()?;
+    let config = body.config.context("manifest has no config")?;
+    let config: Value = serde_json::from_slice(
+        &tokio::fs::read(cache.fetch(registry, repo, &config.digest).await?).await?,
+    )
+    .context("invalid image config")?;
+    let layers: Vec<Layer> = body
+        .layers
+        .iter()
+        .map(|l| Layer {
+            digest: l.digest.clone(),
+            size: l.size,
+        })
+        .collect();
+
+    let mut platform = format!(
+        "{}/{}",
+        config["os"].as_str().unwrap_or_default(),
+        config["architecture"].as_str().unwrap_or_default()
+    );
+    if let Some(variant) = config["variant"].as_str() {
+        platform = format!("{}/{}", platform, variant);
+    }
+    Ok(Image {
+        side: Side {
+            image: image.to_owned(),
+            digest: manifest.digest,
+            platform,
+            layers: layers.len(),
+            size: layers.iter().map(|l| l.size).sum(),
+            created: config["created"].as_str().map(str::to_owned),
+        },
+        layers,
+        config,
+    })
+}
+
+/// Compare `old` with `new`.
+pub fn diff(old: Image, new: Image) -> ImageDiff {
+    let old_digests: HashSet<&str> = old.layers.iter().map(|l| l.digest.as_str()).collect();
+    let new_digests: HashSet<&str> = new.layers.iter().map(|l| l.digest.as_str()).collect();
+    let layers = LayerDiff {
+        added: new
+            .layers
+            .iter()
+            .filter(|l| !old_digests.contains(l.digest.as_str()))
+            .cloned()
+            .collect(),
+        removed: old
+            .layers
+            .iter()
+            .filter(|l| !new_digests.contains(l.digest.as_str()))
+            .cloned()
+            .collect(),
+        unchanged: new
+            .layers
+            .iter()
+            .filter(|l| old_digests.contains(l.digest.as_str()))
+            .count(),
+    };
+
+    ImageDiff {
+        identical: old.side.digest == new.side.digest,
+        size_change: new.side.size as i64 - old.side.size as i64,
+        layers,
+        labels: compare(labels(&old.config), labels(&new.config)),
+        env: compare(env(&old.config), env(&new.config)),
+        old: old.side,
+        new: new.side,
+    }
+}
+
+fn labels(config: &Value) -> BTreeMap<String, String> {
+    config["config"]["Labels"]
+        .as_object()
+        .map(|labels| {
+            labels
+                .iter()
+                .map(|(k, v)| (k.clone(), v.as_str().unwrap_or_default().to_owned()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn env(config: &Value) -> BTreeMap<String, String> {
+    config["config"]["Env"]
+        .as_array()
+        .map(|env| {
+            env.iter()
+                .filter_map(|var| var.as_str())
+                .map(|var| match var.split_once('=') {
+                    Some((name, value)) => (name.to_owned(), value.to_owned()),
+                    None => (var.to_owned(), String::new()),
+                })
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+fn compare(old: BTreeMap<String, String>, mut new: BTreeMap<String, String>) -> MapDiff {
+    let mut diff = MapDiff::default();
+    for (key, old_value) in old {
+        match new.remove(&key) {
+            Some(new_value) if new_value != old_value => {
+                diff.changed.insert(
+                    key,
+                    Change {
+                        old: old_value,
+                        new: new_value,
+                    },
+                );
+            }
+            Some(_) => {}
+            None => {
+                diff.removed.insert(key, old_value);
+            }
+        }
+    }
+    diff.added = new;
+    diff
+}
@@ -0,0 +1,152 @@
+use crate::config::DiscoveryConfig;
+use crate::mirrorlist::Mirrors;
+use crate::registry;
+use anyhow::Context;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use tracing::event;
+use tracing::Level;
+
+/// List the Deployments and Pods of the cluster each interval and hand the
+/// images of those annotated for mirroring to `mirrors`. Images already
+/// pulled from the mirrors in `dest_repository` of `dest_registry` are left
+/// out. A failed listing keeps the images found before.
+pub async fn watch(
+    config: DiscoveryConfig,
+    dest_registry: String,
+    dest_repository: String,
+    mirrors: Arc<Mirrors>,
+) {
+    let mirrored = |image: &str| {
+        let (host, name, _) = registry::parse_reference(image);
+        host == dest_registry
+            && (name == dest_repository || name.starts_with(&format!("{}/", dest_repository)))
+    };
+    let client = match client(&config) {
+        Ok(client) => client,
+        Err(e) => {
+            event!(Level::ERROR, "kubernetes discovery disabled: {:#}", e);
+            return;
+        }
+    };
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        match discover(&config, &client).await {
+            Ok(found) => {
+                let found: BTreeMap<String, Vec<String>> = found
+                    .into_iter()
+                    .filter(|(image, _)| !mirrored(image))
+                    .collect();
+                mirrors.set_discovered(found);
+            }
+            Err(e) => event!(Level::ERROR, "kubernetes discovery failed: {:#}", e),
+        }
+    }
+}
+
+fn client(config: &DiscoveryConfig) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+    if config.ca_file.exists() {
+        let pem = std::fs::read(&config.ca_file)
+            .with_context(|| format!("failed to read {}", config.ca_file.display()))?;
+        for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+            builder = builder.add_root_certificate(cert);
+        }
+    }
+    Ok(builder.build()?)
+}
+
+fn api_server(config: &DiscoveryConfig) -> anyhow::Result<String> {
+    if let Some(url) = &config.api_server {
+        return Ok(url.trim_end_matches('/').to_owned());
+    }
+    let host = std::env::var("KUBERNETES_SERVICE_HOST")
+        .context("not running in Kubernetes and K8S_API_SERVER is unset")?;
+    let port = std::env::var("KUBERNETES_SERVICE_PORT").unwrap_or("443".to_owned());
+    // IPv6 service addresses need brackets
+    if host.contains(':') {
+        Ok(format!("https://[{}]:{}", host, port))
+    } else {
+        Ok(format!("https://{}:{}", host, port))
+    }
+}
+
+// Images of the annotated workloads, each with the workloads running it.
+async fn discover(
+    config: &DiscoveryConfig,
+    client: &reqwest::Client,
+) -> anyhow::Result<BTreeMap<String, Vec<String>>> {
+    let base = api_server(config)?;
+    // read each time, as service account tokens are rotated
+    let token = match tokio::fs::read_to_string(&config.token_file).await {
+        Ok(token) => Some(token.trim().to_owned()),
+        Err(_) if config.api_server.is_some() => None,
+        Err(e) => {
+            return Err(e)
+                .with_context(|| format!("failed to read {}", config.token_file.display()))
+        }
+    };
+
+    let mut found: BTreeMap<String, Vec<String>> = BTreeMap::new();
+    let scopes: Vec<String> = match config.namespaces.is_empty() {
+        true => vec![String::new()],
+        false => config
+            .namespaces
+            .iter()
+            .map(|ns| format!("/namespaces/{}", ns))
+            .collect(),
+    };
+    for scope in &scopes {
+        for (kind, path) in [
+            ("deployment", format!("/apis/apps/v1{}/deployments", scope)),
+            ("pod", format!("/api/v1{}/pods", scope)),
+        ] {
+            let mut request = client.get(format!("{}{}", base, path));
+            if let Some(token) = &token {
+                request = request.bearer_auth(token);
+            }
+            let response = request.send().await?;
+            if !response.status().is_success() {
+                anyhow::bail!("listing {} returned {}", path, response.status());
+            }
+            let list: Value = response.json().await.context("invalid object list")?;
+            for item in list["items"].as_array().into_iter().flatten() {
+                let metadata = &item["metadata"];
+                // Deployments opt in on themselves or on their pod template
+                let spec = match kind {
+                    "deployment" => &item["spec"]["template"]["spec"],
+                    _ => &item["spec"],
+                };
+                let annotated = [
+                    &metadata["annotations"][&config.annotation],
+                    &item["spec"]["template"]["metadata"]["annotations"][&config.annotation],
+                ]
+                .iter()
+                .any(|v| v.as_str() == Some("true"));
+                if !annotated {
+                    continue;
+                }
+                let workload = format!(
+                    "{}/{}/{}",
+                    metadata["namespace"].as_str().unwrap_or_default(),
+                    kind,
+                    metadata["name"].as_str().unwrap_or_default()
+                );
+                let containers = ["initContainers", "containers"]
+                    .into_iter()
+                    .flat_map(|key| spec[key].as_array().into_iter().flatten());
+                for container in containers {
+                    if let Some(image) = container["image"].as_str() {
+                        let workloads = found.entry(image.to_owned()).or_default();
+                        if !workloads.contains(&workload) {
+                            workloads.push(workload.clone());
+                        }
+                    }
+                }
+            }
+        }
+    }
+    Ok(found)
+}
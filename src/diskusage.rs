@@ -0,0 +1,38 @@
+use crate::metrics;
+use std::time::Duration;
+use tracing::event;
+use tracing::Level;
+
+/// Sample the disk usage of the Docker daemon (`docker system df`) every
+/// `interval` into the image storage gauges, so capacity alerts can fire
+/// before syncs fail for want of space. A daemon that cannot be reached
+/// leaves the gauges at their last values until it answers again.
+pub async fn sample(interval: Duration) {
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        let usage = match crate::connect_docker() {
+            Ok(docker) => docker.df().await,
+            Err(e) => Err(e),
+        };
+        let usage = match usage {
+            Ok(usage) => usage,
+            Err(e) => {
+                event!(Level::DEBUG, "daemon disk usage unavailable: {:?}", e);
+                continue;
+            }
+        };
+        let images = usage.images.unwrap_or_default();
+        let total = usage.layers_size.unwrap_or(0);
+        // reclaimable as `docker system df` counts it: all but the layers
+        // that only images in use by a container hold
+        let used: i64 = images
+            .iter()
+            .filter(|i| i.containers > 0 && i.size >= 0 && i.shared_size >= 0)
+            .map(|i| i.size - i.shared_size)
+            .sum();
+        metrics::DAEMON_IMAGES.set(images.len() as i64);
+        metrics::DAEMON_IMAGES_BYTES.set(total);
+        metrics::DAEMON_IMAGES_RECLAIMABLE_BYTES.set((total - used).max(0));
+    }
+}
@@ -0,0 +1,507 @@
+use crate::cache::Corrupt;
+use crate::policy::Violation;
+use crate::registry::StatusError;
+use warp::hyper::StatusCode;
+use warp::reject::Reject;
+
+/// Why a request failed, with the image it was about and the underlying
+/// error where there is one.
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// The request is missing the image or has a malformed parameter.
+    #[error("Invalid request: {0}")]
+    Parse(String),
+    /// The request body is over the configured limit.
+    #[error("Request too large: {0}")]
+    TooLarge(String),
+    #[error("Job {0} not found")]
+    JobNotFound(String),
+    #[error("Batch {0} not found")]
+    BatchNotFound(String),
+    /// The job could not be written to the state directory, so it was not
+    /// accepted.
+    #[error("Failed to record the job")]
+    JobNotRecorded(#[source] anyhow::Error),
+    #[error("No origin is known for {0}")]
+    OriginNotFound(String),
+    /// The job asked to be retried has not failed, or is being retried.
+    #[error("Job {0} cannot be retried: {1}")]
+    NotRetryable(String, String),
+    #[error("Admin token missing or wrong")]
+    Unauthorized,
+    /// A webhook delivery that is unsigned, signed with another secret, too
+    /// old, or seen before.
+    #[error("Webhook rejected: {0}")]
+    Signature(String),
+    /// A sync link that is not signed with the secret, expired, or used.
+    #[error("Sync link rejected: {0}")]
+    LinkRejected(String),
+    /// The Docker daemon cannot be reached.
+    #[error("Docker daemon is unavailable")]
+    DaemonUnavailable(#[source] bollard::errors::Error),
+    /// The Docker daemon failed an operation on `image`.
+    #[error("Docker daemon failed on {image}")]
+    Daemon {
+        image: String,
+        #[source]
+        source: bollard::errors::Error,
+    },
+    /// A registry refused our credentials.
+    #[error("Registry refused the credentials for {image}")]
+    Auth {
+        image: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// Every source says it has no such image or tag, found before pulling.
+    #[error("Image {0} not found in its source registry")]
+    ImageNotFound(String),
+    /// No source could be pulled from; `source` is the last failure.
+    #[error("Failed to pull image {image}")]
+    Pull {
+        image: String,
+        #[source]
+        source: Option<bollard::errors::Error>,
+    },
+    #[error("Failed to push image {image}")]
+    Push {
+        image: String,
+        #[source]
+        source: bollard::errors::Error,
+    },
+    /// A registry request made without the daemon failed.
+    #[error("Registry request for {image} failed")]
+    Registry {
+        image: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// A layer of `image` did not arrive with the digest and size its
+    /// manifest gives.
+    #[error("Image {image} has a corrupt layer")]
+    CorruptLayer {
+        image: String,
+        #[source]
+        source: Corrupt,
+    },
+    #[error("Failed to label image {image}")]
+    Label {
+        image: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to export image {image}")]
+    Export {
+        image: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// Named credentials that are configured but cannot be read.
+    #[error("Failed to read credentials {name}")]
+    Credentials {
+        name: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    #[error("Failed to build bundle")]
+    Bundle(#[source] anyhow::Error),
+    #[error("Failed to profile")]
+    Profile(#[source] anyhow::Error),
+    #[error("Failed to remove {image}")]
+    Cleanup {
+        image: String,
+        #[source]
+        source: bollard::errors::Error,
+    },
+    /// What the destination serves is not what was pushed.
+    #[error("Image {image} did not read back as pushed")]
+    ReadBack {
+        image: String,
+        #[source]
+        source: anyhow::Error,
+    },
+    /// Policy: malformed images are not pushed.
+    #[error("Image {image} was rejected")]
+    Policy {
+        image: String,
+        #[source]
+        source: Violation,
+    },
+    /// Policy: released tags do not move.
+    #[error("Tag {0} already exists with a different image, use force=true to overwrite")]
+    TagConflict(String),
+    /// Policy: tags that move are not mirrored by accident.
+    #[error("Tag {0} is mutable, use allow_mutable=true to sync it anyway")]
+    MutableTag(String),
+    /// Policy: only what was pinned is synced.
+    #[error("Image {0} is not referenced by digest, which DIGEST_ONLY requires")]
+    DigestRequired(String),
+    /// Policy: registries without verified TLS must be allowed by the daemon
+    /// as well.
+    #[error("Registry {0} is configured as insecure but the Docker daemon does not list it in insecure-registries")]
+    InsecureRegistry(String),
+}
+
+impl Reject for Error {}
+
+/// What went wrong upstream, as the Docker daemon words its errors.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DaemonFailure {
+    Unauthorized,
+    NotFound,
+    RateLimited,
+    NoSpace,
+}
+
+impl DaemonFailure {
+    /// Read the message of a daemon error, `None` for ones that name no
+    /// cause we tell apart.
+    pub fn of(error: &bollard::errors::Error) -> Option<DaemonFailure> {
+        let (status, message) = match error {
+            bollard::errors::Error::DockerStreamError { error } => (None, error.as_str()),
+            bollard::errors::Error::DockerResponseServerError {
+                status_code,
+                message,
+            } => (Some(*status_code), message.as_str()),
+            _ => return None,
+        };
+        let message = message.to_lowercase();
+        let says = |words: &[&str]| words.iter().any(|w| message.contains(w));
+        // checked in this order: Docker Hub refuses pulls of repositories
+        // that do not exist with "pull access denied"
+        if says(&["no space left on device", "not enough space"]) {
+            Some(DaemonFailure::NoSpace)
+        } else if says(&["toomanyrequests", "too many requests", "rate limit"]) {
+            Some(DaemonFailure::RateLimited)
+        } else if says(&[
+            "manifest unknown",
+            "name unknown",
+            "repository does not exist",
+            "not found",
+        ]) {
+            Some(DaemonFailure::NotFound)
+        } else if says(&["unauthorized", "denied", "authentication required"])
+            || status == Some(401)
+        {
+            Some(DaemonFailure::Unauthorized)
+        } else {
+            None
+        }
+    }
+
+    fn as_str(self) -> &'static str {
+        match self {
+            DaemonFailure::Unauthorized => "unauthorized",
+            DaemonFailure::NotFound => "not_found",
+            DaemonFailure::RateLimited => "rate_limited",
+            DaemonFailure::NoSpace => "no_space",
+        }
+    }
+}
+
+impl Error {
+    /// A failed registry request for `image`, as an auth error when the
+    /// registry refused our credentials, a policy error when the image
+    /// was refused before pushing, or a corrupt layer when a blob did not
+    /// arrive intact.
+    pub fn registry(image: &str, source: anyhow::Error) -> Error {
+        let source = match source.downcast::<Violation>() {
+            Ok(violation) => {
+                return Error::Policy {
+                    image: image.to_owned(),
+                    source: violation,
+                }
+            }
+            Err(source) => source,
+        };
+        let source = match source.downcast::<Corrupt>() {
+            Ok(corrupt) => {
+                return Error::CorruptLayer {
+                    image: image.to_owned(),
+                    source: corrupt,
+                }
+            }
+            Err(source) => source,
+        };
+        let refused = source.chain().any(|e| {
+            e.downcast_ref::<StatusError>().is_some_and(|e| {
+                e.status == StatusCode::UNAUTHORIZED || e.status == StatusCode::FORBIDDEN
+            })
+        });
+        let image = image.to_owned();
+        if refused {
+            Error::Auth { image, source }
+        } else {
+            Error::Registry { image, source }
+        }
+    }
+
+    /// A failed daemon push of `image`, as an auth error when the registry
+    /// refused our credentials.
+    pub fn push(image: String, source: bollard::errors::Error) -> Error {
+        let refused = match &source {
+            bollard::errors::Error::DockerStreamError { error } => {
+                error.contains("unauthorized") || error.contains("denied")
+            }
+            _ => false,
+        };
+        if refused {
+            Error::Auth {
+                image,
+                source: source.into(),
+            }
+        } else {
+            Error::Push { image, source }
+        }
+    }
+
+    /// Short name of the kind of failure, kept with failed jobs to find
+    /// them by: `pull_` or `push_` and what went wrong for registry
+    /// failures, so `push_unauthorized` when `dest_registry` refused our
+    /// credentials.
+    pub fn code(&self, dest_registry: &str) -> String {
+        let code = match self {
+            Error::Parse(_) => "invalid_request",
+            Error::TooLarge(_) => "too_large",
+            Error::JobNotFound(_) => "job_not_found",
+            Error::BatchNotFound(_) => "batch_not_found",
+            Error::JobNotRecorded(_) => "job_not_recorded",
+            Error::OriginNotFound(_) => "origin_not_found",
+            Error::NotRetryable(..) => "not_retryable",
+            Error::Unauthorized => "unauthorized",
+            Error::Signature(_) => "signature_rejected",
+            Error::LinkRejected(_) => "link_rejected",
+            Error::DaemonUnavailable(_) => "daemon_unavailable",
+            Error::Auth { .. } | Error::Registry { .. } => {
+                return self.registry_code(dest_registry)
+            }
+            Error::ImageNotFound(_) => "image_not_found",
+            Error::Pull { .. } | Error::Push { .. } | Error::Daemon { .. } => {
+                return self.daemon_code()
+            }
+            Error::ReadBack { .. } => "read_back_failed",
+            Error::CorruptLayer { .. } => "layer_corrupt",
+            Error::Label { .. } => "label_failed",
+            Error::Export { .. } => "export_failed",
+            Error::Credentials { .. } => "credentials_unreadable",
+            Error::Bundle(_) => "bundle_failed",
+            Error::Profile(_) => "profile_failed",
+            Error::Cleanup { .. } => "cleanup_failed",
+            Error::Policy { .. } => "policy_rejected",
+            Error::TagConflict(_) => "tag_conflict",
+            Error::MutableTag(_) => "mutable_tag",
+            Error::DigestRequired(_) => "digest_required",
+            Error::InsecureRegistry(_) => "insecure_registry",
+        };
+        code.to_owned()
+    }
+
+    // `code` of a registry failure: a push when the daemon pushed or the
+    // refusing request went to `dest_registry` for another repository than
+    // the source image's, a pull otherwise.
+    fn registry_code(&self, dest_registry: &str) -> String {
+        let (Error::Auth { image, .. } | Error::Registry { image, .. }) = self else {
+            return "failed".to_owned();
+        };
+        let (host, name, _) = crate::registry::parse_reference(image);
+        let pulled = format!("{}/v2/{}/", crate::registry::base_url(&host), name);
+        let dest = format!("{}/", crate::registry::base_url(dest_registry));
+        let mut push = false;
+        let mut status = None;
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            if let Some(e) = e.downcast_ref::<StatusError>() {
+                push = e.url.starts_with(&dest) && !e.url.starts_with(&pulled);
+                status = Some(e.status);
+            }
+            if e.downcast_ref::<bollard::errors::Error>().is_some() {
+                push = matches!(self, Error::Auth { .. });
+            }
+            source = e.source();
+        }
+        let kind = match (self, status) {
+            (Error::Auth { .. }, _) => "unauthorized",
+            (_, Some(StatusCode::NOT_FOUND)) => "not_found",
+            (_, Some(StatusCode::TOO_MANY_REQUESTS)) => "rate_limited",
+            _ => "failed",
+        };
+        format!("{}_{}", if push { "push" } else { "pull" }, kind)
+    }
+
+    // `code` of a daemon failure: `pull_` or `push_` and what the daemon
+    // says went wrong upstream, `no_space` when it ran out of disk.
+    fn daemon_code(&self) -> String {
+        let (kind, failed) = match self {
+            Error::Pull { .. } => ("pull", "pull_failed"),
+            Error::Push { .. } => ("push", "push_failed"),
+            _ => ("daemon", "daemon_failed"),
+        };
+        match (self.daemon_failure(), self) {
+            (Some(DaemonFailure::NoSpace), _) => "no_space".to_owned(),
+            (Some(failure), Error::Pull { .. } | Error::Push { .. }) => {
+                format!("{}_{}", kind, failure.as_str())
+            }
+            _ => failed.to_owned(),
+        }
+    }
+
+    /// What the daemon said went wrong, for errors of daemon operations.
+    pub fn daemon_failure(&self) -> Option<DaemonFailure> {
+        let source = match self {
+            Error::Pull { source, .. } => source.as_ref()?,
+            Error::Push { source, .. } | Error::Daemon { source, .. } => source,
+            _ => return None,
+        };
+        DaemonFailure::of(source)
+    }
+
+    pub fn status(&self) -> StatusCode {
+        if self.daemon_unreachable() {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        // the daemon out of disk whatever it did; for pulls and pushes,
+        // what the registry told it
+        match (self.daemon_failure(), self) {
+            (Some(DaemonFailure::NoSpace), _) => return StatusCode::INSUFFICIENT_STORAGE,
+            (Some(DaemonFailure::NotFound), Error::Pull { .. } | Error::Push { .. }) => {
+                return StatusCode::NOT_FOUND
+            }
+            (Some(DaemonFailure::RateLimited), Error::Pull { .. } | Error::Push { .. }) => {
+                return StatusCode::TOO_MANY_REQUESTS
+            }
+            _ => {}
+        }
+        match self {
+            Error::Parse(_) => StatusCode::BAD_REQUEST,
+            Error::TooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            Error::JobNotFound(_)
+            | Error::BatchNotFound(_)
+            | Error::OriginNotFound(_)
+            | Error::ImageNotFound(_) => StatusCode::NOT_FOUND,
+            Error::Unauthorized | Error::Signature(_) => StatusCode::UNAUTHORIZED,
+            Error::LinkRejected(_) => StatusCode::FORBIDDEN,
+            Error::TagConflict(_) | Error::NotRetryable(..) => StatusCode::CONFLICT,
+            Error::Policy { .. } | Error::MutableTag(_) | Error::DigestRequired(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::Auth { .. }
+            | Error::Pull { .. }
+            | Error::Push { .. }
+            | Error::ReadBack { .. }
+            | Error::CorruptLayer { .. }
+            | Error::Registry { .. } => StatusCode::BAD_GATEWAY,
+            Error::DaemonUnavailable(_) | Error::JobNotRecorded(_) => {
+                StatusCode::SERVICE_UNAVAILABLE
+            }
+            Error::Daemon { .. }
+            | Error::Label { .. }
+            | Error::Export { .. }
+            | Error::Credentials { .. }
+            | Error::Bundle(_)
+            | Error::Profile(_)
+            | Error::Cleanup { .. }
+            | Error::InsecureRegistry(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Whether any cause is the daemon socket refusing or dropping us, as
+    // while the daemon restarts.
+    fn daemon_unreachable(&self) -> bool {
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            match e.downcast_ref::<bollard::errors::Error>() {
+                Some(bollard::errors::Error::HyperResponseError { err }) if err.is_connect() => {
+                    return true
+                }
+                Some(bollard::errors::Error::IOError { .. })
+                | Some(bollard::errors::Error::RequestTimeoutError) => return true,
+                _ => {}
+            }
+            source = e.source();
+        }
+        false
+    }
+
+    /// The message followed by every underlying cause. Causes that already
+    /// print their own source are not repeated.
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut source = std::error::Error::source(self);
+        while let Some(e) = source {
+            // stream errors leave what the daemon said out of their message
+            let message = match e.downcast_ref::<bollard::errors::Error>() {
+                Some(bollard::errors::Error::DockerStreamError { error }) => {
+                    format!("{}: {}", e, error)
+                }
+                _ => e.to_string(),
+            };
+            if !report.contains(&message) {
+                report.push_str(": ");
+                report.push_str(&message);
+            }
+            source = e.source();
+        }
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn stream(error: &str) -> Option<DaemonFailure> {
+        DaemonFailure::of(&bollard::errors::Error::DockerStreamError {
+            error: error.to_owned(),
+        })
+    }
+
+    fn response(status_code: u16, message: &str) -> Option<DaemonFailure> {
+        DaemonFailure::of(&bollard::errors::Error::DockerResponseServerError {
+            status_code,
+            message: message.to_owned(),
+        })
+    }
+
+    #[test]
+    fn daemon_failures() {
+        assert_eq!(
+            stream("write /var/lib/docker/tmp/layer: no space left on device"),
+            Some(DaemonFailure::NoSpace)
+        );
+        assert_eq!(
+            stream("toomanyrequests: You have reached your pull rate limit"),
+            Some(DaemonFailure::RateLimited)
+        );
+        assert_eq!(
+            stream("manifest for nginx:nope not found: manifest unknown"),
+            Some(DaemonFailure::NotFound)
+        );
+        assert_eq!(
+            response(401, "Get https://registry/v2/: unauthorized"),
+            Some(DaemonFailure::Unauthorized)
+        );
+        assert_eq!(response(401, ""), Some(DaemonFailure::Unauthorized));
+        assert_eq!(stream("unexpected EOF"), None);
+        assert_eq!(response(500, "internal error"), None);
+        assert_eq!(
+            DaemonFailure::of(&bollard::errors::Error::RequestTimeoutError),
+            None
+        );
+    }
+
+    #[test]
+    fn missing_repository_is_not_found() {
+        // Docker Hub words pulls of repositories that do not exist as denied
+        assert_eq!(
+            response(
+                404,
+                "pull access denied for nope/nope, repository does not exist or may require 'docker login'"
+            ),
+            Some(DaemonFailure::NotFound)
+        );
+        assert_eq!(
+            stream("pull access denied for private/app"),
+            Some(DaemonFailure::Unauthorized)
+        );
+    }
+}
@@ -0,0 +1,67 @@
+use chrono::DateTime;
+use chrono::Utc;
+use futures::Stream;
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::HashSet;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// A server-wide event, as sent on `/events`.
+#[derive(Serialize, Debug, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub time: DateTime<Utc>,
+    #[serde(rename = "type")]
+    pub kind: &'static str,
+    pub data: Value,
+}
+
+static NEXT: AtomicU64 = AtomicU64::new(1);
+
+// subscribers further behind than this miss events; they share each event
+// rather than getting a copy of its data
+static CHANNEL: Lazy<broadcast::Sender<Arc<Event>>> = Lazy::new(|| broadcast::channel(1024).0);
+
+/// Send an event of type `kind` to every subscriber, if there are any.
+pub fn publish(kind: &'static str, data: Value) {
+    if CHANNEL.receiver_count() == 0 {
+        return;
+    }
+    let _ = CHANNEL.send(Arc::new(Event {
+        id: NEXT.fetch_add(1, Ordering::Relaxed),
+        time: Utc::now(),
+        kind,
+        data,
+    }));
+}
+
+/// Events published from now on, only those of `types` when given. A
+/// subscriber that falls behind gets a `lagged` event saying how many it
+/// missed.
+pub fn subscribe(types: Option<HashSet<String>>) -> impl Stream<Item = Arc<Event>> {
+    futures::stream::unfold(CHANNEL.subscribe(), move |mut rx| {
+        let types = types.clone();
+        async move {
+            loop {
+                let event = match rx.recv().await {
+                    Ok(event) => event,
+                    Err(RecvError::Lagged(missed)) => Arc::new(Event {
+                        id: 0,
+                        time: Utc::now(),
+                        kind: "lagged",
+                        data: serde_json::json!({ "missed": missed }),
+                    }),
+                    Err(RecvError::Closed) => return None,
+                };
+                if event.kind == "lagged" || types.as_ref().is_none_or(|t| t.contains(event.kind)) {
+                    return Some((event, rx));
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,156 @@
+use crate::config::ExportConfig;
+use anyhow::Context;
+use bollard::Docker;
+use futures::stream::StreamExt;
+use serde::Deserialize;
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::io;
+use std::path::Path;
+use tokio::io::AsyncWriteExt;
+use tracing::event;
+use tracing::Level;
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_CONFIG: &str = "application/vnd.oci.image.config.v1+json";
+const OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar";
+
+// entry of the manifest.json written by `docker save`
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SaveManifest {
+    config: String,
+    layers: Vec<String>,
+}
+
+/// Export `image` as an OCI image layout named `name` to every configured
+/// target, returning the locations that were written.
+pub async fn export_image(
+    docker: &Docker,
+    config: &ExportConfig,
+    image: &str,
+    name: &str,
+) -> anyhow::Result<Vec<String>> {
+    let tmp = tempfile::tempdir()?;
+    let archive = tmp.path().join("image.tar");
+
+    // download the `docker save` archive
+    let mut file = tokio::fs::File::create(&archive).await?;
+    let mut stream = docker.export_image(image);
+    while let Some(chunk) = stream.next().await {
+        file.write_all(&chunk?).await?;
+    }
+    file.flush().await?;
+    event!(Level::INFO, "image saved...");
+
+    let layout = match &config.dir {
+        Some(dir) => dir.join(name),
+        None => tmp.path().join("layout"),
+    };
+
+    let (unpack, layout_dir, ref_name) = (tmp.path().join("save"), layout.clone(), name.to_owned());
+    tokio::task::spawn_blocking(move || write_layout(&archive, &unpack, &layout_dir, &ref_name))
+        .await??;
+
+    let mut locations = Vec::new();
+    if config.dir.is_some() {
+        event!(Level::INFO, "image exported to {}", layout.display());
+        locations.push(layout.display().to_string());
+    }
+
+    if let Some(s3) = &config.s3 {
+        let tarball = tmp.path().join("layout.tar");
+        let (src, dst) = (layout.clone(), tarball.clone());
+        tokio::task::spawn_blocking(move || tar_dir(&src, &dst)).await??;
+
+        let key = format!("{}{}.tar", s3.prefix, name);
+        crate::s3::put_object(s3, &key, &tarball).await?;
+        event!(Level::INFO, "image uploaded to s3://{}/{}", s3.bucket, key);
+        locations.push(format!("s3://{}/{}", s3.bucket, key));
+    }
+
+    Ok(locations)
+}
+
+// Rewrite a `docker save` archive as an OCI image layout in `layout`.
+fn write_layout(
+    archive: &Path,
+    unpack: &Path,
+    layout: &Path,
+    ref_name: &str,
+) -> anyhow::Result<()> {
+    tar::Archive::new(fs::File::open(archive)?).unpack(unpack)?;
+
+    let manifest =
+        fs::read(unpack.join("manifest.json")).context("archive has no manifest.json")?;
+    let manifest: Vec<SaveManifest> = serde_json::from_slice(&manifest)?;
+    let manifest = manifest
+        .into_iter()
+        .next()
+        .context("archive manifest.json is empty")?;
+
+    if layout.exists() {
+        fs::remove_dir_all(layout)?;
+    }
+    let blobs = layout.join("blobs").join("sha256");
+    fs::create_dir_all(&blobs)?;
+
+    let config = move_blob(&unpack.join(&manifest.config), &blobs, OCI_CONFIG)?;
+    let layers = manifest
+        .layers
+        .iter()
+        .map(|layer| move_blob(&unpack.join(layer), &blobs, OCI_LAYER))
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    let manifest = serde_json::to_vec(&json!({
+        "schemaVersion": 2,
+        "mediaType": OCI_MANIFEST,
+        "config": config,
+        "layers": layers,
+    }))?;
+    let digest = hex::encode(Sha256::digest(&manifest));
+    fs::write(blobs.join(&digest), &manifest)?;
+
+    let index = json!({
+        "schemaVersion": 2,
+        "manifests": [{
+            "mediaType": OCI_MANIFEST,
+            "digest": format!("sha256:{}", digest),
+            "size": manifest.len(),
+            "annotations": { "org.opencontainers.image.ref.name": ref_name },
+        }],
+    });
+    fs::write(layout.join("index.json"), serde_json::to_vec(&index)?)?;
+    fs::write(
+        layout.join("oci-layout"),
+        br#"{"imageLayoutVersion":"1.0.0"}"#,
+    )?;
+
+    Ok(())
+}
+
+// Move a file into the blob store and return its descriptor.
+fn move_blob(path: &Path, blobs: &Path, media_type: &str) -> anyhow::Result<serde_json::Value> {
+    let mut hasher = Sha256::new();
+    let size = io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    let digest = hex::encode(hasher.finalize());
+
+    let target = blobs.join(&digest);
+    if !target.exists() {
+        fs::rename(path, &target)?;
+    }
+
+    Ok(json!({
+        "mediaType": media_type,
+        "digest": format!("sha256:{}", digest),
+        "size": size,
+    }))
+}
+
+fn tar_dir(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    let mut builder = tar::Builder::new(fs::File::create(dst)?);
+    builder.append_dir_all(".", src)?;
+    builder.finish()?;
+    Ok(())
+}
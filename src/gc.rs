@@ -0,0 +1,283 @@
+use crate::config::GcConfig;
+use crate::config::GcRegistry;
+use crate::events;
+use crate::http;
+use crate::metrics;
+use crate::registry;
+use crate::s3;
+use anyhow::Context;
+use reqwest::StatusCode;
+use sha2::Digest;
+use sha2::Sha256;
+use std::sync::Arc;
+use tracing::event;
+use tracing::Level;
+
+// ECR deletes at most this many images per call
+const ECR_BATCH: usize = 100;
+
+/// Removes the manifests no tag points at any more from the destination,
+/// left behind by retention and by syncs that moved a tag, through the API
+/// of the registry product: the registry API itself cannot list them.
+pub struct Collector {
+    registry: GcRegistry,
+    base_url: String,
+    credentials: (String, String),
+    repository: String,
+    ecr: Option<Ecr>,
+}
+
+// the ECR registry the destination host names, and how to call its API
+struct Ecr {
+    registry_id: String,
+    region: String,
+    endpoint: String,
+    access_key: String,
+    secret_key: String,
+    session_token: Option<String>,
+}
+
+impl Collector {
+    /// A collector for `repository` of `dest_registry`, logging in with
+    /// `credentials` to Harbor, or `None` when `config` turns it off.
+    pub fn new(
+        config: &GcConfig,
+        dest_registry: &str,
+        credentials: (String, String),
+        repository: String,
+    ) -> anyhow::Result<Option<Collector>> {
+        let registry = match config.registry {
+            Some(registry) => registry,
+            None => return Ok(None),
+        };
+        let ecr = match registry {
+            GcRegistry::Harbor => None,
+            GcRegistry::Ecr => {
+                let (registry_id, region, domain) = parse_ecr_host(dest_registry)
+                    .with_context(|| format!("{} is not an ECR registry", dest_registry))?;
+                Some(Ecr {
+                    registry_id,
+                    endpoint: format!("https://api.ecr.{}.{}", region, domain),
+                    region,
+                    access_key: config
+                        .access_key
+                        .clone()
+                        .context("AWS_ACCESS_KEY_ID is not set")?,
+                    secret_key: config
+                        .secret_key
+                        .clone()
+                        .context("AWS_SECRET_ACCESS_KEY is not set")?,
+                    session_token: config.session_token.clone(),
+                })
+            }
+        };
+        Ok(Some(Collector {
+            registry,
+            base_url: registry::base_url(dest_registry),
+            credentials,
+            repository,
+            ecr,
+        }))
+    }
+
+    /// Collect now, logging and publishing the outcome.
+    pub async fn collect(&self) {
+        let result = match &self.ecr {
+            Some(ecr) => self.delete_untagged(ecr).await.map(Some),
+            None => self.start_harbor_gc().await.map(|_| None),
+        };
+        match result {
+            Ok(deleted) => {
+                match deleted {
+                    Some(deleted) => event!(
+                        Level::INFO,
+                        "gc deleted {} untagged manifests from {}",
+                        deleted,
+                        self.repository
+                    ),
+                    None => event!(Level::INFO, "started {:?} gc", self.registry),
+                }
+                events::publish(
+                    "prune_finished",
+                    serde_json::json!({
+                        "kind": "gc",
+                        "repository": self.repository,
+                        "removed": deleted,
+                    }),
+                );
+            }
+            Err(e) => event!(Level::ERROR, "gc of {} failed: {:?}", self.repository, e),
+        }
+    }
+
+    // Harbor collects on its own once started, removing untagged artifacts
+    // of every project.
+    async fn start_harbor_gc(&self) -> anyhow::Result<()> {
+        let url = format!("{}/api/v2.0/system/gc/schedule", self.base_url);
+        let resp = http::client(&self.base_url)
+            .post(&url)
+            .basic_auth(&self.credentials.0, Some(&self.credentials.1))
+            .json(&serde_json::json!({
+                "schedule": { "type": "Manual" },
+                "parameters": { "delete_untagged": true, "dry_run": false },
+            }))
+            .send()
+            .await?;
+        match resp.status() {
+            status if status.is_success() => Ok(()),
+            StatusCode::CONFLICT => {
+                event!(Level::INFO, "Harbor gc is already running");
+                Ok(())
+            }
+            status => {
+                let body = resp.text().await.unwrap_or_default();
+                anyhow::bail!("{} returned {}: {}", url, status, body)
+            }
+        }
+    }
+
+    async fn delete_untagged(&self, ecr: &Ecr) -> anyhow::Result<usize> {
+        let mut untagged = Vec::new();
+        let mut next_token: Option<String> = None;
+        loop {
+            let mut query = serde_json::json!({
+                "registryId": ecr.registry_id,
+                "repositoryName": self.repository,
+                "filter": { "tagStatus": "UNTAGGED" },
+                "maxResults": 1000,
+            });
+            if let Some(token) = next_token {
+                query["nextToken"] = token.into();
+            }
+            let page = ecr.call("ListImages", query).await?;
+            for id in page["imageIds"].as_array().into_iter().flatten() {
+                if let Some(digest) = id["imageDigest"].as_str() {
+                    untagged.push(digest.to_owned());
+                }
+            }
+            next_token = match page["nextToken"].as_str() {
+                Some(token) => Some(token.to_owned()),
+                None => break,
+            };
+        }
+
+        let mut deleted = 0;
+        for batch in untagged.chunks(ECR_BATCH) {
+            let ids: Vec<_> = batch
+                .iter()
+                .map(|digest| serde_json::json!({ "imageDigest": digest }))
+                .collect();
+            let result = ecr
+                .call(
+                    "BatchDeleteImage",
+                    serde_json::json!({
+                        "registryId": ecr.registry_id,
+                        "repositoryName": self.repository,
+                        "imageIds": ids,
+                    }),
+                )
+                .await?;
+            for failure in result["failures"].as_array().into_iter().flatten() {
+                event!(
+                    Level::WARN,
+                    "ECR did not delete {}: {}",
+                    failure["imageId"]["imageDigest"],
+                    failure["failureReason"]
+                );
+            }
+            let removed = result["imageIds"].as_array().map_or(0, Vec::len);
+            metrics::GC_DELETED.inc_by(removed as u64);
+            deleted += removed;
+        }
+        Ok(deleted)
+    }
+}
+
+impl Ecr {
+    // Call `action` of the ECR API, signed with Signature Version 4.
+    async fn call(
+        &self,
+        action: &str,
+        body: serde_json::Value,
+    ) -> anyhow::Result<serde_json::Value> {
+        let body = serde_json::to_vec(&body)?;
+        let host = self.endpoint.trim_start_matches("https://");
+        let target = format!("AmazonEC2ContainerRegistry_V20150921.{}", action);
+
+        let now = chrono::Utc::now();
+        let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+        let date = now.format("%Y%m%d").to_string();
+        let scope = format!("{}/{}/ecr/aws4_request", date, self.region);
+
+        let mut headers = vec![
+            ("content-type", "application/x-amz-json-1.1".to_owned()),
+            ("host", host.to_owned()),
+            ("x-amz-date", amz_date.clone()),
+        ];
+        if let Some(token) = &self.session_token {
+            headers.push(("x-amz-security-token", token.clone()));
+        }
+        headers.push(("x-amz-target", target));
+        let signed_headers = headers
+            .iter()
+            .map(|(name, _)| *name)
+            .collect::<Vec<_>>()
+            .join(";");
+        let canonical_headers: String = headers
+            .iter()
+            .map(|(name, value)| format!("{}:{}\n", name, value))
+            .collect();
+        let canonical_request = format!(
+            "POST\n/\n\n{}\n{}\n{}",
+            canonical_headers,
+            signed_headers,
+            hex::encode(Sha256::digest(&body))
+        );
+        let string_to_sign = format!(
+            "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+            amz_date,
+            scope,
+            hex::encode(Sha256::digest(canonical_request.as_bytes()))
+        );
+        let signing_key = s3::signing_key(&self.secret_key, &date, &self.region, "ecr");
+        let signature = hex::encode(s3::hmac(&signing_key, &string_to_sign));
+        let authorization = format!(
+            "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders={}, Signature={}",
+            self.access_key, scope, signed_headers, signature
+        );
+
+        let mut request = http::client(&self.endpoint)
+            .post(format!("{}/", self.endpoint))
+            .header("authorization", authorization);
+        for (name, value) in headers.into_iter().filter(|(name, _)| *name != "host") {
+            request = request.header(name, value);
+        }
+        let resp = request.body(body).send().await?;
+        let status = resp.status();
+        let text = resp.text().await.unwrap_or_default();
+        if !status.is_success() {
+            anyhow::bail!("ECR {} failed with {}: {}", action, status, text);
+        }
+        Ok(serde_json::from_str(&text)?)
+    }
+}
+
+// `<account>.dkr.ecr.<region>.amazonaws.com` split into the account, the
+// region and the domain of the partition.
+fn parse_ecr_host(host: &str) -> Option<(String, String, String)> {
+    let (account, rest) = host.split_once(".dkr.ecr.")?;
+    let (region, domain) = rest.split_once('.')?;
+    if !domain.starts_with("amazonaws.com") || account.is_empty() || region.is_empty() {
+        return None;
+    }
+    Some((account.to_owned(), region.to_owned(), domain.to_owned()))
+}
+
+/// Collect every `interval`, the first time after one interval has passed.
+pub async fn run(collector: Arc<Collector>, interval: std::time::Duration) {
+    let mut tick = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+    loop {
+        tick.tick().await;
+        collector.collect().await;
+    }
+}
@@ -0,0 +1,54 @@
+use reqwest::StatusCode;
+
+/// Base URL of the GitHub Container Registry.
+pub const BASE: &str = "https://ghcr.io";
+
+/// Username sent along with a token when the credentials leave it out;
+/// GHCR only looks at the token.
+pub const TOKEN_USERNAME: &str = "token";
+
+pub fn is_ghcr(url: &str) -> bool {
+    url == BASE || url.starts_with("https://ghcr.io/")
+}
+
+/// What to do about GHCR answering `url` with `status` and `body`, for the
+/// failures that are about how the package or token is set up rather than
+/// the request. `password` is the token we sent, when it was a login.
+pub fn hint(url: &str, status: StatusCode, body: &str, password: Option<&str>) -> Option<String> {
+    if !is_ghcr(url) {
+        return None;
+    }
+    let body = body.to_lowercase();
+    let hint = if body.contains("installation not allowed") || body.contains("write_package") {
+        "the package is not linked to the repository the workflow runs in: connect it to the \
+         repository, or give the repository Write access under the package's Manage Actions \
+         access, and grant the workflow `packages: write`"
+    } else if body.contains("does not match expected scopes") {
+        "the token lacks the read:packages scope, or write:packages to push"
+    } else if url.starts_with("https://ghcr.io/token") {
+        match password {
+            Some(token) if token.starts_with("github_pat_") => {
+                "GHCR does not accept fine-grained personal access tokens, use a classic one \
+                 with read:packages (write:packages to push) or the workflow's GITHUB_TOKEN"
+            }
+            Some(token) if token.starts_with("ghs_") => {
+                "the workflow's GITHUB_TOKEN needs `packages: write` for pushes, and access to \
+                 packages of other repositories under their Manage Actions access"
+            }
+            Some(token) if token.starts_with("ghp_") => {
+                "the personal access token is expired, revoked, or lacks read:packages \
+                 (write:packages to push)"
+            }
+            Some(_) => "GHCR expects a GitHub token as the password",
+            None => return None,
+        }
+    } else if status == StatusCode::FORBIDDEN || status == StatusCode::UNAUTHORIZED {
+        "private packages need a token of an account that can read them, pushes need write \
+         access to the package"
+    } else if status == StatusCode::NOT_FOUND && url.contains("/manifests/") {
+        "the package does not exist, or is private and the token cannot see it"
+    } else {
+        return None;
+    };
+    Some(hint.to_owned())
+}
@@ -0,0 +1,129 @@
+use crate::jobs::Job;
+use chrono::DateTime;
+use chrono::Utc;
+use std::collections::HashMap;
+
+/// Keep the jobs matching the `state`, `image`, `since` (RFC 3339,
+/// compared with the creation time) and `error` (error codes, comma
+/// separated) parameters of `map`.
+pub fn filter(jobs: &mut Vec<Job>, map: &HashMap<String, String>) -> anyhow::Result<()> {
+    if let Some(state) = map.get("state") {
+        jobs.retain(|job| job.state.as_str() == state);
+    }
+    if let Some(image) = map.get("image") {
+        jobs.retain(|job| &job.image == image);
+    }
+    if let Some(since) = map.get("since") {
+        let since: DateTime<Utc> = DateTime::parse_from_rfc3339(since)?.into();
+        jobs.retain(|job| job.created_at >= since);
+    }
+    if let Some(error) = map.get("error") {
+        let codes: Vec<&str> = error.split(',').map(str::trim).collect();
+        jobs.retain(|job| {
+            job.error_code
+                .as_deref()
+                .is_some_and(|code| codes.contains(&code))
+        });
+    }
+    Ok(())
+}
+
+/// Export formats of `/history/export`.
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Csv,
+    Ndjson,
+}
+
+impl std::str::FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Format> {
+        match s {
+            "csv" => Ok(Format::Csv),
+            "ndjson" => Ok(Format::Ndjson),
+            _ => anyhow::bail!("unknown format {}, expected csv or ndjson", s),
+        }
+    }
+}
+
+const COLUMNS: &[&str] = &[
+    "id",
+    "image",
+    "priority",
+    "state",
+    "created_at",
+    "started_at",
+    "finished_at",
+    "dest_image",
+    "error",
+    "timings",
+    "retry_of",
+    "error_code",
+];
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Format::Csv => "text/csv; charset=utf-8",
+            Format::Ndjson => "application/x-ndjson",
+        }
+    }
+
+    /// First line of the export, if the format has one.
+    pub fn header(&self) -> Option<String> {
+        match self {
+            Format::Csv => Some(format!("{}\n", COLUMNS.join(","))),
+            Format::Ndjson => None,
+        }
+    }
+
+    /// `job` as one line of the export.
+    pub fn row(&self, job: &Job) -> String {
+        match self {
+            Format::Csv => {
+                let time = |t: Option<DateTime<Utc>>| t.map(|t| t.to_rfc3339()).unwrap_or_default();
+                let dest_image = job
+                    .result
+                    .as_ref()
+                    .and_then(|r| r["dest_image"].as_str())
+                    .unwrap_or_default();
+                let fields = [
+                    job.id.clone(),
+                    job.image.clone(),
+                    job.priority.as_str().to_owned(),
+                    job.state.as_str().to_owned(),
+                    job.created_at.to_rfc3339(),
+                    time(job.started_at),
+                    time(job.finished_at),
+                    dest_image.to_owned(),
+                    job.error.clone().unwrap_or_default(),
+                    // `step=ms` pairs, the column count stays fixed
+                    job.timings
+                        .iter()
+                        .map(|(step, ms)| format!("{}={}", step, ms))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                    job.retry_of.clone().unwrap_or_default(),
+                    job.error_code.clone().unwrap_or_default(),
+                ];
+                let fields: Vec<String> = fields.iter().map(|f| escape(f)).collect();
+                format!("{}\n", fields.join(","))
+            }
+            Format::Ndjson => {
+                let mut line = serde_json::to_string(job).unwrap_or_default();
+                line.push('\n');
+                line
+            }
+        }
+    }
+}
+
+// quote fields that would otherwise break the row
+fn escape(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
@@ -0,0 +1,94 @@
+use crate::config::NetworkConfig;
+use crate::config::RegistryConfig;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tracing::event;
+use tracing::Level;
+
+static CONFIG: OnceCell<NetworkConfig> = OnceCell::new();
+
+// clients by registry host, built on first use
+static CLIENTS: Lazy<Mutex<HashMap<String, reqwest::Client>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Set the network settings every registry client is built with. Clients
+/// built before this (or without it, as in the CLI) use reqwest defaults,
+/// which still honor `HTTP_PROXY`/`HTTPS_PROXY`/`NO_PROXY`.
+pub fn init(config: NetworkConfig) {
+    let _ = CONFIG.set(config);
+}
+
+/// Settings configured for a registry host.
+pub fn registry_config(host: &str) -> Option<RegistryConfig> {
+    CONFIG.get()?.registries.get(host).cloned()
+}
+
+/// HTTP client for talking to the registry at `base` (a URL).
+pub fn client(base: &str) -> reqwest::Client {
+    let host = base
+        .split_once("://")
+        .map(|(_, rest)| rest)
+        .unwrap_or(base)
+        .trim_end_matches('/');
+
+    let mut clients = CLIENTS.lock().unwrap();
+    if let Some(client) = clients.get(host) {
+        return client.clone();
+    }
+
+    let client = match build(host) {
+        Ok(c) => c,
+        Err(e) => {
+            event!(
+                Level::ERROR,
+                "invalid client settings for {}: {:?}",
+                host,
+                e
+            );
+            reqwest::Client::new()
+        }
+    };
+    clients.insert(host.to_owned(), client.clone());
+    client
+}
+
+fn build(host: &str) -> anyhow::Result<reqwest::Client> {
+    let mut builder = reqwest::Client::builder();
+
+    if let Some(config) = CONFIG.get() {
+        // an explicit proxy replaces the one picked up from the environment
+        if let Some(url) = &config.proxy {
+            let no_proxy = config
+                .no_proxy
+                .as_deref()
+                .and_then(reqwest::NoProxy::from_string);
+            builder = builder.proxy(reqwest::Proxy::all(url)?.no_proxy(no_proxy));
+        }
+
+        if let Some(registry) = config.registries.get(host) {
+            if registry.insecure {
+                event!(Level::WARN, "TLS verification disabled for {}", host);
+                builder = builder.danger_accept_invalid_certs(true);
+            }
+
+            // redirects are left to the registry client, which follows
+            // them with our authorization
+            if registry.credentials_on_redirect {
+                builder = builder.redirect(reqwest::redirect::Policy::none());
+            }
+
+            if let Some(ca_file) = &registry.ca_file {
+                let pem = std::fs::read(ca_file)
+                    .with_context(|| format!("failed to read {}", ca_file.display()))?;
+                for cert in reqwest::Certificate::from_pem_bundle(&pem)? {
+                    builder = builder.add_root_certificate(cert);
+                }
+            }
+        }
+    }
+
+    Ok(builder.build()?)
+}
@@ -0,0 +1,54 @@
+use crate::labels;
+use crate::registry::Registry;
+
+/// What the sync is about to push, as far as it is known up front.
+pub struct Incoming<'a> {
+    /// Digest of the source manifest.
+    pub source_digest: Option<&'a str>,
+    /// Digest of the image config before any labels are added (the image ID).
+    pub image_id: Option<&'a str>,
+}
+
+/// Digest currently behind `repo:tag` when it holds a different image than
+/// `incoming`, or `None` when the tag is free or already holds this image.
+///
+/// Labels added on sync change the pushed digest every time, so a tag counts
+/// as holding the same image when it was synced from the same source digest
+/// or carries the same unlabeled config.
+pub async fn conflict(
+    dst: &Registry,
+    repo: &str,
+    tag: &str,
+    incoming: &Incoming<'_>,
+) -> anyhow::Result<Option<String>> {
+    let existing = match dst.head_manifest(repo, tag).await? {
+        Some(digest) => digest,
+        None => return Ok(None),
+    };
+    if incoming.source_digest == Some(existing.as_str()) {
+        return Ok(None);
+    }
+
+    let mut image = dst.get_manifest(repo, &existing).await?;
+    if image.is_index() {
+        match image.parse()?.manifests.first() {
+            Some(child) => image = dst.get_manifest(repo, &child.digest).await?,
+            None => return Ok(Some(existing)),
+        }
+    }
+    let config = match image.parse()?.config {
+        Some(config) => config,
+        None => return Ok(Some(existing)),
+    };
+    if incoming.image_id == Some(config.digest.as_str()) {
+        return Ok(None);
+    }
+
+    let blob: serde_json::Value = dst.get_blob(repo, &config.digest).await?.json().await?;
+    let synced_from = blob["config"]["Labels"][labels::SOURCE_DIGEST].as_str();
+    if synced_from.is_some() && synced_from == incoming.source_digest {
+        return Ok(None);
+    }
+
+    Ok(Some(existing))
+}
@@ -0,0 +1,857 @@
+use crate::events;
+use crate::metrics;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::event;
+use tracing::Level;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Queued,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+impl State {
+    pub const ALL: [State; 4] = [
+        State::Queued,
+        State::Running,
+        State::Succeeded,
+        State::Failed,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            State::Queued => "queued",
+            State::Running => "running",
+            State::Succeeded => "succeeded",
+            State::Failed => "failed",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Priority {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl Priority {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Priority::Low => "low",
+            Priority::Normal => "normal",
+            Priority::High => "high",
+        }
+    }
+}
+
+impl std::str::FromStr for Priority {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Priority> {
+        match s {
+            "low" => Ok(Priority::Low),
+            "normal" => Ok(Priority::Normal),
+            "high" => Ok(Priority::High),
+            _ => anyhow::bail!("unknown priority {}", s),
+        }
+    }
+}
+
+/// Step of a sync a job is in, in the order they run.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Pulling,
+    Verifying,
+    Tagging,
+    Labeling,
+    Squashing,
+    Exporting,
+    Transferring,
+    Pushing,
+    ReadingBack,
+    Cleanup,
+}
+
+impl Phase {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Phase::Pulling => "pulling",
+            Phase::Verifying => "verifying",
+            Phase::Tagging => "tagging",
+            Phase::Labeling => "labeling",
+            Phase::Squashing => "squashing",
+            Phase::Exporting => "exporting",
+            Phase::Transferring => "transferring",
+            Phase::Pushing => "pushing",
+            Phase::ReadingBack => "reading_back",
+            Phase::Cleanup => "cleanup",
+        }
+    }
+
+    // name of the phase in `Job::timings`
+    fn step(&self) -> &'static str {
+        match self {
+            Phase::Pulling => "pull",
+            Phase::Verifying => "verify",
+            Phase::Tagging => "tag",
+            Phase::Labeling => "label",
+            Phase::Squashing => "squash",
+            Phase::Exporting => "export",
+            Phase::Transferring => "transfer",
+            Phase::Pushing => "push",
+            Phase::ReadingBack => "read_back",
+            Phase::Cleanup => "cleanup",
+        }
+    }
+}
+
+/// One sync request and what has become of it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Job {
+    pub id: String,
+    pub image: String,
+    /// Query parameters of the request, to run it again.
+    pub request: HashMap<String, String>,
+    pub priority: Priority,
+    pub state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub phase: Option<Phase>,
+    /// Percent of the current phase done.
+    pub progress: f64,
+    pub created_at: DateTime<Utc>,
+    /// Queued until this time by the maintenance windows.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub held_until: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub started_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub finished_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Kind of failure, as `Error::code` names it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error_code: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    /// Times the job was queued again after a restart interrupted it.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub resumed: u32,
+    /// Times the sync was run again after failing with a retryable error.
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub retries: u32,
+    /// Source digest the tag was resolved to, in pinned mode.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub digest: Option<String>,
+    /// Job this one runs again the request of (`POST /jobs/{id}/retry`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_of: Option<String>,
+    /// Jobs queued to run this one's request again.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub retried_by: Vec<String>,
+    /// Milliseconds spent in each step of the last run: `parse` until the
+    /// first phase, then one per phase, added up when a phase is retried.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub timings: BTreeMap<String, u64>,
+    // (done, total) per layer of the current phase
+    #[serde(skip)]
+    items: HashMap<String, (u64, u64)>,
+    // registry being pulled from, empty for the one in `image`
+    #[serde(skip)]
+    source: String,
+    // when the current phase started, and the registry it started with
+    #[serde(skip)]
+    phase_started: Option<(Instant, String)>,
+    // when the job started running, until the first phase
+    #[serde(skip)]
+    run_started: Option<Instant>,
+}
+
+impl Job {
+    // Record how long the current phase took.
+    fn observe_phase(&mut self, outcome: &str) {
+        self.end_parse();
+        if let (Some(phase), Some((started, source))) = (self.phase, self.phase_started.take()) {
+            let elapsed = started.elapsed();
+            *self.timings.entry(phase.step().to_owned()).or_default() += elapsed.as_millis() as u64;
+            let labels = self.sync_labels(&source);
+            let mut values = vec![phase.as_str()];
+            values.extend(labels.iter().map(String::as_str));
+            values.push(outcome);
+            metrics::PHASE_DURATION
+                .with_label_values(&values)
+                .observe(elapsed.as_secs_f64());
+        }
+    }
+
+    // Record how long the job took to get to its first phase.
+    fn end_parse(&mut self) {
+        if let Some(started) = self.run_started.take() {
+            let elapsed = started.elapsed().as_millis() as u64;
+            self.timings.insert("parse".to_owned(), elapsed);
+        }
+    }
+
+    // The configured metric labels of this job, pulling from `registry`.
+    fn sync_labels(&self, registry: &str) -> Vec<String> {
+        let tenant = self.request.get("tenant").map(String::as_str);
+        metrics::sync_labels(&self.image, registry, tenant.unwrap_or_default())
+    }
+}
+
+fn is_zero(n: &u32) -> bool {
+    *n == 0
+}
+
+// changes appended to the write-ahead log between snapshots of every job
+const COMPACT_EVERY: usize = 5000;
+
+/// Every job by id, kept in `<state dir>/jobs.json` so a restart does not
+/// lose them. Each change of a job appends it to `<state dir>/jobs.wal`,
+/// where the last line of a job wins; new jobs are flushed to disk before
+/// they are accepted. The log is folded into `jobs.json` every
+/// `COMPACT_EVERY` changes and after old jobs are dropped.
+///
+/// The files are written by a thread of their own, in the order changes are
+/// made, so no I/O happens under the lock or on the runtime.
+pub struct Jobs {
+    jobs: Mutex<HashMap<String, Job>>,
+    next: AtomicU64,
+    writer: std::sync::mpsc::Sender<Change>,
+    // lines in the log since the last snapshot
+    logged: AtomicUsize,
+    interrupted: Mutex<Vec<Job>>,
+    // what each running job has pulled or tagged in the daemon, by id
+    held: Mutex<HashMap<String, HashSet<String>>>,
+    // taken while a prune decides on an image, and to hold one
+    pruning: tokio::sync::Mutex<()>,
+}
+
+impl Jobs {
+    /// Load the jobs of earlier runs. Jobs that were queued or running when
+    /// the server stopped are queued again, see `take_interrupted`.
+    pub fn open(dir: &Path) -> Jobs {
+        let path = dir.join("jobs.json");
+        let mut jobs: HashMap<String, Job> = match std::fs::read(&path) {
+            Ok(bytes) => serde_json::from_slice(&bytes).unwrap_or_else(|e| {
+                event!(
+                    Level::ERROR,
+                    "ignoring unreadable {}: {:?}",
+                    path.display(),
+                    e
+                );
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+
+        // jobs accepted since jobs.json was last written; a line cut short
+        // by a crash is one that was never accepted
+        let wal = dir.join("jobs.wal");
+        let mut replayed = 0;
+        if let Ok(log) = std::fs::read_to_string(&wal) {
+            for line in log.lines().filter(|l| !l.is_empty()) {
+                match serde_json::from_str::<Job>(line) {
+                    Ok(job) => {
+                        replayed += 1;
+                        jobs.insert(job.id.clone(), job);
+                    }
+                    Err(e) => event!(
+                        Level::WARN,
+                        "ignoring unreadable entry of {}: {}",
+                        wal.display(),
+                        e
+                    ),
+                }
+            }
+        }
+        if replayed > 0 {
+            event!(
+                Level::INFO,
+                "recovered {} job changes from {}",
+                replayed,
+                wal.display()
+            );
+        }
+
+        let mut interrupted = Vec::new();
+        for job in jobs.values_mut() {
+            if job.state == State::Queued || job.state == State::Running {
+                job.state = State::Queued;
+                job.phase = None;
+                job.progress = 0.0;
+                job.started_at = None;
+                job.held_until = None;
+                job.resumed += 1;
+                interrupted.push(job.clone());
+            }
+        }
+        interrupted.sort_by_key(|j| j.created_at);
+
+        let mut store = Store {
+            path,
+            wal,
+            log: None,
+        };
+        // folded in now, so the log only holds changes of this run
+        if replayed > 0 || !interrupted.is_empty() {
+            match serde_json::to_vec(&jobs) {
+                Ok(snapshot) => store.snapshot(&snapshot),
+                Err(e) => event!(Level::ERROR, "failed to save jobs: {:?}", e),
+            }
+        }
+        let (writer, changes) = std::sync::mpsc::channel();
+        std::thread::Builder::new()
+            .name("jobs-writer".to_owned())
+            .spawn(move || store.run(changes))
+            .expect("failed to start the jobs writer");
+
+        Jobs {
+            jobs: Mutex::new(jobs),
+            next: AtomicU64::new(1),
+            writer,
+            logged: AtomicUsize::new(0),
+            interrupted: Mutex::new(interrupted),
+            held: Mutex::new(HashMap::new()),
+            pruning: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    /// Jobs interrupted by the last shutdown, each returned once.
+    pub fn take_interrupted(&self) -> Vec<Job> {
+        std::mem::take(&mut *self.interrupted.lock().unwrap())
+    }
+
+    /// Register a queued job for `image`, returning its id once the job is
+    /// on disk.
+    pub async fn create(
+        &self,
+        image: &str,
+        priority: Priority,
+        request: &HashMap<String, String>,
+    ) -> anyhow::Result<String> {
+        let (id, written) = self.insert(image, priority, request)?;
+        let written = written.await.unwrap_or_else(|_| Err(writer_gone()));
+        if let Err(e) = written {
+            self.jobs.lock().unwrap().remove(&id);
+            return Err(anyhow::Error::new(e).context("failed to record the job"));
+        }
+        Ok(id)
+    }
+
+    // Add a queued job, taking its id, and hand it to the writer.
+    fn insert(
+        &self,
+        image: &str,
+        priority: Priority,
+        request: &HashMap<String, String>,
+    ) -> anyhow::Result<(String, tokio::sync::oneshot::Receiver<std::io::Result<()>>)> {
+        let now = Utc::now();
+        let mut jobs = self.jobs.lock().unwrap();
+
+        // ids only need to be unique, skip any left from an earlier run
+        let id = loop {
+            let id = format!(
+                "{}-{}",
+                now.format("%Y%m%d%H%M%S"),
+                self.next.fetch_add(1, Ordering::Relaxed)
+            );
+            if !jobs.contains_key(&id) {
+                break id;
+            }
+        };
+        let job = Job {
+            id: id.clone(),
+            image: image.to_owned(),
+            request: request.clone(),
+            priority,
+            state: State::Queued,
+            phase: None,
+            progress: 0.0,
+            created_at: now,
+            held_until: None,
+            started_at: None,
+            finished_at: None,
+            error: None,
+            error_code: None,
+            result: None,
+            resumed: 0,
+            retries: 0,
+            digest: None,
+            retry_of: None,
+            retried_by: Vec::new(),
+            timings: BTreeMap::new(),
+            items: HashMap::new(),
+            source: String::new(),
+            phase_started: None,
+            run_started: None,
+        };
+        let mut line = serde_json::to_vec(&job)?;
+        line.push(b'\n');
+        let (done, written) = tokio::sync::oneshot::channel();
+        self.writer
+            .send(Change::Job(line, Some(done)))
+            .map_err(|_| writer_gone())?;
+        // the id is taken while the job goes to disk
+        jobs.insert(id.clone(), job);
+        self.logged.fetch_add(1, Ordering::Relaxed);
+        Ok((id, written))
+    }
+
+    // Append the job `id` as it now is to the write-ahead log, folding the
+    // log into a snapshot of `jobs` once it has grown long. Progress updates
+    // are not logged, a resumed job starts over anyway.
+    fn log(&self, jobs: &HashMap<String, Job>, id: &str) {
+        let Some(job) = jobs.get(id) else {
+            return;
+        };
+        if self.logged.fetch_add(1, Ordering::Relaxed) + 1 >= COMPACT_EVERY {
+            self.snapshot(jobs);
+            return;
+        }
+        let mut line = serde_json::to_vec(job).unwrap();
+        line.push(b'\n');
+        let _ = self.writer.send(Change::Job(line, None));
+    }
+
+    // Replace jobs.json with every job in `jobs`, emptying the log it then
+    // covers.
+    fn snapshot(&self, jobs: &HashMap<String, Job>) {
+        self.logged.store(0, Ordering::Relaxed);
+        match serde_json::to_vec(jobs) {
+            Ok(snapshot) => {
+                let _ = self.writer.send(Change::Snapshot(snapshot));
+            }
+            Err(e) => event!(Level::ERROR, "failed to save jobs: {:?}", e),
+        }
+    }
+
+    /// Record that the job `retry` runs the request of `original` again.
+    pub fn link_retry(&self, original: &str, retry: &str) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(retry) {
+            job.retry_of = Some(original.to_owned());
+        }
+        if let Some(job) = jobs.get_mut(original) {
+            job.retried_by.push(retry.to_owned());
+        }
+        self.log(&jobs, retry);
+        self.log(&jobs, original);
+    }
+
+    pub fn hold(&self, id: &str, until: Option<DateTime<Utc>>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(id) {
+            if job.held_until != until {
+                job.held_until = until;
+                self.log(&jobs, id);
+            }
+        }
+    }
+
+    pub fn start(&self, id: &str) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(id) {
+            job.held_until = None;
+            job.state = State::Running;
+            job.started_at = Some(Utc::now());
+            job.retries = 0;
+            job.timings.clear();
+            job.run_started = Some(Instant::now());
+            events::publish(
+                "job_started",
+                serde_json::json!({ "job_id": id, "image": job.image }),
+            );
+            self.log(&jobs, id);
+        }
+    }
+
+    /// Note that run `attempt` of the job failed and it is run again.
+    pub fn retrying(&self, id: &str, attempt: u32) {
+        let mut jobs = self.jobs.lock().unwrap();
+        if let Some(job) = jobs.get_mut(id) {
+            if let Some(phase) = job.phase {
+                let _ = metrics::JOB_PROGRESS.remove_label_values(&[id, phase.as_str()]);
+                job.observe_phase("failure");
+            }
+            job.phase = None;
+            job.progress = 0.0;
+            job.items.clear();
+            job.retries = attempt;
+            self.log(&jobs, id);
+        }
+    }
+
+    /// Record the outcome of the job: its result, or the report and code of
+    /// the error it failed with.
+    pub fn finish(&self, id: &str, result: Result<serde_json::Value, (String, String)>) {
+        let mut jobs = self.jobs.lock().unwrap();
+        let job = match jobs.get_mut(id) {
+            Some(job) => job,
+            None => return,
+        };
+        if let Some(phase) = job.phase {
+            let _ = metrics::JOB_PROGRESS.remove_label_values(&[id, phase.as_str()]);
+        }
+        job.observe_phase(if result.is_ok() { "success" } else { "failure" });
+        let registry = match job.source.as_str() {
+            "" => crate::registry::parse_reference(&job.image).0,
+            source => source.to_owned(),
+        };
+
+        job.finished_at = Some(Utc::now());
+        job.items.clear();
+        match result {
+            Ok(result) => {
+                job.state = State::Succeeded;
+                job.progress = 100.0;
+                job.result = Some(result);
+            }
+            Err((e, code)) => {
+                job.state = State::Failed;
+                job.error = Some(e);
+                job.error_code = Some(code);
+            }
+        }
+        let labels = job.sync_labels(&registry);
+        let mut values = vec![job.state.as_str()];
+        values.extend(labels.iter().map(String::as_str));
+        metrics::SYNCS.with_label_values(&values).inc();
+        events::publish(
+            "job_finished",
+            serde_json::json!({
+                "job_id": id,
+                "image": job.image,
+                "state": job.state,
+                "error": job.error,
+                "error_code": job.error_code,
+            }),
+        );
+        self.log(&jobs, id);
+        drop(jobs);
+        self.held.lock().unwrap().remove(id);
+    }
+
+    /// Keep prunes from deciding on images while the guard lives, so that no
+    /// job takes hold of an image between a prune checking and removing it.
+    pub async fn lock_prune(&self) -> tokio::sync::MutexGuard<'_, ()> {
+        self.pruning.lock().await
+    }
+
+    /// Whether a running job holds the daemon image `id`, known under
+    /// `names` (its tags and digests), taken for a prune under `lock_prune`.
+    pub fn is_held(&self, id: &str, names: &[String]) -> bool {
+        let held = self.held.lock().unwrap();
+        held.values().any(|images| {
+            images.contains(id)
+                || names
+                    .iter()
+                    .any(|name| images.contains(&crate::registry::repository_name(name)))
+        })
+    }
+
+    /// Forget finished jobs older than `max_age`, then the oldest finished
+    /// ones beyond `max_jobs`, returning how many were removed.
+    pub fn gc(&self, max_age: Option<std::time::Duration>, max_jobs: Option<usize>) -> usize {
+        let mut jobs = self.jobs.lock().unwrap();
+        let before = jobs.len();
+
+        if let Some(max_age) = max_age.and_then(|d| chrono::Duration::from_std(d).ok()) {
+            let cutoff = Utc::now() - max_age;
+            jobs.retain(|_, job| job.finished_at.is_none_or(|t| t > cutoff));
+        }
+
+        if let Some(max_jobs) = max_jobs {
+            if jobs.len() > max_jobs {
+                let mut finished: Vec<(DateTime<Utc>, String)> = jobs
+                    .values()
+                    .filter_map(|job| job.finished_at.map(|t| (t, job.id.clone())))
+                    .collect();
+                finished.sort();
+                for (_, id) in finished.into_iter().take(jobs.len() - max_jobs) {
+                    jobs.remove(&id);
+                }
+            }
+        }
+
+        let removed = before - jobs.len();
+        if removed > 0 {
+            self.snapshot(&jobs);
+        }
+        removed
+    }
+
+    /// Number of jobs in each state, and the creation time of the oldest
+    /// queued one.
+    pub fn counts(&self) -> (HashMap<State, usize>, Option<DateTime<Utc>>) {
+        let jobs = self.jobs.lock().unwrap();
+        let mut counts = HashMap::new();
+        let mut oldest_queued: Option<DateTime<Utc>> = None;
+        for job in jobs.values() {
+            *counts.entry(job.state).or_insert(0) += 1;
+            if job.state == State::Queued {
+                oldest_queued = Some(match oldest_queued {
+                    Some(t) => t.min(job.created_at),
+                    None => job.created_at,
+                });
+            }
+        }
+        (counts, oldest_queued)
+    }
+
+    pub fn get(&self, id: &str) -> Option<Job> {
+        self.jobs.lock().unwrap().get(id).cloned()
+    }
+
+    /// Every job, oldest first.
+    pub fn list(&self) -> Vec<Job> {
+        let mut jobs: Vec<Job> = self.jobs.lock().unwrap().values().cloned().collect();
+        jobs.sort_by_key(|j| j.created_at);
+        jobs
+    }
+
+    pub fn progress(self: &Arc<Self>, id: &str) -> Progress {
+        Progress {
+            jobs: Some(self.clone()),
+            id: id.to_owned(),
+        }
+    }
+}
+
+// What the jobs writer is asked to do, in the order the changes were made.
+enum Change {
+    // a line of the write-ahead log, and who waits for it to be on disk
+    Job(
+        Vec<u8>,
+        Option<tokio::sync::oneshot::Sender<std::io::Result<()>>>,
+    ),
+    // every job, for jobs.json
+    Snapshot(Vec<u8>),
+}
+
+fn writer_gone() -> std::io::Error {
+    std::io::Error::other("the jobs writer stopped")
+}
+
+// The files of `Jobs`, owned by the writer thread.
+struct Store {
+    path: PathBuf,
+    wal: PathBuf,
+    log: Option<std::fs::File>,
+}
+
+impl Store {
+    // Write what `changes` asks for until `Jobs` goes away. Lines that
+    // arrive together are flushed to disk together.
+    fn run(mut self, changes: std::sync::mpsc::Receiver<Change>) {
+        while let Ok(first) = changes.recv() {
+            let mut waiting = Vec::new();
+            let mut lines = Vec::new();
+            for change in std::iter::once(first).chain(changes.try_iter()) {
+                match change {
+                    Change::Job(line, done) => {
+                        lines.extend_from_slice(&line);
+                        waiting.extend(done);
+                    }
+                    Change::Snapshot(snapshot) => {
+                        // lines from before are in it
+                        lines.clear();
+                        self.snapshot(&snapshot);
+                    }
+                }
+            }
+            if lines.is_empty() && waiting.is_empty() {
+                continue;
+            }
+            let appended = self.append(&lines);
+            if let Err(e) = &appended {
+                event!(Level::ERROR, "failed to log job changes: {:?}", e);
+            }
+            for done in waiting {
+                let _ = done.send(match &appended {
+                    Ok(()) => Ok(()),
+                    Err(e) => Err(std::io::Error::new(e.kind(), e.to_string())),
+                });
+            }
+        }
+    }
+
+    // Append `lines` to the write-ahead log, flushed to disk.
+    fn append(&mut self, lines: &[u8]) -> std::io::Result<()> {
+        let log = match &mut self.log {
+            Some(log) => log,
+            None => {
+                if let Some(dir) = self.wal.parent() {
+                    std::fs::create_dir_all(dir)?;
+                }
+                self.log.insert(
+                    std::fs::OpenOptions::new()
+                        .create(true)
+                        .append(true)
+                        .open(&self.wal)?,
+                )
+            }
+        };
+        let written = log.write_all(lines).and_then(|_| log.sync_data());
+        if written.is_err() {
+            // opened again for the next lines
+            self.log = None;
+        }
+        written
+    }
+
+    // Replace jobs.json atomically, then empty the write-ahead log it now
+    // covers.
+    fn snapshot(&mut self, snapshot: &[u8]) {
+        let written = (|| -> anyhow::Result<()> {
+            let dir = self.path.parent().unwrap_or(Path::new("."));
+            std::fs::create_dir_all(dir)?;
+            let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+            tmp.write_all(snapshot)?;
+            tmp.as_file().sync_all()?;
+            tmp.persist(&self.path)?;
+            std::fs::File::open(dir)?.sync_all()?;
+            match &self.log {
+                Some(log) => log.set_len(0)?,
+                None if std::fs::metadata(&self.wal).is_ok_and(|m| m.len() > 0) => {
+                    std::fs::File::create(&self.wal)?;
+                }
+                None => {}
+            }
+            Ok(())
+        })();
+        if let Err(e) = written {
+            event!(Level::ERROR, "failed to save jobs: {:?}", e);
+        }
+    }
+}
+
+/// Handle a sync reports its progress through. `Progress::none()` discards
+/// everything, for copies that are not jobs.
+#[derive(Clone)]
+pub struct Progress {
+    jobs: Option<Arc<Jobs>>,
+    id: String,
+}
+
+impl Progress {
+    pub fn none() -> Progress {
+        Progress {
+            jobs: None,
+            id: String::new(),
+        }
+    }
+
+    /// Note the registry the following phases pull from, when it is not the
+    /// one named in the image (a mirror).
+    pub fn source(&self, registry: &str) {
+        self.with_job(|job| job.source = registry.to_owned());
+    }
+
+    /// Note the digest the requested tag was resolved to.
+    pub fn digest(&self, digest: &str) {
+        self.with_job(|job| job.digest = Some(digest.to_owned()));
+    }
+
+    /// Enter `phase`, starting its progress from zero. Going back to the same
+    /// or an earlier phase means the previous attempt failed.
+    pub fn phase(&self, phase: Phase) {
+        self.with_job(|job| {
+            job.end_parse();
+            if let Some(previous) = job.phase {
+                let _ = metrics::JOB_PROGRESS.remove_label_values(&[&job.id, previous.as_str()]);
+                job.observe_phase(if previous < phase {
+                    "success"
+                } else {
+                    "failure"
+                });
+            }
+            let source = match job.source.as_str() {
+                "" => crate::registry::parse_reference(&job.image).0,
+                source => source.to_owned(),
+            };
+            job.phase_started = Some((Instant::now(), source));
+            job.phase = Some(phase);
+            job.progress = 0.0;
+            job.items.clear();
+            metrics::JOB_PROGRESS
+                .with_label_values(&[&job.id, phase.as_str()])
+                .set(0.0);
+        });
+    }
+
+    /// Record that `done` of the `total` bytes (or steps) of `item` are done.
+    pub fn update(&self, item: &str, done: u64, total: u64) {
+        self.with_job(|job| {
+            match job.items.get_mut(item) {
+                Some(counts) => *counts = (done.min(total), total),
+                None => {
+                    job.items.insert(item.to_owned(), (done.min(total), total));
+                }
+            }
+            let (done, total) = job
+                .items
+                .values()
+                .fold((0, 0), |(d, t), (done, total)| (d + done, t + total));
+            if total > 0 {
+                job.progress = (done as f64 * 100.0 / total as f64 * 10.0).round() / 10.0;
+            }
+            if let Some(phase) = job.phase {
+                metrics::JOB_PROGRESS
+                    .with_label_values(&[&job.id, phase.as_str()])
+                    .set(job.progress);
+            }
+        });
+    }
+
+    /// Keep `image`, a reference or image id in the daemon, from being
+    /// pruned until the job finishes. References hold their whole
+    /// repository, as images pulled by digest or whose tag moved on have no
+    /// tag to tell them by.
+    pub async fn hold_image(&self, image: &str) {
+        let Some(jobs) = &self.jobs else {
+            return;
+        };
+        let held = if image.starts_with("sha256:") {
+            image.to_owned()
+        } else {
+            crate::registry::repository_name(image)
+        };
+        let _pruning = jobs.lock_prune().await;
+        jobs.held
+            .lock()
+            .unwrap()
+            .entry(self.id.clone())
+            .or_default()
+            .insert(held);
+    }
+
+    fn with_job<F: FnOnce(&mut Job)>(&self, f: F) {
+        if let Some(jobs) = &self.jobs {
+            if let Some(job) = jobs.jobs.lock().unwrap().get_mut(&self.id) {
+                f(job);
+            }
+        }
+    }
+}
@@ -0,0 +1,142 @@
+use crate::registry::Registry;
+use bollard::container::Config;
+use bollard::container::CreateContainerOptions;
+use bollard::container::RemoveContainerOptions;
+use bollard::image::CommitContainerOptions;
+use bollard::Docker;
+use std::collections::BTreeMap;
+
+pub const SOURCE: &str = "io.imagesync.source";
+pub const SOURCE_DIGEST: &str = "io.imagesync.source-digest";
+pub const SYNCED_AT: &str = "io.imagesync.synced-at";
+pub const VERSION: &str = "io.imagesync.version";
+
+/// Name prefix of the throwaway containers `apply` commits, so ones left by
+/// a crash can be found.
+pub const CONTAINER_PREFIX: &str = "imagesync-label-";
+
+// command of containers of images that have none, never run
+const NOOP: &str = "/imagesync-noop";
+
+/// Labels recording where a synced image came from.
+pub fn provenance(source: &str, digest: Option<&str>) -> BTreeMap<String, String> {
+    let mut labels = BTreeMap::new();
+    labels.insert(SOURCE.to_owned(), source.to_owned());
+    if let Some(digest) = digest {
+        labels.insert(SOURCE_DIGEST.to_owned(), digest.to_owned());
+    }
+    labels.insert(SYNCED_AT.to_owned(), chrono::Utc::now().to_rfc3339());
+    labels.insert(VERSION.to_owned(), env!("CARGO_PKG_VERSION").to_owned());
+    labels
+}
+
+/// Add `labels` to the local image `repo:tag`, replacing it in place.
+///
+/// The daemon cannot change an image config directly, so this commits an
+/// unstarted container of the image with `LABEL` changes; the layers stay the
+/// same.
+pub async fn apply(
+    docker: &Docker,
+    repo: &str,
+    tag: &str,
+    labels: &BTreeMap<String, String>,
+) -> anyhow::Result<()> {
+    if labels.is_empty() {
+        return Ok(());
+    }
+
+    let image = format!("{}:{}", repo, tag);
+    // the daemon refuses containers with nothing to run, such as of data
+    // images; the container is never started and the command not kept
+    let inspected = docker.inspect_image(&image).await?.config;
+    let runnable = inspected.is_some_and(|c| {
+        c.cmd.is_some_and(|c| !c.is_empty()) || c.entrypoint.is_some_and(|e| !e.is_empty())
+    });
+    let cmd = (!runnable).then(|| vec![NOOP.to_owned()]);
+    let container = docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: format!(
+                    "{}{}",
+                    CONTAINER_PREFIX,
+                    chrono::Utc::now().timestamp_nanos()
+                ),
+                platform: None,
+            }),
+            Config {
+                image: Some(image),
+                cmd,
+                ..Default::default()
+            },
+        )
+        .await?;
+
+    let mut changes = labels
+        .iter()
+        .map(|(k, v)| format!("LABEL {}={}", quote(k), quote(v)))
+        .collect::<Vec<_>>();
+    if !runnable {
+        changes.push("CMD []".to_owned());
+    }
+    let changes = changes.join("\n");
+    let committed = docker
+        .commit_container(
+            CommitContainerOptions {
+                container: container.id.as_str(),
+                repo,
+                tag,
+                comment: "",
+                author: "",
+                pause: false,
+                changes: Some(changes.as_str()),
+            },
+            Config::<String>::default(),
+        )
+        .await;
+
+    docker
+        .remove_container(
+            &container.id,
+            Some(RemoveContainerOptions {
+                force: true,
+                ..Default::default()
+            }),
+        )
+        .await?;
+    committed?;
+
+    Ok(())
+}
+
+/// Digest of `repo:reference` in `registry` and the labels of its image
+/// config, of the first platform for a multi-platform image.
+pub async fn read(
+    registry: &Registry,
+    repo: &str,
+    reference: &str,
+) -> anyhow::Result<(String, BTreeMap<String, String>)> {
+    let manifest = registry.get_manifest(repo, reference).await?;
+    let mut image = manifest.clone();
+    if image.is_index() {
+        match image.parse()?.manifests.first() {
+            Some(child) => image = registry.get_manifest(repo, &child.digest).await?,
+            None => return Ok((manifest.digest, BTreeMap::new())),
+        }
+    }
+    let config = match image.parse()?.config {
+        Some(config) => config,
+        None => return Ok((manifest.digest, BTreeMap::new())),
+    };
+    let blob: serde_json::Value = registry
+        .get_blob(repo, &config.digest)
+        .await?
+        .json()
+        .await?;
+    let labels = serde_json::from_value(blob["config"]["Labels"].clone()).unwrap_or_default();
+    Ok((manifest.digest, labels))
+}
+
+// Dockerfile double-quoted string
+fn quote(s: &str) -> String {
+    serde_json::to_string(s).unwrap()
+}
@@ -0,0 +1,85 @@
+use crate::error::Error;
+use futures::Stream;
+use futures::StreamExt;
+use serde::de::DeserializeOwned;
+use warp::Buf;
+use warp::Filter;
+use warp::Rejection;
+
+/// A JSON request body of at most `max` bytes. Larger bodies are refused
+/// with 413, by `Content-Length` up front or, for chunked bodies, as soon as
+/// they grow past the limit.
+pub fn json<T: DeserializeOwned + Send>(
+    max: u64,
+) -> impl Filter<Extract = (T,), Error = Rejection> + Clone {
+    bytes(max).and_then(|data: Vec<u8>| async move {
+        serde_json::from_slice(&data).map_err(|e| warp::reject::custom(Error::Parse(e.to_string())))
+    })
+}
+
+/// A request body of at most `max` bytes as it was sent, for bodies that
+/// are checked before they are parsed.
+pub fn bytes(max: u64) -> impl Filter<Extract = (Vec<u8>,), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::body::stream())
+        .and_then(move |length: Option<u64>, body| async move {
+            read(length, body, max).await.map_err(warp::reject::custom)
+        })
+}
+
+async fn read(
+    length: Option<u64>,
+    body: impl Stream<Item = Result<impl Buf, warp::Error>>,
+    max: u64,
+) -> Result<Vec<u8>, Error> {
+    let too_large = || Error::TooLarge(format!("request body is larger than {} bytes", max));
+    if length.is_some_and(|length| length > max) {
+        return Err(too_large());
+    }
+
+    let mut data = Vec::new();
+    futures::pin_mut!(body);
+    while let Some(chunk) = body.next().await {
+        let mut chunk = chunk.map_err(|e| Error::Parse(e.to_string()))?;
+        if (data.len() + chunk.remaining()) as u64 > max {
+            return Err(too_large());
+        }
+        while chunk.has_remaining() {
+            let part = chunk.chunk();
+            let n = part.len();
+            data.extend_from_slice(part);
+            chunk.advance(n);
+        }
+    }
+    Ok(data)
+}
+
+/// Passes requests whose query string is at most `max` bytes.
+pub fn query(max: usize) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::query::raw()
+        .or(warp::any().map(String::new))
+        .unify()
+        .and_then(move |query: String| async move {
+            if query.len() > max {
+                Err(warp::reject::custom(Error::Parse(format!(
+                    "query string is longer than {} bytes",
+                    max
+                ))))
+            } else {
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// Refuses batches of more than `max` items.
+pub fn batch<T>(items: &[T], max: usize) -> Result<(), Error> {
+    if items.len() > max {
+        return Err(Error::Parse(format!(
+            "{} items in one request, at most {} are allowed",
+            items.len(),
+            max
+        )));
+    }
+    Ok(())
+}
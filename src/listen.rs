@@ -0,0 +1,141 @@
+use futures::Stream;
+use std::path::Path;
+
+/// Listening socket handed over by systemd socket activation.
+pub enum Inherited {
+    Tcp(tokio::net::TcpListener),
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener),
+}
+
+/// Connections to a Unix socket at `path`, which is created with `mode`
+/// permissions. A socket left by an earlier run is replaced.
+#[cfg(unix)]
+pub fn unix(
+    path: &Path,
+    mode: u32,
+) -> anyhow::Result<impl Stream<Item = std::io::Result<tokio::net::UnixStream>>> {
+    use anyhow::Context;
+    use std::os::unix::fs::FileTypeExt;
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if !metadata.file_type().is_socket() {
+            anyhow::bail!("{} exists and is not a socket", path.display());
+        }
+        std::fs::remove_file(path)?;
+    }
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    let listener = tokio::net::UnixListener::bind(path)
+        .with_context(|| format!("failed to listen on {}", path.display()))?;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+    Ok(unix_incoming(listener))
+}
+
+#[cfg(not(unix))]
+pub fn unix(
+    path: &Path,
+    _mode: u32,
+) -> anyhow::Result<futures::stream::Empty<std::io::Result<tokio::net::TcpStream>>> {
+    anyhow::bail!(
+        "cannot listen on {}, Unix sockets are not supported here",
+        path.display()
+    )
+}
+
+#[cfg(unix)]
+pub fn unix_incoming(
+    listener: tokio::net::UnixListener,
+) -> impl Stream<Item = std::io::Result<tokio::net::UnixStream>> {
+    futures::stream::unfold(listener, |listener| async {
+        let conn = listener.accept().await.map(|(stream, _)| stream);
+        Some((conn, listener))
+    })
+}
+
+pub fn tcp_incoming(
+    listener: tokio::net::TcpListener,
+) -> impl Stream<Item = std::io::Result<tokio::net::TcpStream>> {
+    futures::stream::unfold(listener, |listener| async {
+        let conn = listener.accept().await.map(|(stream, _)| stream);
+        Some((conn, listener))
+    })
+}
+
+/// Sockets passed by systemd (`LISTEN_FDS`, starting at fd 3), if it
+/// started us for a `.socket` unit.
+#[cfg(unix)]
+pub fn systemd() -> anyhow::Result<Vec<Inherited>> {
+    use std::os::unix::io::FromRawFd;
+    use std::os::unix::io::IntoRawFd;
+
+    const FIRST_FD: i32 = 3;
+
+    // the variables are meant for us only if LISTEN_PID says so
+    let for_us = std::env::var("LISTEN_PID").ok() == Some(std::process::id().to_string());
+    let count: i32 = match std::env::var("LISTEN_FDS") {
+        Ok(n) if for_us => n.parse()?,
+        _ => return Ok(Vec::new()),
+    };
+    std::env::remove_var("LISTEN_PID");
+    std::env::remove_var("LISTEN_FDS");
+    std::env::remove_var("LISTEN_FDNAMES");
+
+    let mut sockets = Vec::new();
+    for fd in FIRST_FD..FIRST_FD + count {
+        // SAFETY: systemd hands these fds to us, nothing else owns them
+        let tcp = unsafe { std::net::TcpListener::from_raw_fd(fd) };
+        // only inet sockets have an address std can read
+        if tcp.local_addr().is_ok() {
+            tcp.set_nonblocking(true)?;
+            sockets.push(Inherited::Tcp(tokio::net::TcpListener::from_std(tcp)?));
+        } else {
+            // SAFETY: the same fd, given back up by the TcpListener above
+            let unix = unsafe { std::os::unix::net::UnixListener::from_raw_fd(tcp.into_raw_fd()) };
+            unix.set_nonblocking(true)?;
+            sockets.push(Inherited::Unix(tokio::net::UnixListener::from_std(unix)?));
+        }
+    }
+    Ok(sockets)
+}
+
+#[cfg(not(unix))]
+pub fn systemd() -> anyhow::Result<Vec<Inherited>> {
+    Ok(Vec::new())
+}
+
+/// Tell systemd a `Type=notify` service is up (`NOTIFY_SOCKET`), once every
+/// socket is listening.
+#[cfg(unix)]
+pub fn notify_ready() {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = match std::env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let sent = (|| -> std::io::Result<()> {
+        let socket = UnixDatagram::unbound()?;
+        match path.strip_prefix('@') {
+            #[cfg(target_os = "linux")]
+            Some(name) => {
+                use std::os::linux::net::SocketAddrExt;
+                let addr = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+                socket.send_to_addr(b"READY=1", &addr)?;
+            }
+            _ => {
+                socket.send_to(b"READY=1", &path)?;
+            }
+        }
+        Ok(())
+    })();
+    if let Err(e) = sent {
+        tracing::event!(tracing::Level::WARN, "failed to notify systemd: {:?}", e);
+    }
+}
+
+#[cfg(not(unix))]
+pub fn notify_ready() {}
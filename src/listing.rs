@@ -0,0 +1,113 @@
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Paging, ordering and field selection shared by the list endpoints:
+/// `limit`, `offset`, `sort=<field>` (`-<field>` for descending) and
+/// `fields=a,b,c`.
+#[derive(Debug, Clone)]
+pub struct ListQuery {
+    pub limit: Option<usize>,
+    pub offset: usize,
+    pub sort: Option<String>,
+    pub descending: bool,
+    pub fields: Option<Vec<String>>,
+}
+
+/// What an item is ordered by.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum SortKey {
+    Time(Option<DateTime<Utc>>),
+    Number(i64),
+    Text(String),
+}
+
+/// One page of items, with the number of items before paging.
+pub struct Page {
+    pub total: usize,
+    pub items: Vec<serde_json::Value>,
+}
+
+impl ListQuery {
+    /// Read the list parameters of `map`; only fields in `sortable` can be
+    /// sorted by.
+    pub fn parse(map: &HashMap<String, String>, sortable: &[&str]) -> anyhow::Result<ListQuery> {
+        let limit = match map.get("limit") {
+            Some(limit) => Some(limit.parse()?),
+            None => None,
+        };
+        let offset = match map.get("offset") {
+            Some(offset) => offset.parse()?,
+            None => 0,
+        };
+
+        let (sort, descending) = match map.get("sort") {
+            Some(sort) => {
+                let (field, descending) = match sort.strip_prefix('-') {
+                    Some(field) => (field, true),
+                    None => (sort.as_str(), false),
+                };
+                if !sortable.contains(&field) {
+                    anyhow::bail!(
+                        "cannot sort by {}, expected one of {}",
+                        field,
+                        sortable.join(", ")
+                    );
+                }
+                (Some(field.to_owned()), descending)
+            }
+            None => (None, false),
+        };
+
+        let fields = map.get("fields").map(|fields| {
+            fields
+                .split(',')
+                .map(str::trim)
+                .filter(|f| !f.is_empty())
+                .map(str::to_owned)
+                .collect()
+        });
+
+        Ok(ListQuery {
+            limit,
+            offset,
+            sort,
+            descending,
+            fields,
+        })
+    }
+
+    /// Sort `items` with `key` (given the sort field), then cut out the
+    /// requested page and keep only the selected fields.
+    pub fn apply<T: Serialize>(
+        &self,
+        mut items: Vec<T>,
+        key: impl Fn(&T, &str) -> SortKey,
+    ) -> anyhow::Result<Page> {
+        if let Some(field) = &self.sort {
+            // stable, so equal keys keep their default order
+            if self.descending {
+                items.sort_by_cached_key(|item| std::cmp::Reverse(key(item, field)));
+            } else {
+                items.sort_by_cached_key(|item| key(item, field));
+            }
+        }
+
+        let total = items.len();
+        let items = items
+            .into_iter()
+            .skip(self.offset)
+            .take(self.limit.unwrap_or(usize::MAX))
+            .map(|item| {
+                let mut value = serde_json::to_value(item)?;
+                if let (Some(fields), Some(object)) = (&self.fields, value.as_object_mut()) {
+                    object.retain(|k, _| fields.iter().any(|f| f == k));
+                }
+                Ok(value)
+            })
+            .collect::<anyhow::Result<Vec<_>>>()?;
+
+        Ok(Page { total, items })
+    }
+}
@@ -0,0 +1,192 @@
+use crate::config::Config;
+use crate::registry::Platform;
+use bollard::image::ListImagesOptions;
+use bollard::image::RemoveImageOptions;
+use bollard::image::TagImageOptions;
+use bollard::Docker;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::event;
+use tracing::Level;
+
+// guards the file recording when each kept tag was last used, which every
+// job updates
+static USAGE: Mutex<()> = Mutex::new(());
+
+/// `repository:tag` of a kept image of `digest`, for `platform` or else the
+/// daemon's own platform.
+pub async fn find(
+    docker: &Docker,
+    config: &Config,
+    digest: &str,
+    platform: Option<&Platform>,
+) -> Option<String> {
+    let wanted = match platform {
+        Some(platform) => platform.clone(),
+        None => {
+            let version = docker.version().await.ok()?;
+            Platform {
+                os: version.os?,
+                architecture: version.arch?,
+                variant: None,
+            }
+        }
+    };
+
+    let repository = &config.local_cache.repository;
+    let prefix = format!("{}:{}_", repository, digest.replace(':', "_"));
+    for image in kept(docker, repository).await.ok()? {
+        let tag = match image.repo_tags.iter().find(|t| t.starts_with(&prefix)) {
+            Some(tag) => tag,
+            None => continue,
+        };
+        let local = match docker.inspect_image(tag).await {
+            Ok(local) => local,
+            Err(_) => continue,
+        };
+        let local_platform = Platform {
+            os: local.os.unwrap_or_default(),
+            architecture: local.architecture.unwrap_or_default(),
+            variant: local.variant,
+        };
+        if wanted.matches(&local_platform) {
+            touch(config, tag);
+            return Some(tag.clone());
+        }
+    }
+    None
+}
+
+/// Keep the local image `image` of `digest` under the cache repository, then
+/// evict the least recently used images while they add up to more than the
+/// configured size.
+pub async fn keep(
+    docker: &Docker,
+    config: &Config,
+    image: &str,
+    digest: &str,
+) -> anyhow::Result<()> {
+    let local = docker.inspect_image(image).await?;
+    let platform = Platform {
+        os: local.os.unwrap_or_default(),
+        architecture: local.architecture.unwrap_or_default(),
+        variant: local.variant,
+    };
+    let repository = &config.local_cache.repository;
+    let tag = format!(
+        "{}_{}",
+        digest.replace(':', "_"),
+        platform.to_string().replace('/', "_")
+    );
+    docker
+        .tag_image(
+            image,
+            Some(TagImageOptions {
+                repo: repository.as_str(),
+                tag: tag.as_str(),
+            }),
+        )
+        .await?;
+    touch(config, &format!("{}:{}", repository, tag));
+
+    evict(docker, config).await
+}
+
+async fn evict(docker: &Docker, config: &Config) -> anyhow::Result<()> {
+    let max_size = match config.local_cache.max_size {
+        Some(max_size) => max_size,
+        None => return Ok(()),
+    };
+    let repository = &config.local_cache.repository;
+    let prefix = format!("{}:", repository);
+    let usage = load(config);
+
+    // images are counted whole, layers they share add up more than once
+    let mut images: Vec<(Vec<String>, u64, i64)> = kept(docker, repository)
+        .await?
+        .into_iter()
+        .map(|image| {
+            let tags: Vec<String> = image
+                .repo_tags
+                .into_iter()
+                .filter(|t| t.starts_with(&prefix))
+                .collect();
+            let used = tags.iter().filter_map(|t| usage.get(t)).max().copied();
+            (tags, image.size.max(0) as u64, used.unwrap_or(0))
+        })
+        .collect();
+    images.sort_by_key(|(_, _, used)| *used);
+
+    let mut total: u64 = images.iter().map(|(_, size, _)| size).sum();
+    let mut evicted = Vec::new();
+    for (tags, size, _) in images {
+        if total <= max_size {
+            break;
+        }
+        for tag in &tags {
+            // only untags where other tags still use the image
+            match docker
+                .remove_image(tag, Some(RemoveImageOptions::default()), None)
+                .await
+            {
+                Ok(_) => evicted.push(tag.clone()),
+                Err(e) => event!(Level::WARN, "could not evict {}: {:?}", tag, e),
+            }
+        }
+        total = total.saturating_sub(size);
+    }
+    if !evicted.is_empty() {
+        event!(
+            Level::INFO,
+            "evicted {} from the local cache",
+            evicted.join(", ")
+        );
+        let _lock = USAGE.lock().unwrap();
+        let mut usage = load(config);
+        for tag in &evicted {
+            usage.remove(tag);
+        }
+        save(config, &usage);
+    }
+    Ok(())
+}
+
+async fn kept(
+    docker: &Docker,
+    repository: &str,
+) -> Result<Vec<bollard::models::ImageSummary>, bollard::errors::Error> {
+    let filters = HashMap::from([("reference".to_owned(), vec![repository.to_owned()])]);
+    docker
+        .list_images(Some(ListImagesOptions {
+            filters,
+            ..Default::default()
+        }))
+        .await
+}
+
+fn touch(config: &Config, tag: &str) {
+    let _lock = USAGE.lock().unwrap();
+    let mut usage = load(config);
+    usage.insert(tag.to_owned(), chrono::Utc::now().timestamp());
+    save(config, &usage);
+}
+
+fn path(config: &Config) -> PathBuf {
+    config.state_dir.join("local-cache.json")
+}
+
+fn load(config: &Config) -> HashMap<String, i64> {
+    std::fs::read(path(config))
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save(config: &Config, usage: &HashMap<String, i64>) {
+    let saved = std::fs::create_dir_all(&config.state_dir)
+        .and_then(|_| std::fs::write(path(config), serde_json::to_vec(usage).unwrap()));
+    if let Err(e) = saved {
+        event!(Level::WARN, "failed to record local cache use: {:?}", e);
+    }
+}
@@ -0,0 +1,327 @@
+use crate::config::LogConfig;
+use crate::config::LogFileConfig;
+use crate::config::LogFormat;
+use crate::config::Rotation;
+use crate::tracecontext;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Local;
+use once_cell::sync::OnceCell;
+use serde_json::Map;
+use serde_json::Value;
+use std::fmt;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Mutex;
+use tracing::field::Field;
+use tracing::field::Visit;
+use tracing::Event;
+use tracing::Subscriber;
+use tracing_subscriber::field::RecordFields;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::format::Writer;
+use tracing_subscriber::fmt::writer::BoxMakeWriter;
+use tracing_subscriber::fmt::writer::MakeWriterExt;
+use tracing_subscriber::fmt::FmtContext;
+use tracing_subscriber::fmt::FormatEvent;
+use tracing_subscriber::fmt::FormatFields;
+use tracing_subscriber::fmt::FormattedFields;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::EnvFilter;
+
+// swaps the filter of the installed subscriber, set by `init`
+type Reload = Box<dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync>;
+
+static FILTER: OnceCell<(Reload, Mutex<String>)> = OnceCell::new();
+
+/// Install the global subscriber, filtered by `filter` (`RUST_LOG` syntax).
+/// Logs go to stdout, and to the log file when one is configured.
+pub fn init(config: &LogConfig, filter: String) -> anyhow::Result<()> {
+    let writer = match &config.file {
+        Some(file) => BoxMakeWriter::new(io::stdout.and(RotatingFile::open(file)?)),
+        None => BoxMakeWriter::new(io::stdout),
+    };
+
+    // Configure the default `tracing` subscriber.
+    // The `fmt` subscriber from the `tracing-subscriber` crate logs `tracing`
+    // events to stdout. Other subscribers are available for integrating with
+    // distributed tracing systems such as OpenTelemetry.
+    let builder = tracing_subscriber::fmt()
+        // Use the filter we built above to determine which traces to record.
+        .with_env_filter(filter.clone())
+        // Record an event when each span closes. This can be used to time our
+        // routes' durations!
+        .with_span_events(FmtSpan::CLOSE)
+        // no color codes in the file
+        .with_ansi(config.file.is_none())
+        .with_writer(writer);
+
+    // the subscriber types differ by format, so each keeps its own handle
+    let reload: Reload = match config.format {
+        LogFormat::Text => {
+            let builder = builder.with_filter_reloading();
+            let handle = builder.reload_handle();
+            builder.init();
+            Box::new(move |filter| handle.reload(filter).map_err(|e| e.to_string()))
+        }
+        LogFormat::Json => {
+            let builder = builder
+                .event_format(Json)
+                .fmt_fields(JsonFields)
+                .with_filter_reloading();
+            let handle = builder.reload_handle();
+            builder.init();
+            Box::new(move |filter| handle.reload(filter).map_err(|e| e.to_string()))
+        }
+    };
+    let _ = FILTER.set((reload, Mutex::new(filter)));
+    Ok(())
+}
+
+/// The filter events are currently recorded by, in `RUST_LOG` syntax.
+pub fn filter() -> Option<String> {
+    FILTER
+        .get()
+        .map(|(_, current)| current.lock().unwrap().clone())
+}
+
+/// Record events by `directives` from now on, `RUST_LOG` syntax as well,
+/// returning the filter they replace.
+pub fn set_filter(directives: &str) -> Result<String, String> {
+    let directives = directives.trim();
+    if directives.is_empty() {
+        return Err("the filter is empty".to_owned());
+    }
+    let filter = EnvFilter::try_new(directives).map_err(|e| e.to_string())?;
+    let (reload, current) = FILTER.get().ok_or("logging is not set up")?;
+    let mut current = current.lock().unwrap();
+    reload(filter)?;
+    // records of the `log` crate are dropped above the level set at start
+    tracing_log::log::set_max_level(tracing_log::AsLog::as_log(
+        &tracing::level_filters::LevelFilter::current(),
+    ));
+    Ok(std::mem::replace(&mut *current, directives.to_owned()))
+}
+
+// Log file moved to `<path>.1` (and older ones one further) when its hour or
+// day is over or it would grow past `max_size`. Events are written whole, so
+// rotation never splits a line.
+struct RotatingFile {
+    config: LogFileConfig,
+    current: Mutex<Current>,
+}
+
+struct Current {
+    file: File,
+    size: u64,
+    period: String,
+}
+
+impl RotatingFile {
+    fn open(config: &LogFileConfig) -> anyhow::Result<RotatingFile> {
+        if let Some(dir) = config.path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        let file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&config.path)
+            .with_context(|| format!("failed to open {}", config.path.display()))?;
+
+        // a file left from an earlier run belongs to the period it was
+        // last written in
+        let metadata = file.metadata()?;
+        let modified = metadata
+            .modified()
+            .map(DateTime::<Local>::from)
+            .unwrap_or_else(|_| Local::now());
+        Ok(RotatingFile {
+            current: Mutex::new(Current {
+                file,
+                size: metadata.len(),
+                period: period(config.rotation, modified),
+            }),
+            config: config.clone(),
+        })
+    }
+
+    fn rotated(&self, n: usize) -> PathBuf {
+        let mut path = self.config.path.clone().into_os_string();
+        path.push(format!(".{}", n));
+        path.into()
+    }
+
+    fn rotate(&self, current: &mut Current) -> io::Result<()> {
+        if self.config.keep == 0 {
+            std::fs::remove_file(&self.config.path)?;
+        } else {
+            for n in (1..self.config.keep).rev() {
+                let _ = std::fs::rename(self.rotated(n), self.rotated(n + 1));
+            }
+            std::fs::rename(&self.config.path, self.rotated(1))?;
+        }
+        current.file = File::create(&self.config.path)?;
+        current.size = 0;
+        Ok(())
+    }
+}
+
+fn period(rotation: Rotation, time: DateTime<Local>) -> String {
+    match rotation {
+        Rotation::Never => String::new(),
+        Rotation::Hourly => time.format("%Y%m%d%H").to_string(),
+        Rotation::Daily => time.format("%Y%m%d").to_string(),
+    }
+}
+
+impl io::Write for &RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut current = self.current.lock().unwrap();
+        let period = period(self.config.rotation, Local::now());
+        let full = self
+            .config
+            .max_size
+            .is_some_and(|max| current.size > 0 && current.size + buf.len() as u64 > max);
+        if period != current.period || full {
+            self.rotate(&mut current)?;
+            current.period = period;
+        }
+        let written = io::Write::write(&mut current.file, buf)?;
+        current.size += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.current.lock().unwrap().file.flush()
+    }
+}
+
+impl<'a> MakeWriter<'a> for RotatingFile {
+    type Writer = &'a RotatingFile;
+
+    fn make_writer(&'a self) -> &'a RotatingFile {
+        self
+    }
+}
+
+/// Span of one HTTP request, with an id to find its log lines by.
+pub fn request_span(info: warp::trace::Info) -> tracing::Span {
+    static NEXT: AtomicU64 = AtomicU64::new(1);
+    // warp's own target, so filters written for `warp::trace::request` apply
+    // the caller's trace, so its logs can be found by trace id
+    let trace_id = info
+        .request_headers()
+        .get("traceparent")
+        .and_then(|v| v.to_str().ok())
+        .and_then(tracecontext::trace_id);
+    tracing::info_span!(
+        target: "warp::filters::trace",
+        "request",
+        request_id = NEXT.fetch_add(1, Ordering::Relaxed),
+        method = %info.method(),
+        path = %info.path(),
+        trace_id = trace_id.as_deref().unwrap_or_default(),
+    )
+}
+
+// One JSON object per event: time, level, target, the fields of every span
+// it is in (inner spans win) and its own fields.
+struct Json;
+
+impl<S, N> FormatEvent<S, N> for Json
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        ctx: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &Event<'_>,
+    ) -> fmt::Result {
+        let meta = event.metadata();
+        let mut object = Map::new();
+        object.insert("timestamp".into(), chrono::Utc::now().to_rfc3339().into());
+        object.insert("level".into(), meta.level().as_str().into());
+        object.insert("target".into(), meta.target().into());
+
+        if let Some(scope) = ctx.event_scope() {
+            let mut spans = Vec::new();
+            for span in scope.from_root() {
+                spans.push(Value::from(span.name()));
+                if let Some(fields) = span.extensions().get::<FormattedFields<N>>() {
+                    if let Ok(Value::Object(fields)) = serde_json::from_str(&fields.fields) {
+                        object.extend(fields);
+                    }
+                }
+            }
+            object.insert("spans".into(), spans.into());
+        }
+
+        event.record(&mut Visitor(&mut object));
+        writeln!(writer, "{}", Value::Object(object))
+    }
+}
+
+// Span fields stored as a JSON object, for `Json` to merge into its lines.
+struct JsonFields;
+
+impl<'writer> FormatFields<'writer> for JsonFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        mut writer: Writer<'writer>,
+        fields: R,
+    ) -> fmt::Result {
+        let mut object = Map::new();
+        fields.record(&mut Visitor(&mut object));
+        write!(writer, "{}", Value::Object(object))
+    }
+
+    fn add_fields(
+        &self,
+        current: &'writer mut FormattedFields<Self>,
+        fields: &tracing::span::Record<'_>,
+    ) -> fmt::Result {
+        let mut object = match serde_json::from_str(&current.fields) {
+            Ok(Value::Object(object)) => object,
+            _ => Map::new(),
+        };
+        fields.record(&mut Visitor(&mut object));
+        current.fields = Value::Object(object).to_string();
+        Ok(())
+    }
+}
+
+struct Visitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for Visitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().into(), value.into());
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.0
+            .insert(field.name().into(), format!("{:?}", value).into());
+    }
+}
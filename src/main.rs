@@ -1,27 +1,98 @@
+// the futures of a sync nest deeper than the default limit allows
+#![recursion_limit = "256"]
+
+mod admin;
+mod auth;
+mod batch;
+mod bundle;
+mod cache;
+mod cleanup;
+mod cli;
+mod config;
+mod copy;
+mod daemonevents;
+mod details;
+mod diff;
+mod discovery;
+mod diskusage;
+mod error;
+mod events;
+mod export;
+mod gc;
+mod ghcr;
+mod history;
+mod http;
+mod immutable;
+mod jobs;
+mod labels;
+mod limits;
+mod listen;
+mod listing;
+mod localcache;
+mod logging;
+mod maintenance;
+mod mappings;
+mod metrics;
+mod mirrorlist;
+mod policy;
+mod profiling;
+mod progresslog;
+mod proxy;
+mod ratelimit;
+mod readback;
+mod readiness;
+mod registry;
+mod retention;
+mod retry;
+mod s3;
+mod scheduler;
+mod settings;
+mod signature;
+mod spans;
+mod squash;
+mod statsd;
+mod strip;
+mod synclink;
+mod top;
+mod tracecontext;
+mod version;
+mod webhook;
+
 use bollard::auth::DockerCredentials;
 use bollard::image::CreateImageOptions;
-use bollard::image::PruneImagesOptions;
+use bollard::image::ListImagesOptions;
 use bollard::image::PushImageOptions;
-use bollard::image::TagImageOptions;
 use bollard::image::RemoveImageOptions;
+use bollard::image::TagImageOptions;
 use bollard::Docker;
+use error::Error;
 use futures::stream::StreamExt;
 use serde::Deserialize;
 use serde::Serialize;
+use std::collections::BTreeMap;
 use std::collections::HashMap;
 use std::default::Default;
 use std::env;
+use std::sync::Arc;
+use std::sync::RwLock;
+use std::time::Duration;
 use tracing::event;
+use tracing::field::Empty;
+use tracing::Instrument;
 use tracing::Level;
-use tracing_subscriber::fmt::format::FmtSpan;
 use warp::hyper::StatusCode;
-use warp::reject::Reject;
 use warp::Filter;
 use warp::Rejection;
 use warp::Reply;
 
 #[tokio::main]
 async fn main() {
+    // run a subcommand instead of the server when given arguments
+    let args: Vec<String> = env::args().skip(1).collect();
+    if !args.is_empty() {
+        std::process::exit(cli::run(&args).await);
+    }
+
     // read Docker username from env
     let docker_username = env::var("USERNAME").unwrap_or_else(|e| {
         eprintln!("Failed to read Docker username: {}", e);
@@ -34,76 +105,547 @@ async fn main() {
         std::process::exit(1);
     });
 
-    let docker_username_filter = warp::any().map(move || docker_username.clone().into());
-    let docker_password_filter = warp::any().map(move || docker_password.clone().into());
+    let config = Arc::new(config::Config::from_env().unwrap_or_else(|e| {
+        eprintln!("Failed to read configuration: {}", e);
+        std::process::exit(1);
+    }));
+
+    http::init(config.network.clone());
+    retry::init(config.retry.clone());
+    auth::init(
+        config.pull_anonymous_fallback,
+        (docker_username.clone(), docker_password.clone()),
+    );
+    progresslog::init(&config.log);
+    let network = config.network.clone();
+    let log = config.log.clone();
+    let listen = config.listen.clone();
+    let cache = Arc::new(cache::BlobCache::new(config.cache_dir.clone()));
+
+    // pull-through cache registry mode
+    let proxy = config.proxy.upstream.as_ref().map(|upstream| {
+        let upstream_host = upstream
+            .trim_start_matches("https://")
+            .trim_start_matches("http://")
+            .trim_end_matches('/');
+        let mirror = config.proxy.mirror.then(|| proxy::Mirror {
+            registry: registry::Registry::new(
+                &config.dest_registry,
+                Some((docker_username.clone(), docker_password.clone())),
+            )
+            .with_chunk_size(config.upload_chunk_size)
+            .with_session_retries(config.upload_session_retries),
+            repository: config.dest_repository.clone(),
+            upstream_host: upstream_host.to_owned(),
+            nested: (config.dest_naming == config::Naming::Nested)
+                .then(|| config.nested_root(upstream_host)),
+            policy: config.policy.clone(),
+            parallelism: config.layer_parallelism,
+        });
+        Arc::new(proxy::Proxy::new(
+            &config.proxy,
+            cache.clone(),
+            registry::Registry::new(
+                upstream,
+                auth::pull_credentials(&config, upstream_host).unwrap_or_else(|e| {
+                    eprintln!("Failed to read upstream credentials: {}", e.report());
+                    std::process::exit(1);
+                }),
+            ),
+            mirror,
+        ))
+    });
+    let proxy_filter = warp::any().map(move || proxy.clone());
+
+    // removal of untagged manifests through the destination's own API
+    let gc = gc::Collector::new(
+        &config.gc,
+        &config.dest_registry,
+        (docker_username.clone(), docker_password.clone()),
+        config.dest_repository.clone(),
+    )
+    .unwrap_or_else(|e| {
+        eprintln!("Failed to set up DEST_GC: {:#}", e);
+        std::process::exit(1);
+    })
+    .map(Arc::new);
+
+    // scheduled cleanup of old tags in the destination
+    let retention = config.retention.is_enabled().then(|| {
+        retention::run(
+            config.retention.clone(),
+            registry::Registry::new(
+                &config.dest_registry,
+                Some((docker_username.clone(), docker_password.clone())),
+            ),
+            config.dest_repository.clone(),
+            gc.clone(),
+        )
+    });
+    let jobs = Arc::new(jobs::Jobs::open(&config.state_dir));
+    let mappings = Arc::new(mappings::Mappings::open(&config.state_dir));
+    let worker = Arc::new(Worker {
+        username: docker_username.clone(),
+        password: docker_password.clone(),
+        config: RwLock::new(config.clone()),
+        cache,
+        jobs: jobs.clone(),
+        mappings: mappings.clone(),
+        batches: Arc::new(batch::Batches::open(&config.state_dir)),
+        scheduler: Arc::new(scheduler::Scheduler::new(
+            config.max_concurrent_syncs,
+            config.priority_preempt,
+            config.fair_share_weights.clone(),
+        )),
+    });
+    let jobs_filter = warp::any().map(move || jobs.clone());
+    let mappings_filter = warp::any().map(move || mappings.clone());
+
+    let config_worker = worker.clone();
+    let config_filter = warp::any().map(move || config_worker.config());
 
     // Filter traces based on the RUST_LOG env var, or, if it's not set,
     // default to show the output of the example.
     let filter = std::env::var("RUST_LOG").unwrap_or("tracing=info,warp=debug".to_owned());
+    if let Err(e) = logging::init(&log, filter) {
+        eprintln!("Failed to set up logging: {:#}", e);
+        std::process::exit(1);
+    }
+
+    metrics::init(&worker.config().metric_labels);
+    tokio::spawn(check_daemon_proxy(network));
+    if let Some(retention) = retention {
+        tokio::spawn(retention);
+    }
+    if let Some(gc) = gc {
+        tokio::spawn(gc::run(gc, worker.config().gc.interval));
+    }
+
+    // pick up syncs a restart interrupted, after clearing what they and any
+    // other cut short sync left behind
+    let interrupted = worker.jobs.take_interrupted();
+    if let Ok(docker) = connect_docker() {
+        cleanup::remove_orphans(&docker, &worker.config(), &interrupted).await;
+    }
+    if worker.config().daemon_events {
+        tokio::spawn(daemonevents::watch(worker.config(), worker.jobs.clone()));
+    }
+    if let Some(interval) = worker.config().dest_check_interval {
+        let dest = registry::Registry::new(
+            &worker.config().dest_registry,
+            Some((worker.username.clone(), worker.password.clone())),
+        );
+        let repository = worker.config().dest_repository.clone();
+        tokio::spawn(readiness::watch(dest, repository, interval));
+    }
+    if let Some(interval) = worker.config().daemon_df_interval {
+        tokio::spawn(diskusage::sample(interval));
+    }
+    let statsd_worker = worker.clone();
+    tokio::spawn(statsd::export(worker.config().statsd.clone(), move || {
+        refresh_gauges(&statsd_worker)
+    }));
+    for job in interrupted {
+        event!(Level::INFO, "resuming job {} for {}", job.id, job.image);
+        tokio::spawn(worker.clone().run(job.id, job.priority, job.request));
+    }
+
+    // drop old job history
+    let (history, jobs) = (worker.config().history.clone(), worker.jobs.clone());
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(history.gc_interval);
+        loop {
+            interval.tick().await;
+            let removed = jobs.gc(history.max_age, history.max_jobs);
+            if removed > 0 {
+                event!(Level::INFO, "removed {} old jobs", removed);
+                events::publish(
+                    "prune_finished",
+                    serde_json::json!({ "kind": "jobs", "removed": removed }),
+                );
+            }
+        }
+    });
+    // keep what the mirror list names synced
+    let mirrors_file = worker.config().mirror_list.path(&worker.config().state_dir);
+    let mirror_list = (mirrors_file.is_some() || worker.config().discovery.enabled).then(|| {
+        Arc::new(mirrorlist::Mirrors::new(
+            mirrors_file,
+            worker.config().state_dir.clone(),
+        ))
+    });
+    if let Some(mirrors) = &mirror_list {
+        if worker.config().discovery.enabled {
+            tokio::spawn(discovery::watch(
+                worker.config().discovery.clone(),
+                worker.config().dest_registry.clone(),
+                worker.config().dest_repository.clone(),
+                mirrors.clone(),
+            ));
+        }
+        if let Some(git) = worker.config().mirror_list.git.clone() {
+            tokio::spawn(mirrorlist::follow_git(
+                git,
+                worker.config().state_dir.clone(),
+                mirrors.clone(),
+            ));
+        }
+        tokio::spawn(reconcile_mirrors(worker.clone(), mirrors.clone()));
+    }
+    let mirrors_filter = warp::any().map(move || mirror_list.clone());
 
-    // Configure the default `tracing` subscriber.
-    // The `fmt` subscriber from the `tracing-subscriber` crate logs `tracing`
-    // events to stdout. Other subscribers are available for integrating with
-    // distributed tracing systems such as OpenTelemetry.
-    tracing_subscriber::fmt()
-        // Use the filter we built above to determine which traces to record.
-        .with_env_filter(filter)
-        // Record an event when each span closes. This can be used to time our
-        // routes' durations!
-        .with_span_events(FmtSpan::CLOSE)
-        .init();
+    let admin_token = worker.config().admin_token.clone();
+    let limits = worker.config().limits.clone();
+    let verifier = webhook::Verifier::new(&worker.config().webhook).map(Arc::new);
+    let verifier_filter = warp::any().map(move || verifier.clone());
+    let links =
+        synclink::Links::new(&worker.config().sync_links, &worker.config().state_dir).map(Arc::new);
+    let links_filter = warp::any().map(move || links.clone());
+    let worker_filter = warp::any().map(move || worker.clone());
 
     let health = warp::get()
         .and(warp::path("health"))
         .and(warp::path::end())
         .and_then(health_check);
 
+    let ready = warp::get()
+        .and(warp::path("ready"))
+        .and(warp::path::end())
+        .and(worker_filter.clone())
+        .and_then(ready_check);
+
+    let version = warp::get()
+        .and(warp::path("version"))
+        .and(warp::path::end())
+        .and(worker_filter.clone())
+        .and_then(get_version);
+
     let image_sync = warp::get()
         .and(warp::path("imagesync"))
         .and(warp::path::end())
         .and(warp::query())
-        .and(docker_username_filter.clone())
-        .and(docker_password_filter.clone())
+        .and(tracecontext::headers())
+        .and(worker_filter.clone())
         .and_then(sync_image);
 
+    let list_jobs = warp::get()
+        .and(warp::path("jobs"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(jobs_filter.clone())
+        .and_then(list_jobs);
+
+    let get_job = warp::get()
+        .and(warp::path("jobs"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(jobs_filter.clone())
+        .and_then(get_job);
+
+    let get_batch = warp::get()
+        .and(warp::path("batches"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(worker_filter.clone())
+        .and_then(get_batch);
+
+    let retry_job = warp::post()
+        .and(warp::path("jobs"))
+        .and(warp::path::param())
+        .and(warp::path("retry"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(tracecontext::headers())
+        .and(worker_filter.clone())
+        .and_then(retry_job);
+
+    let retry_jobs = warp::post()
+        .and(warp::path("jobs"))
+        .and(warp::path("retry"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(tracecontext::headers())
+        .and(worker_filter.clone())
+        .and_then(retry_jobs);
+
+    let export_history = warp::get()
+        .and(warp::path("history"))
+        .and(warp::path("export"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(jobs_filter.clone())
+        .and_then(export_history);
+
     let prune_images = warp::get()
         .and(warp::path("prune_images"))
         .and(warp::path::end())
-        .and(docker_username_filter.clone())
-        .and(docker_password_filter.clone())
+        .and(jobs_filter.clone())
         .and_then(prune_images);
 
+    let bundle = warp::post()
+        .and(warp::path("bundle"))
+        .and(warp::path::end())
+        .and(limits::json(limits.body_bytes))
+        .and(config_filter.clone())
+        .and_then(create_bundle);
+
+    let copy = warp::post()
+        .and(warp::path("copy"))
+        .and(warp::path::end())
+        .and(limits::json(limits.body_bytes))
+        .and(tracecontext::headers())
+        .and(worker_filter.clone())
+        .and_then(copy);
+
+    let generic_webhook = warp::post()
+        .and(warp::path("webhooks"))
+        .and(warp::path("generic"))
+        .and(warp::path::end())
+        .and(warp::header::optional::<String>("x-imagesync-timestamp"))
+        .and(warp::header::optional::<String>("x-imagesync-signature"))
+        .and(limits::bytes(limits.body_bytes))
+        .and(tracecontext::headers())
+        .and(verifier_filter)
+        .and(worker_filter.clone())
+        .and_then(generic_webhook);
+
+    let redeem_link = warp::post()
+        .and(warp::path("sync-links"))
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(tracecontext::headers())
+        .and(links_filter.clone())
+        .and(worker_filter.clone())
+        .and_then(redeem_link);
+
+    let diff_images = warp::get()
+        .and(warp::path("diff"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(worker_filter.clone())
+        .and_then(diff_images);
+
+    let mirror_status = warp::get()
+        .and(warp::path("mirrors"))
+        .and(warp::path::end())
+        .and(mirrors_filter)
+        .and_then(mirror_status);
+
+    let origin = warp::get()
+        .and(warp::path("origin"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(worker_filter.clone())
+        .and_then(origin);
+
+    let list_mappings = warp::get()
+        .and(warp::path("mappings"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and(mappings_filter)
+        .and_then(list_mappings);
+
+    let events = warp::get()
+        .and(warp::path("events"))
+        .and(warp::path::end())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(stream_events);
+
+    let metrics = warp::get()
+        .and(warp::path("metrics"))
+        .and(warp::path::end())
+        .and(worker_filter.clone())
+        .and_then(export_metrics);
+
+    let admin = admin::authorized(admin_token);
+
+    let debug_heap = warp::get()
+        .and(warp::path("debug"))
+        .and(warp::path("heap"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and_then(debug_heap);
+
+    let debug_cpu = warp::get()
+        .and(warp::path("debug"))
+        .and(warp::path("cpu"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(warp::query::<HashMap<String, String>>())
+        .and_then(debug_cpu);
+
+    let get_loglevel = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("loglevel"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and_then(get_loglevel);
+
+    let set_loglevel = warp::put()
+        .and(warp::path("admin"))
+        .and(warp::path("loglevel"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(limits::json(limits.body_bytes))
+        .and(worker_filter.clone())
+        .and_then(set_loglevel);
+
+    let get_config = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("config"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(worker_filter.clone())
+        .and_then(get_config);
+
+    let get_settings = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("settings"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(worker_filter.clone())
+        .and_then(get_settings);
+
+    let set_settings = warp::patch()
+        .and(warp::path("admin"))
+        .and(warp::path("settings"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(limits::json(limits.body_bytes))
+        .and(worker_filter.clone())
+        .and_then(set_settings);
+
+    let queue_status = warp::get()
+        .and(warp::path("admin"))
+        .and(warp::path("queue"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(worker_filter.clone())
+        .and_then(queue_status);
+
+    let pause_queue = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("queue"))
+        .and(warp::path("pause"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(worker_filter.clone())
+        .and_then(pause_queue);
+
+    let resume_queue = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("queue"))
+        .and(warp::path("resume"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(worker_filter.clone())
+        .and_then(resume_queue);
+
+    let sign_link = warp::post()
+        .and(warp::path("admin"))
+        .and(warp::path("sync-links"))
+        .and(warp::path::end())
+        .and(admin.clone())
+        .and(limits::json(limits.body_bytes))
+        .and(links_filter)
+        .and_then(sign_link);
+
+    // boxed, the route tree is too deep for the compiler otherwise
+    let admin_api = debug_heap
+        .or(debug_cpu)
+        .or(get_loglevel)
+        .or(set_loglevel)
+        .or(get_config)
+        .or(get_settings)
+        .or(set_settings)
+        .or(queue_status)
+        .or(pause_queue)
+        .or(resume_queue)
+        .or(sign_link)
+        .boxed();
+
+    let registry_api = warp::path("v2")
+        .and(warp::method())
+        .and(warp::path::tail())
+        .and(proxy_filter.clone())
+        .and_then(serve_registry);
+
     let routes = image_sync
         .or(health)
+        .or(ready)
+        .or(version)
+        .or(list_jobs)
+        .or(get_job)
+        .or(get_batch)
+        .or(retry_job)
+        .or(retry_jobs)
+        .or(export_history)
         .or(prune_images)
-        .with(warp::trace::request())
+        .or(bundle)
+        .or(copy)
+        .or(generic_webhook)
+        .or(redeem_link)
+        .or(diff_images)
+        .or(mirror_status)
+        .or(list_mappings)
+        .or(origin)
+        .or(events)
+        .or(metrics)
+        .or(admin_api)
+        .or(registry_api);
+    let routes = limits::query(limits.query_bytes)
+        .and(routes)
+        .with(warp::trace(logging::request_span))
         .recover(return_error);
 
-    warp::serve(routes).run(([127, 0, 0, 1], 3030)).await;
-}
-
-#[derive(Debug)]
-pub enum Error {
-    ImageFormatError,
-}
-
-impl Reject for Error {}
-
-impl std::fmt::Display for Error {
-    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match self {
-            Error::ImageFormatError => write!(f, "Image is null"),
+    // sockets from systemd socket activation, otherwise TCP, a Unix socket,
+    // or both
+    let mut servers: Vec<std::pin::Pin<Box<dyn std::future::Future<Output = ()> + Send>>> =
+        Vec::new();
+    let inherited = listen::systemd().unwrap_or_else(|e| {
+        eprintln!("Failed to use the sockets passed by systemd: {:#}", e);
+        std::process::exit(1);
+    });
+    let activated = !inherited.is_empty();
+    for socket in inherited {
+        match socket {
+            listen::Inherited::Tcp(l) => servers.push(Box::pin(
+                warp::serve(routes.clone()).run_incoming(listen::tcp_incoming(l)),
+            )),
+            #[cfg(unix)]
+            listen::Inherited::Unix(l) => servers.push(Box::pin(
+                warp::serve(routes.clone()).run_incoming(listen::unix_incoming(l)),
+            )),
+        }
+    }
+    if let Some(addr) = listen.tcp.filter(|_| !activated) {
+        servers.push(Box::pin(warp::serve(routes.clone()).run(addr)));
+    }
+    if let Some(path) = listen.unix.as_ref().filter(|_| !activated) {
+        match listen::unix(path, listen.unix_mode) {
+            Ok(incoming) => {
+                event!(Level::INFO, "listening on {}", path.display());
+                servers.push(Box::pin(warp::serve(routes.clone()).run_incoming(incoming)));
+            }
+            Err(e) => {
+                eprintln!("Failed to listen: {:#}", e);
+                std::process::exit(1);
+            }
         }
     }
+    if servers.is_empty() {
+        eprintln!("Nothing to listen on, set LISTEN_ADDR or LISTEN_UNIX");
+        std::process::exit(1);
+    }
+    listen::notify_ready();
+    futures::future::join_all(servers).await;
 }
 
 #[tracing::instrument]
 pub async fn return_error(r: Rejection) -> Result<impl Reply, Rejection> {
-    if let Some(crate::Error::ImageFormatError) = r.find() {
-        Ok(warp::reply::with_status(
-            "Image is null".to_string(),
-            StatusCode::UNAUTHORIZED,
-        ))
+    if let Some(e) = r.find::<Error>() {
+        Ok(warp::reply::with_status(e.report(), e.status()))
     } else {
         Ok(warp::reply::with_status(
             "Route not found".to_string(),
@@ -114,8 +656,139 @@ pub async fn return_error(r: Rejection) -> Result<impl Reply, Rejection> {
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct SyncImageRes {
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub job_id: Option<String>,
     pub source_image: String,
     pub dest_image: String,
+    /// Digest of the source manifest synced, when known.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub source_digest: Option<String>,
+    /// Digest of the manifest pushed, when known.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub digest: Option<String>,
+    /// Extra destination tag derived from the source digest, in pinned mode.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub digest_tag: Option<String>,
+    /// Source registry the image was pulled from, when mirrors are configured.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub pulled_from: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub exported: Vec<String>,
+    /// Entrypoint, ports and such of each synced platform.
+    #[serde(skip_serializing_if = "Vec::is_empty", default)]
+    pub images: Vec<details::ImageDetails>,
+    /// Milliseconds spent in each step, as kept with the job.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty", default)]
+    pub timings: BTreeMap<String, u64>,
+}
+
+// What the sync `res` of job `id`, run for `map`, mirrored where.
+fn mapping(
+    config: &config::Config,
+    id: &str,
+    map: &HashMap<String, String>,
+    res: &SyncImageRes,
+) -> mappings::Mapping {
+    let image = map.get("image").cloned().unwrap_or_default();
+    // the default tag is known to the result, digests only to the request
+    let source = match image.contains('@') {
+        true => image,
+        false => res.source_image.clone(),
+    };
+    let (destination, digest_tag) = match map.get("destination") {
+        Some(_) => (res.dest_image.clone(), None),
+        None => {
+            // flat naming reports the tag alone
+            let (repository, tag) = match res.dest_image.rsplit_once(':') {
+                Some((repository, tag)) => (repository.to_owned(), tag.to_owned()),
+                None => (config.dest_repository.clone(), res.dest_image.clone()),
+            };
+            let repository = format!("{}/{}", config.dest_registry, repository);
+            (
+                format!("{}:{}", repository, tag),
+                res.digest_tag
+                    .as_ref()
+                    .map(|tag| format!("{}:{}", repository, tag)),
+            )
+        }
+    };
+    mappings::Mapping {
+        source,
+        source_digest: res.source_digest.clone(),
+        destination,
+        digest: res.digest.clone(),
+        digest_tag,
+        job_id: id.to_owned(),
+        synced_at: chrono::Utc::now(),
+    }
+}
+
+// `dest_image` of a sync: the tag alone in the shared repository of flat
+// naming, with its repository otherwise.
+fn reported_dest(config: &config::Config, repository: &str, tag: &str) -> String {
+    if repository == config.dest_repository {
+        tag.to_owned()
+    } else {
+        format!("{}:{}", repository, tag)
+    }
+}
+
+/// How many times a daemon push refused part way through is started again.
+const PUSH_AUTH_ATTEMPTS: u32 = 2;
+
+/// A source tag resolved to a digest before syncing.
+#[derive(Debug, Clone)]
+pub struct Pin {
+    /// `name@digest` the image is synced from.
+    pub reference: String,
+    pub digest: String,
+    /// Destination tag derived from `reference`, in pinned mode.
+    pub tag: Option<String>,
+}
+
+/// Body of `POST /copy`. Both references include the registry host.
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct CopyReq {
+    pub source: String,
+    pub destination: String,
+    /// Name of the `credentials:` entry to pull with; anonymous when unset.
+    pub source_credentials: Option<String>,
+    /// Name of the `credentials:` entry to push with; unset uses our own
+    /// login for the configured destination registry and none elsewhere.
+    pub destination_credentials: Option<String>,
+    pub priority: Option<String>,
+    /// Reported as the `tenant` label of the sync metrics.
+    pub tenant: Option<String>,
+    /// Copy only these platforms of a multi-arch image.
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    /// Merge the layers of every image into one.
+    #[serde(default)]
+    pub squash: bool,
+    #[serde(default)]
+    pub force: bool,
+    /// Copy a source tag listed in `MUTABLE_TAGS`.
+    #[serde(default)]
+    pub allow_mutable: bool,
+    #[serde(default = "default_wait")]
+    pub wait: bool,
+}
+
+fn default_wait() -> bool {
+    true
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct BundleReq {
+    pub images: Vec<String>,
+    pub name: Option<String>,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct BundleRes {
+    pub path: String,
+    pub manifest: bundle::BundleManifest,
 }
 
 #[tracing::instrument]
@@ -123,179 +796,2514 @@ async fn health_check() -> Result<impl Reply, Rejection> {
     Ok(warp::reply::with_status("OK".to_string(), StatusCode::OK))
 }
 
-#[tracing::instrument]
-async fn sync_image(
-    map: HashMap<String, String>,
-    username: String,
-    password: String,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    // check request parameters
-    if map.is_empty() {
-        return Err(warp::reject::custom(Error::ImageFormatError));
+// Readiness for sync traffic, which the destination being reachable with
+// our credentials is part of; `/health` only says the process is alive.
+#[tracing::instrument(skip(worker))]
+async fn ready_check(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    let ready = readiness::is_ready(worker.config().dest_check_interval.is_some());
+    let status = match ready {
+        true => StatusCode::OK,
+        false => StatusCode::SERVICE_UNAVAILABLE,
+    };
+    let body = serde_json::json!({
+        "ready": ready,
+        "destination": readiness::destination(),
+    });
+    Ok(warp::reply::with_status(warp::reply::json(&body), status))
+}
+
+#[tracing::instrument(skip(worker))]
+async fn get_version(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&version::version(&worker.config())))
+}
+
+// Connects lazily, so this only fails for a bad DOCKER_HOST; a daemon that is
+// down or restarting fails the requests made to it with a 503 instead.
+fn docker() -> Result<Docker, Error> {
+    connect_docker().map_err(Error::DaemonUnavailable)
+}
+
+/// The local Docker daemon: the Unix socket on Unix and the named pipe on
+/// Windows, either taken from `DOCKER_HOST` (`unix://` or `npipe://`) when
+/// set.
+pub fn connect_docker() -> Result<Docker, bollard::errors::Error> {
+    #[cfg(windows)]
+    if let Ok(host) = std::env::var("DOCKER_HOST") {
+        if host.starts_with("npipe://") {
+            return Docker::connect_with_named_pipe(&host, 120, bollard::API_DEFAULT_VERSION);
+        }
     }
+    Docker::connect_with_local_defaults()
+}
 
-    let image = match map.get("image") {
-        Some(value) => value,
-        None => return Err(warp::reject::custom(Error::ImageFormatError)),
+// The daemon pulls and pushes through its own proxy settings, which we
+// cannot change over the API; point out when they look wrong.
+async fn check_daemon_proxy(network: config::NetworkConfig) {
+    let info = match connect_docker() {
+        Ok(docker) => docker.info().await,
+        Err(e) => Err(e),
+    };
+    let info = match info {
+        Ok(info) => info,
+        Err(e) => {
+            event!(Level::WARN, "could not read daemon proxy settings: {:?}", e);
+            return;
+        }
     };
 
-    let mut parts: Vec<&str> = Vec::new();
-    if image.contains('@') {
-        parts.push(image);
-    } else {
-        parts = image.split(':').collect();
-    }
+    let daemon_proxy = info
+        .https_proxy
+        .filter(|p| !p.is_empty())
+        .or(info.http_proxy.filter(|p| !p.is_empty()));
+    let our_proxy = network
+        .proxy
+        .or_else(|| env::var("HTTPS_PROXY").ok())
+        .or_else(|| env::var("https_proxy").ok());
 
-    // length > 2
-    if parts.len() > 2 {
-        return Err(warp::reject::custom(Error::ImageFormatError));
+    match (daemon_proxy, our_proxy) {
+        (None, Some(ours)) => event!(
+            Level::WARN,
+            "registry traffic goes through {} but the Docker daemon has no proxy configured, daemon pulls and pushes may fail",
+            ours
+        ),
+        (Some(daemon), _) => event!(Level::INFO, "Docker daemon uses proxy {}", daemon),
+        (None, None) => {}
     }
+}
 
-    // pull latest tag image
-    if parts.len() == 1 {
-        if !parts[0].contains("@") {
-            parts.push("latest");
+// Digest behind a tag, asking the source registry or its mirrors in order.
+async fn resolve_digest(config: &config::Config, image: &str) -> anyhow::Result<Option<String>> {
+    let (host, _, _) = registry::parse_reference(image);
+    let mut error = None;
+    for endpoint in config.sources(&host) {
+        let (host, name, reference) =
+            registry::parse_reference(&registry::at_endpoint(image, &endpoint));
+        let src = registry::Registry::new(&host, auth::pull_credentials(config, &host)?);
+        match src.head_manifest(&src.repository(&name), &reference).await {
+            Ok(digest) => return Ok(digest),
+            Err(e) => error = Some(e),
         }
     }
+    Err(error.unwrap_or_else(|| anyhow::anyhow!("no source for {}", image)))
+}
 
-    let mut joined_image_str = parts.join(":");
-    let mut tag_image_str = parts.join("_");
-
-    // contain @ char
-    if parts[0].contains("@") {
-        joined_image_str = parts[0]
-            .replace('/', "_")
-            .replace('@', "_")
-            .replace(':', "_")
-            .to_string();
-        tag_image_str = parts[0]
-            .replace('/', "_")
-            .replace('@', "_")
-            .replace(':', "_")
-            .to_string();
+// Whether a source has `image`, asked with a HEAD request so a typo fails
+// in a second rather than after a pull: what the first source having it says
+// of its manifest, `ImageNotFound` when every source says it has no such
+// image, and `None` when none could say.
+async fn preflight(
+    config: &config::Config,
+    image: &str,
+) -> Result<Option<registry::ManifestHead>, Error> {
+    let (host, _, _) = registry::parse_reference(image);
+    let endpoints = config.sources(&host);
+    let mut missing = 0;
+    for endpoint in &endpoints {
+        let (host, name, reference) =
+            registry::parse_reference(&registry::at_endpoint(image, endpoint));
+        let src = registry::Registry::new(&host, auth::pull_credentials(config, &host)?);
+        match src.stat_manifest(&src.repository(&name), &reference).await {
+            Ok(Some(head)) => return Ok(Some(head)),
+            Ok(None) => missing += 1,
+            Err(e) => event!(
+                Level::DEBUG,
+                "cannot check {} at {}: {:?}",
+                image,
+                endpoint,
+                e
+            ),
+        }
     }
+    if missing == endpoints.len() {
+        return Err(Error::ImageNotFound(image.to_owned()));
+    }
+    Ok(None)
+}
 
-    // create docker client
-    let docker = Docker::connect_with_socket_defaults().unwrap();
-
-    // create pull image options
-    let mut pull_options = Some(CreateImageOptions {
-        from_image: joined_image_str.clone(),
+// Remove `source`, pulled for a sync that was then refused by policy or
+// for a conflicting tag, unless the local cache keeps it.
+async fn remove_rejected(docker: &Docker, config: &config::Config, source: &str) {
+    if source.starts_with(&format!("{}:", config.local_cache.repository)) {
+        return;
+    }
+    let options = Some(RemoveImageOptions {
+        force: true,
         ..Default::default()
     });
+    if let Err(e) = docker.remove_image(source, options, None).await {
+        event!(Level::WARN, "could not remove refused {}: {:?}", source, e);
+    }
+}
 
-    if parts[0].contains("@") {
-        pull_options = Some(CreateImageOptions {
-            from_image: parts[0].to_string().clone(),
-            ..Default::default()
-        });
+// `name@digest` of a local image, and the source it came from, that is
+// `image` down to the digest, `digest` when already known, and, if given,
+// of `platform`.
+async fn local_image(
+    docker: &Docker,
+    config: &config::Config,
+    image: &str,
+    digest: Option<&str>,
+    platform: Option<&registry::Platform>,
+) -> Option<(String, String)> {
+    let (host, name, reference) = registry::parse_reference(image);
+    let digest = if reference.contains(':') {
+        reference
+    } else if let Some(digest) = digest {
+        digest.to_owned()
+    } else {
+        match resolve_digest(config, image).await {
+            Ok(Some(digest)) => digest,
+            Ok(None) => return None,
+            Err(e) => {
+                event!(Level::DEBUG, "cannot resolve {}: {:?}", image, e);
+                return None;
+            }
+        }
+    };
+
+    // the tag as an earlier pull left it, or else the digest
+    let pinned = format!("{}/{}@{}", host, name, digest);
+    for endpoint in config.sources(&host) {
+        for candidate in [
+            registry::at_endpoint(image, &endpoint),
+            registry::at_endpoint(&pinned, &endpoint),
+        ] {
+            let local = match docker.inspect_image(&candidate).await {
+                Ok(local) => local,
+                Err(_) => continue,
+            };
+            let suffix = format!("@{}", digest);
+            let repo_digests = local.repo_digests.unwrap_or_default();
+            if !repo_digests.iter().any(|d| d.ends_with(&suffix)) {
+                continue;
+            }
+            let local_platform = registry::Platform {
+                os: local.os.unwrap_or_default(),
+                architecture: local.architecture.unwrap_or_default(),
+                variant: local.variant,
+            };
+            if let Some(p) = platform.filter(|p| !p.matches(&local_platform)) {
+                event!(
+                    Level::DEBUG,
+                    "{} is present for {}, not {}",
+                    candidate,
+                    local_platform,
+                    p
+                );
+                continue;
+            }
+            return Some((candidate, endpoint));
+        }
+    }
+    if config.local_cache.is_enabled() {
+        let kept = localcache::find(docker, config, &digest, platform).await;
+        return kept.map(|kept| (kept, host));
+    }
+    None
+}
+
+// Refuse to sync `image` by a tag when only digests are allowed.
+fn check_pinned(config: &config::Config, image: &str) -> Result<(), Error> {
+    if config.digest_only && !image.contains('@') {
+        return Err(Error::DigestRequired(image.to_owned()));
+    }
+    Ok(())
+}
+
+// Refuse to sync `image` by its tag `tag` when the tag is one that moves,
+// unless the request allows it.
+fn check_mutable(
+    config: &config::Config,
+    map: &HashMap<String, String>,
+    image: &str,
+    tag: &str,
+) -> Result<(), Error> {
+    if config.is_mutable_tag(tag) && map.get("allow_mutable").map(String::as_str) != Some("true") {
+        return Err(Error::MutableTag(image.to_owned()));
     }
+    Ok(())
+}
 
-    // create image stream
-    let stream = docker.create_image(pull_options, None, None);
+async fn check_tag(
+    dst: &registry::Registry,
+    repo: &str,
+    tag: &str,
+    incoming: &immutable::Incoming<'_>,
+) -> Result<(), Error> {
+    match immutable::conflict(dst, repo, tag, incoming).await {
+        Ok(None) => Ok(()),
+        Ok(Some(existing)) => {
+            event!(
+                Level::ERROR,
+                "{}:{} already points at {}",
+                repo,
+                tag,
+                existing
+            );
+            Err(Error::TagConflict(format!("{}:{}", repo, tag)))
+        }
+        Err(e) => {
+            event!(Level::ERROR, "{:?}", e);
+            Err(Error::registry(&format!("{}:{}", repo, tag), e))
+        }
+    }
+}
 
-    // waiting pull image
-    stream
-        .for_each(|info| async {
-            event!(Level::INFO, "{:?}", info.unwrap());
-            // tracing::info!("{:?}", info.unwrap());
+// The daemon pulls and pushes itself, so hosts we talk to without verified
+// TLS must be in its insecure-registries too, or it fails with a TLS error.
+async fn check_daemon_insecure(
+    docker: &Docker,
+    config: &config::Config,
+    hosts: &[&str],
+) -> Result<(), Error> {
+    let insecure: Vec<&str> = hosts
+        .iter()
+        .copied()
+        .filter(|h| {
+            config
+                .network
+                .registries
+                .get(*h)
+                .is_some_and(|r| r.is_insecure())
         })
-        .await;
-    event!(Level::INFO, "image pulled...");
+        .collect();
+    if insecure.is_empty() {
+        return Ok(());
+    }
 
-    // create tag image options
-    let tag_options = Some(TagImageOptions {
-        repo: "dierbei/csi_demo",
-        tag: &tag_image_str,
-        // ..Default::default()
-    });
+    let info = match docker.info().await {
+        Ok(info) => info,
+        Err(e) => {
+            event!(
+                Level::WARN,
+                "could not read daemon registry settings: {:?}",
+                e
+            );
+            return Ok(());
+        }
+    };
+    let index_configs = info
+        .registry_config
+        .and_then(|c| c.index_configs)
+        .unwrap_or_default();
 
-    if parts[0].contains("@") {
-        // playing image tag
-        let _ret = docker.tag_image(&parts[0], tag_options).await;
-        event!(Level::INFO, "played image tag...");
-    } else {
-        // playing image tag
-        let _ret = docker.tag_image(&joined_image_str, tag_options).await;
-        event!(Level::INFO, "played image tag...");
+    for host in insecure {
+        // loopback registries are always insecure for the daemon
+        let loopback = host.starts_with("localhost") || host.starts_with("127.");
+        let daemon_insecure = index_configs
+            .get(host)
+            .and_then(|i| i.secure)
+            .map(|secure| !secure);
+        if !loopback && daemon_insecure != Some(true) {
+            return Err(Error::InsecureRegistry(host.to_owned()));
+        }
     }
 
-    // create docker credentials
-    let credentials = Some(DockerCredentials {
-        username: Some(username.to_string()),
-        password: Some(password.to_string()),
-        ..Default::default()
-    });
+    Ok(())
+}
 
-    // create push image options
-    let push_options = Some(PushImageOptions {
-        tag: &tag_image_str,
-    });
+#[tracing::instrument(skip(jobs))]
+async fn list_jobs(
+    map: HashMap<String, String>,
+    jobs: Arc<jobs::Jobs>,
+) -> Result<impl Reply, Rejection> {
+    use listing::SortKey;
 
-    // create push image steam
-    let stream = docker.push_image("dierbei/csi_demo", push_options, credentials);
+    let invalid = |e: anyhow::Error| warp::reject::custom(Error::Parse(e.to_string()));
+    let query = listing::ListQuery::parse(
+        &map,
+        &[
+            "created_at",
+            "started_at",
+            "finished_at",
+            "state",
+            "priority",
+        ],
+    )
+    .map_err(invalid)?;
 
-    // pushing image
-    stream
-        .for_each(|l| async {
-            event!(Level::INFO, "{:?}", l.unwrap());
+    let mut list = jobs.list();
+    history::filter(&mut list, &map).map_err(invalid)?;
+    let page = query
+        .apply(list, |job, field| match field {
+            "started_at" => SortKey::Time(job.started_at),
+            "finished_at" => SortKey::Time(job.finished_at),
+            "state" => SortKey::Text(job.state.as_str().to_owned()),
+            "priority" => SortKey::Number(job.priority as i64),
+            _ => SortKey::Time(Some(job.created_at)),
         })
-        .await;
+        .map_err(invalid)?;
 
-    let remove_source_options = Some(RemoveImageOptions {
-        force: true,
-        ..Default::default()
-    });
-    
-    let _resp = match docker.remove_image(&joined_image_str, remove_source_options, None).await {
-        Ok(r) => r,
-        Err(e) => {
-            event!(Level::ERROR, "{:?}", e);
-            return Err(warp::reject::custom(Error::ImageFormatError));
-        }
-    };
+    Ok(warp::reply::with_header(
+        warp::reply::json(&page.items),
+        "X-Total-Count",
+        page.total.to_string(),
+    ))
+}
 
-    let remove_dst_options = Some(RemoveImageOptions {
-        force: true,
-        ..Default::default()
-    });
+#[tracing::instrument(skip(jobs))]
+async fn export_history(
+    map: HashMap<String, String>,
+    jobs: Arc<jobs::Jobs>,
+) -> Result<impl Reply, Rejection> {
+    let invalid = |e: anyhow::Error| warp::reject::custom(Error::Parse(e.to_string()));
+    let format: history::Format = map
+        .get("format")
+        .map(String::as_str)
+        .unwrap_or("ndjson")
+        .parse()
+        .map_err(invalid)?;
 
-    let _resp = match docker.remove_image(&format!("dierbei/csi_demo:{}", tag_image_str), remove_dst_options, None).await {
-        Ok(r) => r,
-        Err(e) => {
-            event!(Level::ERROR, "{:?}", e);
-            return Err(warp::reject::custom(Error::ImageFormatError));
-        }
-    };
+    let mut list = jobs.list();
+    history::filter(&mut list, &map).map_err(invalid)?;
 
-    Ok(warp::reply::json(&SyncImageRes {
-        source_image: joined_image_str.clone(),
-        dest_image: tag_image_str.clone(),
-    }))
+    // rows are formatted as the body is sent
+    let rows = format
+        .header()
+        .into_iter()
+        .chain(list.into_iter().map(move |job| format.row(&job)))
+        .map(Ok::<_, std::convert::Infallible>);
+    let body = warp::hyper::Body::wrap_stream(futures::stream::iter(rows));
+
+    Ok(warp::reply::with_header(
+        warp::reply::Response::new(body),
+        "Content-Type",
+        format.content_type(),
+    ))
 }
 
-#[tracing::instrument]
-async fn prune_images(
-    username: String,
-    password: String,
-) -> Result<impl warp::Reply, warp::Rejection> {
-    // create docker client
-    let docker = Docker::connect_with_socket_defaults().unwrap();
+#[tracing::instrument(skip(jobs))]
+async fn get_job(id: String, jobs: Arc<jobs::Jobs>) -> Result<impl Reply, Rejection> {
+    match jobs.get(&id) {
+        Some(job) => Ok(warp::reply::json(&job)),
+        None => Err(warp::reject::custom(Error::JobNotFound(id))),
+    }
+}
 
-    let mut filters = HashMap::new();
-    filters.insert("until", vec!["1m"]);
+// The outcome of every image of the batch `id`, in the batch schema.
+#[tracing::instrument(skip(worker))]
+async fn get_batch(id: String, worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    match worker.batches.report(&id, &worker.jobs) {
+        Some(report) => Ok(warp::reply::json(&report)),
+        None => Err(warp::reject::custom(Error::BatchNotFound(id))),
+    }
+}
+
+// Queue the request of the failed job `id` again. Pinned syncs keep the
+// digest the job resolved unless `resolve=true`; answers right away unless
+// `wait=true`.
+#[tracing::instrument(skip(trace, worker))]
+async fn retry_job(
+    id: String,
+    query: HashMap<String, String>,
+    trace: HashMap<String, String>,
+    worker: Arc<Worker>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let job = worker
+        .jobs
+        .get(&id)
+        .ok_or_else(|| warp::reject::custom(Error::JobNotFound(id.clone())))?;
+    let mut map = retry_request(job, &query, trace, &worker).map_err(warp::reject::custom)?;
+    let wait = query.get("wait").map_or("false", String::as_str);
+    map.insert("wait".to_owned(), wait.to_owned());
+    submit(map, worker, Some(&id)).await
+}
 
-    let options = Some(PruneImagesOptions { filters });
+// Queue again every failed job matching the `since`, `image` and `error`
+// filters, as `retry_job` does one. Jobs that were retried already are
+// left to their retries.
+#[tracing::instrument(skip(trace, worker))]
+async fn retry_jobs(
+    query: HashMap<String, String>,
+    trace: HashMap<String, String>,
+    worker: Arc<Worker>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let invalid = |message: String| warp::reject::custom(Error::Parse(message));
+    if !["since", "image", "error"]
+        .iter()
+        .any(|key| query.contains_key(*key))
+    {
+        return Err(invalid(
+            "set since, image or error to choose the jobs to retry".to_owned(),
+        ));
+    }
+    let mut filters = query.clone();
+    filters.insert("state".to_owned(), "failed".to_owned());
+    let mut failed = worker.jobs.list();
+    history::filter(&mut failed, &filters).map_err(|e| invalid(e.to_string()))?;
+    failed.retain(|job| job.retried_by.is_empty());
+    failed.sort_by_key(|job| job.created_at);
+    limits::batch(&failed, worker.config().limits.batch_size).map_err(warp::reject::custom)?;
 
-    let resp = match docker.prune_images(options).await {
-        Ok(r) => r,
-        Err(e) => {
-            event!(Level::ERROR, "{:?}", e);
-            return Err(warp::reject::custom(Error::ImageFormatError));
-        }
-    };
+    let mut started = Vec::new();
+    let mut items = Vec::new();
+    for job in failed {
+        let (original, image) = (job.id.clone(), job.image.clone());
+        let map = match retry_request(job, &query, trace.clone(), &worker) {
+            Ok(map) => map,
+            Err(e) => {
+                event!(Level::WARN, "not retrying job {}: {}", original, e);
+                items.push(batch::Entry::skipped(&image, None, &e.to_string()).retrying(&original));
+                continue;
+            }
+        };
+        let priority = match map.get("priority") {
+            Some(priority) => priority.parse().unwrap_or(jobs::Priority::Normal),
+            None => jobs::Priority::Normal,
+        };
+        let id = match worker.jobs.create(&image, priority, &map).await {
+            Ok(id) => id,
+            Err(e) => {
+                event!(Level::ERROR, "not retrying job {}: {:?}", original, e);
+                break;
+            }
+        };
+        worker.jobs.link_retry(&original, &id);
+        tokio::spawn(worker.clone().run(id.clone(), priority, map));
+        items.push(batch::Entry::job(&image, &id).retrying(&original));
+        started.push(serde_json::json!({ "image": image, "job_id": id, "retry_of": original }));
+    }
+    event!(Level::INFO, "retrying {} failed jobs", started.len());
+    let batch_id = worker.batches.record(batch::Kind::Retry, items);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "batch_id": batch_id, "jobs": started })),
+        StatusCode::ACCEPTED,
+    )
+    .into_response())
+}
+
+// The request parameters to run the failed `job` again with, in a new
+// trace, or why it cannot be.
+fn retry_request(
+    job: jobs::Job,
+    query: &HashMap<String, String>,
+    trace: HashMap<String, String>,
+    worker: &Worker,
+) -> Result<HashMap<String, String>, Error> {
+    let not_retryable = |why: String| Error::NotRetryable(job.id.clone(), why);
+    if job.state != jobs::State::Failed {
+        return Err(not_retryable(format!("it is {}", job.state.as_str())));
+    }
+    if let Some(retry) = job.retried_by.iter().find(|retry| {
+        worker
+            .jobs
+            .get(retry)
+            .is_some_and(|r| matches!(r.state, jobs::State::Queued | jobs::State::Running))
+    }) {
+        return Err(not_retryable(format!("job {} is retrying it", retry)));
+    }
+
+    let mut map = job.request;
+    // a new request, not a child of the original's caller
+    map.remove("traceparent");
+    map.remove("tracestate");
+    map.extend(trace);
+    match (query.get("resolve").map(String::as_str), job.digest) {
+        (Some("true"), _) => {
+            map.remove("digest");
+        }
+        (_, Some(digest)) => {
+            map.insert("digest".to_owned(), digest);
+        }
+        (_, None) => (),
+    }
+    event!(
+        Level::INFO,
+        "retrying failed job {} for {}",
+        job.id,
+        job.image
+    );
+    Ok(map)
+}
+
+// Compare the `old` and `new` images of a `/diff` request, of `platform`
+// when they are multi-arch.
+#[tracing::instrument(skip(worker))]
+async fn diff_images(
+    map: HashMap<String, String>,
+    worker: Arc<Worker>,
+) -> Result<impl Reply, Rejection> {
+    let invalid = |message: String| warp::reject::custom(Error::Parse(message));
+    let platform = match map.get("platform") {
+        Some(platform) => Some(
+            platform
+                .parse::<registry::Platform>()
+                .map_err(|e| invalid(e.to_string()))?,
+        ),
+        None => None,
+    };
+
+    let mut images = Vec::new();
+    for key in ["old", "new"] {
+        let image = map
+            .get(key)
+            .ok_or_else(|| invalid(format!("missing {} parameter", key)))?;
+        let (host, name, reference) = registry::parse_reference(image);
+        let credentials = if host == worker.config().dest_registry {
+            Some((worker.username.clone(), worker.password.clone()))
+        } else {
+            auth::pull_credentials(&worker.config(), &host).map_err(warp::reject::custom)?
+        };
+        let src = registry::Registry::new(&host, credentials);
+        let repo = src.repository(&name);
+        match diff::read(
+            &worker.cache,
+            &src,
+            image,
+            &repo,
+            &reference,
+            platform.as_ref(),
+        )
+        .await
+        {
+            Ok(read) => images.push(read),
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                return Err(warp::reject::custom(Error::registry(image, e)));
+            }
+        }
+    }
+    let new = images.pop().unwrap();
+    let old = images.pop().unwrap();
+    Ok(warp::reply::json(&diff::diff(old, new)))
+}
+
+// Server-wide events as server-sent events, `types=` picking some of them.
+async fn stream_events(map: HashMap<String, String>) -> Result<impl Reply, Rejection> {
+    let types = map
+        .get("types")
+        .map(|types| types.split(',').map(|t| t.trim().to_owned()).collect());
+    let stream = events::subscribe(types).map(|event| {
+        let mut sse = warp::sse::Event::default().event(event.kind);
+        // lagged notices are not events a client could resume after
+        if event.id > 0 {
+            sse = sse.id(event.id.to_string());
+        }
+        Ok::<_, std::convert::Infallible>(sse.json_data(&*event).expect("events serialize"))
+    });
+    Ok(warp::sse::reply(warp::sse::keep_alive().stream(stream)))
+}
+
+// Where destination images came from, or went to, as deploy tooling asks
+// with `source=` or `dest=`.
+async fn list_mappings(
+    map: HashMap<String, String>,
+    mappings: Arc<mappings::Mappings>,
+) -> Result<impl Reply, Rejection> {
+    let found = mappings.find(
+        map.get("source").map(String::as_str),
+        map.get("dest").map(String::as_str),
+    );
+    Ok(warp::reply::json(&serde_json::json!({ "mappings": found })))
+}
+
+// Where the destination image `image=`, a reference or digest, came from:
+// the sync that pushed it, or the provenance labels it carries when no sync
+// of it is recorded.
+#[tracing::instrument(skip(worker))]
+async fn origin(
+    map: HashMap<String, String>,
+    worker: Arc<Worker>,
+) -> Result<impl Reply, Rejection> {
+    let image = match map.get("image") {
+        Some(image) => image,
+        None => {
+            return Err(warp::reject::custom(Error::Parse(
+                "image is missing".to_owned(),
+            )))
+        }
+    };
+    if let Some(mapping) = worker.mappings.origin(image) {
+        let mut origin = serde_json::to_value(mapping).unwrap_or_default();
+        origin["from"] = "record".into();
+        return Ok(warp::reply::json(&origin));
+    }
+    if registry::is_digest(image) {
+        return Err(warp::reject::custom(Error::OriginNotFound(image.clone())));
+    }
+
+    let config = worker.config();
+    let (host, name, reference) = registry::parse_reference(image);
+    let credentials = match host == config.dest_registry {
+        true => Some((worker.username.clone(), worker.password.clone())),
+        false => auth::pull_credentials(&config, &host).map_err(warp::reject::custom)?,
+    };
+    let dst = registry::Registry::new(&host, credentials);
+    let (digest, labels) = match labels::read(&dst, &dst.repository(&name), &reference).await {
+        Ok(read) => read,
+        Err(e) if registry::is_not_found(&e) => {
+            return Err(warp::reject::custom(Error::OriginNotFound(image.clone())))
+        }
+        Err(e) => return Err(warp::reject::custom(Error::registry(image, e))),
+    };
+    let source = match labels.get(labels::SOURCE) {
+        Some(source) => source,
+        None => return Err(warp::reject::custom(Error::OriginNotFound(image.clone()))),
+    };
+    Ok(warp::reply::json(&serde_json::json!({
+        "source": source,
+        "source_digest": labels.get(labels::SOURCE_DIGEST),
+        "destination": mappings::canonical(image),
+        "digest": digest,
+        "synced_at": labels.get(labels::SYNCED_AT),
+        "from": "labels",
+    })))
+}
+
+#[tracing::instrument(skip(mirrors))]
+async fn mirror_status(mirrors: Option<Arc<mirrorlist::Mirrors>>) -> Result<impl Reply, Rejection> {
+    match mirrors {
+        Some(mirrors) => Ok(warp::reply::json(&mirrors.report())),
+        None => Err(warp::reject::not_found()),
+    }
+}
+
+// Check every image of the mirror list against its source each interval, or
+// as soon as the list changes, and sync those that drifted.
+async fn reconcile_mirrors(worker: Arc<Worker>, mirrors: Arc<mirrorlist::Mirrors>) {
+    let config = worker.config();
+    let dst = registry::Registry::new(
+        &config.dest_registry,
+        Some((worker.username.clone(), worker.password.clone())),
+    );
+    let mut last_round: Option<std::time::Instant> = None;
+    let mut tick = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        tick.tick().await;
+        let changed = mirrors.reload();
+        if !changed && last_round.is_some_and(|t| t.elapsed() < config.mirror_list.interval) {
+            continue;
+        }
+        last_round = Some(std::time::Instant::now());
+        // settings changed at runtime apply from the next round
+        let config = worker.config();
+
+        let wanted = mirrors.wanted(&config).await;
+        mirrors.retain(&wanted);
+        // the round as a batch, every image of the list in it
+        let mut items = Vec::new();
+        for wanted in wanted {
+            if mirrors.is_syncing(&wanted.image) {
+                items.push(batch::Entry::skipped(
+                    &wanted.image,
+                    None,
+                    "already syncing",
+                ));
+                continue;
+            }
+            let digest = match resolve_digest(&config, &wanted.image).await {
+                Ok(Some(digest)) => digest,
+                Ok(None) => {
+                    let error = format!("{} not found", wanted.image);
+                    items.push(batch::Entry::failed(
+                        &wanted.image,
+                        "image_not_found",
+                        error.clone(),
+                    ));
+                    mirrors.set(&wanted.image, mirrorlist::State::Failed, None, Some(error));
+                    continue;
+                }
+                Err(e) => {
+                    let error = Error::registry(&wanted.image, e);
+                    let code = error.code(&config.dest_registry);
+                    items.push(batch::Entry::failed(&wanted.image, &code, error.report()));
+                    mirrors.set(
+                        &wanted.image,
+                        mirrorlist::State::Failed,
+                        None,
+                        Some(error.report()),
+                    );
+                    continue;
+                }
+            };
+
+            // a tag deleted from the destination drifted too
+            let (repository, tag) = match config.dest_naming {
+                config::Naming::Flat => (
+                    config.dest_repository.clone(),
+                    config::flat_tag(&wanted.image),
+                ),
+                config::Naming::Nested => config.nested_destination(&wanted.image),
+            };
+            let present = matches!(dst.head_manifest(&repository, &tag).await, Ok(Some(_)));
+            if present && mirrors.synced(&wanted.image).as_ref() == Some(&digest) {
+                items.push(batch::Entry::skipped(
+                    &wanted.image,
+                    Some(digest.clone()),
+                    "in sync",
+                ));
+                mirrors.set(&wanted.image, mirrorlist::State::InSync, Some(digest), None);
+                continue;
+            }
+            mirrors.set(
+                &wanted.image,
+                mirrorlist::State::Drift,
+                Some(digest.clone()),
+                None,
+            );
+
+            let mut map = HashMap::from([("image".to_owned(), wanted.image.clone())]);
+            if !wanted.platforms.is_empty() {
+                map.insert("platforms".to_owned(), wanted.platforms.clone());
+            }
+            let id = match worker
+                .jobs
+                .create(&wanted.image, wanted.priority, &map)
+                .await
+            {
+                Ok(id) => id,
+                Err(e) => {
+                    event!(Level::ERROR, "failed to queue {}: {:?}", wanted.image, e);
+                    let error = Error::JobNotRecorded(e);
+                    items.push(batch::Entry::failed(
+                        &wanted.image,
+                        "job_not_recorded",
+                        error.report(),
+                    ));
+                    continue;
+                }
+            };
+            items.push(batch::Entry::job(&wanted.image, &id));
+            event!(
+                Level::INFO,
+                "{} drifted from the mirror list, syncing it in job {}",
+                wanted.image,
+                id
+            );
+            mirrors.syncing(&wanted.image, &id);
+            let (worker, mirrors) = (worker.clone(), mirrors.clone());
+            tokio::spawn(async move {
+                let result = worker.run(id, wanted.priority, map).await;
+                mirrors.finished(
+                    &wanted.image,
+                    &digest,
+                    result.map(|_| ()).map_err(|e| e.report()),
+                );
+            });
+        }
+        if !items.is_empty() {
+            let id = worker.batches.record(batch::Kind::MirrorList, items);
+            event!(Level::DEBUG, "mirror list round recorded as {}", id);
+        }
+    }
+}
+
+#[tracing::instrument(skip(worker))]
+async fn export_metrics(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    refresh_gauges(&worker);
+    Ok(warp::reply::with_header(
+        metrics::render(),
+        "content-type",
+        "text/plain; version=0.0.4",
+    ))
+}
+
+// queue gauges are read at scrape time
+fn refresh_gauges(worker: &Worker) {
+    let (running, waiting) = worker.scheduler.counts();
+    metrics::WORKERS_ACTIVE.set(running as i64);
+    metrics::QUEUE_DEPTH.set(waiting as i64);
+    metrics::WORKERS_MAX.set(worker.config().max_concurrent_syncs.max(1) as i64);
+    metrics::QUEUE_PAUSED.set(worker.scheduler.is_paused() as i64);
+    let (counts, oldest_queued) = worker.jobs.counts();
+    for state in jobs::State::ALL {
+        metrics::JOBS
+            .with_label_values(&[state.as_str()])
+            .set(counts.get(&state).copied().unwrap_or(0) as i64);
+    }
+    metrics::OLDEST_QUEUED_AGE.set(
+        oldest_queued
+            .map(|t| (chrono::Utc::now() - t).num_seconds().max(0))
+            .unwrap_or(0),
+    );
+}
+
+#[tracing::instrument]
+async fn debug_heap() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&profiling::heap()))
+}
+
+#[tracing::instrument]
+async fn debug_cpu(map: HashMap<String, String>) -> Result<impl Reply, Rejection> {
+    let seconds: u64 = match map.get("seconds") {
+        Some(s) => s
+            .parse()
+            .map_err(|_| warp::reject::custom(Error::Parse(format!("invalid seconds {}", s))))?,
+        None => 10,
+    };
+    match profiling::cpu(Duration::from_secs(seconds.clamp(1, 300))).await {
+        Ok(profile) => Ok(warp::reply::json(&profile)),
+        Err(e) => Err(warp::reject::custom(Error::Profile(e))),
+    }
+}
+
+/// Body of `PUT /admin/loglevel`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct LogLevelReq {
+    /// Directives in `RUST_LOG` syntax, e.g. `image_sync=debug,warp=info`.
+    filter: String,
+}
+
+#[tracing::instrument]
+async fn get_loglevel() -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(
+        &serde_json::json!({ "filter": logging::filter() }),
+    ))
+}
+
+#[tracing::instrument(skip(worker))]
+async fn set_loglevel(req: LogLevelReq, worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    let filter = req.filter.trim();
+    match logging::set_filter(filter) {
+        Ok(previous) => {
+            event!(
+                Level::WARN,
+                "log filter changed from {} to {}",
+                previous,
+                filter
+            );
+            let change = settings::Change {
+                from: previous.clone().into(),
+                to: filter.into(),
+            };
+            settings::audit(
+                &worker.config().state_dir,
+                "loglevel",
+                &BTreeMap::from([("filter", change)]),
+            );
+            Ok(warp::reply::json(&serde_json::json!({
+                "filter": filter,
+                "previous": previous,
+            })))
+        }
+        Err(e) => Err(warp::reject::custom(Error::Parse(format!(
+            "invalid filter {}: {}",
+            req.filter, e
+        )))),
+    }
+}
+
+#[tracing::instrument(skip(worker))]
+async fn get_config(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    let mut config = serde_json::to_value(&*worker.config()).unwrap_or_default();
+    // the login for the destination is kept apart from the rest
+    config["username"] = worker.username.clone().into();
+    config["password"] = "<redacted>".into();
+    config["log"]["filter"] = logging::filter().into();
+    Ok(warp::reply::json(&config))
+}
+
+#[tracing::instrument(skip(worker))]
+async fn get_settings(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    Ok(warp::reply::json(&settings::current(&worker.config())))
+}
+
+#[tracing::instrument(skip(worker))]
+async fn set_settings(
+    changes: settings::Changes,
+    worker: Arc<Worker>,
+) -> Result<impl Reply, Rejection> {
+    let changed = worker
+        .reconfigure(&changes)
+        .map_err(|problems| warp::reject::custom(Error::Parse(problems.join("; "))))?;
+    let config = worker.config();
+    if !changed.is_empty() {
+        for (name, change) in &changed {
+            event!(
+                Level::WARN,
+                "setting {} changed from {} to {}",
+                name,
+                change.from,
+                change.to
+            );
+        }
+        settings::audit(&config.state_dir, "settings", &changed);
+    }
+    Ok(warp::reply::json(&serde_json::json!({
+        "settings": settings::current(&config),
+        "changed": changed,
+    })))
+}
+
+// whether the queue is paused, and the jobs running and held
+fn queue_report(worker: &Worker) -> impl Reply {
+    let (running, waiting) = worker.scheduler.counts();
+    warp::reply::json(&serde_json::json!({
+        "paused": worker.scheduler.is_paused(),
+        "running": running,
+        "waiting": waiting,
+        "waiting_by_share": worker.scheduler.waiting_by_share(),
+    }))
+}
+
+// The share a job takes turns in with others waiting, as `FAIR_SHARE` says;
+// jobs without a tenant share one.
+fn fair_share(config: &config::Config, map: &HashMap<String, String>) -> String {
+    match config.fair_share {
+        config::FairShare::Tenant => map
+            .get("tenant")
+            .cloned()
+            .unwrap_or_else(|| "default".to_owned()),
+        config::FairShare::Repository => {
+            let image = map.get("image").map(String::as_str).unwrap_or_default();
+            let (host, name, _) = registry::parse_reference(image);
+            format!("{}/{}", host, name)
+        }
+        config::FairShare::Off => String::new(),
+    }
+}
+
+#[tracing::instrument(skip(worker))]
+async fn queue_status(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    Ok(queue_report(&worker))
+}
+
+#[tracing::instrument(skip(worker))]
+async fn pause_queue(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    if worker.scheduler.pause() {
+        let (running, waiting) = worker.scheduler.counts();
+        event!(
+            Level::WARN,
+            "queue paused, {} jobs running, {} waiting",
+            running,
+            waiting
+        );
+        events::publish("queue_paused", serde_json::json!({ "running": running }));
+        metrics::QUEUE_PAUSED.set(1);
+    }
+    Ok(queue_report(&worker))
+}
+
+#[tracing::instrument(skip(worker))]
+async fn resume_queue(worker: Arc<Worker>) -> Result<impl Reply, Rejection> {
+    let (_, waiting) = worker.scheduler.counts();
+    if worker.scheduler.resume() {
+        event!(Level::WARN, "queue resumed, {} jobs waiting", waiting);
+        events::publish("queue_resumed", serde_json::json!({ "waiting": waiting }));
+        metrics::QUEUE_PAUSED.set(0);
+    }
+    Ok(queue_report(&worker))
+}
+
+// Sign a link running the sync `req` describes once.
+#[tracing::instrument(skip(links))]
+async fn sign_link(
+    req: synclink::LinkReq,
+    links: Option<Arc<synclink::Links>>,
+) -> Result<impl Reply, Rejection> {
+    let links = links.ok_or_else(warp::reject::not_found)?;
+    let image = req.request.get("image").cloned().unwrap_or_default();
+    let (token, expires_at) = links.sign(req).map_err(warp::reject::custom)?;
+    event!(
+        Level::INFO,
+        "signed sync link for {}, valid until {}",
+        image,
+        expires_at.to_rfc3339()
+    );
+    Ok(warp::reply::json(&serde_json::json!({
+        "path": format!("/sync-links/{}", token),
+        "expires_at": expires_at,
+    })))
+}
+
+// Run the sync a signed link stands for, unless it was used before. Like
+// `/imagesync` this waits for the outcome unless `wait=false`.
+#[tracing::instrument(skip(token, query, trace, links, worker))]
+async fn redeem_link(
+    token: String,
+    query: HashMap<String, String>,
+    trace: HashMap<String, String>,
+    links: Option<Arc<synclink::Links>>,
+    worker: Arc<Worker>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let links = links.ok_or_else(warp::reject::not_found)?;
+    let mut map = links.redeem(&token).map_err(warp::reject::custom)?;
+    map.extend(trace);
+    if let Some(wait) = query.get("wait") {
+        map.insert("wait".to_owned(), wait.clone());
+    }
+    event!(
+        Level::INFO,
+        "sync link redeemed for {}",
+        map.get("image").map_or("", String::as_str)
+    );
+    submit(map, worker, None).await
+}
+
+/// Runs sync jobs through the maintenance windows and the scheduler.
+pub struct Worker {
+    username: String,
+    password: String,
+    // replaced whole when settings change at runtime, syncs keep the one
+    // they started with
+    config: RwLock<Arc<config::Config>>,
+    cache: Arc<cache::BlobCache>,
+    jobs: Arc<jobs::Jobs>,
+    mappings: Arc<mappings::Mappings>,
+    batches: Arc<batch::Batches>,
+    scheduler: Arc<scheduler::Scheduler>,
+}
+
+impl Worker {
+    /// The settings in effect.
+    fn config(&self) -> Arc<config::Config> {
+        self.config.read().unwrap().clone()
+    }
+
+    /// Change the settings `changes` names, all of them or, when any is
+    /// invalid, none. Returns what changed.
+    fn reconfigure(
+        &self,
+        changes: &settings::Changes,
+    ) -> Result<BTreeMap<&'static str, settings::Change>, Vec<String>> {
+        let mut config = self.config.write().unwrap();
+        let (new, changed) = settings::apply(&config, changes)?;
+        self.scheduler.set_max_running(new.max_concurrent_syncs);
+        *config = Arc::new(new);
+        Ok(changed)
+    }
+
+    /// Run the queued job `id`, recording the outcome in the job. The job
+    /// is a span of the trace its request came with, or of a new one.
+    #[tracing::instrument(
+        skip_all,
+        fields(
+            job_id = %id,
+            image = map.get("image").map(String::as_str).unwrap_or_default(),
+            trace_id = tracing::field::Empty,
+            span_id = tracing::field::Empty,
+            parent_span_id = tracing::field::Empty,
+        )
+    )]
+    async fn run(
+        self: Arc<Self>,
+        id: String,
+        priority: jobs::Priority,
+        map: HashMap<String, String>,
+    ) -> Result<SyncImageRes, Error> {
+        maintenance::wait_for_window(&self.config().maintenance, &self.jobs, &id, priority).await;
+        let share = fair_share(&self.config(), &map);
+        let _permit = self.scheduler.acquire(priority, share).await;
+
+        let trace = tracecontext::TraceContext::of_request(&map);
+        let span = tracing::Span::current();
+        span.record("trace_id", trace.trace_id.as_str());
+        span.record("span_id", trace.span_id.as_str());
+        if let Some(parent) = &trace.parent_id {
+            span.record("parent_span_id", parent.as_str());
+        }
+
+        self.jobs.start(&id);
+        let mut attempt = 1;
+        let result = loop {
+            // boxed, as the sync is too large a future to take along on the stack
+            let result = trace
+                .clone()
+                .scope(Box::pin(run_sync(
+                    map.clone(),
+                    self.username.clone(),
+                    self.password.clone(),
+                    self.config(),
+                    self.cache.clone(),
+                    self.jobs.progress(&id),
+                )))
+                .await;
+            match &result {
+                Err(e) if attempt < retry::attempts() && retry::is_retryable(e) => {
+                    let backoff = retry::backoff(attempt);
+                    event!(
+                        Level::WARN,
+                        "sync failed, retrying in {}s ({}/{}): {}",
+                        backoff.as_secs(),
+                        attempt,
+                        retry::attempts(),
+                        e.report()
+                    );
+                    self.jobs.retrying(&id, attempt);
+                    tokio::time::sleep(backoff).await;
+                    attempt += 1;
+                }
+                _ => break result,
+            }
+        };
+        match &result {
+            Ok(res) => {
+                self.mappings
+                    .record(mapping(&self.config(), &id, &map, res));
+                self.jobs
+                    .finish(&id, Ok(serde_json::to_value(res).unwrap_or_default()))
+            }
+            Err(e) => self
+                .jobs
+                .finish(&id, Err((e.report(), e.code(&self.config().dest_registry)))),
+        }
+        // the last step only ends with the job
+        result.map(|mut res| {
+            if let Some(job) = self.jobs.get(&id) {
+                res.timings = job.timings;
+            }
+            res
+        })
+    }
+}
+
+#[tracing::instrument(skip(trace, worker))]
+async fn sync_image(
+    mut map: HashMap<String, String>,
+    trace: HashMap<String, String>,
+    worker: Arc<Worker>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    map.extend(trace);
+    submit(map, worker, None).await
+}
+
+#[tracing::instrument(skip(trace, worker))]
+async fn copy(
+    req: CopyReq,
+    trace: HashMap<String, String>,
+    worker: Arc<Worker>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let invalid = |message: String| Err(warp::reject::custom(Error::Parse(message)));
+    for reference in [&req.source, &req.destination] {
+        if !registry::is_qualified(reference) {
+            return invalid(format!(
+                "{} is not fully qualified, it must start with the registry host",
+                reference
+            ));
+        }
+    }
+    if registry::parse_reference(&req.destination).2.contains(':') {
+        return invalid(format!("destination {} must be a tag", req.destination));
+    }
+    for name in [&req.source_credentials, &req.destination_credentials]
+        .into_iter()
+        .flatten()
+    {
+        if !worker.config().credentials.contains_key(name) {
+            return invalid(format!("unknown credentials {}", name));
+        }
+    }
+
+    // kept with the job like the parameters of /imagesync, so an interrupted
+    // copy resumes the same way
+    let mut map = HashMap::from([
+        ("image".to_owned(), req.source),
+        ("destination".to_owned(), req.destination),
+    ]);
+    map.extend(trace);
+    if let Some(name) = req.source_credentials {
+        map.insert("source_credentials".to_owned(), name);
+    }
+    if let Some(name) = req.destination_credentials {
+        map.insert("destination_credentials".to_owned(), name);
+    }
+    if let Some(priority) = req.priority {
+        map.insert("priority".to_owned(), priority);
+    }
+    if let Some(tenant) = req.tenant {
+        map.insert("tenant".to_owned(), tenant);
+    }
+    if !req.platforms.is_empty() {
+        map.insert("platforms".to_owned(), req.platforms.join(","));
+    }
+    if req.squash {
+        map.insert("squash".to_owned(), "true".to_owned());
+    }
+    if req.force {
+        map.insert("force".to_owned(), "true".to_owned());
+    }
+    if req.allow_mutable {
+        map.insert("allow_mutable".to_owned(), "true".to_owned());
+    }
+    if !req.wait {
+        map.insert("wait".to_owned(), "false".to_owned());
+    }
+    submit(map, worker, None).await
+}
+
+// Queue a job for the request parameters `map`, running again the request
+// of the job `retry_of` if given, and unless `wait=false` answer with its
+// outcome.
+async fn submit(
+    map: HashMap<String, String>,
+    worker: Arc<Worker>,
+    retry_of: Option<&str>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let image = match map.get("image") {
+        Some(value) => value.clone(),
+        None => {
+            return Err(warp::reject::custom(Error::Parse(
+                "image is missing".to_owned(),
+            )))
+        }
+    };
+    let wait = map.get("wait").map(String::as_str) != Some("false");
+    let priority = match map.get("priority") {
+        Some(priority) => match priority.parse() {
+            Ok(p) => p,
+            Err(e) => return Err(warp::reject::custom(Error::Parse(format!("{}", e)))),
+        },
+        None => jobs::Priority::Normal,
+    };
+    let id = worker
+        .jobs
+        .create(&image, priority, &map)
+        .await
+        .map_err(|e| warp::reject::custom(Error::JobNotRecorded(e)))?;
+    if let Some(original) = retry_of {
+        worker.jobs.link_retry(original, &id);
+    }
+    // the job runs on its own task so that a client going away does not
+    // leave it half done
+    let job = tokio::spawn(worker.clone().run(id.clone(), priority, map));
+
+    // wait=false answers right away, the job status has the outcome
+    if !wait {
+        return Ok(warp::reply::with_status(
+            warp::reply::json(&serde_json::json!({ "job_id": id })),
+            StatusCode::ACCEPTED,
+        )
+        .into_response());
+    }
+
+    match job.await {
+        Ok(Ok(mut res)) => {
+            res.job_id = Some(id);
+            Ok(warp::reply::json(&res).into_response())
+        }
+        Ok(Err(e)) => Err(warp::reject::custom(e)),
+        Err(e) => std::panic::resume_unwind(e.into_panic()),
+    }
+}
+
+#[tracing::instrument(skip(password, config, cache, progress))]
+async fn run_sync(
+    map: HashMap<String, String>,
+    username: String,
+    password: String,
+    config: Arc<config::Config>,
+    cache: Arc<cache::BlobCache>,
+    progress: jobs::Progress,
+) -> Result<SyncImageRes, Error> {
+    if let Some(destination) = map.get("destination") {
+        return run_copy(
+            &map,
+            destination,
+            (username, password),
+            &config,
+            &cache,
+            &progress,
+        )
+        .await;
+    }
+
+    // check request parameters
+    let image = match map.get("image") {
+        Some(value) => value,
+        None => return Err(Error::Parse("image is missing".to_owned())),
+    };
+    check_pinned(&config, image)?;
+    // `name:tag@digest` syncs the digest, named after the tag
+    let (image, referenced_digest) = match registry::tagged_digest(image) {
+        Some((_, digest)) if !registry::is_digest(&digest) => {
+            return Err(Error::Parse(format!("invalid digest {}", digest)))
+        }
+        Some((tagged, digest)) => (tagged, Some(digest)),
+        None => (image.clone(), None),
+    };
+    // a port in the registry host is not a tag
+    let image = &match &config.default_tag {
+        _ if registry::has_reference(&image) => image,
+        Some(tag) => format!("{}:{}", image, tag),
+        None => return Err(Error::Parse(format!("image {} has no tag", image))),
+    };
+    let by_digest = image.contains('@');
+    let (_, _, tag) = registry::parse_reference(image);
+    // the name the tag goes after
+    let untagged = &image[..image.len() - tag.len() - 1];
+    if !by_digest && referenced_digest.is_none() {
+        check_mutable(&config, &map, image, &tag)?;
+    }
+
+    let joined_image_str = match by_digest {
+        true => image.replace(['/', '@', ':'], "_"),
+        false => image.clone(),
+    };
+
+    let sync_mode = match map.get("mode") {
+        Some(mode) => match mode.parse() {
+            Ok(m) => m,
+            Err(e) => return Err(Error::Parse(format!("{}", e))),
+        },
+        None => config.sync_mode,
+    };
+    let naming = match map.get("naming") {
+        Some(naming) => match naming.parse() {
+            Ok(n) => n,
+            Err(e) => return Err(Error::Parse(format!("{}", e))),
+        },
+        None => config.dest_naming,
+    };
+    let platforms = platforms(&map)?;
+    let squash = map.get("squash").map(String::as_str) == Some("true");
+    let read_back = read_back(&map, &config)?;
+
+    let requested = image.clone();
+    let (dest_repository, tag_image_str) = match naming {
+        config::Naming::Flat => (config.dest_repository.clone(), config::flat_tag(&requested)),
+        config::Naming::Nested => config.nested_destination(&requested),
+    };
+
+    // resolve the tag first so the upstream moving it mid-sync doesn't matter
+    let pin_digest = match map.get("pin") {
+        Some(pin) => pin == "true",
+        None => config.pin_digest,
+    };
+    let mut pin = None;
+    if (pin_digest || referenced_digest.is_some()) && !by_digest {
+        let digest = match (&referenced_digest, map.get("digest")) {
+            (Some(digest), _) => Ok(Some(digest.clone())),
+            // as resolved before, for a retry of the same image
+            (None, Some(digest)) if registry::is_digest(digest) => Ok(Some(digest.clone())),
+            (None, Some(digest)) => return Err(Error::Parse(format!("invalid digest {}", digest))),
+            (None, None) => resolve_digest(&config, &requested).await,
+        };
+        let digest = match digest {
+            Ok(Some(digest)) => digest,
+            Ok(None) => return Err(Error::ImageNotFound(requested)),
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                return Err(Error::registry(&requested, e));
+            }
+        };
+        progress.digest(&digest);
+        let pinned = format!("{}@{}", untagged, digest);
+        pin = Some(Pin {
+            tag: pin_digest.then(|| match naming {
+                config::Naming::Flat => config::flat_tag(&pinned),
+                config::Naming::Nested => digest.replace(':', "_"),
+            }),
+            reference: pinned,
+            digest,
+        });
+    }
+
+    if sync_mode == config::SyncMode::Daemonless {
+        let dst = registry::Registry::new(&config.dest_registry, Some((username, password)))
+            .with_chunk_size(config.upload_chunk_size)
+            .with_session_retries(config.upload_session_retries);
+        return sync_daemonless(
+            &requested,
+            pin.as_ref(),
+            None,
+            &dst,
+            &dest_repository,
+            &tag_image_str,
+            &platforms,
+            squash,
+            map.get("force").map(String::as_str) == Some("true"),
+            read_back,
+            &config,
+            &cache,
+            &progress,
+        )
+        .await;
+    }
+
+    // the daemon keeps one platform of an image
+    let platform = match platforms.as_slice() {
+        [] => None,
+        [platform] => Some(platform.to_string()),
+        _ => {
+            return Err(Error::Parse(
+                "more than one platform needs mode=daemonless".to_owned(),
+            ))
+        }
+    };
+    if squash {
+        return Err(Error::Parse("squash needs mode=daemonless".to_owned()));
+    }
+    // the daemon can add to an image config but not take anything out
+    if !config.strip.is_empty() {
+        return Err(Error::Parse(
+            "stripping build metadata needs mode=daemonless".to_owned(),
+        ));
+    }
+
+    // create docker client
+    let docker = docker()?;
+
+    let wanted = pin
+        .as_ref()
+        .map(|p| p.reference.clone())
+        .unwrap_or_else(|| requested.clone());
+    let (host, _, _) = registry::parse_reference(&wanted);
+    let endpoints = config.sources(&host);
+
+    let mut insecure_hosts: Vec<&str> = endpoints.iter().map(String::as_str).collect();
+    insecure_hosts.push(&config.dest_registry);
+    if let Err(e) = check_daemon_insecure(&docker, &config, &insecure_hosts).await {
+        event!(Level::ERROR, "{}", e);
+        return Err(e);
+    }
+
+    // a pinned reference was found when resolving it
+    let mut known_digest = pin.as_ref().map(|p| p.digest.clone());
+    if config.pull_preflight && pin.is_none() {
+        if let Some(head) = preflight(&config, &wanted).await? {
+            event!(
+                Level::INFO,
+                "{} is {} ({} manifest bytes)",
+                wanted,
+                head.digest.as_deref().unwrap_or("of unknown digest"),
+                head.size.map_or("?".to_owned(), |s| s.to_string())
+            );
+            if let Some(digest) = &head.digest {
+                progress.digest(digest);
+            }
+            known_digest = head.digest;
+        }
+    }
+
+    // kept from prunes from before it is found or pulled until the job ends
+    for endpoint in &endpoints {
+        progress
+            .hold_image(&registry::at_endpoint(&wanted, endpoint))
+            .await;
+    }
+
+    // an image left by an earlier attempt whose push failed, or pulled by
+    // someone else, spares the pull when it is the very one wanted
+    let mut pulled = local_image(
+        &docker,
+        &config,
+        &wanted,
+        known_digest.as_deref(),
+        platforms.first(),
+    )
+    .await;
+    if let Some((local, _)) = &pulled {
+        event!(
+            Level::INFO,
+            "{} is already present, skipping the pull",
+            local
+        );
+    }
+    // an image this sync did not pull is not its to remove
+    let reused = pulled.is_some();
+
+    // the source registry, or its mirrors in the configured order
+    let mut pull_error = None;
+    let mut pull_span = None;
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        if pulled.is_some() {
+            break;
+        }
+        let last = i + 1 == endpoints.len();
+        let source = registry::at_endpoint(&wanted, endpoint);
+
+        // hold the pull while the Docker Hub quota is nearly used up, unless
+        // there is another source to try
+        let (host, name, reference) = registry::parse_reference(&source);
+        let pull_credentials = auth::pull_credentials(&config, &host)?;
+        if host == "docker.io" {
+            // the quota is that of whoever pulls
+            let hub = registry::Registry::new(&host, pull_credentials.clone());
+            let repo = hub.repository(&name);
+            if last {
+                ratelimit::wait_for_quota(&config.ratelimit, &hub, &repo, &reference).await;
+            } else if !ratelimit::has_quota(&config.ratelimit, &hub, &repo, &reference).await {
+                event!(
+                    Level::WARN,
+                    "{} is rate limited, trying the next source",
+                    endpoint
+                );
+                continue;
+            }
+        }
+
+        // create pull image options
+        let pull_options = Some(CreateImageOptions {
+            from_image: source.clone(),
+            platform: platform.clone().unwrap_or_default(),
+            ..Default::default()
+        });
+
+        // create image stream
+        let credentials = pull_credentials.map(|(username, password)| DockerCredentials {
+            username: Some(username),
+            password: Some(password),
+            ..Default::default()
+        });
+        let mut stream = docker.create_image(pull_options, None, credentials);
+
+        // waiting pull image
+        progress.source(&registry::parse_reference(&source).0);
+        progress.phase(jobs::Phase::Pulling);
+        let span = tracing::info_span!(
+            "pull",
+            image = %source,
+            registry = %endpoint,
+            digest = Empty,
+            bytes = Empty,
+            layers = Empty,
+        );
+        let failed = async {
+            let mut layers = spans::Layers::new(&tracing::Span::current());
+            let mut log = progresslog::ProgressLog::new(&source);
+            while let Some(info) = stream.next().await {
+                let info = match info {
+                    Ok(info) => info,
+                    Err(e) => return Some(e),
+                };
+                if let (Some(layer), Some(status)) = (&info.id, &info.status) {
+                    let detail = info.progress_detail.as_ref();
+                    layers.update(layer, status, detail.and_then(|d| d.total));
+                    // per layer byte counts while downloading
+                    if let Some((Some(current), Some(total))) = detail.map(|d| (d.current, d.total))
+                    {
+                        if status == "Downloading" {
+                            progress.update(layer, current as u64, total as u64);
+                        }
+                    }
+                }
+                let detail = info.progress_detail.as_ref();
+                log.record(
+                    &info,
+                    info.id.as_deref(),
+                    info.status.as_deref(),
+                    detail.and_then(|d| Some((d.current?, d.total?))),
+                );
+            }
+            layers.finish();
+            None
+        }
+        .instrument(span.clone())
+        .await;
+        match failed {
+            None => {
+                pulled = Some((source, endpoint.clone()));
+                pull_span = Some(span);
+                break;
+            }
+            Some(e) => {
+                event!(Level::WARN, "pulling {} failed: {:?}", source, e);
+                pull_error = Some(e);
+            }
+        }
+    }
+    let (source, pulled_from) = match pulled {
+        Some(p) => p,
+        None => {
+            return Err(Error::Pull {
+                image: wanted,
+                source: pull_error,
+            })
+        }
+    };
+    event!(Level::INFO, "image pulled...");
+
+    let (source_digest, image_id, created, images) = match docker.inspect_image(&source).await {
+        Ok(image) => {
+            // inspect output names the config fields as image configs do
+            let image_config = serde_json::json!({
+                "created": image.created,
+                "os": image.os,
+                "architecture": image.architecture,
+                "variant": image.variant,
+                "config": image.config,
+            });
+            (
+                image
+                    .repo_digests
+                    .unwrap_or_default()
+                    .iter()
+                    .find_map(|d| d.split_once('@').map(|(_, digest)| digest.to_owned())),
+                image.id,
+                image.created,
+                vec![details::from_config(
+                    &image_config,
+                    &serde_json::Value::Null,
+                )],
+            )
+        }
+        Err(e) => {
+            event!(Level::WARN, "could not inspect {}: {:?}", source, e);
+            (None, None, None, Vec::new())
+        }
+    };
+    if let (Some(span), Some(digest)) = (pull_span, &source_digest) {
+        span.record("digest", digest.as_str());
+    }
+    if let Some(id) = &image_id {
+        progress.hold_image(id).await;
+    }
+
+    // the pulled image is only pushed once its digest is known to be signed
+    if config.signatures.is_enabled() {
+        let (host, name, _) =
+            registry::parse_reference(&registry::at_endpoint(&wanted, &pulled_from));
+        let src = registry::Registry::new(&host, auth::pull_credentials(&config, &host)?);
+        let repo = src.repository(&name);
+        let digest = source_digest.as_deref();
+        if let Err(e) =
+            policy::check_signature(&config, &requested, &src, &repo, digest, &progress).await
+        {
+            event!(Level::ERROR, "{:?}", e);
+            if !reused {
+                remove_rejected(&docker, &config, &source).await;
+            }
+            return Err(Error::registry(&requested, e));
+        }
+    }
+
+    // the daemon only knows uncompressed layer sizes, from the image history;
+    // the age is that of the image as pulled, labeling commits a new one
+    let mut problems = Vec::new();
+    policy::check_age(&config.policy, created.as_deref(), &mut problems, &source);
+    if config.policy.max_layers.is_some() || config.policy.max_layer_size.is_some() {
+        let mut layers = match docker.inspect_image(&source).await {
+            Ok(image) => image
+                .root_fs
+                .and_then(|r| r.layers)
+                .unwrap_or_default()
+                .into_iter()
+                .map(|layer| (layer, 0))
+                .collect::<Vec<_>>(),
+            Err(e) => {
+                return Err(Error::Daemon {
+                    image: source,
+                    source: e,
+                })
+            }
+        };
+        let history = match docker.image_history(&source).await {
+            Ok(history) => history,
+            Err(e) => {
+                return Err(Error::Daemon {
+                    image: source,
+                    source: e,
+                })
+            }
+        };
+        // history is newest first and lists empty layers too
+        let sizes = history.iter().rev().filter(|h| h.size > 0);
+        for ((_, size), item) in layers.iter_mut().zip(sizes) {
+            *size = item.size as u64;
+        }
+        policy::check_limits(&config.policy, &layers, &mut problems, &source);
+    }
+    if !problems.is_empty() {
+        event!(Level::ERROR, "{}", problems.join("; "));
+        if !reused {
+            remove_rejected(&docker, &config, &source).await;
+        }
+        return Err(Error::Policy {
+            image: source,
+            source: policy::Violation(problems),
+        });
+    }
+
+    progress.phase(jobs::Phase::Tagging);
+
+    // released tags must not silently change
+    if config.immutable_tags && map.get("force").map(String::as_str) != Some("true") {
+        let dst = registry::Registry::new(
+            &config.dest_registry,
+            Some((username.clone(), password.clone())),
+        );
+        let incoming = immutable::Incoming {
+            source_digest: source_digest.as_deref(),
+            image_id: image_id.as_deref(),
+        };
+        // a conflict fails the same way next time, a registry error may not
+        let checked = check_tag(&dst, &dest_repository, &tag_image_str, &incoming).await;
+        if let Err(e @ Error::TagConflict(_)) = checked {
+            if !reused {
+                remove_rejected(&docker, &config, &source).await;
+            }
+            return Err(e);
+        }
+        checked?;
+    }
+
+    // create tag image options
+    let dest_repo = config.dest_image_repo(&dest_repository);
+    let tag_options = Some(TagImageOptions {
+        repo: &dest_repo,
+        tag: &tag_image_str,
+        // ..Default::default()
+    });
+
+    // playing image tag
+    let dest = format!("{}:{}", dest_repo, tag_image_str);
+    progress.hold_image(&dest).await;
+    let span = tracing::info_span!("tag", image = %source, dest = %dest);
+    if let Err(e) = docker
+        .tag_image(&source, tag_options)
+        .instrument(span)
+        .await
+    {
+        event!(Level::ERROR, "{:?}", e);
+        return Err(Error::Daemon {
+            image: source,
+            source: e,
+        });
+    }
+    event!(Level::INFO, "played image tag...");
+
+    // configured labels plus where the image came from
+    let mut image_labels = config.labels.clone();
+    if config.provenance {
+        image_labels.extend(labels::provenance(&requested, source_digest.as_deref()));
+    }
+    progress.phase(jobs::Phase::Labeling);
+    if let Err(e) = labels::apply(&docker, &dest_repo, &tag_image_str, &image_labels).await {
+        event!(Level::ERROR, "{:?}", e);
+        return Err(Error::Label {
+            image: format!("{}:{}", dest_repo, tag_image_str),
+            source: e,
+        });
+    }
+
+    // the same image again under its digest-derived tag
+    let mut dest_tags = vec![tag_image_str.clone()];
+    if let Some(digest_tag) = pin.as_ref().and_then(|p| p.tag.as_ref()) {
+        let digest_tag_options = Some(TagImageOptions {
+            repo: &dest_repo,
+            tag: digest_tag,
+        });
+        let tagged = format!("{}:{}", dest_repo, tag_image_str);
+        let dest = format!("{}:{}", dest_repo, digest_tag);
+        let span = tracing::info_span!("tag", image = %tagged, dest = %dest);
+        if let Err(e) = docker
+            .tag_image(&tagged, digest_tag_options)
+            .instrument(span)
+            .await
+        {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(Error::Daemon {
+                image: tagged,
+                source: e,
+            });
+        }
+        dest_tags.push(digest_tag.clone());
+    }
+
+    // export OCI image layout
+    let mut exported = Vec::new();
+    if config.export.is_enabled() {
+        progress.phase(jobs::Phase::Exporting);
+        let dest_image = format!("{}:{}", dest_repo, tag_image_str);
+        exported = match export::export_image(&docker, &config.export, &dest_image, &tag_image_str)
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                return Err(Error::Export {
+                    image: dest_image,
+                    source: e,
+                });
+            }
+        };
+    }
+
+    // create docker credentials
+    let credentials = Some(DockerCredentials {
+        username: Some(username.to_string()),
+        password: Some(password.to_string()),
+        ..Default::default()
+    });
+
+    // push progress counts finished layers, the daemon does not say which
+    // layer its byte counts belong to
+    progress.phase(jobs::Phase::Pushing);
+    let (layers, size) = match docker
+        .inspect_image(&format!("{}:{}", dest_repo, tag_image_str))
+        .await
+    {
+        Ok(image) => (
+            image
+                .root_fs
+                .and_then(|r| r.layers)
+                .map(|l| l.len() as u64)
+                .unwrap_or(0),
+            image.size,
+        ),
+        Err(_) => (0, None),
+    };
+
+    let mut pushed_digest = None;
+    for tag in &dest_tags {
+        let name = format!("{}:{}", dest_repo, tag);
+        let span = tracing::info_span!(
+            "push",
+            image = %name,
+            registry = %config.dest_registry,
+            digest = Empty,
+            bytes = size,
+            layers = Empty,
+        );
+        progress.update(tag, 0, layers);
+        let failed = async {
+            let mut refreshed = 0;
+            loop {
+                // create push image steam, the daemon logs in anew for each
+                let push_options = Some(PushImageOptions { tag });
+                let mut stream = docker.push_image(&dest_repo, push_options, credentials.clone());
+
+                // pushing image
+                let mut log = progresslog::ProgressLog::new(&name);
+                let mut pushed = 0;
+                let mut failed = None;
+                while let Some(l) = stream.next().await {
+                    let l = match l {
+                        Ok(l) => l,
+                        Err(e) => {
+                            failed = Some(Error::push(name.clone(), e));
+                            break;
+                        }
+                    };
+                    if let Some(status) = &l.status {
+                        if status == "Pushed"
+                            || status == "Layer already exists"
+                            || status.starts_with("Mounted from")
+                        {
+                            pushed += 1;
+                            progress.update(tag, pushed, layers);
+                        }
+                        if let Some(digest) = spans::pushed(status) {
+                            tracing::Span::current().record("digest", digest);
+                            pushed_digest.get_or_insert_with(|| digest.to_owned());
+                        }
+                    }
+                    let detail = l.progress_detail.as_ref();
+                    log.record(
+                        &l,
+                        None,
+                        l.status.as_deref(),
+                        detail.and_then(|d| Some((d.current?, d.total?))),
+                    );
+                }
+                match failed {
+                    // refused after layers went through, so the credentials
+                    // are good and the token ran out during a long push;
+                    // pushing again skips the layers already there
+                    Some(Error::Auth { source, .. })
+                        if pushed > 0 && refreshed < PUSH_AUTH_ATTEMPTS =>
+                    {
+                        refreshed += 1;
+                        event!(
+                            Level::WARN,
+                            "push of {} was refused after {} layers ({:?}), logging in again",
+                            name,
+                            pushed,
+                            source
+                        );
+                    }
+                    failed => {
+                        tracing::Span::current().record("layers", pushed);
+                        return failed;
+                    }
+                }
+            }
+        }
+        .instrument(span)
+        .await;
+        if let Some(e) = failed {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(e);
+        }
+    }
+
+    if let Some(depth) = read_back {
+        progress.phase(jobs::Phase::ReadingBack);
+        let dst = registry::Registry::new(
+            &config.dest_registry,
+            Some((username.clone(), password.clone())),
+        );
+        let repo = dst.repository(&dest_repository);
+        let checked = readback::check(
+            &dst,
+            &repo,
+            &tag_image_str,
+            pushed_digest.as_deref(),
+            depth,
+            &progress,
+        );
+        if let Err(e) = checked.await {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(Error::ReadBack {
+                image: format!("{}:{}", dest_repo, tag_image_str),
+                source: e,
+            });
+        }
+    }
+
+    progress.phase(jobs::Phase::Cleanup);
+
+    // a kept image stays under the cache repository only, the source tag
+    // goes either way unless it was found rather than pulled
+    let kept_prefix = format!("{}:", config.local_cache.repository);
+    if let (true, Some(digest)) = (config.local_cache.is_enabled(), &source_digest) {
+        if let Err(e) = localcache::keep(&docker, &config, &source, digest).await {
+            event!(Level::WARN, "could not keep {} locally: {:?}", source, e);
+        }
+    }
+
+    let remove_source_options = Some(RemoveImageOptions {
+        force: true,
+        ..Default::default()
+    });
+
+    if !reused && !source.starts_with(&kept_prefix) {
+        if let Err(e) = docker
+            .remove_image(&source, remove_source_options, None)
+            .instrument(tracing::info_span!("remove", image = %source))
+            .await
+        {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(Error::Cleanup {
+                image: source,
+                source: e,
+            });
+        }
+    }
+
+    for tag in &dest_tags {
+        let remove_dst_options = Some(RemoveImageOptions {
+            force: true,
+            ..Default::default()
+        });
+
+        let dest_image = format!("{}:{}", dest_repo, tag);
+        let _resp = match docker
+            .remove_image(&dest_image, remove_dst_options, None)
+            .instrument(tracing::info_span!("remove", image = %dest_image))
+            .await
+        {
+            Ok(r) => r,
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                return Err(Error::Cleanup {
+                    image: dest_image,
+                    source: e,
+                });
+            }
+        };
+    }
+
+    Ok(SyncImageRes {
+        job_id: None,
+        source_image: joined_image_str.clone(),
+        dest_image: reported_dest(&config, &dest_repository, &tag_image_str),
+        source_digest,
+        digest: pushed_digest,
+        digest_tag: pin.and_then(|p| p.tag),
+        pulled_from: (endpoints.len() > 1).then_some(pulled_from),
+        exported,
+        images,
+        timings: BTreeMap::new(),
+    })
+}
+
+// Copy an image registry to registry without going through the daemon.
+#[allow(clippy::too_many_arguments)]
+async fn sync_daemonless(
+    source: &str,
+    pin: Option<&Pin>,
+    src_credentials: Option<(String, String)>,
+    dst: &registry::Registry,
+    dest_repo: &str,
+    dest_tag: &str,
+    platforms: &[registry::Platform],
+    squash: bool,
+    force: bool,
+    read_back: Option<config::ReadBack>,
+    config: &config::Config,
+    cache: &cache::BlobCache,
+    progress: &jobs::Progress,
+) -> Result<SyncImageRes, Error> {
+    // the source registry, or its mirrors in the configured order
+    let (host, _, _) = registry::parse_reference(source);
+    let endpoints = config.sources(&host);
+    let mut copied = None;
+    for (i, endpoint) in endpoints.iter().enumerate() {
+        let last = i + 1 == endpoints.len();
+        let (host, name, reference) =
+            registry::parse_reference(&registry::at_endpoint(source, endpoint));
+        // credentials the request names, or those configured for the host
+        let credentials = match &src_credentials {
+            Some(credentials) => Some(credentials.clone()),
+            None => auth::pull_credentials(config, &host)?,
+        };
+        let src = registry::Registry::new(&host, credentials);
+        let repo = src.repository(&name);
+        progress.source(&host);
+        if src.is_docker_hub() {
+            if last {
+                ratelimit::wait_for_quota(&config.ratelimit, &src, &repo, &reference).await;
+            } else if !ratelimit::has_quota(&config.ratelimit, &src, &repo, &reference).await {
+                event!(
+                    Level::WARN,
+                    "{} is rate limited, trying the next source",
+                    endpoint
+                );
+                continue;
+            }
+        }
+
+        // pin the tag so the recorded and compared digest is the one copied
+        let mut reference = match pin {
+            Some(pin) => pin.digest.clone(),
+            None => reference,
+        };
+        if !reference.contains(':') {
+            reference = match src.head_manifest(&repo, &reference).await {
+                Ok(Some(digest)) => digest,
+                Ok(None) => reference,
+                Err(e) if !last => {
+                    event!(
+                        Level::WARN,
+                        "{} failed, trying the next source: {:?}",
+                        endpoint,
+                        e
+                    );
+                    continue;
+                }
+                Err(e) => {
+                    event!(Level::ERROR, "{:?}", e);
+                    return Err(Error::registry(source, e));
+                }
+            };
+        }
+        match policy::check_signature(config, source, &src, &repo, Some(&reference), progress).await
+        {
+            Ok(()) => {}
+            Err(e) if !last => {
+                event!(
+                    Level::WARN,
+                    "{} failed, trying the next source: {:?}",
+                    endpoint,
+                    e
+                );
+                continue;
+            }
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                return Err(Error::registry(source, e));
+            }
+        }
+        let source_digest = Some(reference.as_str()).filter(|r| r.contains(':'));
+        let mut labels = config.labels.clone();
+        if config.provenance {
+            labels.extend(labels::provenance(source, source_digest));
+        }
+
+        // released tags must not silently change
+        if config.immutable_tags && !force {
+            let incoming = immutable::Incoming {
+                source_digest,
+                image_id: None,
+            };
+            check_tag(dst, dest_repo, dest_tag, &incoming).await?;
+        }
+
+        match copy::copy_image(
+            cache,
+            &src,
+            &repo,
+            &reference,
+            dst,
+            dest_repo,
+            dest_tag,
+            platforms,
+            &config.policy,
+            &copy::Rewrite {
+                labels,
+                squash,
+                strip: config.strip.clone(),
+            },
+            config.layer_parallelism,
+            progress,
+        )
+        .await
+        {
+            Ok(m) => {
+                copied = Some((m, endpoint.clone(), source_digest.map(str::to_owned)));
+                break;
+            }
+            Err(e) if !last => {
+                event!(
+                    Level::WARN,
+                    "{} failed, trying the next source: {:?}",
+                    endpoint,
+                    e
+                );
+            }
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                return Err(Error::registry(source, e));
+            }
+        }
+    }
+    let (manifest, pulled_from, source_digest) = match copied {
+        Some(c) => c,
+        None => {
+            return Err(Error::Pull {
+                image: source.to_owned(),
+                source: None,
+            })
+        }
+    };
+
+    // the same image again under its digest-derived tag
+    if let Some((pin, digest_tag)) = pin.and_then(|p| Some((p, p.tag.as_ref()?))) {
+        if let Err(e) = dst.put_manifest(dest_repo, digest_tag, &manifest).await {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(Error::registry(&pin.reference, e));
+        }
+    }
+
+    if let Some(depth) = read_back {
+        progress.phase(jobs::Phase::ReadingBack);
+        let checked = readback::check(
+            dst,
+            dest_repo,
+            dest_tag,
+            Some(&manifest.digest),
+            depth,
+            progress,
+        );
+        if let Err(e) = checked.await {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(Error::ReadBack {
+                image: format!("{}:{}", dest_repo, dest_tag),
+                source: e,
+            });
+        }
+    }
+
+    Ok(SyncImageRes {
+        job_id: None,
+        source_image: source.to_string(),
+        dest_image: reported_dest(config, dest_repo, dest_tag),
+        source_digest,
+        digest: Some(manifest.digest.clone()),
+        digest_tag: pin.and_then(|p| p.tag.clone()),
+        pulled_from: (endpoints.len() > 1).then_some(pulled_from),
+        exported: Vec::new(),
+        images: details::collect(cache, dst, dest_repo, &manifest).await,
+        timings: BTreeMap::new(),
+    })
+}
+
+// How much of the pushed image to read back, `read_back=` of the request or
+// the configured depth.
+fn read_back(
+    map: &HashMap<String, String>,
+    config: &config::Config,
+) -> Result<Option<config::ReadBack>, Error> {
+    match map.get("read_back").map(String::as_str) {
+        Some("off") => Ok(None),
+        Some(depth) => depth
+            .parse()
+            .map(Some)
+            .map_err(|e: anyhow::Error| Error::Parse(e.to_string())),
+        None => Ok(config.read_back),
+    }
+}
+
+// The `platforms` parameter, empty when all of them are wanted.
+fn platforms(map: &HashMap<String, String>) -> Result<Vec<registry::Platform>, Error> {
+    match map.get("platforms") {
+        Some(list) => registry::parse_platforms(list).map_err(|e| Error::Parse(e.to_string())),
+        None => Ok(Vec::new()),
+    }
+}
+
+// Copy `image` of a `/copy` request to `destination`, registry to registry.
+async fn run_copy(
+    map: &HashMap<String, String>,
+    destination: &str,
+    default_credentials: (String, String),
+    config: &config::Config,
+    cache: &cache::BlobCache,
+    progress: &jobs::Progress,
+) -> Result<SyncImageRes, Error> {
+    let source = match map.get("image") {
+        Some(value) => value,
+        None => return Err(Error::Parse("image is missing".to_owned())),
+    };
+    check_pinned(config, source)?;
+    let source = &match &config.default_tag {
+        _ if registry::has_reference(source) => source.clone(),
+        Some(tag) => format!("{}:{}", source, tag),
+        None => return Err(Error::Parse(format!("image {} has no tag", source))),
+    };
+    let (_, _, source_tag) = registry::parse_reference(source);
+    if !registry::is_digest(&source_tag) {
+        check_mutable(config, map, source, &source_tag)?;
+    }
+    let credentials = |key: &str| -> Result<Option<(String, String)>, Error> {
+        let name = match map.get(key) {
+            Some(name) => name,
+            None => return Ok(None),
+        };
+        let resolved = match config.credentials.get(name) {
+            Some(credential) => credential.resolve(),
+            None => Err(anyhow::anyhow!("not configured")),
+        };
+        resolved.map(Some).map_err(|source| Error::Credentials {
+            name: name.clone(),
+            source,
+        })
+    };
+
+    let (host, name, tag) = registry::parse_reference(destination);
+    // our own login only ever goes to our own destination registry
+    let dst_credentials = match credentials("destination_credentials")? {
+        Some(c) => Some(c),
+        None => (host == config.dest_registry).then_some(default_credentials),
+    };
+    let dst = registry::Registry::new(&host, dst_credentials)
+        .with_chunk_size(config.upload_chunk_size)
+        .with_session_retries(config.upload_session_retries);
+    let dest_repo = dst.repository(&name);
+
+    let mut res = sync_daemonless(
+        source,
+        None,
+        credentials("source_credentials")?,
+        &dst,
+        &dest_repo,
+        &tag,
+        &platforms(map)?,
+        map.get("squash").map(String::as_str) == Some("true"),
+        map.get("force").map(String::as_str) == Some("true"),
+        read_back(map, config)?,
+        config,
+        cache,
+        progress,
+    )
+    .await?;
+    res.dest_image = destination.to_owned();
+    Ok(res)
+}
+
+// Start a sync of every image a signed CI delivery lists, answering with
+// the job ids right away.
+#[tracing::instrument(skip(body, trace, verifier, worker))]
+async fn generic_webhook(
+    timestamp: Option<String>,
+    signature: Option<String>,
+    body: Vec<u8>,
+    trace: HashMap<String, String>,
+    verifier: Option<Arc<webhook::Verifier>>,
+    worker: Arc<Worker>,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let verifier = verifier.ok_or_else(warp::reject::not_found)?;
+    verifier
+        .verify(timestamp.as_deref(), signature.as_deref(), &body)
+        .map_err(warp::reject::custom)?;
+    let req: webhook::GenericReq = serde_json::from_slice(&body)
+        .map_err(|e| warp::reject::custom(Error::Parse(e.to_string())))?;
+    if req.images.is_empty() {
+        return Err(warp::reject::custom(Error::Parse(
+            "images is empty".to_owned(),
+        )));
+    }
+    limits::batch(&req.images, worker.config().limits.batch_size).map_err(warp::reject::custom)?;
+    let priority = match &req.priority {
+        Some(priority) => priority
+            .parse()
+            .map_err(|e| warp::reject::custom(Error::Parse(format!("{}", e))))?,
+        None => jobs::Priority::Normal,
+    };
+
+    let mut started = Vec::new();
+    let mut items = Vec::new();
+    for image in req.images {
+        let mut map = HashMap::from([("image".to_owned(), image.clone())]);
+        map.extend(trace.clone());
+        if !req.platforms.is_empty() {
+            map.insert("platforms".to_owned(), req.platforms.join(","));
+        }
+        if let Some(priority) = &req.priority {
+            map.insert("priority".to_owned(), priority.clone());
+        }
+        let id = worker
+            .jobs
+            .create(&image, priority, &map)
+            .await
+            .map_err(|e| warp::reject::custom(Error::JobNotRecorded(e)))?;
+        event!(Level::INFO, "webhook started job {} for {}", id, image);
+        tokio::spawn(worker.clone().run(id.clone(), priority, map));
+        items.push(batch::Entry::job(&image, &id));
+        started.push(serde_json::json!({ "image": image, "job_id": id }));
+    }
+    let batch_id = worker.batches.record(batch::Kind::Webhook, items);
+    Ok(warp::reply::with_status(
+        warp::reply::json(&serde_json::json!({ "batch_id": batch_id, "jobs": started })),
+        StatusCode::ACCEPTED,
+    )
+    .into_response())
+}
+
+#[tracing::instrument(skip(config))]
+async fn create_bundle(
+    req: BundleReq,
+    config: Arc<config::Config>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    if req.images.is_empty() {
+        return Err(warp::reject::custom(Error::Parse(
+            "images is empty".to_owned(),
+        )));
+    }
+    limits::batch(&req.images, config.limits.batch_size).map_err(warp::reject::custom)?;
+
+    // the name ends up in a file path, keep it to a single plain component
+    let name = req
+        .name
+        .unwrap_or_else(|| format!("bundle-{}", chrono::Utc::now().format("%Y%m%d%H%M%S")));
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.')
+        || name.starts_with('.')
+    {
+        return Err(warp::reject::custom(Error::Parse(format!(
+            "invalid bundle name {}",
+            name
+        ))));
+    }
+    let path = config.bundle_dir.join(format!("{}.tar.gz", name));
+
+    // create docker client
+    let docker = docker().map_err(warp::reject::custom)?;
+
+    let manifest = match bundle::create_bundle(&docker, &req.images, &path).await {
+        Ok(r) => r,
+        Err(e) => {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(warp::reject::custom(Error::Bundle(e)));
+        }
+    };
+
+    Ok(warp::reply::json(&BundleRes {
+        path: path.display().to_string(),
+        manifest,
+    }))
+}
+
+#[tracing::instrument(skip(proxy))]
+async fn serve_registry(
+    method: warp::http::Method,
+    tail: warp::path::Tail,
+    proxy: Option<Arc<proxy::Proxy>>,
+) -> Result<impl warp::Reply, warp::Rejection> {
+    let proxy = match proxy {
+        Some(p) => p,
+        None => return Err(warp::reject::not_found()),
+    };
+
+    if method != warp::http::Method::GET && method != warp::http::Method::HEAD {
+        return Err(warp::reject::not_found());
+    }
+
+    Ok(proxy
+        .handle(method, tail.as_str().trim_end_matches('/'))
+        .await)
+}
+
+#[tracing::instrument(skip(jobs))]
+async fn prune_images(jobs: Arc<jobs::Jobs>) -> Result<impl warp::Reply, warp::Rejection> {
+    // create docker client
+    let docker = docker().map_err(warp::reject::custom)?;
+
+    // what the daemon's own prune would remove, less what running jobs
+    // hold: an image pulled by digest has no tag until it is pushed
+    let mut filters = HashMap::new();
+    filters.insert("dangling", vec!["true"]);
+    let options = Some(ListImagesOptions {
+        filters,
+        ..Default::default()
+    });
+    let images = match docker.list_images(options).await {
+        Ok(images) => images,
+        Err(e) => {
+            event!(Level::ERROR, "{:?}", e);
+            return Err(warp::reject::custom(Error::Cleanup {
+                image: "unused images".to_owned(),
+                source: e,
+            }));
+        }
+    };
+
+    // images held by no job are still left alone for a minute, as before
+    let until = chrono::Utc::now().timestamp() - 60;
+    let mut deleted = Vec::new();
+    let mut space_reclaimed = 0;
+    let mut held = Vec::new();
+    for image in images {
+        let _pruning = jobs.lock_prune().await;
+        let names: Vec<String> = image
+            .repo_tags
+            .iter()
+            .chain(&image.repo_digests)
+            .filter(|name| !name.starts_with("<none>"))
+            .cloned()
+            .collect();
+        if jobs.is_held(&image.id, &names) {
+            held.push(image.id);
+            continue;
+        }
+        if image.created > until {
+            continue;
+        }
+        match docker.remove_image(&image.id, None, None).await {
+            Ok(removed) => {
+                deleted.extend(removed);
+                space_reclaimed += image.size;
+            }
+            // used by a container, or removed by someone else meanwhile
+            Err(bollard::errors::Error::DockerResponseServerError {
+                status_code: 404 | 409,
+                ..
+            }) => {}
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                return Err(warp::reject::custom(Error::Cleanup {
+                    image: image.id,
+                    source: e,
+                }));
+            }
+        }
+    }
+    if !held.is_empty() {
+        event!(
+            Level::INFO,
+            "kept {} images held by running jobs from the prune",
+            held.len()
+        );
+    }
 
-    Ok(warp::reply::json(&resp))
+    events::publish(
+        "prune_finished",
+        serde_json::json!({
+            "kind": "images",
+            "removed": deleted.len(),
+            "space_reclaimed": space_reclaimed,
+            "held": held.len(),
+        }),
+    );
+    Ok(warp::reply::json(&serde_json::json!({
+        "ImagesDeleted": deleted,
+        "SpaceReclaimed": space_reclaimed,
+        "held": held,
+    })))
 }
 
 // async fn list_image() -> Result<()> {
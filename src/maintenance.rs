@@ -0,0 +1,65 @@
+use crate::config::MaintenanceConfig;
+use crate::jobs::Jobs;
+use crate::jobs::Priority;
+use chrono::DateTime;
+use chrono::Duration;
+use chrono::Local;
+use chrono::Timelike;
+use chrono::Utc;
+use tracing::event;
+use tracing::Level;
+
+impl MaintenanceConfig {
+    /// Whether a sync of `priority` may start at `now`.
+    pub fn allows(&self, priority: Priority, now: DateTime<Local>) -> bool {
+        if !self.priorities.contains(&priority) {
+            return true;
+        }
+        let t = now.time();
+        (self.windows.is_empty() || self.windows.iter().any(|w| w.contains(t)))
+            && !self.blackouts.iter().any(|w| w.contains(t))
+    }
+
+    /// Next minute a sync of `priority` may start, or `None` when it may
+    /// start now. Windows repeat daily, so two days ahead is far enough.
+    pub fn next_opening(
+        &self,
+        priority: Priority,
+        now: DateTime<Local>,
+    ) -> Option<DateTime<Local>> {
+        if self.allows(priority, now) {
+            return None;
+        }
+        let minute = now.with_second(0)?.with_nanosecond(0)?;
+        (1..=2 * 24 * 60)
+            .map(|m| minute + Duration::minutes(m))
+            .find(|t| self.allows(priority, *t))
+    }
+}
+
+/// Hold a queued job until the maintenance windows let it start.
+pub async fn wait_for_window(
+    config: &MaintenanceConfig,
+    jobs: &Jobs,
+    id: &str,
+    priority: Priority,
+) {
+    loop {
+        let opening = match config.next_opening(priority, Local::now()) {
+            Some(opening) => opening,
+            None => {
+                jobs.hold(id, None);
+                return;
+            }
+        };
+        jobs.hold(id, Some(opening.with_timezone(&Utc)));
+        event!(Level::INFO, "job {} held until {}", id, opening);
+
+        // check again at least every minute in case the clock jumps
+        let wait = (opening - Local::now())
+            .to_std()
+            .unwrap_or_default()
+            .min(std::time::Duration::from_secs(60));
+        tokio::time::sleep(wait).await;
+    }
+}
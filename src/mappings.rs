@@ -0,0 +1,202 @@
+use crate::registry;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use tracing::event;
+use tracing::Level;
+
+/// Where a destination image was synced from.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Mapping {
+    /// Source reference as requested, with its registry host.
+    pub source: String,
+    /// Digest of the source manifest, when it was known.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub source_digest: Option<String>,
+    /// Destination reference pushed to, with its registry host.
+    pub destination: String,
+    /// Digest of the manifest pushed, when it was known.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub digest: Option<String>,
+    /// The digest-derived destination tag of pinned syncs, as a reference.
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub digest_tag: Option<String>,
+    pub job_id: String,
+    pub synced_at: DateTime<Utc>,
+}
+
+/// Every destination reference with the source it was last synced from,
+/// kept in `mappings.json` of the state directory, and every destination
+/// digest ever pushed with the sync that pushed it, in `origins.json`, so
+/// images a tag no longer points at can still be traced.
+pub struct Mappings {
+    dir: PathBuf,
+    // by destination reference
+    destinations: Mutex<BTreeMap<String, Mapping>>,
+    // by destination digest
+    origins: Mutex<BTreeMap<String, Mapping>>,
+}
+
+impl Mappings {
+    pub fn open(state_dir: &Path) -> Mappings {
+        Mappings {
+            dir: state_dir.to_owned(),
+            destinations: Mutex::new(load(&state_dir.join("mappings.json"))),
+            origins: Mutex::new(load(&state_dir.join("origins.json"))),
+        }
+    }
+
+    /// Note a finished sync, replacing what its destination was synced from
+    /// before.
+    pub fn record(&self, mut mapping: Mapping) {
+        mapping.source = canonical(&mapping.source);
+        mapping.destination = canonical(&mapping.destination);
+        mapping.digest_tag = mapping.digest_tag.as_deref().map(canonical);
+        if let Some(digest) = &mapping.digest {
+            let mut origins = self.origins.lock().unwrap();
+            origins.insert(digest.clone(), mapping.clone());
+            save(&self.dir.join("origins.json"), &origins);
+        }
+        let mut destinations = self.destinations.lock().unwrap();
+        destinations.insert(mapping.destination.clone(), mapping);
+        save(&self.dir.join("mappings.json"), &destinations);
+    }
+
+    /// The mappings whose source matches `source` and destination matches
+    /// `dest`, either left out to match any. A source matches by reference,
+    /// or by digest given alone or as `name@digest`; a destination by
+    /// reference, including the digest-derived tag.
+    pub fn find(&self, source: Option<&str>, dest: Option<&str>) -> Vec<Mapping> {
+        let source = source.map(Query::new);
+        let dest = dest.map(canonical);
+        let destinations = self.destinations.lock().unwrap();
+        destinations
+            .values()
+            .filter(|m| source.as_ref().is_none_or(|q| q.matches(m)))
+            .filter(|m| {
+                dest.as_ref()
+                    .is_none_or(|d| &m.destination == d || m.digest_tag.as_ref() == Some(d))
+            })
+            .cloned()
+            .collect()
+    }
+
+    /// Where the destination `image` came from: a reference it was last
+    /// synced to, or a digest given alone or as `name@digest` that was
+    /// pushed, even when no tag points at it any more.
+    pub fn origin(&self, image: &str) -> Option<Mapping> {
+        let digest = match registry::is_digest(image) {
+            true => Some((None, image.to_owned())),
+            false => {
+                let reference = canonical(image);
+                reference
+                    .split_once('@')
+                    .map(|(repository, digest)| (Some(repository.to_owned()), digest.to_owned()))
+            }
+        };
+        match digest {
+            Some((repository, digest)) => self
+                .origins
+                .lock()
+                .unwrap()
+                .get(&digest)
+                .filter(|m| repository.is_none_or(|r| untagged(&m.destination) == r))
+                .cloned(),
+            None => self.find(None, Some(image)).into_iter().next(),
+        }
+    }
+}
+
+fn load(path: &Path) -> BTreeMap<String, Mapping> {
+    match std::fs::read(path) {
+        Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+            event!(Level::WARN, "ignoring unreadable {}: {}", path.display(), e);
+            BTreeMap::new()
+        }),
+        Err(_) => BTreeMap::new(),
+    }
+}
+
+// Write `mappings` to `path`, replacing the file atomically.
+fn save(path: &Path, mappings: &BTreeMap<String, Mapping>) {
+    let written = (|| -> anyhow::Result<()> {
+        let dir = path.parent().unwrap_or(Path::new("."));
+        std::fs::create_dir_all(dir)?;
+        let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+        serde_json::to_writer(&mut tmp, mappings)?;
+        tmp.persist(path)?;
+        Ok(())
+    })();
+    if let Err(e) = written {
+        event!(Level::ERROR, "failed to save {}: {:?}", path.display(), e);
+    }
+}
+
+// a source to look up, by reference or by digest
+enum Query {
+    Reference(String),
+    Digest {
+        repository: Option<String>,
+        digest: String,
+    },
+}
+
+impl Query {
+    fn new(source: &str) -> Query {
+        if registry::is_digest(source) {
+            return Query::Digest {
+                repository: None,
+                digest: source.to_owned(),
+            };
+        }
+        let reference = canonical(source);
+        match reference.split_once('@') {
+            Some((repository, digest)) => Query::Digest {
+                repository: Some(repository.to_owned()),
+                digest: digest.to_owned(),
+            },
+            None => Query::Reference(reference),
+        }
+    }
+
+    fn matches(&self, mapping: &Mapping) -> bool {
+        match self {
+            Query::Reference(reference) => &mapping.source == reference,
+            Query::Digest { repository, digest } => {
+                mapping.source_digest.as_ref() == Some(digest)
+                    && repository
+                        .as_ref()
+                        .is_none_or(|r| untagged(&mapping.source) == r)
+            }
+        }
+    }
+}
+
+// canonical `image` without its tag or digest
+fn untagged(image: &str) -> &str {
+    let (host, rest) = image.split_once('/').unwrap_or(("", image));
+    let end = rest
+        .find(['@', ':'])
+        .map_or(image.len(), |i| host.len() + 1 + i);
+    &image[..end]
+}
+
+/// `image` spelled out in full, so different spellings of one image match:
+/// with its registry host, Docker Hub's `library/` and its tag or digest.
+pub fn canonical(image: &str) -> String {
+    let (host, name, reference) = registry::parse_reference(image);
+    let name = if host == "docker.io" && !name.contains('/') {
+        format!("library/{}", name)
+    } else {
+        name
+    };
+    match registry::is_digest(&reference) {
+        true => format!("{}/{}@{}", host, name, reference),
+        false => format!("{}/{}:{}", host, name, reference),
+    }
+}
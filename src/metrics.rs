@@ -0,0 +1,292 @@
+use crate::config::glob_match;
+use crate::config::MetricLabelsConfig;
+use once_cell::sync::Lazy;
+use once_cell::sync::OnceCell;
+use prometheus::exponential_buckets;
+use prometheus::register_gauge;
+use prometheus::register_gauge_vec;
+use prometheus::register_histogram_vec;
+use prometheus::register_int_counter;
+use prometheus::register_int_counter_vec;
+use prometheus::register_int_gauge;
+use prometheus::register_int_gauge_vec;
+use prometheus::Encoder;
+use prometheus::Gauge;
+use prometheus::GaugeVec;
+use prometheus::HistogramVec;
+use prometheus::IntCounter;
+use prometheus::IntCounterVec;
+use prometheus::IntGauge;
+use prometheus::IntGaugeVec;
+use prometheus::TextEncoder;
+use sha2::Digest;
+use sha2::Sha256;
+
+// per-sync labels, set by `init`
+static LABELS: OnceCell<MetricLabelsConfig> = OnceCell::new();
+
+pub static DOCKER_HUB_RATELIMIT_LIMIT: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_dockerhub_ratelimit_limit",
+        "Docker Hub pull quota for the current window"
+    )
+    .unwrap()
+});
+
+pub static DOCKER_HUB_RATELIMIT_REMAINING: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_dockerhub_ratelimit_remaining",
+        "Docker Hub pulls remaining in the current window"
+    )
+    .unwrap()
+});
+
+pub static RATELIMIT_DELAYS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "imagesync_ratelimit_delays_total",
+        "Pulls delayed because the Docker Hub quota was nearly exhausted"
+    )
+    .unwrap()
+});
+
+pub static ANONYMOUS_FALLBACKS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "imagesync_anonymous_fallbacks_total",
+        "Pulls that went on anonymously after the registry refused our credentials",
+        &["registry"]
+    )
+    .unwrap()
+});
+
+pub static DAEMON_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "imagesync_daemon_events_total",
+        "Docker daemon image events about synced images, by action and whether a sync was running",
+        &["action", "during_job"]
+    )
+    .unwrap()
+});
+
+pub static DAEMON_IMAGES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_daemon_images",
+        "Images stored by the Docker daemon"
+    )
+    .unwrap()
+});
+
+pub static DAEMON_IMAGES_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_daemon_images_bytes",
+        "Disk space taken by the image layers of the Docker daemon"
+    )
+    .unwrap()
+});
+
+pub static DAEMON_IMAGES_RECLAIMABLE_BYTES: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_daemon_images_reclaimable_bytes",
+        "Image disk space of the Docker daemon no container uses"
+    )
+    .unwrap()
+});
+
+pub static SHARED_TRANSFERS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "imagesync_shared_transfers_total",
+        "Blob pulls and pushes that waited for another job moving the same blob",
+        &["direction"]
+    )
+    .unwrap()
+});
+
+pub static UPLOAD_SESSION_RESTARTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "imagesync_upload_session_restarts_total",
+        "Blob uploads started over after the registry lost their upload session"
+    )
+    .unwrap()
+});
+
+pub static DESTINATION_UP: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_destination_up",
+        "1 when the destination registry answered the last check and took our credentials"
+    )
+    .unwrap()
+});
+
+pub static DESTINATION_CHECK_SECONDS: Lazy<Gauge> = Lazy::new(|| {
+    register_gauge!(
+        "imagesync_destination_check_seconds",
+        "Time the last check of the destination registry took"
+    )
+    .unwrap()
+});
+
+pub static GC_DELETED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "imagesync_gc_deleted_total",
+        "Untagged manifests deleted from the destination through the registry's API"
+    )
+    .unwrap()
+});
+
+pub static RETENTION_DELETED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "imagesync_retention_deleted_total",
+        "Manifests deleted from the destination by the retention policy"
+    )
+    .unwrap()
+});
+
+pub static JOB_PROGRESS: Lazy<GaugeVec> = Lazy::new(|| {
+    register_gauge_vec!(
+        "imagesync_job_progress_percent",
+        "Progress of the current phase of running sync jobs",
+        &["job", "phase"]
+    )
+    .unwrap()
+});
+
+pub static PHASE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "imagesync_phase_duration_seconds",
+        "Time sync jobs spent in each phase by outcome and the configured sync labels",
+        &[&["phase"], &sync_label_names()[..], &["outcome"]].concat(),
+        exponential_buckets(0.5, 2.0, 12).unwrap()
+    )
+    .unwrap()
+});
+
+pub static SYNCS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "imagesync_syncs_total",
+        "Finished sync jobs by state and the configured sync labels",
+        &[&["state"], &sync_label_names()[..]].concat()
+    )
+    .unwrap()
+});
+
+pub static QUEUE_DEPTH: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("imagesync_queue_depth", "Sync jobs waiting for a free slot").unwrap()
+});
+
+pub static WORKERS_ACTIVE: Lazy<IntGauge> =
+    Lazy::new(|| register_int_gauge!("imagesync_workers_active", "Sync jobs running").unwrap());
+
+pub static WORKERS_MAX: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!("imagesync_workers_max", "Sync jobs allowed to run at once").unwrap()
+});
+
+pub static QUEUE_PAUSED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_queue_paused",
+        "1 while the queue is paused and no sync job starts"
+    )
+    .unwrap()
+});
+
+pub static JOBS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("imagesync_jobs", "Known sync jobs by state", &["state"]).unwrap()
+});
+
+pub static OLDEST_QUEUED_AGE: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "imagesync_oldest_queued_job_age_seconds",
+        "Age of the oldest queued sync job, 0 when none is queued"
+    )
+    .unwrap()
+});
+
+fn labels() -> &'static MetricLabelsConfig {
+    LABELS.get_or_init(|| MetricLabelsConfig {
+        image: false,
+        registry: true,
+        tenant: false,
+        images: Vec::new(),
+        image_buckets: None,
+    })
+}
+
+fn sync_label_names() -> Vec<&'static str> {
+    let labels = labels();
+    [
+        (labels.image, "image"),
+        (labels.registry, "registry"),
+        (labels.tenant, "tenant"),
+    ]
+    .into_iter()
+    .filter_map(|(on, name)| on.then_some(name))
+    .collect()
+}
+
+/// Values of the configured sync labels for a sync of `image` from
+/// `registry` on behalf of `tenant`, in the order of their names.
+pub fn sync_labels(image: &str, registry: &str, tenant: &str) -> Vec<String> {
+    let labels = labels();
+    let mut values = Vec::new();
+    if labels.image {
+        values.push(image_label(labels, image));
+    }
+    if labels.registry {
+        values.push(registry.to_owned());
+    }
+    if labels.tenant {
+        values.push(tenant.to_owned());
+    }
+    values
+}
+
+// The repository of `image`, without its tag, when it may be named.
+fn image_label(labels: &MetricLabelsConfig, image: &str) -> String {
+    let (host, name, _) = crate::registry::parse_reference(image);
+    let repository = format!("{}/{}", host, name);
+    let listed = labels.images.iter().any(|p| glob_match(p, &repository));
+    match (labels.image_buckets, labels.images.is_empty()) {
+        _ if listed => repository,
+        (None, true) => repository,
+        (None, false) => "other".to_owned(),
+        (Some(buckets), _) => {
+            let hash = Sha256::digest(repository.as_bytes());
+            let n = u32::from_be_bytes([hash[0], hash[1], hash[2], hash[3]]);
+            format!("bucket-{}", n % buckets)
+        }
+    }
+}
+
+/// Render every registered metric in the Prometheus text format.
+pub fn render() -> String {
+    let mut buffer = Vec::new();
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .unwrap();
+    String::from_utf8(buffer).unwrap()
+}
+
+/// Register every metric up front so they are exported before first use.
+pub fn init(labels: &MetricLabelsConfig) {
+    let _ = LABELS.set(labels.clone());
+    Lazy::force(&DOCKER_HUB_RATELIMIT_LIMIT);
+    Lazy::force(&DOCKER_HUB_RATELIMIT_REMAINING);
+    Lazy::force(&RATELIMIT_DELAYS);
+    Lazy::force(&SHARED_TRANSFERS);
+    Lazy::force(&DAEMON_EVENTS);
+    Lazy::force(&DAEMON_IMAGES);
+    Lazy::force(&DAEMON_IMAGES_BYTES);
+    Lazy::force(&DAEMON_IMAGES_RECLAIMABLE_BYTES);
+    Lazy::force(&RETENTION_DELETED);
+    Lazy::force(&UPLOAD_SESSION_RESTARTS);
+    Lazy::force(&GC_DELETED);
+    Lazy::force(&DESTINATION_UP);
+    Lazy::force(&DESTINATION_CHECK_SECONDS);
+    Lazy::force(&JOB_PROGRESS);
+    Lazy::force(&PHASE_DURATION);
+    Lazy::force(&SYNCS);
+    Lazy::force(&QUEUE_DEPTH);
+    Lazy::force(&WORKERS_ACTIVE);
+    Lazy::force(&WORKERS_MAX);
+    Lazy::force(&QUEUE_PAUSED);
+    Lazy::force(&JOBS);
+    Lazy::force(&OLDEST_QUEUED_AGE);
+}
@@ -0,0 +1,493 @@
+use crate::auth;
+use crate::config::glob_match;
+use crate::config::Config;
+use crate::config::GitSource;
+use crate::events;
+use crate::jobs::Priority;
+use crate::registry;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Deserialize;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::collections::HashSet;
+use std::path::Path;
+use std::path::PathBuf;
+use std::process::Command;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tracing::event;
+use tracing::Level;
+
+/// Layout of the mirror list file.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct MirrorList {
+    mirrors: Vec<Entry>,
+}
+
+/// One entry of the mirror list: an image, or the tags of a repository
+/// matching `tags` (all of them when empty).
+#[derive(Deserialize, Debug, Clone)]
+#[serde(deny_unknown_fields)]
+pub struct Entry {
+    pub image: Option<String>,
+    pub repository: Option<String>,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    /// Priority of the syncs, `low` unless set so maintenance windows apply.
+    #[serde(default)]
+    pub priority: Option<Priority>,
+}
+
+/// An image the mirror list wants in the destination.
+#[derive(Debug, Clone)]
+pub struct Wanted {
+    pub image: String,
+    /// Comma separated, empty for all of them.
+    pub platforms: String,
+    pub priority: Priority,
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum State {
+    InSync,
+    /// The source moved, or the destination lacks the image, and no sync
+    /// is running yet.
+    Drift,
+    Syncing,
+    /// The check or the sync failed; tried again next round.
+    Failed,
+}
+
+/// Where one listed image stands.
+#[derive(Serialize, Debug, Clone)]
+pub struct Status {
+    pub state: State,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub source_digest: Option<String>,
+    /// Source digest of the last successful sync.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub synced_digest: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub job_id: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub checked_at: DateTime<Utc>,
+}
+
+/// Answer of `/mirrors`.
+#[derive(Serialize, Debug)]
+pub struct Report {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub loaded_at: Option<DateTime<Utc>>,
+    /// Why the file could not be read; the entries last read stay in force.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub git: Option<GitStatus>,
+    /// Images of annotated Kubernetes workloads, with the workloads running
+    /// them.
+    #[serde(skip_serializing_if = "BTreeMap::is_empty")]
+    pub discovered: BTreeMap<String, Vec<String>>,
+    pub in_sync: usize,
+    pub drifted: usize,
+    pub images: BTreeMap<String, Status>,
+}
+
+/// Where the clone of a mirror list kept in Git stands.
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct GitStatus {
+    pub branch: String,
+    /// Commit the list in force was read from.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub revision: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fetched_at: Option<DateTime<Utc>>,
+    /// Why the last fetch failed; the commit checked out before stays.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// The mirror list file, and the images discovered in Kubernetes, and where
+/// each image they name stands.
+pub struct Mirrors {
+    path: Option<PathBuf>,
+    // source digest each image was last synced from, kept across restarts
+    synced_path: PathBuf,
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    modified: Option<SystemTime>,
+    loaded_at: Option<DateTime<Utc>>,
+    error: Option<String>,
+    entries: Vec<Entry>,
+    images: BTreeMap<String, Status>,
+    synced: HashMap<String, String>,
+    git: Option<GitStatus>,
+    discovered: BTreeMap<String, Vec<String>>,
+    // discovery changed what is wanted since the last reload
+    dirty: bool,
+}
+
+impl Mirrors {
+    pub fn new(path: Option<PathBuf>, state_dir: PathBuf) -> Mirrors {
+        let synced_path = state_dir.join("mirrors.json");
+        let synced = std::fs::read(&synced_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default();
+        Mirrors {
+            path,
+            synced_path,
+            inner: Mutex::new(Inner {
+                synced,
+                ..Default::default()
+            }),
+        }
+    }
+
+    /// Read the file again if it changed since it was last read, returning
+    /// whether it did or discovery found other images. A file that fails to
+    /// parse keeps the entries read before in force.
+    pub fn reload(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+        let dirty = std::mem::take(&mut inner.dirty);
+        let Some(path) = &self.path else {
+            return dirty;
+        };
+        let modified = std::fs::metadata(path).and_then(|m| m.modified()).ok();
+        // a missing file is only reported once
+        if modified == inner.modified && (modified.is_some() || inner.error.is_some()) {
+            return dirty;
+        }
+        inner.modified = modified;
+        match read(path) {
+            Ok(entries) => {
+                event!(
+                    Level::INFO,
+                    "read {} entries from {}",
+                    entries.len(),
+                    path.display()
+                );
+                events::publish(
+                    "mirror_list_reloaded",
+                    serde_json::json!({ "file": path, "entries": entries.len() }),
+                );
+                inner.entries = entries;
+                inner.loaded_at = Some(Utc::now());
+                inner.error = None;
+            }
+            Err(e) => {
+                event!(Level::ERROR, "{:#}", e);
+                events::publish(
+                    "mirror_list_reloaded",
+                    serde_json::json!({ "file": path, "error": format!("{:#}", e) }),
+                );
+                inner.error = Some(format!("{:#}", e));
+            }
+        }
+        true
+    }
+
+    /// Replace the images discovered in Kubernetes, each with the workloads
+    /// running it.
+    pub fn set_discovered(&self, discovered: BTreeMap<String, Vec<String>>) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.discovered != discovered {
+            inner.discovered = discovered;
+            inner.dirty = true;
+        }
+    }
+
+    /// Every image the list names, listing the tags of repositories.
+    /// Repositories that cannot be listed are reported and skipped.
+    pub async fn wanted(&self, config: &Config) -> Vec<Wanted> {
+        let (entries, discovered) = {
+            let inner = self.inner.lock().unwrap();
+            (inner.entries.clone(), inner.discovered.clone())
+        };
+        let mut wanted = Vec::new();
+        for entry in entries {
+            let platforms = entry.platforms.join(",");
+            let priority = entry.priority.unwrap_or(Priority::Low);
+            match (&entry.image, &entry.repository) {
+                (Some(image), _) => wanted.push(Wanted {
+                    image: with_tag(image, config.default_tag.as_deref()),
+                    platforms,
+                    priority,
+                }),
+                (None, Some(repository)) => {
+                    let (host, name, _) = registry::parse_reference(repository);
+                    let listed = match auth::pull_credentials(config, &host) {
+                        Ok(credentials) => {
+                            let src = registry::Registry::new(&host, credentials);
+                            src.list_tags(&src.repository(&name)).await
+                        }
+                        Err(e) => Err(e.into()),
+                    };
+                    match listed {
+                        Ok(tags) => {
+                            for tag in tags {
+                                if entry.tags.is_empty()
+                                    || entry.tags.iter().any(|p| glob_match(p, &tag))
+                                {
+                                    wanted.push(Wanted {
+                                        image: format!("{}:{}", repository, tag),
+                                        platforms: platforms.clone(),
+                                        priority,
+                                    });
+                                }
+                            }
+                        }
+                        Err(e) => {
+                            event!(Level::WARN, "could not list {}: {:?}", repository, e);
+                            self.set(repository, State::Failed, None, Some(format!("{:#}", e)));
+                        }
+                    }
+                }
+                (None, None) => {}
+            }
+        }
+        // the list decides the platforms and priority of images it names too
+        let listed: HashSet<String> = wanted.iter().map(|w| w.image.clone()).collect();
+        for image in discovered.into_keys() {
+            // Kubernetes pulls `latest` whatever our default is
+            let image = with_tag(&image, Some("latest"));
+            if !listed.contains(&image) {
+                wanted.push(Wanted {
+                    image,
+                    platforms: String::new(),
+                    priority: Priority::Low,
+                });
+            }
+        }
+        wanted
+    }
+
+    /// Forget images no longer listed.
+    pub fn retain(&self, wanted: &[Wanted]) {
+        let mut names: HashSet<&str> = wanted.iter().map(|w| w.image.as_str()).collect();
+        let mut inner = self.inner.lock().unwrap();
+        // repositories that could not be listed stay reported
+        for entry in &inner.entries {
+            if let Some(repository) = &entry.repository {
+                names.insert(repository);
+            }
+        }
+        let names: HashSet<String> = names.into_iter().map(str::to_owned).collect();
+        inner.images.retain(|image, _| names.contains(image));
+    }
+
+    pub fn is_syncing(&self, image: &str) -> bool {
+        self.inner
+            .lock()
+            .unwrap()
+            .images
+            .get(image)
+            .is_some_and(|s| s.state == State::Syncing)
+    }
+
+    /// Source digest `image` was last synced from.
+    pub fn synced(&self, image: &str) -> Option<String> {
+        self.inner.lock().unwrap().synced.get(image).cloned()
+    }
+
+    /// Record the outcome of a check of `image`.
+    pub fn set(
+        &self,
+        image: &str,
+        state: State,
+        source_digest: Option<String>,
+        error: Option<String>,
+    ) {
+        let mut inner = self.inner.lock().unwrap();
+        let synced_digest = inner.synced.get(image).cloned();
+        inner.images.insert(
+            image.to_owned(),
+            Status {
+                state,
+                source_digest,
+                synced_digest,
+                job_id: None,
+                error,
+                checked_at: Utc::now(),
+            },
+        );
+    }
+
+    /// Record that job `id` syncs `image`.
+    pub fn syncing(&self, image: &str, id: &str) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(status) = inner.images.get_mut(image) {
+            status.state = State::Syncing;
+            status.job_id = Some(id.to_owned());
+        }
+    }
+
+    /// Record the outcome of the sync of `image` from `digest`.
+    pub fn finished(&self, image: &str, digest: &str, result: Result<(), String>) {
+        let mut inner = self.inner.lock().unwrap();
+        if result.is_ok() {
+            inner.synced.insert(image.to_owned(), digest.to_owned());
+            let saved = std::fs::write(
+                &self.synced_path,
+                serde_json::to_vec(&inner.synced).unwrap(),
+            );
+            if let Err(e) = saved {
+                event!(Level::WARN, "failed to record mirrored digests: {:?}", e);
+            }
+        }
+        let synced_digest = inner.synced.get(image).cloned();
+        if let Some(status) = inner.images.get_mut(image) {
+            status.synced_digest = synced_digest;
+            match result {
+                Ok(()) => status.state = State::InSync,
+                Err(e) => {
+                    status.state = State::Failed;
+                    status.error = Some(e);
+                }
+            }
+        }
+    }
+
+    pub fn report(&self) -> Report {
+        let inner = self.inner.lock().unwrap();
+        let count = |state| inner.images.values().filter(|s| s.state == state).count();
+        Report {
+            file: self.path.as_ref().map(|p| p.display().to_string()),
+            loaded_at: inner.loaded_at,
+            error: inner.error.clone(),
+            git: inner.git.clone(),
+            discovered: inner.discovered.clone(),
+            in_sync: count(State::InSync),
+            drifted: inner.images.len() - count(State::InSync),
+            images: inner.images.clone(),
+        }
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<Vec<Entry>> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let list: MirrorList = serde_yaml::from_str(&content)
+        .with_context(|| format!("invalid mirror list {}", path.display()))?;
+    for entry in &list.mirrors {
+        match (&entry.image, &entry.repository) {
+            (Some(_), None) if entry.tags.is_empty() => {}
+            (Some(image), None) => anyhow::bail!("tags of {} need repository", image),
+            (None, Some(_)) => {}
+            _ => anyhow::bail!("mirror list entries need one of image and repository"),
+        }
+        registry::parse_platforms(&entry.platforms.join(","))?;
+    }
+    Ok(list.mirrors)
+}
+
+/// Keep the clone of `git` the mirror list is read from up to date,
+/// fetching its branch each interval. The list is read again once the
+/// checkout changes it.
+pub async fn follow_git(git: GitSource, state_dir: PathBuf, mirrors: Arc<Mirrors>) {
+    let dir = state_dir.join("mirrors-git");
+    mirrors.inner.lock().unwrap().git = Some(GitStatus {
+        branch: git.branch.clone(),
+        ..Default::default()
+    });
+    let mut interval = tokio::time::interval(git.interval);
+    loop {
+        interval.tick().await;
+        let (source, target) = (git.clone(), dir.clone());
+        let fetched = tokio::task::spawn_blocking(move || update(&source, &target))
+            .await
+            .unwrap_or_else(|e| Err(anyhow::anyhow!(e)));
+        let mut inner = mirrors.inner.lock().unwrap();
+        let status = inner.git.get_or_insert_with(Default::default);
+        match fetched {
+            Ok(revision) => {
+                if status.revision.as_ref() != Some(&revision) {
+                    event!(Level::INFO, "mirror list at {} of {}", revision, git.branch);
+                }
+                status.revision = Some(revision);
+                status.fetched_at = Some(Utc::now());
+                status.error = None;
+            }
+            Err(e) => {
+                event!(Level::ERROR, "could not fetch the mirror list: {:#}", e);
+                status.error = Some(format!("{:#}", e));
+            }
+        }
+    }
+}
+
+// Clone `git` into `dir`, or bring the clone there to the tip of its
+// branch, returning the commit checked out.
+fn update(git: &GitSource, dir: &Path) -> anyhow::Result<String> {
+    let cloned = dir.join(".git").exists()
+        && run(git_in(dir).args(["remote", "get-url", "origin"]))
+            .is_ok_and(|url| url.trim() == git.url);
+    if cloned {
+        run(git_in(dir).args(["fetch", "--quiet", "--depth", "1", "origin", &git.branch]))?;
+        run(git_in(dir).args(["reset", "--quiet", "--hard", "FETCH_HEAD"]))?;
+    } else {
+        // a clone of another repository, or one cut short
+        if dir.exists() {
+            std::fs::remove_dir_all(dir)
+                .with_context(|| format!("failed to remove {}", dir.display()))?;
+        }
+        run(Command::new("git")
+            .args([
+                "clone",
+                "--quiet",
+                "--depth",
+                "1",
+                "--single-branch",
+                "--branch",
+            ])
+            .arg(&git.branch)
+            .arg("--")
+            .arg(&git.url)
+            .arg(dir))?;
+    }
+    Ok(run(git_in(dir).args(["rev-parse", "HEAD"]))?
+        .trim()
+        .to_owned())
+}
+
+fn git_in(dir: &Path) -> Command {
+    let mut command = Command::new("git");
+    command.arg("-C").arg(dir);
+    command
+}
+
+fn run(command: &mut Command) -> anyhow::Result<String> {
+    let output = command
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .output()
+        .context("failed to run git")?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "git failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+// `image` spelled as `/imagesync` syncs it, with the implicit tag
+// `default`, so its destination is found.
+fn with_tag(image: &str, default: Option<&str>) -> String {
+    match default {
+        Some(tag) if !registry::has_reference(image) => format!("{}:{}", image, tag),
+        _ => image.to_owned(),
+    }
+}
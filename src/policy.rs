@@ -0,0 +1,437 @@
+use crate::cache::hex_digest;
+use crate::cache::BlobCache;
+use crate::config::glob_match;
+use crate::config::Config;
+use crate::config::PolicyConfig;
+use crate::jobs::Phase;
+use crate::jobs::Progress;
+use crate::registry::Manifest;
+use crate::registry::Registry;
+use crate::registry::MANIFEST_TYPES;
+use crate::signature;
+use serde::Serialize;
+use serde_json::Value;
+use std::io::Write;
+use std::sync::Mutex;
+use tracing::event;
+use tracing::Level;
+
+// guards the signature audit log, which every job appends to
+static AUDIT: Mutex<()> = Mutex::new(());
+
+/// Why an image was refused before anything of it was pushed.
+#[derive(Debug, thiserror::Error)]
+#[error("{}", .0.join("; "))]
+pub struct Violation(pub Vec<String>);
+
+const CONFIG_TYPES: &[&str] = &[
+    "application/vnd.oci.image.config.v1+json",
+    "application/vnd.docker.container.image.v1+json",
+];
+
+// layer types are versioned and come in compressed and foreign variants
+const LAYER_TYPE_PREFIXES: &[&str] = &[
+    "application/vnd.oci.image.layer.",
+    "application/vnd.docker.image.rootfs.",
+];
+
+/// Check that `manifest`, fetched as `reference`, is the one asked for when
+/// `reference` is a digest. This is the manifest as served, before any
+/// platforms are trimmed from it.
+pub fn check_digest(reference: &str, manifest: &Manifest) -> anyhow::Result<()> {
+    if reference.contains(':') && manifest.digest != reference {
+        return Err(Violation(vec![format!(
+            "manifest {} was served with digest {}",
+            reference, manifest.digest
+        )])
+        .into());
+    }
+    Ok(())
+}
+
+/// Check `manifest`, and for an index the `children` it lists, against the
+/// OCI image spec before any of it is pushed: media types, digests and sizes
+/// of what the registry served, that every config describes as many layers
+/// as its manifest has, and the layer limits of `policy`.
+pub async fn validate(
+    policy: &PolicyConfig,
+    cache: &BlobCache,
+    src: &Registry,
+    src_repo: &str,
+    manifest: &Manifest,
+    children: &[Manifest],
+) -> anyhow::Result<()> {
+    let mut problems = Vec::new();
+    let body = match json(manifest, &mut problems, "manifest") {
+        Some(body) => body,
+        None => return Err(Violation(problems).into()),
+    };
+    check_media_type(manifest, &body, &mut problems, "manifest");
+
+    if manifest.is_index() {
+        let entries = body["manifests"].as_array().cloned().unwrap_or_default();
+        for (entry, child) in entries.iter().zip(children) {
+            let what = format!("manifest {}", child.digest);
+            if entry["digest"].as_str() != Some(&child.digest) {
+                problems.push(format!(
+                    "index lists {} but the registry served {}",
+                    entry["digest"], child.digest
+                ));
+            }
+            if entry["size"].as_u64() != Some(child.bytes.len() as u64) {
+                problems.push(format!(
+                    "index lists {} with size {}, it is {} bytes",
+                    child.digest,
+                    entry["size"],
+                    child.bytes.len()
+                ));
+            }
+            if entry["mediaType"]
+                .as_str()
+                .is_some_and(|t| t != child.media_type)
+            {
+                problems.push(format!(
+                    "index lists {} as {}, it was served as {}",
+                    child.digest, entry["mediaType"], child.media_type
+                ));
+            }
+            // build attestations ride along in indexes with their own layer types
+            let attestation =
+                entry["annotations"]["vnd.docker.reference.type"] == "attestation-manifest";
+            if let Some(child_body) = json(child, &mut problems, &what) {
+                check_media_type(child, &child_body, &mut problems, &what);
+                let config = check_image(
+                    policy,
+                    cache,
+                    src,
+                    src_repo,
+                    &child_body,
+                    attestation,
+                    &mut problems,
+                    &what,
+                )
+                .await?;
+                if let (Some(config), false) = (config, attestation) {
+                    for key in ["os", "architecture"] {
+                        let listed = &entry["platform"][key];
+                        if !listed.is_null() && *listed != config[key] {
+                            problems.push(format!(
+                                "index lists {} for {} {}, its config says {}",
+                                what, key, listed, config[key]
+                            ));
+                        }
+                    }
+                }
+            }
+        }
+    } else {
+        check_image(
+            policy,
+            cache,
+            src,
+            src_repo,
+            &body,
+            false,
+            &mut problems,
+            "manifest",
+        )
+        .await?;
+    }
+
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(Violation(problems).into())
+    }
+}
+
+/// Signature decision on one source image, as a line of the audit log.
+#[derive(Serialize, Debug)]
+struct SignatureAudit<'a> {
+    time: String,
+    image: &'a str,
+    reference: Option<&'a str>,
+    /// Source pattern of the rule that applied, none where `verify` decided.
+    rule: Option<&'a str>,
+    required: bool,
+    /// `verified`, `rejected`, `failed` when the check could not be made,
+    /// or `unchecked` when no signature was required.
+    outcome: &'static str,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    detail: String,
+}
+
+/// Apply the signature rules to `image`, pulled as `reference` (none when
+/// its digest is unknown) from `repo` of `src`: verify its signature when
+/// the rule for its source requires one, and record the decision in the
+/// audit log either way.
+pub async fn check_signature(
+    config: &Config,
+    image: &str,
+    src: &Registry,
+    repo: &str,
+    reference: Option<&str>,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let signatures = &config.signatures;
+    if !signatures.is_enabled() {
+        return Ok(());
+    }
+    let source = crate::registry::repository_name(image);
+    let rule = signatures
+        .rules
+        .iter()
+        .find(|r| glob_match(&r.source, &source));
+    let required = rule.map_or(signatures.verify, |r| r.require);
+
+    let verified = match (required, reference) {
+        (false, _) => Ok(String::new()),
+        (true, Some(reference)) => {
+            progress.phase(Phase::Verifying);
+            signature::verify(signatures, src, repo, reference).await
+        }
+        (true, None) => Err(Violation(vec![format!("{} has no digest to verify", image)]).into()),
+    };
+    let (outcome, detail) = match &verified {
+        Ok(_) if !required => ("unchecked", String::new()),
+        Ok(signer) => ("verified", format!("signed by {}", signer)),
+        Err(e) if e.is::<Violation>() => ("rejected", e.to_string()),
+        Err(e) => ("failed", format!("{:#}", e)),
+    };
+    audit(
+        config,
+        &SignatureAudit {
+            time: chrono::Utc::now().to_rfc3339(),
+            image,
+            reference,
+            rule: rule.map(|r| r.source.as_str()),
+            required,
+            outcome,
+            detail,
+        },
+    );
+    verified.map(|_| ())
+}
+
+fn audit(config: &Config, record: &SignatureAudit) {
+    let mut line = serde_json::to_vec(record).unwrap();
+    line.push(b'\n');
+    let _lock = AUDIT.lock().unwrap();
+    let written = std::fs::create_dir_all(&config.state_dir).and_then(|_| {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(config.state_dir.join("signature-audit.jsonl"))?
+            .write_all(&line)
+    });
+    if let Err(e) = written {
+        event!(Level::WARN, "failed to record signature decision: {:?}", e);
+    }
+}
+
+/// Add to `problems` where the layers of `what`, as (name, size in bytes),
+/// exceed the limits of `policy`.
+pub fn check_limits(
+    policy: &PolicyConfig,
+    layers: &[(String, u64)],
+    problems: &mut Vec<String>,
+    what: &str,
+) {
+    if let Some(max) = policy.max_layers {
+        if layers.len() > max {
+            problems.push(format!(
+                "{} has {} layers, at most {} are allowed",
+                what,
+                layers.len(),
+                max
+            ));
+        }
+    }
+    if let Some(max) = policy.max_layer_size {
+        for (layer, size) in layers.iter().filter(|(_, size)| *size > max) {
+            problems.push(format!(
+                "{} has layer {} of {} bytes, at most {} are allowed",
+                what, layer, size, max
+            ));
+        }
+    }
+}
+
+/// Add to `problems` where `what`, created at `created` as its config
+/// says, is older than `policy` allows, or only log it in warn mode. Images
+/// without a time, or built reproducibly at the Unix epoch, are let through.
+pub fn check_age(
+    policy: &PolicyConfig,
+    created: Option<&str>,
+    problems: &mut Vec<String>,
+    what: &str,
+) {
+    let Some(max) = policy.max_age else {
+        return;
+    };
+    let Some(created) = created.and_then(|c| chrono::DateTime::parse_from_rfc3339(c).ok()) else {
+        return;
+    };
+    if created.timestamp() <= 0 {
+        return;
+    }
+    let age = chrono::Utc::now().signed_duration_since(created);
+    let max_days = max.as_secs() / (24 * 60 * 60);
+    if age.to_std().is_ok_and(|age| age > max) {
+        let problem = format!(
+            "{} was created {} days ago on {}, at most {} days are allowed",
+            what,
+            age.num_days(),
+            created.to_rfc3339(),
+            max_days
+        );
+        if policy.max_age_warn {
+            event!(Level::WARN, "{}", problem);
+        } else {
+            problems.push(problem);
+        }
+    }
+}
+
+fn json(manifest: &Manifest, problems: &mut Vec<String>, what: &str) -> Option<Value> {
+    match serde_json::from_slice::<Value>(&manifest.bytes) {
+        Ok(body) if body.is_object() => Some(body),
+        _ => {
+            problems.push(format!("{} is not a JSON object", what));
+            None
+        }
+    }
+}
+
+fn check_media_type(manifest: &Manifest, body: &Value, problems: &mut Vec<String>, what: &str) {
+    let served = manifest
+        .media_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim();
+    if !MANIFEST_TYPES.contains(&served) {
+        problems.push(format!(
+            "{} has unsupported media type {:?}",
+            what, manifest.media_type
+        ));
+    }
+    if let Some(declared) = body["mediaType"].as_str() {
+        if declared != served {
+            problems.push(format!(
+                "{} was served as {} but declares {}",
+                what, manifest.media_type, declared
+            ));
+        }
+    }
+    if body["schemaVersion"] != 2 {
+        problems.push(format!(
+            "{} has schemaVersion {}, expected 2",
+            what, body["schemaVersion"]
+        ));
+    }
+}
+
+// Check the descriptors of an image manifest and its config blob, returning
+// the config when it could be read.
+#[allow(clippy::too_many_arguments)]
+async fn check_image(
+    policy: &PolicyConfig,
+    cache: &BlobCache,
+    src: &Registry,
+    src_repo: &str,
+    body: &Value,
+    attestation: bool,
+    problems: &mut Vec<String>,
+    what: &str,
+) -> anyhow::Result<Option<Value>> {
+    let layers = body["layers"].as_array().cloned().unwrap_or_default();
+    let sizes: Vec<(String, u64)> = layers
+        .iter()
+        .map(|l| {
+            let digest = l["digest"].as_str().unwrap_or_default().to_owned();
+            (digest, l["size"].as_u64().unwrap_or(0))
+        })
+        .collect();
+    check_limits(policy, &sizes, problems, what);
+    for layer in &layers {
+        let digest = layer["digest"].as_str().unwrap_or_default();
+        if hex_digest(digest).is_err() {
+            problems.push(format!("{} has a layer with digest {:?}", what, digest));
+        }
+        if layer["size"].as_u64().unwrap_or(0) == 0 {
+            problems.push(format!("{} lists layer {} without a size", what, digest));
+        }
+        let media_type = layer["mediaType"].as_str().unwrap_or_default();
+        if !attestation
+            && !LAYER_TYPE_PREFIXES
+                .iter()
+                .any(|p| media_type.starts_with(p))
+        {
+            problems.push(format!(
+                "{} has layer {} of unsupported media type {:?}",
+                what, digest, media_type
+            ));
+        }
+    }
+
+    let descriptor = &body["config"];
+    let media_type = descriptor["mediaType"].as_str().unwrap_or_default();
+    if !CONFIG_TYPES.contains(&media_type) {
+        problems.push(format!(
+            "{} has a config of unsupported media type {:?}",
+            what, media_type
+        ));
+        return Ok(None);
+    }
+    let digest = descriptor["digest"].as_str().unwrap_or_default();
+    if hex_digest(digest).is_err() {
+        problems.push(format!("{} has a config with digest {:?}", what, digest));
+        return Ok(None);
+    }
+
+    // the cache checks the digest of what it downloads
+    let bytes = tokio::fs::read(cache.fetch(src, src_repo, digest).await?).await?;
+    if descriptor["size"].as_u64() != Some(bytes.len() as u64) {
+        problems.push(format!(
+            "{} lists config {} with size {}, it is {} bytes",
+            what,
+            digest,
+            descriptor["size"],
+            bytes.len()
+        ));
+    }
+    let config = match serde_json::from_slice::<Value>(&bytes) {
+        Ok(config) if config.is_object() => config,
+        _ => {
+            problems.push(format!(
+                "config {} of {} is not a JSON object",
+                digest, what
+            ));
+            return Ok(None);
+        }
+    };
+    if !attestation {
+        check_age(policy, config["created"].as_str(), problems, what);
+    }
+    for key in ["os", "architecture"] {
+        if !config[key].is_string() {
+            problems.push(format!("config {} of {} has no {}", digest, what, key));
+        }
+    }
+    match config["rootfs"]["diff_ids"].as_array() {
+        Some(diff_ids) if diff_ids.len() == layers.len() => {}
+        Some(diff_ids) => problems.push(format!(
+            "config {} of {} describes {} layers, the manifest has {}",
+            digest,
+            what,
+            diff_ids.len(),
+            layers.len()
+        )),
+        None => problems.push(format!(
+            "config {} of {} has no rootfs.diff_ids",
+            digest, what
+        )),
+    }
+    Ok(Some(config))
+}
@@ -0,0 +1,169 @@
+use serde::Serialize;
+use std::alloc::GlobalAlloc;
+use std::alloc::Layout;
+use std::alloc::System;
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::AtomicUsize;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+// The system allocator, counting what goes through it for `/debug/heap`.
+struct Counting;
+
+static ALLOCATED: AtomicUsize = AtomicUsize::new(0);
+static PEAK: AtomicUsize = AtomicUsize::new(0);
+static ALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+static DEALLOCATIONS: AtomicU64 = AtomicU64::new(0);
+
+unsafe impl GlobalAlloc for Counting {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            added(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            added(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+        DEALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let new = System.realloc(ptr, layout, new_size);
+        if !new.is_null() {
+            ALLOCATED.fetch_sub(layout.size(), Ordering::Relaxed);
+            let now = ALLOCATED.fetch_add(new_size, Ordering::Relaxed) + new_size;
+            PEAK.fetch_max(now, Ordering::Relaxed);
+        }
+        new
+    }
+}
+
+fn added(size: usize) {
+    let now = ALLOCATED.fetch_add(size, Ordering::Relaxed) + size;
+    PEAK.fetch_max(now, Ordering::Relaxed);
+    ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[global_allocator]
+static GLOBAL: Counting = Counting;
+
+#[derive(Serialize, Debug)]
+pub struct Heap {
+    /// Bytes currently allocated by the process.
+    pub allocated_bytes: usize,
+    /// Most bytes allocated at once since startup.
+    pub peak_bytes: usize,
+    pub allocations_total: u64,
+    pub deallocations_total: u64,
+    /// Resident memory as the kernel sees it, fragmentation included.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rss_bytes: Option<u64>,
+}
+
+pub fn heap() -> Heap {
+    Heap {
+        allocated_bytes: ALLOCATED.load(Ordering::Relaxed),
+        peak_bytes: PEAK.load(Ordering::Relaxed),
+        allocations_total: ALLOCATIONS.load(Ordering::Relaxed),
+        deallocations_total: DEALLOCATIONS.load(Ordering::Relaxed),
+        rss_bytes: rss(),
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct CpuProfile {
+    pub seconds: u64,
+    /// CPU seconds the whole process used during the profile.
+    pub cpu_seconds: f64,
+    /// CPU seconds per thread, busiest first.
+    pub threads: Vec<ThreadCpu>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ThreadCpu {
+    pub tid: u32,
+    pub name: String,
+    pub cpu_seconds: f64,
+}
+
+/// Sample the CPU time of every thread over `duration`. Only available on
+/// Linux, where the kernel keeps the counters in `/proc`.
+pub async fn cpu(duration: Duration) -> anyhow::Result<CpuProfile> {
+    let before = thread_times()?;
+    tokio::time::sleep(duration).await;
+    let after = thread_times()?;
+
+    let mut threads: Vec<ThreadCpu> = after
+        .into_iter()
+        .map(|(tid, (name, ticks))| {
+            let start = before.get(&tid).map(|(_, t)| *t).unwrap_or(0);
+            ThreadCpu {
+                tid,
+                name,
+                cpu_seconds: ticks.saturating_sub(start) as f64 / CLOCK_TICKS,
+            }
+        })
+        .filter(|t| t.cpu_seconds > 0.0)
+        .collect();
+    threads.sort_by(|a, b| b.cpu_seconds.total_cmp(&a.cpu_seconds));
+
+    Ok(CpuProfile {
+        seconds: duration.as_secs(),
+        cpu_seconds: threads.iter().fold(0.0, |sum, t| sum + t.cpu_seconds),
+        threads,
+    })
+}
+
+// USER_HZ, fixed at 100 for the /proc interface
+const CLOCK_TICKS: f64 = 100.0;
+
+// thread id to (name, user + system ticks)
+fn thread_times() -> anyhow::Result<HashMap<u32, (String, u64)>> {
+    let mut times = HashMap::new();
+    for entry in std::fs::read_dir("/proc/self/task")? {
+        let entry = entry?;
+        let tid = match entry.file_name().to_str().and_then(|n| n.parse().ok()) {
+            Some(tid) => tid,
+            None => continue,
+        };
+        // the thread may have exited since the listing
+        let stat = match std::fs::read_to_string(entry.path().join("stat")) {
+            Ok(stat) => stat,
+            Err(_) => continue,
+        };
+        // `pid (comm) state ...`, comm may contain spaces and parentheses
+        let (name, rest) = match (stat.find('('), stat.rfind(')')) {
+            (Some(open), Some(close)) => (&stat[open + 1..close], &stat[close + 1..]),
+            _ => continue,
+        };
+        let fields: Vec<&str> = rest.split_whitespace().collect();
+        // utime and stime are fields 14 and 15 of the whole line
+        let ticks: u64 = fields
+            .get(11..13)
+            .into_iter()
+            .flatten()
+            .filter_map(|f| f.parse::<u64>().ok())
+            .sum();
+        times.insert(tid, (name.to_owned(), ticks));
+    }
+    Ok(times)
+}
+
+fn rss() -> Option<u64> {
+    let status = std::fs::read_to_string("/proc/self/status").ok()?;
+    let line = status.lines().find(|l| l.starts_with("VmRSS:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib * 1024)
+}
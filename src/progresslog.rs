@@ -0,0 +1,129 @@
+use crate::config::LogConfig;
+use once_cell::sync::OnceCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::event;
+use tracing::Level;
+
+// (interval, step), set by `init`
+static CONFIG: OnceCell<(Duration, u64)> = OnceCell::new();
+
+/// Set how often progress is summarized. Without this a layer gets a line
+/// every 10 seconds or 10%.
+pub fn init(config: &LogConfig) {
+    let _ = CONFIG.set((config.progress_interval, config.progress_step));
+}
+
+/// Summary of the progress the Docker daemon streams while pulling,
+/// pushing or loading images. Every line it sends goes to TRACE; INFO gets
+/// a line when a layer changes status, and while its status stays the same
+/// only once per interval or step of its percentage. Only the layers still
+/// in progress are kept, so memory stays bounded however long the stream.
+pub struct ProgressLog {
+    image: String,
+    interval: Duration,
+    step: u64,
+    layers: HashMap<String, Layer>,
+}
+
+struct Layer {
+    status: String,
+    logged_at: Instant,
+    percent: u64,
+}
+
+// statuses after which the daemon says no more about a layer
+const DONE: &[&str] = &[
+    "Pull complete",
+    "Already exists",
+    "Pushed",
+    "Layer already exists",
+];
+
+impl ProgressLog {
+    pub fn new(image: &str) -> ProgressLog {
+        let (interval, step) = CONFIG
+            .get()
+            .copied()
+            .unwrap_or((Duration::from_secs(10), 10));
+        ProgressLog {
+            image: image.to_owned(),
+            interval,
+            step: step.clamp(1, 100),
+            layers: HashMap::new(),
+        }
+    }
+
+    /// Note the daemon sending `line`, about `layer` when it says which,
+    /// with the bytes done and in all as `progress` when it says those.
+    pub fn record<T: Debug>(
+        &mut self,
+        line: &T,
+        layer: Option<&str>,
+        status: Option<&str>,
+        progress: Option<(i64, i64)>,
+    ) {
+        event!(Level::TRACE, "{:?}", line);
+        let Some(status) = status else {
+            return;
+        };
+        let percent = progress
+            .filter(|(_, total)| *total > 0)
+            .map(|(current, total)| (current.max(0) as u64 * 100 / total as u64).min(100));
+        let key = layer.unwrap_or_default();
+        let now = Instant::now();
+        let percent_or_zero = percent.unwrap_or(0);
+        // known layers are updated in place so a busy stream allocates
+        // nothing for the lines it is not logged for
+        match self.layers.get_mut(key) {
+            None => {
+                self.layers.insert(
+                    key.to_owned(),
+                    Layer {
+                        status: status.to_owned(),
+                        logged_at: now,
+                        percent: percent_or_zero,
+                    },
+                );
+            }
+            Some(last) if last.status != status => {
+                last.status.clear();
+                last.status.push_str(status);
+                last.logged_at = now;
+                last.percent = percent_or_zero;
+            }
+            Some(last) => {
+                let due = now.duration_since(last.logged_at) >= self.interval
+                    || percent.is_some_and(|p| p / self.step > last.percent / self.step);
+                if !due {
+                    return;
+                }
+                last.logged_at = now;
+                last.percent = percent_or_zero;
+            }
+        }
+        // the daemon says no more about finished layers
+        if DONE.contains(&status) {
+            self.layers.remove(key);
+        }
+        match (layer, percent, progress) {
+            (Some(layer), Some(percent), Some((current, total))) => event!(
+                Level::INFO,
+                "{}: {} {} {}% ({} of {} bytes)",
+                self.image,
+                layer,
+                status,
+                percent,
+                current,
+                total
+            ),
+            (Some(layer), _, _) => event!(Level::INFO, "{}: {} {}", self.image, layer, status),
+            (None, Some(percent), _) => {
+                event!(Level::INFO, "{}: {} {}%", self.image, status, percent)
+            }
+            (None, None, _) => event!(Level::INFO, "{}: {}", self.image, status),
+        }
+    }
+}
@@ -0,0 +1,258 @@
+use crate::cache::hex_digest;
+use crate::cache::BlobCache;
+use crate::config;
+use crate::config::PolicyConfig;
+use crate::config::ProxyConfig;
+use crate::copy;
+use crate::jobs::Progress;
+use crate::registry::Manifest;
+use crate::registry::Registry;
+use crate::registry::DIGEST_HEADER;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
+use std::time::Instant;
+use tokio::sync::Mutex;
+use tokio_util::io::ReaderStream;
+use tracing::event;
+use tracing::Level;
+use warp::http::Method;
+use warp::http::Response;
+use warp::hyper::Body;
+use warp::hyper::StatusCode;
+
+/// Where pulled-through images are additionally pushed.
+pub struct Mirror {
+    pub registry: Registry,
+    pub repository: String,
+    /// Host of the proxied registry, part of the flat tags.
+    pub upstream_host: String,
+    /// In nested naming, the path upstream repositories are recreated under
+    /// instead of sharing `repository`.
+    pub nested: Option<String>,
+    pub policy: PolicyConfig,
+    pub parallelism: usize,
+}
+
+/// Caching proxy serving the OCI distribution API from an upstream registry.
+pub struct Proxy {
+    upstream: Registry,
+    cache: Arc<BlobCache>,
+    tag_ttl: Duration,
+    mirror: Option<Mirror>,
+    // "repo:tag" -> (digest, fetched at)
+    tags: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl Proxy {
+    pub fn new(
+        config: &ProxyConfig,
+        cache: Arc<BlobCache>,
+        upstream: Registry,
+        mirror: Option<Mirror>,
+    ) -> Proxy {
+        Proxy {
+            upstream,
+            cache,
+            tag_ttl: config.tag_ttl,
+            mirror,
+            tags: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Serve `/v2/<tail>`.
+    pub async fn handle(self: Arc<Self>, method: Method, tail: &str) -> Response<Body> {
+        let head = method == Method::HEAD;
+
+        if tail.is_empty() {
+            return Response::builder()
+                .header("docker-distribution-api-version", "registry/2.0")
+                .body(Body::from("{}"))
+                .unwrap();
+        }
+
+        let result = if let Some((name, reference)) = tail.rsplit_once("/manifests/") {
+            self.manifest(name, reference, head).await
+        } else if let Some((name, digest)) = tail.rsplit_once("/blobs/") {
+            self.blob(name, digest, head).await
+        } else {
+            return error(
+                StatusCode::NOT_FOUND,
+                "NAME_UNKNOWN",
+                "unsupported endpoint",
+            );
+        };
+
+        match result {
+            Ok(resp) => resp,
+            Err(e) => {
+                event!(Level::ERROR, "{:?}", e);
+                error(
+                    StatusCode::NOT_FOUND,
+                    "MANIFEST_UNKNOWN",
+                    &format!("{:#}", e),
+                )
+            }
+        }
+    }
+
+    async fn manifest(
+        self: &Arc<Self>,
+        name: &str,
+        reference: &str,
+        head: bool,
+    ) -> anyhow::Result<Response<Body>> {
+        let repo = self.upstream.repository(name);
+        let is_tag = !reference.contains(':');
+
+        // tags are mutable, only trust the cached digest for a while
+        let mut digest = reference.to_owned();
+        if is_tag {
+            let key = format!("{}:{}", repo, reference);
+            let cached = self.tags.lock().await.get(&key).cloned();
+            digest = match cached {
+                Some((digest, at)) if at.elapsed() < self.tag_ttl => digest,
+                _ => {
+                    let manifest = self.upstream.get_manifest(&repo, reference).await?;
+                    self.store_manifest(&manifest).await?;
+                    let digest = manifest.digest.clone();
+                    self.tags
+                        .lock()
+                        .await
+                        .insert(key, (digest.clone(), Instant::now()));
+
+                    if self.mirror.is_some() {
+                        let (proxy, repo, tag) = (self.clone(), repo.clone(), reference.to_owned());
+                        tokio::spawn(async move {
+                            if let Err(e) = proxy.push_to_mirror(&repo, &tag, &manifest).await {
+                                event!(Level::ERROR, "mirroring {}:{} failed: {:?}", repo, tag, e);
+                            }
+                        });
+                    }
+                    digest
+                }
+            };
+        }
+
+        let manifest = self.cached_manifest(&repo, &digest).await?;
+        let resp = Response::builder()
+            .header("content-type", &manifest.media_type)
+            .header("content-length", manifest.bytes.len())
+            .header(DIGEST_HEADER, &manifest.digest);
+        let body = if head {
+            Body::empty()
+        } else {
+            Body::from(manifest.bytes)
+        };
+
+        Ok(resp.body(body)?)
+    }
+
+    async fn blob(&self, name: &str, digest: &str, head: bool) -> anyhow::Result<Response<Body>> {
+        let repo = self.upstream.repository(name);
+        let path = self.cache.fetch(&self.upstream, &repo, digest).await?;
+
+        let file = tokio::fs::File::open(&path).await?;
+        let resp = Response::builder()
+            .header("content-type", "application/octet-stream")
+            .header("content-length", file.metadata().await?.len())
+            .header(DIGEST_HEADER, digest);
+        let body = if head {
+            Body::empty()
+        } else {
+            Body::wrap_stream(ReaderStream::new(file))
+        };
+
+        Ok(resp.body(body)?)
+    }
+
+    // Manifest by digest, fetched from upstream on a cache miss.
+    async fn cached_manifest(&self, repo: &str, digest: &str) -> anyhow::Result<Manifest> {
+        let path = self.manifest_path(digest)?;
+        if let (Ok(bytes), Ok(media_type)) = (
+            tokio::fs::read(&path).await,
+            tokio::fs::read_to_string(path.with_extension("type")).await,
+        ) {
+            return Ok(Manifest {
+                digest: digest.to_owned(),
+                media_type,
+                bytes,
+            });
+        }
+
+        let manifest = self.upstream.get_manifest(repo, digest).await?;
+        if manifest.digest != digest {
+            anyhow::bail!("upstream returned {} for {}", manifest.digest, digest);
+        }
+        self.store_manifest(&manifest).await?;
+        Ok(manifest)
+    }
+
+    async fn store_manifest(&self, manifest: &Manifest) -> anyhow::Result<()> {
+        let path = self.manifest_path(&manifest.digest)?;
+        tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+        tokio::fs::write(path.with_extension("type"), &manifest.media_type).await?;
+        tokio::fs::write(&path, &manifest.bytes).await?;
+        Ok(())
+    }
+
+    fn manifest_path(&self, digest: &str) -> anyhow::Result<PathBuf> {
+        Ok(self.cache.dir().join("manifests").join(hex_digest(digest)?))
+    }
+
+    // Copy a pulled-through image into the mirror repository.
+    async fn push_to_mirror(
+        &self,
+        repo: &str,
+        tag: &str,
+        manifest: &Manifest,
+    ) -> anyhow::Result<()> {
+        let mirror = self.mirror.as_ref().context("no mirror configured")?;
+
+        // same naming as the sync endpoint; no labels, so the mirror serves
+        // the same digests as upstream
+        let (dest_repo, dest_tag) = match &mirror.nested {
+            Some(root) => (format!("{}/{}", root, repo), tag.to_owned()),
+            None => (
+                mirror.repository.clone(),
+                config::flat_tag(&format!("{}/{}:{}", mirror.upstream_host, repo, tag)),
+            ),
+        };
+        copy::copy_image(
+            &self.cache,
+            &self.upstream,
+            repo,
+            &manifest.digest,
+            &mirror.registry,
+            &dest_repo,
+            &dest_tag,
+            &[],
+            &mirror.policy,
+            &copy::Rewrite::default(),
+            mirror.parallelism,
+            &Progress::none(),
+        )
+        .await?;
+        event!(
+            Level::INFO,
+            "mirrored {}:{} as {}:{}",
+            repo,
+            tag,
+            dest_repo,
+            dest_tag
+        );
+
+        Ok(())
+    }
+}
+
+fn error(status: StatusCode, code: &str, message: &str) -> Response<Body> {
+    let body = serde_json::json!({ "errors": [{ "code": code, "message": message }] });
+    Response::builder()
+        .status(status)
+        .header("content-type", "application/json")
+        .body(Body::from(body.to_string()))
+        .unwrap()
+}
@@ -0,0 +1,116 @@
+use crate::config::RateLimitConfig;
+use crate::events;
+use crate::metrics;
+use crate::registry::Registry;
+use reqwest::header::HeaderMap;
+use reqwest::StatusCode;
+use std::sync::Mutex;
+use std::time::Instant;
+use tracing::event;
+use tracing::Level;
+
+/// Last Docker Hub pull quota seen in a registry response.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Quota {
+    pub limit: Option<u64>,
+    pub remaining: Option<u64>,
+}
+
+static DOCKER_HUB: Mutex<Quota> = Mutex::new(Quota {
+    limit: None,
+    remaining: None,
+});
+
+pub fn docker_hub_quota() -> Quota {
+    *DOCKER_HUB.lock().unwrap()
+}
+
+/// Update the quota from a Docker Hub response.
+///
+/// Docker Hub answers with `ratelimit-limit: 100;w=21600` and
+/// `ratelimit-remaining: 76;w=21600`, or 429 once the quota is used up.
+pub fn record(headers: &HeaderMap, status: StatusCode) {
+    let mut quota = DOCKER_HUB.lock().unwrap();
+
+    if let Some(limit) = parse(headers, "ratelimit-limit") {
+        quota.limit = Some(limit);
+        metrics::DOCKER_HUB_RATELIMIT_LIMIT.set(limit as i64);
+    }
+    if let Some(remaining) = parse(headers, "ratelimit-remaining") {
+        quota.remaining = Some(remaining);
+    } else if status == StatusCode::TOO_MANY_REQUESTS {
+        quota.remaining = Some(0);
+    } else {
+        return;
+    }
+    metrics::DOCKER_HUB_RATELIMIT_REMAINING.set(quota.remaining.unwrap_or_default() as i64);
+}
+
+fn parse(headers: &HeaderMap, name: &str) -> Option<u64> {
+    let value = headers.get(name)?.to_str().ok()?;
+    value.split(';').next()?.trim().parse().ok()
+}
+
+/// Probe the quota once, returning whether pulls are still allowed.
+pub async fn has_quota(
+    config: &RateLimitConfig,
+    hub: &Registry,
+    repo: &str,
+    reference: &str,
+) -> bool {
+    if let Err(e) = hub.head_manifest(repo, reference).await {
+        event!(Level::DEBUG, "quota probe failed: {:?}", e);
+    }
+    !matches!(docker_hub_quota().remaining, Some(r) if r <= config.min_remaining)
+}
+
+/// Hold a pull of `repo:reference` from Docker Hub while the quota is nearly
+/// exhausted, instead of spending what is left on pulls bound to fail.
+///
+/// The quota is probed with a manifest HEAD request, which Docker Hub does
+/// not count as a pull. Gives up waiting after `max_wait` and lets the pull
+/// go ahead.
+pub async fn wait_for_quota(config: &RateLimitConfig, hub: &Registry, repo: &str, reference: &str) {
+    let started = Instant::now();
+
+    loop {
+        if let Err(e) = hub.head_manifest(repo, reference).await {
+            event!(Level::DEBUG, "quota probe failed: {:?}", e);
+        }
+
+        let remaining = match docker_hub_quota().remaining {
+            Some(r) if r <= config.min_remaining => r,
+            _ => return,
+        };
+        if started.elapsed() >= config.max_wait {
+            event!(
+                Level::WARN,
+                "Docker Hub quota still at {}, pulling anyway",
+                remaining
+            );
+            return;
+        }
+
+        metrics::RATELIMIT_DELAYS.inc();
+        // once per held pull, not per probe
+        if started.elapsed() < config.retry_interval {
+            events::publish(
+                "rate_limited",
+                serde_json::json!({
+                    "registry": "docker.io",
+                    "remaining": remaining,
+                    "image": format!("{}:{}", repo, reference),
+                }),
+            );
+        }
+        event!(
+            Level::WARN,
+            "Docker Hub quota at {}, delaying pull of {}:{} for {:?}",
+            remaining,
+            repo,
+            reference,
+            config.retry_interval
+        );
+        tokio::time::sleep(config.retry_interval).await;
+    }
+}
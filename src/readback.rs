@@ -0,0 +1,103 @@
+use crate::config::ReadBack;
+use crate::jobs::Progress;
+use crate::registry::Descriptor;
+use crate::registry::Registry;
+use futures::stream::StreamExt;
+use sha2::Digest;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use tracing::event;
+use tracing::Level;
+
+/// Read `repo:tag` back from `dst` after pushing it and check that the
+/// registry serves what was pushed: the manifest with the `pushed` digest
+/// when known, every manifest of an index, and as `depth` says the configs
+/// and the smallest layer of each image, or every blob, with their digests
+/// and sizes.
+pub async fn check(
+    dst: &Registry,
+    repo: &str,
+    tag: &str,
+    pushed: Option<&str>,
+    depth: ReadBack,
+    progress: &Progress,
+) -> anyhow::Result<()> {
+    let manifest = dst.get_manifest(repo, tag).await?;
+    if let Some(pushed) = pushed.filter(|p| *p != manifest.digest) {
+        anyhow::bail!(
+            "{}:{} is served as {}, {} was pushed",
+            repo,
+            tag,
+            manifest.digest,
+            pushed
+        );
+    }
+
+    // children are fetched by digest, which checks them
+    let mut images = Vec::new();
+    if manifest.is_index() {
+        for child in manifest.parse()?.manifests {
+            images.push(dst.get_manifest(repo, &child.digest).await?.parse()?);
+        }
+    } else {
+        images.push(manifest.parse()?);
+    }
+    if depth == ReadBack::Manifest {
+        return Ok(());
+    }
+
+    let mut blobs = BTreeMap::new();
+    for image in &images {
+        if let Some(config) = &image.config {
+            blobs.insert(config.digest.clone(), config.clone());
+        }
+        let layers = image.layers.iter();
+        match depth {
+            ReadBack::Full => blobs.extend(layers.map(|l| (l.digest.clone(), l.clone()))),
+            _ => {
+                if let Some(layer) = layers.min_by_key(|l| l.size) {
+                    blobs.insert(layer.digest.clone(), layer.clone());
+                }
+            }
+        }
+    }
+    let total = blobs.len() as u64;
+    for (done, blob) in blobs.values().enumerate() {
+        progress.update("readback", done as u64, total);
+        check_blob(dst, repo, blob).await?;
+    }
+    progress.update("readback", total, total);
+    event!(
+        Level::INFO,
+        "{}:{} read back with {} manifests and {} blobs",
+        repo,
+        tag,
+        images.len(),
+        total
+    );
+    Ok(())
+}
+
+async fn check_blob(dst: &Registry, repo: &str, blob: &Descriptor) -> anyhow::Result<()> {
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    let mut stream = dst.get_blob(repo, &blob.digest).await?.bytes_stream();
+    while let Some(chunk) = stream.next().await {
+        let chunk = chunk?;
+        size += chunk.len() as u64;
+        hasher.update(&chunk);
+    }
+    let actual = format!("sha256:{}", hex::encode(hasher.finalize()));
+    if actual != blob.digest {
+        anyhow::bail!("blob {} is served as {}", blob.digest, actual);
+    }
+    if size != blob.size {
+        anyhow::bail!(
+            "blob {} is served with {} bytes, its manifest says {}",
+            blob.digest,
+            size,
+            blob.size
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,75 @@
+use crate::metrics;
+use crate::registry::Registry;
+use chrono::DateTime;
+use chrono::Utc;
+use serde::Serialize;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+use tracing::event;
+use tracing::Level;
+
+/// Outcome of the last check of the destination registry.
+#[derive(Serialize, Debug, Clone)]
+pub struct Destination {
+    pub registry: String,
+    pub up: bool,
+    pub checked_at: DateTime<Utc>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+// a destination slower than this to answer counts as down
+const CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+// `None` until the first check finished
+static DESTINATION: Mutex<Option<Destination>> = Mutex::new(None);
+
+/// The last check of the destination, `None` before the first finished.
+pub fn destination() -> Option<Destination> {
+    DESTINATION.lock().unwrap().clone()
+}
+
+/// Whether syncs can be sent here: the destination answered its last check
+/// and took our credentials. Without checks the destination counts as up.
+pub fn is_ready(checking: bool) -> bool {
+    !checking || destination().is_some_and(|d| d.up)
+}
+
+/// Check every `interval` that the destination registry answers on `/v2/`
+/// and accepts the credentials we push `repository` with, for the readiness
+/// endpoint and the `imagesync_destination_up` gauge. Changes are logged.
+pub async fn watch(dest: Registry, repository: String, interval: Duration) {
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        let started = Instant::now();
+        let checked = tokio::time::timeout(CHECK_TIMEOUT, dest.ping(&repository))
+            .await
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("no answer within {:?}", CHECK_TIMEOUT)));
+        metrics::DESTINATION_CHECK_SECONDS.set(started.elapsed().as_secs_f64());
+        metrics::DESTINATION_UP.set(checked.is_ok() as i64);
+        let error = checked.err().map(|e| format!("{:#}", e));
+        let was_up = destination().map(|d| d.up);
+        match (&error, was_up) {
+            (Some(e), Some(true) | None) => {
+                event!(
+                    Level::WARN,
+                    "destination {} is not ready: {}",
+                    dest.base(),
+                    e
+                )
+            }
+            (None, Some(false)) => {
+                event!(Level::INFO, "destination {} is ready again", dest.base())
+            }
+            _ => {}
+        }
+        *DESTINATION.lock().unwrap() = Some(Destination {
+            registry: dest.base().to_owned(),
+            up: error.is_none(),
+            checked_at: Utc::now(),
+            error,
+        });
+    }
+}
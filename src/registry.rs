@@ -0,0 +1,1125 @@
+use crate::auth;
+use crate::auth::Token;
+use crate::auth::DEFAULT_TOKEN_LIFETIME;
+use crate::ghcr;
+use crate::http;
+use crate::metrics;
+use crate::ratelimit;
+use crate::tracecontext;
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use reqwest::header::HeaderMap;
+use reqwest::header::ACCEPT;
+use reqwest::header::AUTHORIZATION;
+use reqwest::header::CONTENT_LENGTH;
+use reqwest::header::CONTENT_RANGE;
+use reqwest::header::CONTENT_TYPE;
+use reqwest::header::LOCATION;
+use reqwest::header::RANGE;
+use reqwest::header::WWW_AUTHENTICATE;
+use reqwest::Method;
+use reqwest::RequestBuilder;
+use reqwest::Response;
+use reqwest::StatusCode;
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::SeekFrom;
+use std::path::Path;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::io::AsyncSeekExt;
+use tokio_util::io::ReaderStream;
+use tracing::event;
+use tracing::Level;
+
+/// Manifest media types we are able to copy, most specific first.
+pub const MANIFEST_TYPES: &[&str] = &[
+    "application/vnd.oci.image.index.v1+json",
+    "application/vnd.docker.distribution.manifest.list.v2+json",
+    "application/vnd.oci.image.manifest.v1+json",
+    "application/vnd.docker.distribution.manifest.v2+json",
+];
+
+pub const DIGEST_HEADER: &str = "docker-content-digest";
+
+/// A manifest exactly as served by a registry.
+#[derive(Debug, Clone)]
+pub struct Manifest {
+    pub digest: String,
+    pub media_type: String,
+    pub bytes: Vec<u8>,
+}
+
+impl Manifest {
+    pub fn new(media_type: String, bytes: Vec<u8>) -> Manifest {
+        Manifest {
+            digest: format!("sha256:{}", hex::encode(Sha256::digest(&bytes))),
+            media_type,
+            bytes,
+        }
+    }
+
+    pub fn parse(&self) -> anyhow::Result<ManifestBody> {
+        Ok(serde_json::from_slice(&self.bytes)?)
+    }
+
+    pub fn is_index(&self) -> bool {
+        self.media_type.contains("index") || self.media_type.contains("manifest.list")
+    }
+}
+
+/// The parts of image manifests and indexes we care about.
+#[derive(Deserialize, Debug, Clone, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ManifestBody {
+    pub config: Option<Descriptor>,
+    #[serde(default)]
+    pub layers: Vec<Descriptor>,
+    #[serde(default)]
+    pub manifests: Vec<Descriptor>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Descriptor {
+    pub digest: String,
+    #[serde(default)]
+    pub size: u64,
+    /// Platform of an image in an index.
+    pub platform: Option<Platform>,
+}
+
+/// Platform an image is built for, written `os/architecture[/variant]`.
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+pub struct Platform {
+    pub os: String,
+    pub architecture: String,
+    pub variant: Option<String>,
+}
+
+impl Platform {
+    /// Whether an image for `image` is one of this platform. Without a
+    /// variant here, any variant is.
+    pub fn matches(&self, image: &Platform) -> bool {
+        self.os == image.os
+            && self.architecture == image.architecture
+            && (self.variant.is_none() || self.variant == image.variant)
+    }
+}
+
+impl std::str::FromStr for Platform {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Platform> {
+        let parts: Vec<&str> = s.split('/').collect();
+        match parts.as_slice() {
+            [os, architecture] | [os, architecture, _]
+                if !os.is_empty() && !architecture.is_empty() =>
+            {
+                Ok(Platform {
+                    os: os.to_string(),
+                    architecture: architecture.to_string(),
+                    variant: parts.get(2).map(|v| v.to_string()),
+                })
+            }
+            _ => anyhow::bail!("invalid platform {}, expected os/architecture[/variant]", s),
+        }
+    }
+}
+
+impl std::fmt::Display for Platform {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.os, self.architecture)?;
+        if let Some(variant) = &self.variant {
+            write!(f, "/{}", variant)?;
+        }
+        Ok(())
+    }
+}
+
+/// A comma separated list of platforms, such as `linux/amd64,linux/arm64`.
+pub fn parse_platforms(list: &str) -> anyhow::Result<Vec<Platform>> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|p| !p.is_empty())
+        .map(str::parse)
+        .collect()
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    token: Option<String>,
+    access_token: Option<String>,
+    expires_in: Option<u64>,
+}
+
+/// Minimal client for the OCI distribution API of a single registry.
+pub struct Registry {
+    client: reqwest::Client,
+    base: String,
+    credentials: Option<(String, String)>,
+    // blobs larger than this are uploaded in resumable chunks
+    chunk_size: Option<u64>,
+    // how many times a blob upload whose session the registry lost is
+    // started over
+    session_retries: u32,
+    // follow redirects ourselves, keeping the authorization
+    credentials_on_redirect: bool,
+}
+
+/// What a registry says about a manifest without sending it.
+#[derive(Debug, Clone)]
+pub struct ManifestHead {
+    pub digest: Option<String>,
+    /// Bytes of the manifest, not of the image.
+    pub size: Option<u64>,
+}
+
+/// How many redirects in a row are followed with our authorization.
+const MAX_REDIRECTS: usize = 10;
+
+/// How many times an interrupted chunked upload is resumed before giving up.
+const UPLOAD_RESUME_ATTEMPTS: u32 = 5;
+
+/// How many times an upload refused for an expired token is sent again
+/// with a new one.
+const UPLOAD_AUTH_ATTEMPTS: u32 = 2;
+
+impl Registry {
+    /// `host` is a registry host such as `docker.io` or `quay.io`, or a full
+    /// `http(s)://` URL.
+    pub fn new(host: &str, credentials: Option<(String, String)>) -> Registry {
+        let base = base_url(host);
+        let credentials_on_redirect =
+            http::registry_config(host).is_some_and(|r| r.credentials_on_redirect);
+        Registry {
+            client: http::client(&base),
+            base,
+            credentials,
+            chunk_size: None,
+            session_retries: 0,
+            credentials_on_redirect,
+        }
+    }
+
+    /// Upload blobs larger than `size` bytes in chunks, resuming from the
+    /// last acknowledged offset when a chunk fails.
+    pub fn with_chunk_size(mut self, size: Option<u64>) -> Registry {
+        self.chunk_size = size.filter(|s| *s > 0);
+        self
+    }
+
+    /// Start a blob upload over in a new session up to `retries` times when
+    /// the registry no longer knows the one it was sent in.
+    pub fn with_session_retries(mut self, retries: u32) -> Registry {
+        self.session_retries = retries;
+        self
+    }
+
+    /// Base URL, identifying the registry.
+    pub fn base(&self) -> &str {
+        &self.base
+    }
+
+    pub fn is_docker_hub(&self) -> bool {
+        self.base == "https://registry-1.docker.io"
+    }
+
+    /// Repository name as the registry expects it, adding Docker Hub's
+    /// implicit `library/` namespace, and in lowercase for GHCR, where
+    /// names follow GitHub owners spelled with capitals.
+    pub fn repository(&self, name: &str) -> String {
+        if self.is_docker_hub() && !name.contains('/') {
+            format!("library/{}", name)
+        } else if ghcr::is_ghcr(&self.base) {
+            name.to_lowercase()
+        } else {
+            name.to_owned()
+        }
+    }
+
+    pub async fn get_manifest(&self, repo: &str, reference: &str) -> anyhow::Result<Manifest> {
+        let url = format!("{}/v2/{}/manifests/{}", self.base, repo, reference);
+        let resp = self
+            .send(&[scope(repo, "pull")], || {
+                self.client
+                    .get(&url)
+                    .header(ACCEPT, MANIFEST_TYPES.join(", "))
+            })
+            .await?;
+        let resp = check(resp, &url).await?;
+
+        let media_type = header(resp.headers(), CONTENT_TYPE.as_str()).unwrap_or_default();
+        let bytes = resp.bytes().await?.to_vec();
+
+        // what was asked for by digest must be what arrived
+        let manifest = Manifest::new(media_type, bytes);
+        if is_digest(reference) && manifest.digest != reference {
+            anyhow::bail!(
+                "manifest {} of {} has digest {}",
+                reference,
+                repo,
+                manifest.digest
+            );
+        }
+        Ok(manifest)
+    }
+
+    /// Digest of a manifest, or `None` when it does not exist.
+    pub async fn head_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+    ) -> anyhow::Result<Option<String>> {
+        Ok(self
+            .stat_manifest(repo, reference)
+            .await?
+            .and_then(|head| head.digest))
+    }
+
+    /// Digest and size of a manifest as a HEAD request reports them, or
+    /// `None` when it does not exist.
+    pub async fn stat_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+    ) -> anyhow::Result<Option<ManifestHead>> {
+        let url = format!("{}/v2/{}/manifests/{}", self.base, repo, reference);
+        let resp = self
+            .send(&[scope(repo, "pull")], || {
+                self.client
+                    .head(&url)
+                    .header(ACCEPT, MANIFEST_TYPES.join(", "))
+            })
+            .await?;
+        if resp.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let resp = check(resp, &url).await?;
+
+        Ok(Some(ManifestHead {
+            digest: header(resp.headers(), DIGEST_HEADER),
+            size: header(resp.headers(), CONTENT_LENGTH.as_str()).and_then(|l| l.parse().ok()),
+        }))
+    }
+
+    /// Check that the registry answers on `/v2/` and takes our credentials
+    /// for pushing to `repo`.
+    pub async fn ping(&self, repo: &str) -> anyhow::Result<()> {
+        let url = format!("{}/v2/", self.base);
+        let resp = self
+            .send(&[scope(repo, "pull,push")], || self.client.get(&url))
+            .await?;
+        check(resp, &url).await?;
+
+        Ok(())
+    }
+
+    /// Start downloading a blob; the caller consumes the body.
+    pub async fn get_blob(&self, repo: &str, digest: &str) -> anyhow::Result<Response> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base, repo, digest);
+        let resp = self
+            .send(&[scope(repo, "pull")], || self.client.get(&url))
+            .await?;
+        check(resp, &url).await
+    }
+
+    pub async fn has_blob(&self, repo: &str, digest: &str) -> anyhow::Result<bool> {
+        let url = format!("{}/v2/{}/blobs/{}", self.base, repo, digest);
+        let resp = self
+            .send(&[scope(repo, "pull,push")], || self.client.head(&url))
+            .await?;
+        match resp.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => check(resp, &url).await.map(|_| true),
+        }
+    }
+
+    /// Ask the registry to mount a blob it holds under another repository,
+    /// returning whether it did.
+    pub async fn mount_blob(&self, repo: &str, digest: &str, from: &str) -> anyhow::Result<bool> {
+        let url = format!(
+            "{}/v2/{}/blobs/uploads/?mount={}&from={}",
+            self.base, repo, digest, from
+        );
+        let scopes = [scope(repo, "pull,push"), scope(from, "pull")];
+        let resp = self.send(&scopes, || self.client.post(&url)).await?;
+        let resp = check(resp, &url).await?;
+        if resp.status() == StatusCode::CREATED {
+            event!(Level::INFO, "blob {} mounted from {}", digest, from);
+            return Ok(true);
+        }
+
+        // the registry opened a regular upload instead, we don't need it
+        if let Ok(location) = self.upload_location(&resp) {
+            let _ = self.send(&scopes, || self.client.delete(&location)).await;
+        }
+        Ok(false)
+    }
+
+    /// Upload a blob from a local file.
+    pub async fn push_blob(&self, repo: &str, digest: &str, path: &Path) -> anyhow::Result<()> {
+        let length = tokio::fs::metadata(path)
+            .await
+            .with_context(|| format!("failed to open {}", path.display()))?
+            .len();
+
+        let mut restarts = 0;
+        loop {
+            let pushed = match self.chunk_size {
+                Some(chunk_size) if length > chunk_size => {
+                    self.push_blob_chunked(repo, digest, path, length, chunk_size)
+                        .await
+                }
+                _ => self.push_blob_monolithic(repo, digest, path, length).await,
+            };
+            match pushed {
+                Err(e) if restarts < self.session_retries && is_upload_unknown(&e) => {
+                    restarts += 1;
+                    metrics::UPLOAD_SESSION_RESTARTS.inc();
+                    event!(
+                        Level::WARN,
+                        "upload session of {} was lost ({:#}), starting it over ({}/{})",
+                        digest,
+                        e,
+                        restarts,
+                        self.session_retries
+                    );
+                    tokio::time::sleep(Duration::from_secs(1 << restarts.min(5))).await;
+                }
+                pushed => return pushed,
+            }
+        }
+    }
+
+    async fn start_upload(&self, repo: &str) -> anyhow::Result<String> {
+        let url = format!("{}/v2/{}/blobs/uploads/", self.base, repo);
+        let resp = self
+            .send(&[scope(repo, "pull,push")], || self.client.post(&url))
+            .await?;
+        let resp = check(resp, &url).await?;
+        self.upload_location(&resp)
+    }
+
+    async fn push_blob_monolithic(
+        &self,
+        repo: &str,
+        digest: &str,
+        path: &Path,
+        length: u64,
+    ) -> anyhow::Result<()> {
+        let scopes = [scope(repo, "pull,push")];
+        let mut refreshed = 0;
+        loop {
+            let location = self.start_upload(repo).await?;
+            let file = tokio::fs::File::open(path).await?;
+            let url = with_digest(&location, digest);
+
+            let token = self.token(&scopes).await;
+            let mut req = tracecontext::inject(self.client.put(&url))
+                .header(CONTENT_TYPE, "application/octet-stream")
+                .header(CONTENT_LENGTH, length)
+                .body(reqwest::Body::wrap_stream(ReaderStream::new(file)));
+            if let Some(token) = token {
+                req = req.header(AUTHORIZATION, token);
+            }
+            let resp = req.send().await?;
+
+            // a token can run out while a large blob is streamed, the body
+            // is not kept so it is sent again in a new session
+            if resp.status() == StatusCode::UNAUTHORIZED && refreshed < UPLOAD_AUTH_ATTEMPTS {
+                refreshed += 1;
+                event!(
+                    Level::WARN,
+                    "upload of {} was refused, re-authenticating and uploading again",
+                    digest
+                );
+                self.reauthenticate(&resp, &scopes).await?;
+                continue;
+            }
+            check(resp, &url).await?;
+            return Ok(());
+        }
+    }
+
+    // PATCH the blob chunk by chunk; when a chunk fails ask the registry how
+    // much it already has and carry on from there.
+    async fn push_blob_chunked(
+        &self,
+        repo: &str,
+        digest: &str,
+        path: &Path,
+        length: u64,
+        chunk_size: u64,
+    ) -> anyhow::Result<()> {
+        let scopes = [scope(repo, "pull,push")];
+        let mut file = tokio::fs::File::open(path).await?;
+        let mut location = self.start_upload(repo).await?;
+        let mut offset = 0;
+        let mut failures = 0;
+
+        while offset < length {
+            let end = (offset + chunk_size).min(length);
+            let mut chunk = vec![0; (end - offset) as usize];
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.read_exact(&mut chunk).await?;
+
+            let resp = self
+                .send(&scopes, || {
+                    self.client
+                        .patch(&location)
+                        .header(CONTENT_TYPE, "application/octet-stream")
+                        .header(CONTENT_RANGE, format!("{}-{}", offset, end - 1))
+                        .header(CONTENT_LENGTH, chunk.len())
+                        .body(chunk.clone())
+                })
+                .await;
+
+            match resp {
+                Ok(resp) if resp.status().is_success() => {
+                    location = self.upload_location(&resp)?;
+                    offset = end;
+                }
+                result => {
+                    failures += 1;
+                    let reason = match result {
+                        Ok(resp) => format!("status {}", resp.status()),
+                        Err(e) => format!("{:#}", e),
+                    };
+                    if failures > UPLOAD_RESUME_ATTEMPTS {
+                        anyhow::bail!(
+                            "upload of {} failed at offset {}: {}",
+                            digest,
+                            offset,
+                            reason
+                        );
+                    }
+                    event!(
+                        Level::WARN,
+                        "chunk {}-{} of {} failed ({}), resuming",
+                        offset,
+                        end - 1,
+                        digest,
+                        reason
+                    );
+                    tokio::time::sleep(Duration::from_secs(1 << failures.min(5))).await;
+
+                    match self.upload_offset(repo, &location).await {
+                        Ok(Some(acknowledged)) => offset = acknowledged,
+                        // the session is gone, start over
+                        _ => {
+                            location = self.start_upload(repo).await?;
+                            offset = 0;
+                        }
+                    }
+                }
+            }
+        }
+
+        let url = with_digest(&location, digest);
+        let resp = self
+            .send(&scopes, || self.client.put(&url).header(CONTENT_LENGTH, 0))
+            .await?;
+        check(resp, &url).await?;
+
+        Ok(())
+    }
+
+    // Bytes the registry holds for an upload session, `None` if it is gone.
+    async fn upload_offset(&self, repo: &str, location: &str) -> anyhow::Result<Option<u64>> {
+        let resp = self
+            .send(&[scope(repo, "pull,push")], || self.client.get(location))
+            .await?;
+        if !resp.status().is_success() {
+            return Ok(None);
+        }
+
+        // "Range: 0-<last byte>", where "0-0" is also sent for an empty upload
+        Ok(match header(resp.headers(), RANGE.as_str()) {
+            Some(range) if range == "0-0" => Some(0),
+            Some(range) => range
+                .rsplit('-')
+                .next()
+                .and_then(|last| last.parse::<u64>().ok())
+                .map(|last| last + 1),
+            None => Some(0),
+        })
+    }
+
+    pub async fn put_manifest(
+        &self,
+        repo: &str,
+        reference: &str,
+        manifest: &Manifest,
+    ) -> anyhow::Result<()> {
+        let url = format!("{}/v2/{}/manifests/{}", self.base, repo, reference);
+        let resp = self
+            .send(&[scope(repo, "pull,push")], || {
+                self.client
+                    .put(&url)
+                    .header(CONTENT_TYPE, &manifest.media_type)
+                    .body(manifest.bytes.clone())
+            })
+            .await?;
+        check(resp, &url).await?;
+
+        Ok(())
+    }
+
+    /// Every tag of a repository, following pagination.
+    pub async fn list_tags(&self, repo: &str) -> anyhow::Result<Vec<String>> {
+        #[derive(Deserialize)]
+        struct TagList {
+            tags: Option<Vec<String>>,
+        }
+
+        let mut tags = Vec::new();
+        let mut url = format!("{}/v2/{}/tags/list?n=1000", self.base, repo);
+        loop {
+            let resp = self
+                .send(&[scope(repo, "pull")], || self.client.get(&url))
+                .await?;
+            let resp = check(resp, &url).await?;
+
+            // Link: </v2/<repo>/tags/list?last=..&n=..>; rel="next"
+            let next = header(resp.headers(), "link").and_then(|link| {
+                let (target, rel) = link.split_once(';')?;
+                rel.contains("next").then(|| {
+                    target
+                        .trim()
+                        .trim_start_matches('<')
+                        .trim_end_matches('>')
+                        .to_owned()
+                })
+            });
+            tags.extend(resp.json::<TagList>().await?.tags.unwrap_or_default());
+
+            match next {
+                Some(next) if next.starts_with("http") => url = next,
+                Some(next) => url = format!("{}{}", self.base, next),
+                None => return Ok(tags),
+            }
+        }
+    }
+
+    /// Delete a manifest, and with it every tag pointing at it.
+    pub async fn delete_manifest(&self, repo: &str, digest: &str) -> anyhow::Result<()> {
+        let url = format!("{}/v2/{}/manifests/{}", self.base, repo, digest);
+        let resp = self
+            .send(&[scope(repo, "delete")], || self.client.delete(&url))
+            .await?;
+        check(resp, &url).await?;
+
+        Ok(())
+    }
+
+    // Send a request, authenticating and retrying once if challenged.
+    async fn send<F>(&self, scopes: &[String], build: F) -> anyhow::Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        let mut req = tracecontext::inject(build());
+        let token = self.token(scopes).await;
+        if let Some(token) = &token {
+            req = req.header(AUTHORIZATION, token);
+        }
+        let resp = req.send().await?;
+        if self.is_docker_hub() {
+            ratelimit::record(resp.headers(), resp.status());
+        }
+        if resp.status() != StatusCode::UNAUTHORIZED {
+            return self.follow(resp, &build, token).await;
+        }
+
+        let token = self.reauthenticate(&resp, scopes).await?;
+        let mut authorization = Some(token.value.clone());
+        let mut resp = tracecontext::inject(build())
+            .header(AUTHORIZATION, token.value)
+            .send()
+            .await?;
+        // refused basic credentials; refused token logins already fell back
+        // in `authenticate`
+        let refused =
+            resp.status() == StatusCode::UNAUTHORIZED || resp.status() == StatusCode::FORBIDDEN;
+        if refused && token.expires.is_none() && auth::falls_back(scopes) {
+            self.falling_back();
+            authorization = None;
+            resp = tracecontext::inject(build()).send().await?;
+        }
+        if self.is_docker_hub() {
+            ratelimit::record(resp.headers(), resp.status());
+        }
+        self.follow(resp, &build, authorization).await
+    }
+
+    // With `credentials_on_redirect` the client leaves redirects to us:
+    // follow those of GET and HEAD requests, sending `authorization` to
+    // wherever they lead, since reqwest drops it for other hosts.
+    async fn follow<F>(
+        &self,
+        mut resp: Response,
+        build: &F,
+        authorization: Option<String>,
+    ) -> anyhow::Result<Response>
+    where
+        F: Fn() -> RequestBuilder,
+    {
+        if !self.credentials_on_redirect || !resp.status().is_redirection() {
+            return Ok(resp);
+        }
+        let method = build().build()?.method().clone();
+        if method != Method::GET && method != Method::HEAD {
+            return Ok(resp);
+        }
+        for _ in 0..MAX_REDIRECTS {
+            let Some(location) = header(resp.headers(), LOCATION.as_str()) else {
+                return Ok(resp);
+            };
+            let url = resp.url().join(&location)?;
+            let mut req = tracecontext::inject(self.client.request(method.clone(), url));
+            if let Some(authorization) = &authorization {
+                req = req.header(AUTHORIZATION, authorization);
+            }
+            resp = req.send().await?;
+            if !resp.status().is_redirection() {
+                return Ok(resp);
+            }
+        }
+        anyhow::bail!("more than {} redirects from {}", MAX_REDIRECTS, self.base)
+    }
+
+    // Note that the registry refused our credentials for a pull, which goes
+    // on anonymously.
+    fn falling_back(&self) {
+        event!(
+            Level::WARN,
+            "{} refused the credentials of {}, pulling anonymously",
+            self.base,
+            self.credentials.as_ref().map_or("-", |(u, _)| u.as_str())
+        );
+        metrics::ANONYMOUS_FALLBACKS
+            .with_label_values(&[&self.base])
+            .inc();
+    }
+
+    // Answer the challenge of a 401 response with a new token for `scopes`,
+    // replacing the cached one. Scopes the challenge names that we did not
+    // ask for are asked for too, the registry wants them for this request.
+    async fn reauthenticate(&self, resp: &Response, scopes: &[String]) -> anyhow::Result<Token> {
+        let challenge = header(resp.headers(), WWW_AUTHENTICATE.as_str()).unwrap_or_default();
+        auth::set_challenge(&self.base, &challenge);
+        let mut wanted = scopes.to_vec();
+        if let Some(scope) = parse_challenge(&challenge).get("scope") {
+            for scope in scope.split(' ') {
+                if !scope.is_empty() && !wanted.iter().any(|s| s == scope) {
+                    wanted.push(scope.to_owned());
+                }
+            }
+        }
+        let token = self.authenticate(&challenge, &wanted).await?;
+        auth::put(self.token_key(scopes), token.clone());
+        Ok(token)
+    }
+
+    // Authorization for `scopes`, from the shared cache when still fresh.
+    // Tokens close to expiry are replaced before they are used, so long
+    // uploads sending many requests never present an expired token.
+    async fn token(&self, scopes: &[String]) -> Option<String> {
+        let key = self.token_key(scopes);
+        let cached = auth::get(&key);
+        if let Some(token) = &cached {
+            if !token.needs_refresh() {
+                return Some(token.value.clone());
+            }
+        }
+
+        // we only know how to get a token once the registry challenged us
+        let challenge = auth::challenge(&self.base)?;
+        match self.authenticate(&challenge, scopes).await {
+            Ok(token) => {
+                auth::put(key, token.clone());
+                Some(token.value)
+            }
+            Err(e) => {
+                event!(Level::WARN, "token refresh failed: {:?}", e);
+                cached.map(|t| t.value)
+            }
+        }
+    }
+
+    fn token_key(&self, scopes: &[String]) -> String {
+        let username = self.credentials.as_ref().map(|(u, _)| u.as_str());
+        auth::key(&self.base, username, scopes)
+    }
+
+    // Answer a WWW-Authenticate challenge.
+    async fn authenticate(&self, challenge: &str, scopes: &[String]) -> anyhow::Result<Token> {
+        if challenge.to_ascii_lowercase().starts_with("basic") {
+            let (username, password) = self
+                .credentials
+                .as_ref()
+                .context("registry requires credentials")?;
+            return Ok(Token::basic(format!(
+                "Basic {}",
+                BASE64.encode(format!("{}:{}", username, password))
+            )));
+        }
+
+        let params = parse_challenge(challenge);
+        let realm = params
+            .get("realm")
+            .with_context(|| format!("unsupported auth challenge: {}", challenge))?;
+
+        let mut query: Vec<(&str, &str)> = scopes.iter().map(|s| ("scope", s.as_str())).collect();
+        if let Some(service) = params.get("service") {
+            query.push(("service", service));
+        }
+        let req = self
+            .client
+            .request(Method::GET, realm.as_str())
+            .query(&query);
+        let resp = match &self.credentials {
+            Some((username, password)) => {
+                let resp = req
+                    .try_clone()
+                    .context("token request cannot be repeated")?
+                    .basic_auth(username, Some(password))
+                    .send()
+                    .await?;
+                let refused = resp.status() == StatusCode::UNAUTHORIZED
+                    || resp.status() == StatusCode::FORBIDDEN;
+                if refused && auth::falls_back(scopes) {
+                    self.falling_back();
+                    check(req.send().await?, realm).await?
+                } else {
+                    checked(resp, realm, Some(password)).await?
+                }
+            }
+            None => check(req.send().await?, realm).await?,
+        };
+        let token: TokenResponse = resp.json().await?;
+        let lifetime = token
+            .expires_in
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_TOKEN_LIFETIME);
+        let token = token
+            .token
+            .or(token.access_token)
+            .context("token response has no token")?;
+
+        Ok(Token::bearer(format!("Bearer {}", token), lifetime))
+    }
+
+    fn upload_location(&self, resp: &Response) -> anyhow::Result<String> {
+        let location =
+            header(resp.headers(), LOCATION.as_str()).context("upload has no location")?;
+        if location.starts_with('/') {
+            Ok(format!("{}{}", self.base, location))
+        } else {
+            Ok(location)
+        }
+    }
+}
+
+/// Split `[host/]name[:tag|@digest]` into registry host, repository and
+/// tag or digest, defaulting to Docker Hub and `latest`.
+pub fn parse_reference(image: &str) -> (String, String, String) {
+    let (host, rest) = match image.split_once('/') {
+        Some((first, rest)) if is_qualified(image) => (first.to_owned(), rest),
+        _ => ("docker.io".to_owned(), image),
+    };
+
+    let (name, reference) = if let Some((name, digest)) = rest.split_once('@') {
+        // a tag alongside the digest only names the image
+        let name = match name.rsplit_once(':') {
+            Some((untagged, tag)) if !tag.contains('/') => untagged,
+            _ => name,
+        };
+        (name, digest.to_owned())
+    } else {
+        match rest.rsplit_once(':') {
+            Some((name, tag)) => (name, tag.to_owned()),
+            None => (rest, "latest".to_owned()),
+        }
+    };
+
+    (host, name.to_owned(), reference)
+}
+
+/// `host/repository` of `image`, with Docker Hub's implicit namespace, the
+/// same whichever way the image is written.
+pub fn repository_name(image: &str) -> String {
+    let (host, name, _) = parse_reference(image);
+    if host == "docker.io" && !name.contains('/') {
+        format!("{}/library/{}", host, name)
+    } else {
+        format!("{}/{}", host, name)
+    }
+}
+
+/// Whether `error` is a registry answering that what was asked for does not
+/// exist.
+pub fn is_not_found(error: &anyhow::Error) -> bool {
+    error.chain().any(|e| {
+        e.downcast_ref::<StatusError>()
+            .is_some_and(|e| e.status == StatusCode::NOT_FOUND)
+    })
+}
+
+/// Whether `image` gives a tag or digest rather than leaving it to the
+/// default.
+pub fn has_reference(image: &str) -> bool {
+    let name = image.rsplit('/').next().unwrap_or(image);
+    image.contains('@') || name.contains(':')
+}
+
+/// `name:tag@digest` split into `name:tag` and the digest, for references
+/// that give both. The digest decides what is pulled, the tag only names it.
+pub fn tagged_digest(image: &str) -> Option<(String, String)> {
+    let (name, digest) = image.split_once('@')?;
+    let (untagged, tag) = name.rsplit_once(':')?;
+    if tag.contains('/') || tag.is_empty() || untagged.is_empty() {
+        return None;
+    }
+    Some((name.to_owned(), digest.to_owned()))
+}
+
+/// Whether `image` names its registry host rather than relying on the
+/// Docker Hub default.
+pub fn is_qualified(image: &str) -> bool {
+    match image.split_once('/') {
+        Some((first, _)) => first.contains('.') || first.contains(':') || first == "localhost",
+        None => false,
+    }
+}
+
+/// URL the API of the registry at `host` is under: Docker Hub's API host
+/// for `docker.io`, plain HTTP for registries configured so.
+pub fn base_url(host: &str) -> String {
+    if host.starts_with("http://") || host.starts_with("https://") {
+        host.trim_end_matches('/').to_owned()
+    } else if host == "docker.io" || host == "index.docker.io" {
+        "https://registry-1.docker.io".to_owned()
+    } else if http::registry_config(host).is_some_and(|r| r.http) {
+        format!("http://{}", host)
+    } else {
+        format!("https://{}", host)
+    }
+}
+
+/// Whether `digest` is a well-formed `sha256:<hex>` digest.
+pub fn is_digest(digest: &str) -> bool {
+    digest.strip_prefix("sha256:").is_some_and(|hex| {
+        hex.len() == 64
+            && hex
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    })
+}
+
+/// `image` as pulled from `endpoint`, a mirror of its registry.
+pub fn at_endpoint(image: &str, endpoint: &str) -> String {
+    let (host, name, reference) = parse_reference(image);
+    if endpoint == host {
+        return image.to_owned();
+    }
+
+    // mirrors of Docker Hub spell out the implicit namespace
+    let name = if host == "docker.io" && !name.contains('/') {
+        format!("library/{}", name)
+    } else {
+        name
+    };
+    if reference.contains(':') {
+        format!("{}/{}@{}", endpoint, name, reference)
+    } else {
+        format!("{}/{}:{}", endpoint, name, reference)
+    }
+}
+
+fn with_digest(location: &str, digest: &str) -> String {
+    let separator = if location.contains('?') { '&' } else { '?' };
+    format!("{}{}digest={}", location, separator, digest)
+}
+
+fn scope(repo: &str, actions: &str) -> String {
+    format!("repository:{}:{}", repo, actions)
+}
+
+pub fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(|v| v.to_owned())
+}
+
+/// An error status from a registry, with its message and, for registries
+/// whose failures need explaining, what to do about it.
+#[derive(Debug, thiserror::Error)]
+#[error("{url} returned {status}: {body}{}", hint.as_ref().map(|h| format!(" ({})", h)).unwrap_or_default())]
+pub struct StatusError {
+    pub url: String,
+    pub status: StatusCode,
+    pub body: String,
+    pub hint: Option<String>,
+}
+
+// Whether `e` is the registry no longer knowing an upload session, which
+// it reports as BLOB_UPLOAD_UNKNOWN, or as a bare 404 for the session URL.
+fn is_upload_unknown(e: &anyhow::Error) -> bool {
+    e.chain().any(|e| {
+        e.downcast_ref::<StatusError>().is_some_and(|e| {
+            e.body.contains("BLOB_UPLOAD_UNKNOWN")
+                || e.body.contains("blob upload unknown")
+                || (e.status == StatusCode::NOT_FOUND && e.url.contains("/blobs/uploads/"))
+        })
+    })
+}
+
+// Turn error statuses into errors carrying the registry's message.
+async fn check(resp: Response, url: &str) -> anyhow::Result<Response> {
+    checked(resp, url, None).await
+}
+
+// `check` for a login with `password`.
+async fn checked(resp: Response, url: &str, password: Option<&str>) -> anyhow::Result<Response> {
+    if resp.status().is_success() {
+        return Ok(resp);
+    }
+    let status = resp.status();
+    let body = resp.text().await.unwrap_or_default();
+    Err(StatusError {
+        url: url.to_owned(),
+        status,
+        hint: ghcr::hint(url, status, &body, password),
+        body: body.trim().to_owned(),
+    }
+    .into())
+}
+
+// parse `Bearer realm="...",service="...",scope="..."`
+fn parse_challenge(challenge: &str) -> HashMap<String, String> {
+    let params = challenge.split_once(' ').map(|x| x.1).unwrap_or_default();
+    let mut map = HashMap::new();
+    let mut rest = params;
+    while let Some(eq) = rest.find('=') {
+        let key = rest[..eq]
+            .trim()
+            .trim_start_matches(',')
+            .trim()
+            .to_ascii_lowercase();
+        rest = &rest[eq + 1..];
+        let value = if let Some(quoted) = rest.strip_prefix('"') {
+            let end = quoted.find('"').unwrap_or(quoted.len());
+            let value = &quoted[..end];
+            rest = quoted.get(end + 1..).unwrap_or_default();
+            value
+        } else {
+            let end = rest.find(',').unwrap_or(rest.len());
+            let value = &rest[..end];
+            rest = &rest[end..];
+            value
+        };
+        map.insert(key, value.to_owned());
+    }
+    map
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parsed(image: &str) -> (String, String, String) {
+        parse_reference(image)
+    }
+
+    fn triple(host: &str, name: &str, reference: &str) -> (String, String, String) {
+        (host.to_owned(), name.to_owned(), reference.to_owned())
+    }
+
+    const DIGEST: &str = "sha256:0123456789abcdef0123456789abcdef0123456789abcdef0123456789abcdef";
+
+    #[test]
+    fn parse_docker_hub() {
+        assert_eq!(parsed("nginx"), triple("docker.io", "nginx", "latest"));
+        assert_eq!(parsed("nginx:1.25"), triple("docker.io", "nginx", "1.25"));
+        assert_eq!(
+            parsed("bitnami/redis:7"),
+            triple("docker.io", "bitnami/redis", "7")
+        );
+    }
+
+    #[test]
+    fn parse_with_port() {
+        assert_eq!(
+            parsed("localhost:5000/app"),
+            triple("localhost:5000", "app", "latest")
+        );
+        assert_eq!(
+            parsed("registry:5000/team/app:1.0"),
+            triple("registry:5000", "team/app", "1.0")
+        );
+        assert_eq!(
+            parsed("localhost/app"),
+            triple("localhost", "app", "latest")
+        );
+        assert_eq!(
+            parsed("ghcr.io/org/app:v2"),
+            triple("ghcr.io", "org/app", "v2")
+        );
+    }
+
+    #[test]
+    fn parse_with_digest() {
+        assert_eq!(
+            parsed(&format!("nginx@{}", DIGEST)),
+            triple("docker.io", "nginx", DIGEST)
+        );
+        assert_eq!(
+            parsed(&format!("ghcr.io/org/app@{}", DIGEST)),
+            triple("ghcr.io", "org/app", DIGEST)
+        );
+    }
+
+    #[test]
+    fn parse_with_tag_and_digest() {
+        // the tag alongside a digest is dropped
+        assert_eq!(
+            parsed(&format!("nginx:1.25@{}", DIGEST)),
+            triple("docker.io", "nginx", DIGEST)
+        );
+        assert_eq!(
+            parsed(&format!("registry:5000/app:1.0@{}", DIGEST)),
+            triple("registry:5000", "app", DIGEST)
+        );
+    }
+
+    #[test]
+    fn tagged_digests() {
+        assert_eq!(
+            tagged_digest(&format!("nginx:1.25@{}", DIGEST)),
+            Some(("nginx:1.25".to_owned(), DIGEST.to_owned()))
+        );
+        assert_eq!(
+            tagged_digest(&format!("registry:5000/app:1.0@{}", DIGEST)),
+            Some(("registry:5000/app:1.0".to_owned(), DIGEST.to_owned()))
+        );
+        // the port is not a tag
+        assert_eq!(
+            tagged_digest(&format!("registry:5000/app@{}", DIGEST)),
+            None
+        );
+        assert_eq!(tagged_digest(&format!("nginx@{}", DIGEST)), None);
+        assert_eq!(tagged_digest("nginx:1.25"), None);
+    }
+
+    #[test]
+    fn repository_names() {
+        assert_eq!(repository_name("nginx:1.25"), "docker.io/library/nginx");
+        assert_eq!(
+            repository_name(&format!("registry:5000/app@{}", DIGEST)),
+            "registry:5000/app"
+        );
+    }
+}
@@ -0,0 +1,129 @@
+use crate::config::RetentionConfig;
+use crate::events;
+use crate::gc;
+use crate::labels;
+use crate::metrics;
+use crate::registry::Registry;
+use chrono::DateTime;
+use chrono::Utc;
+use std::collections::HashSet;
+use std::sync::Arc;
+use tracing::event;
+use tracing::Level;
+
+/// Apply the retention policy to `repo` every `config.interval`, collecting
+/// the deleted manifests with `gc` if given.
+pub async fn run(
+    config: RetentionConfig,
+    registry: Registry,
+    repo: String,
+    gc: Option<Arc<gc::Collector>>,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        match apply(&config, &registry, &repo).await {
+            Ok(deleted) => {
+                event!(
+                    Level::INFO,
+                    "retention removed {} manifests from {}",
+                    deleted,
+                    repo
+                );
+                events::publish(
+                    "prune_finished",
+                    serde_json::json!({ "kind": "retention", "repository": repo, "removed": deleted }),
+                );
+                if let (true, Some(gc)) = (deleted > 0, &gc) {
+                    gc.collect().await;
+                }
+            }
+            Err(e) => event!(Level::ERROR, "retention for {} failed: {:?}", repo, e),
+        }
+    }
+}
+
+/// Delete the manifests of tags outside the policy, returning how many were
+/// deleted. A manifest is kept while any tag we keep still points at it.
+pub async fn apply(
+    config: &RetentionConfig,
+    registry: &Registry,
+    repo: &str,
+) -> anyhow::Result<usize> {
+    let mut tags = Vec::new();
+    for tag in registry.list_tags(repo).await? {
+        let manifest = registry.get_manifest(repo, &tag).await?;
+        let created = created(registry, repo, &manifest).await?;
+        tags.push((tag, manifest.digest, created));
+    }
+
+    // newest first, tags without a date count as oldest
+    tags.sort_by_key(|t| std::cmp::Reverse(t.2));
+
+    let now = Utc::now();
+    let mut keep = HashSet::new();
+    let mut expired = Vec::new();
+    for (i, (tag, digest, created)) in tags.iter().enumerate() {
+        let over_count = config.keep.is_some_and(|n| i >= n);
+        let too_old = config.max_age.is_some_and(|max_age| match created {
+            Some(created) => (now - *created).to_std().unwrap_or_default() > max_age,
+            None => true,
+        });
+        if over_count || too_old {
+            expired.push((tag, digest));
+        } else {
+            keep.insert(digest);
+        }
+    }
+
+    let mut deleted = HashSet::new();
+    for (tag, digest) in expired {
+        if keep.contains(digest) || deleted.contains(digest) {
+            continue;
+        }
+        registry.delete_manifest(repo, digest).await?;
+        event!(
+            Level::INFO,
+            "retention deleted {}:{} ({})",
+            repo,
+            tag,
+            digest
+        );
+        metrics::RETENTION_DELETED.inc();
+        deleted.insert(digest);
+    }
+
+    Ok(deleted.len())
+}
+
+// When the image was synced, falling back to when it was built.
+async fn created(
+    registry: &Registry,
+    repo: &str,
+    manifest: &crate::registry::Manifest,
+) -> anyhow::Result<Option<DateTime<Utc>>> {
+    let mut image = manifest.clone();
+    if manifest.is_index() {
+        match manifest.parse()?.manifests.first() {
+            Some(child) => image = registry.get_manifest(repo, &child.digest).await?,
+            None => return Ok(None),
+        }
+    }
+    let config = match image.parse()?.config {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+
+    let blob: serde_json::Value = registry
+        .get_blob(repo, &config.digest)
+        .await?
+        .json()
+        .await?;
+    let date = blob["config"]["Labels"][labels::SYNCED_AT]
+        .as_str()
+        .or(blob["created"].as_str());
+
+    Ok(date
+        .and_then(|d| DateTime::parse_from_rfc3339(d).ok())
+        .map(|d| d.with_timezone(&Utc)))
+}
@@ -0,0 +1,124 @@
+use crate::config::RetryConfig;
+use crate::error::Error;
+use crate::registry::StatusError;
+use once_cell::sync::OnceCell;
+use std::time::Duration;
+
+static CONFIG: OnceCell<RetryConfig> = OnceCell::new();
+
+/// Set the rules failures are classified by. Without this the defaults of
+/// `RetryConfig` apply.
+pub fn init(config: RetryConfig) {
+    let _ = CONFIG.set(config);
+}
+
+fn config() -> &'static RetryConfig {
+    CONFIG.get_or_init(RetryConfig::default)
+}
+
+/// Runs of a sync in all.
+pub fn attempts() -> u32 {
+    config().attempts.max(1)
+}
+
+/// How long to wait before run `attempt` + 1, after `attempt` failed.
+pub fn backoff(attempt: u32) -> Duration {
+    Duration::from_secs(config().backoff.saturating_mul(1 << (attempt - 1).min(10)))
+}
+
+/// Whether a sync that failed with `error` may succeed when run again.
+/// Requests that are wrong or refused by policy never do.
+pub fn is_retryable(error: &Error) -> bool {
+    match error {
+        Error::Parse(_)
+        | Error::TooLarge(_)
+        | Error::JobNotFound(_)
+        | Error::BatchNotFound(_)
+        | Error::JobNotRecorded(_)
+        | Error::OriginNotFound(_)
+        | Error::ImageNotFound(_)
+        | Error::NotRetryable(..)
+        | Error::Unauthorized
+        | Error::Signature(_)
+        | Error::LinkRejected(_)
+        | Error::Credentials { .. }
+        | Error::Policy { .. }
+        | Error::TagConflict(_)
+        | Error::MutableTag(_)
+        | Error::DigestRequired(_)
+        | Error::InsecureRegistry(_) => return false,
+        _ => (),
+    }
+    classify(&statuses(error), &error.report())
+}
+
+fn classify(statuses: &[u16], message: &str) -> bool {
+    let message = message.to_lowercase();
+    let matches = |rule: &String| match rule.parse::<u16>() {
+        Ok(status) if rule.len() == 3 => statuses.contains(&status),
+        _ => !message.is_empty() && message.contains(&rule.to_lowercase()),
+    };
+    let config = config();
+    if config.permanent.iter().any(matches) {
+        return false;
+    }
+    config.retryable.iter().any(matches)
+}
+
+// HTTP statuses anywhere in the causes of `error`.
+fn statuses(error: &Error) -> Vec<u16> {
+    let mut statuses = Vec::new();
+    let mut source = std::error::Error::source(error);
+    while let Some(e) = source {
+        if let Some(e) = e.downcast_ref::<StatusError>() {
+            statuses.push(e.status.as_u16());
+        }
+        if let Some(bollard::errors::Error::DockerResponseServerError { status_code, .. }) =
+            e.downcast_ref::<bollard::errors::Error>()
+        {
+            statuses.push(*status_code);
+        }
+        source = e.source();
+    }
+    statuses
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_by_status() {
+        assert!(classify(&[503], ""));
+        assert!(classify(&[429], ""));
+        assert!(!classify(&[404], ""));
+        assert!(!classify(&[401], ""));
+        // a permanent status wins over a retryable one
+        assert!(!classify(&[503, 404], ""));
+        assert!(!classify(&[200], ""));
+    }
+
+    #[test]
+    fn classify_by_message() {
+        assert!(classify(
+            &[],
+            "error sending request for url: Connection reset by peer"
+        ));
+        assert!(classify(&[], "operation timed out"));
+        assert!(classify(
+            &[],
+            "toomanyrequests: You have reached your pull rate limit"
+        ));
+        assert!(!classify(&[], "manifest unknown: manifest unknown"));
+        assert!(!classify(&[], "pull access denied for private/app"));
+        assert!(!classify(&[], "timeout waiting for unauthorized response"));
+        assert!(!classify(&[], "something else entirely"));
+        assert!(!classify(&[], ""));
+    }
+
+    #[test]
+    fn status_rules_need_the_status() {
+        // three digits in the message are not a status
+        assert!(!classify(&[], "layer 503 bytes short"));
+    }
+}
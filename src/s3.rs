@@ -0,0 +1,101 @@
+use crate::config::S3Config;
+use anyhow::Context;
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tokio_util::io::ReaderStream;
+
+type HmacSha256 = Hmac<Sha256>;
+
+const UNSIGNED_PAYLOAD: &str = "UNSIGNED-PAYLOAD";
+
+/// Upload a local file to `key` in the configured bucket.
+///
+/// Uses path-style addressing and an unsigned payload, which both AWS S3
+/// and MinIO accept, so the file can be streamed without hashing it first.
+pub async fn put_object(config: &S3Config, key: &str, path: &Path) -> anyhow::Result<()> {
+    let file = tokio::fs::File::open(path)
+        .await
+        .with_context(|| format!("failed to open {}", path.display()))?;
+    let length = file.metadata().await?.len();
+
+    let canonical_uri = format!("/{}/{}", uri_encode(&config.bucket), uri_encode(key));
+    let url = reqwest::Url::parse(&format!("{}{}", config.endpoint, canonical_uri))?;
+    let host = match (url.host_str(), url.port()) {
+        (Some(host), Some(port)) => format!("{}:{}", host, port),
+        (Some(host), None) => host.to_owned(),
+        (None, _) => anyhow::bail!("invalid S3 endpoint {}", config.endpoint),
+    };
+
+    let now = chrono::Utc::now();
+    let amz_date = now.format("%Y%m%dT%H%M%SZ").to_string();
+    let date = now.format("%Y%m%d").to_string();
+    let scope = format!("{}/{}/s3/aws4_request", date, config.region);
+
+    let canonical_request = format!(
+        "PUT\n{}\n\nhost:{}\nx-amz-content-sha256:{}\nx-amz-date:{}\n\nhost;x-amz-content-sha256;x-amz-date\n{}",
+        canonical_uri, host, UNSIGNED_PAYLOAD, amz_date, UNSIGNED_PAYLOAD
+    );
+    let string_to_sign = format!(
+        "AWS4-HMAC-SHA256\n{}\n{}\n{}",
+        amz_date,
+        scope,
+        hex::encode(Sha256::digest(canonical_request.as_bytes()))
+    );
+
+    let signing_key = signing_key(&config.secret_key, &date, &config.region, "s3");
+    let signature = hex::encode(hmac(&signing_key, &string_to_sign));
+
+    let authorization = format!(
+        "AWS4-HMAC-SHA256 Credential={}/{}, SignedHeaders=host;x-amz-content-sha256;x-amz-date, Signature={}",
+        config.access_key, scope, signature
+    );
+
+    let resp = crate::http::client(&config.endpoint)
+        .put(url)
+        .header("x-amz-date", amz_date)
+        .header("x-amz-content-sha256", UNSIGNED_PAYLOAD)
+        .header("authorization", authorization)
+        .header("content-length", length)
+        .body(reqwest::Body::wrap_stream(ReaderStream::new(file)))
+        .send()
+        .await?;
+
+    if !resp.status().is_success() {
+        let status = resp.status();
+        let body = resp.text().await.unwrap_or_default();
+        anyhow::bail!("S3 upload of {} failed with {}: {}", key, status, body);
+    }
+
+    Ok(())
+}
+
+/// AWS Signature Version 4 key for `service` in `region` on `date`
+/// (`YYYYMMDD`).
+pub fn signing_key(secret_key: &str, date: &str, region: &str, service: &str) -> Vec<u8> {
+    let mut key = hmac(format!("AWS4{}", secret_key).as_bytes(), date);
+    for part in [region, service, "aws4_request"] {
+        key = hmac(&key, part);
+    }
+    key
+}
+
+pub fn hmac(key: &[u8], data: &str) -> Vec<u8> {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+    mac.update(data.as_bytes());
+    mac.finalize().into_bytes().to_vec()
+}
+
+// percent-encode everything except unreserved characters and '/'
+fn uri_encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for b in s.bytes() {
+        match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                out.push(b as char)
+            }
+            _ => out.push_str(&format!("%{:02X}", b)),
+        }
+    }
+    out
+}
@@ -0,0 +1,334 @@
+use crate::jobs::Priority;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+
+// what one turn of a share weighing 1 costs
+const STRIDE: u64 = 1 << 20;
+
+/// Limits how many syncs run at once; waiting jobs start by priority, then
+/// taking turns by share (tenant or repository) as often as the share's
+/// weight says, then in the order they were queued. While paused no job
+/// starts.
+pub struct Scheduler {
+    // high priority jobs start right away even when every slot is taken
+    preempt: bool,
+    weights: HashMap<String, u32>,
+    state: Mutex<State>,
+}
+
+struct State {
+    max_running: usize,
+    paused: bool,
+    running: usize,
+    seq: u64,
+    waiting: Vec<Waiting>,
+    // stride scheduling: the share with the lowest pass goes next and its
+    // pass grows by its stride, so shares take turns by weight
+    passes: HashMap<String, u64>,
+    // pass of the latest turn, where shares that start waiting join in
+    pass: u64,
+}
+
+impl State {
+    // hand free slots to the waiting jobs first in line
+    fn start_waiting(&mut self, weights: &HashMap<String, u32>) {
+        while !self.paused && self.running < self.max_running {
+            let top = match self.waiting.iter().map(|w| w.priority).max() {
+                Some(top) => top,
+                None => return,
+            };
+            let passes = &self.passes;
+            let next = self
+                .waiting
+                .iter()
+                .enumerate()
+                .filter(|(_, w)| w.priority == top)
+                .min_by_key(|(_, w)| (passes.get(&w.share).copied().unwrap_or(0), w.seq))
+                .map(|(i, _)| i);
+            let next = match next {
+                Some(i) => self.waiting.remove(i),
+                None => return,
+            };
+            // a waiter that went away (request dropped) does not take the slot
+            if next.wake.send(()).is_ok() {
+                self.running += 1;
+                let weight = weights.get(&next.share).copied().unwrap_or(1).max(1);
+                let pass = self.passes.entry(next.share).or_insert(self.pass);
+                self.pass = self.pass.max(*pass);
+                *pass += STRIDE / weight as u64;
+            }
+        }
+        self.forget_idle();
+    }
+
+    // shares with nothing waiting that are not ahead of the others would
+    // join at the current pass anyway
+    fn forget_idle(&mut self) {
+        let (waiting, pass) = (&self.waiting, self.pass);
+        self.passes
+            .retain(|share, p| *p > pass || waiting.iter().any(|w| &w.share == share));
+    }
+}
+
+struct Waiting {
+    priority: Priority,
+    share: String,
+    seq: u64,
+    wake: oneshot::Sender<()>,
+}
+
+/// A running slot, released on drop.
+pub struct Permit {
+    scheduler: Arc<Scheduler>,
+}
+
+impl Scheduler {
+    /// `weights` give how many turns a share takes for each turn of a share
+    /// weighing 1, which shares not listed do.
+    pub fn new(max_running: usize, preempt: bool, weights: HashMap<String, u32>) -> Scheduler {
+        Scheduler {
+            preempt,
+            weights,
+            state: Mutex::new(State {
+                max_running: max_running.max(1),
+                paused: false,
+                running: 0,
+                seq: 0,
+                waiting: Vec::new(),
+                passes: HashMap::new(),
+                pass: 0,
+            }),
+        }
+    }
+
+    /// Wait for a slot to run a job of `priority` on behalf of `share`.
+    pub async fn acquire(self: &Arc<Self>, priority: Priority, share: String) -> Permit {
+        let rx = {
+            let mut state = self.state.lock().unwrap();
+            let preempt = self.preempt && priority == Priority::High;
+            if !state.paused && (state.running < state.max_running || preempt) {
+                state.running += 1;
+                return Permit {
+                    scheduler: self.clone(),
+                };
+            }
+
+            // a share that has been away does not get turns for the time it
+            // was, it joins where the others are
+            let pass = state.pass;
+            let joined = state.passes.entry(share.clone()).or_insert(pass);
+            *joined = (*joined).max(pass);
+
+            let (tx, rx) = oneshot::channel();
+            state.seq += 1;
+            let seq = state.seq;
+            state.waiting.push(Waiting {
+                priority,
+                share,
+                seq,
+                wake: tx,
+            });
+            rx
+        };
+
+        // the slot is handed over by the permit that freed it
+        let mut pending = Pending {
+            scheduler: self.clone(),
+            rx: Some(rx),
+        };
+        let _ = pending.rx.as_mut().unwrap().await;
+        pending.rx = None;
+        Permit {
+            scheduler: self.clone(),
+        }
+    }
+
+    /// Jobs running and jobs waiting for a slot.
+    pub fn counts(&self) -> (usize, usize) {
+        let state = self.state.lock().unwrap();
+        (state.running, state.waiting.len())
+    }
+
+    /// Jobs waiting for a slot by share.
+    pub fn waiting_by_share(&self) -> BTreeMap<String, usize> {
+        let state = self.state.lock().unwrap();
+        let mut shares = BTreeMap::new();
+        for waiting in &state.waiting {
+            *shares.entry(waiting.share.clone()).or_default() += 1;
+        }
+        shares
+    }
+
+    /// Run at most `max_running` jobs at once from now on. Waiting jobs
+    /// start when it grows; when it shrinks, running jobs finish and no new
+    /// ones start until fewer than that remain.
+    pub fn set_max_running(&self, max_running: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.max_running = max_running.max(1);
+        state.start_waiting(&self.weights);
+    }
+
+    /// Hold every job not running yet until `resume`, letting those
+    /// running finish. Returns whether the queue was running before.
+    pub fn pause(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        !std::mem::replace(&mut state.paused, true)
+    }
+
+    /// Start the held jobs again. Returns whether the queue was paused.
+    pub fn resume(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let paused = std::mem::replace(&mut state.paused, false);
+        state.start_waiting(&self.weights);
+        paused
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    fn release(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.running -= 1;
+        state.start_waiting(&self.weights);
+    }
+}
+
+// Gives back a slot handed to a waiter that was cancelled before taking it.
+struct Pending {
+    scheduler: Arc<Scheduler>,
+    rx: Option<oneshot::Receiver<()>>,
+}
+
+impl Drop for Pending {
+    fn drop(&mut self) {
+        if let Some(mut rx) = self.rx.take() {
+            rx.close();
+            if rx.try_recv().is_ok() {
+                self.scheduler.release();
+            }
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.scheduler.release();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::sync::Mutex as StdMutex;
+
+    // queue one waiter per share in `shares`, in order, behind a held
+    // slot and return the order they start in
+    async fn start_order(weights: HashMap<String, u32>, shares: &[&str]) -> Vec<String> {
+        let scheduler = Arc::new(Scheduler::new(1, false, weights));
+        let held = scheduler.acquire(Priority::Normal, "held".to_owned()).await;
+        let started = Arc::new(StdMutex::new(Vec::new()));
+        let mut waiters = Vec::new();
+        for share in shares {
+            let (scheduler, started, share) =
+                (scheduler.clone(), started.clone(), share.to_string());
+            waiters.push(tokio::spawn(async move {
+                let _permit = scheduler.acquire(Priority::Normal, share.clone()).await;
+                started.lock().unwrap().push(share);
+            }));
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(scheduler.counts(), (1, shares.len()));
+        drop(held);
+        for waiter in waiters {
+            waiter.await.unwrap();
+        }
+        let order = started.lock().unwrap().clone();
+        order
+    }
+
+    #[tokio::test]
+    async fn shares_take_turns_by_weight() {
+        let weights = HashMap::from([("a".to_owned(), 2)]);
+        let order = start_order(weights, &["a", "a", "a", "b", "b", "b"]).await;
+        assert_eq!(order, ["a", "b", "a", "a", "b", "b"]);
+    }
+
+    #[tokio::test]
+    async fn equal_shares_alternate() {
+        let order = start_order(HashMap::new(), &["a", "a", "b", "b"]).await;
+        assert_eq!(order, ["a", "b", "a", "b"]);
+    }
+
+    #[tokio::test]
+    async fn high_priority_starts_first() {
+        let scheduler = Arc::new(Scheduler::new(1, false, HashMap::new()));
+        let held = scheduler.acquire(Priority::Normal, "a".to_owned()).await;
+        let normal = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire(Priority::Normal, "a".to_owned()).await }
+        });
+        tokio::task::yield_now().await;
+        let high = tokio::spawn({
+            let scheduler = scheduler.clone();
+            async move { scheduler.acquire(Priority::High, "b".to_owned()).await }
+        });
+        tokio::task::yield_now().await;
+        drop(held);
+        let high = high.await.unwrap();
+        assert!(!normal.is_finished());
+        drop(high);
+        normal.await.unwrap();
+    }
+
+    #[test]
+    fn high_priority_preempts() {
+        let scheduler = Arc::new(Scheduler::new(1, true, HashMap::new()));
+        let _held = scheduler
+            .acquire(Priority::Normal, "a".to_owned())
+            .now_or_never()
+            .unwrap();
+        assert!(scheduler
+            .acquire(Priority::Normal, "a".to_owned())
+            .now_or_never()
+            .is_none());
+        let high = scheduler
+            .acquire(Priority::High, "b".to_owned())
+            .now_or_never();
+        assert!(high.is_some());
+        assert_eq!(scheduler.counts().0, 2);
+    }
+
+    #[test]
+    fn high_priority_waits_without_preemption() {
+        let scheduler = Arc::new(Scheduler::new(1, false, HashMap::new()));
+        let _held = scheduler
+            .acquire(Priority::Normal, "a".to_owned())
+            .now_or_never()
+            .unwrap();
+        assert!(scheduler
+            .acquire(Priority::High, "b".to_owned())
+            .now_or_never()
+            .is_none());
+        assert_eq!(scheduler.counts().0, 1);
+    }
+
+    #[test]
+    fn paused_holds_new_jobs() {
+        let scheduler = Arc::new(Scheduler::new(2, false, HashMap::new()));
+        assert!(scheduler.pause());
+        assert!(scheduler
+            .acquire(Priority::Normal, "a".to_owned())
+            .now_or_never()
+            .is_none());
+        assert!(scheduler.resume());
+        assert!(scheduler
+            .acquire(Priority::Normal, "a".to_owned())
+            .now_or_never()
+            .is_some());
+    }
+}
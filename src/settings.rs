@@ -0,0 +1,193 @@
+use crate::config::Config;
+use crate::config::Naming;
+use serde::Deserialize;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::event;
+use tracing::Level;
+
+// guards the admin audit log
+static AUDIT: Mutex<()> = Mutex::new(());
+
+/// Body of `PATCH /admin/settings`: the settings to change, named like
+/// their environment variables. Those left out stay as they are.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Changes {
+    pub max_concurrent_syncs: Option<usize>,
+    pub layer_parallelism: Option<usize>,
+    pub ratelimit_min_remaining: Option<u64>,
+    /// Seconds.
+    pub ratelimit_retry_interval: Option<u64>,
+    /// Seconds.
+    pub ratelimit_max_wait: Option<u64>,
+    pub dest_repository: Option<String>,
+    /// `flat` or `nested`.
+    pub dest_naming: Option<String>,
+    /// Empty to remove the prefix.
+    pub dest_prefix: Option<String>,
+}
+
+/// The settings `Changes` may change, as they are in `config`.
+pub fn current(config: &Config) -> BTreeMap<&'static str, Value> {
+    BTreeMap::from([
+        ("max_concurrent_syncs", config.max_concurrent_syncs.into()),
+        ("layer_parallelism", config.layer_parallelism.into()),
+        (
+            "ratelimit_min_remaining",
+            config.ratelimit.min_remaining.into(),
+        ),
+        (
+            "ratelimit_retry_interval",
+            config.ratelimit.retry_interval.as_secs().into(),
+        ),
+        (
+            "ratelimit_max_wait",
+            config.ratelimit.max_wait.as_secs().into(),
+        ),
+        ("dest_repository", config.dest_repository.clone().into()),
+        (
+            "dest_naming",
+            match config.dest_naming {
+                Naming::Flat => "flat",
+                Naming::Nested => "nested",
+            }
+            .into(),
+        ),
+        ("dest_prefix", config.dest_prefix.clone().into()),
+    ])
+}
+
+/// Setting changed, from what to what.
+#[derive(Serialize, Debug)]
+pub struct Change {
+    pub from: Value,
+    pub to: Value,
+}
+
+/// `config` with `changes` made, and what they changed, or the problems
+/// found with them. Nothing is changed unless all of them are valid.
+pub fn apply(
+    config: &Config,
+    changes: &Changes,
+) -> Result<(Config, BTreeMap<&'static str, Change>), Vec<String>> {
+    let mut problems = Vec::new();
+    let mut new = config.clone();
+    if let Some(n) = changes.max_concurrent_syncs {
+        match n {
+            1..=256 => new.max_concurrent_syncs = n,
+            _ => problems.push(format!("max_concurrent_syncs {} is not within 1–256", n)),
+        }
+    }
+    if let Some(n) = changes.layer_parallelism {
+        match n {
+            1..=64 => new.layer_parallelism = n,
+            _ => problems.push(format!("layer_parallelism {} is not within 1–64", n)),
+        }
+    }
+    if let Some(n) = changes.ratelimit_min_remaining {
+        new.ratelimit.min_remaining = n;
+    }
+    if let Some(secs) = changes.ratelimit_retry_interval {
+        match secs {
+            0 => problems.push("ratelimit_retry_interval must be at least 1".to_owned()),
+            _ => new.ratelimit.retry_interval = Duration::from_secs(secs),
+        }
+    }
+    if let Some(secs) = changes.ratelimit_max_wait {
+        new.ratelimit.max_wait = Duration::from_secs(secs);
+    }
+    if let Some(repository) = &changes.dest_repository {
+        match repository_problem(repository) {
+            Some(problem) => problems.push(format!("dest_repository {}", problem)),
+            None => new.dest_repository = repository.clone(),
+        }
+    }
+    if let Some(naming) = &changes.dest_naming {
+        match naming.parse() {
+            Ok(naming) => new.dest_naming = naming,
+            Err(e) => problems.push(format!("dest_naming: {}", e)),
+        }
+    }
+    if let Some(prefix) = &changes.dest_prefix {
+        let prefix = prefix.trim_matches('/');
+        if prefix.is_empty() {
+            new.dest_prefix = None;
+        } else if let Some(problem) = repository_problem(prefix) {
+            problems.push(format!("dest_prefix {}", problem));
+        } else {
+            new.dest_prefix = Some(prefix.to_owned());
+        }
+    }
+    if !problems.is_empty() {
+        return Err(problems);
+    }
+
+    let (before, after) = (current(config), current(&new));
+    let changed = after
+        .into_iter()
+        .filter(|(name, to)| before[name] != *to)
+        .map(|(name, to)| {
+            let from = before[name].clone();
+            (name, Change { from, to })
+        })
+        .collect();
+    Ok((new, changed))
+}
+
+// What keeps `name` from being a repository path, by the distribution
+// spec's grammar.
+fn repository_problem(name: &str) -> Option<String> {
+    if name.is_empty() || name.len() > 255 {
+        return Some("must be 1–255 characters".to_owned());
+    }
+    for component in name.split('/') {
+        let valid = !component.is_empty()
+            && component
+                .bytes()
+                .all(|b| b.is_ascii_lowercase() || b.is_ascii_digit() || b"._-".contains(&b))
+            && component.starts_with(|c: char| c.is_ascii_alphanumeric())
+            && component.ends_with(|c: char| c.is_ascii_alphanumeric());
+        if !valid {
+            return Some(format!("has invalid component {:?}", component));
+        }
+    }
+    None
+}
+
+/// A change made through the admin endpoints, as a line of the audit log.
+#[derive(Serialize, Debug)]
+struct AdminAudit<'a> {
+    time: String,
+    /// `settings` or `loglevel`.
+    action: &'a str,
+    changes: &'a BTreeMap<&'a str, Change>,
+}
+
+/// Record in `admin-audit.jsonl` of `state_dir` that `action` made
+/// `changes`.
+pub fn audit(state_dir: &Path, action: &str, changes: &BTreeMap<&str, Change>) {
+    let record = AdminAudit {
+        time: chrono::Utc::now().to_rfc3339(),
+        action,
+        changes,
+    };
+    let mut line = serde_json::to_vec(&record).unwrap();
+    line.push(b'\n');
+    let _lock = AUDIT.lock().unwrap();
+    let written = std::fs::create_dir_all(state_dir).and_then(|_| {
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(state_dir.join("admin-audit.jsonl"))?
+            .write_all(&line)
+    });
+    if let Err(e) = written {
+        event!(Level::WARN, "failed to record admin change: {:?}", e);
+    }
+}
@@ -0,0 +1,407 @@
+use crate::config::glob_match;
+use crate::config::SignatureConfig;
+use crate::policy::Violation;
+use crate::registry::Registry;
+use anyhow::Context;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use ring::signature::UnparsedPublicKey;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::path::Path;
+use tracing::event;
+use tracing::Level;
+
+const SIMPLE_SIGNING: &str = "application/vnd.dev.cosign.simplesigning.v1+json";
+const SIGNATURE_ANNOTATION: &str = "dev.cosignproject.cosign/signature";
+const CERTIFICATE_ANNOTATION: &str = "dev.sigstore.cosign/certificate";
+const CHAIN_ANNOTATION: &str = "dev.sigstore.cosign/chain";
+const BUNDLE_ANNOTATION: &str = "dev.sigstore.cosign/bundle";
+
+// DER encoded object identifiers
+const EC_PUBLIC_KEY: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x02, 0x01];
+const P256: &[u8] = &[0x2a, 0x86, 0x48, 0xce, 0x3d, 0x03, 0x01, 0x07];
+const P384: &[u8] = &[0x2b, 0x81, 0x04, 0x00, 0x22];
+const CODE_SIGNING: &[u8] = &[0x2b, 0x06, 0x01, 0x05, 0x05, 0x07, 0x03, 0x03];
+const SUBJECT_ALT_NAME: &[u8] = &[0x55, 0x1d, 0x11];
+// Fulcio's OIDC issuer extensions, the first holding the raw string
+const FULCIO_ISSUER: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xbf, 0x30, 0x01, 0x01];
+const FULCIO_ISSUER_V2: &[u8] = &[0x2b, 0x06, 0x01, 0x04, 0x01, 0x83, 0xbf, 0x30, 0x01, 0x08];
+
+static CHAIN_ALGORITHMS: &[&webpki::SignatureAlgorithm] = &[
+    &webpki::ECDSA_P256_SHA256,
+    &webpki::ECDSA_P256_SHA384,
+    &webpki::ECDSA_P384_SHA256,
+    &webpki::ECDSA_P384_SHA384,
+];
+
+type PublicKey = UnparsedPublicKey<Vec<u8>>;
+
+/// Check that `reference` in `repo` of `src` has a cosign signature made
+/// with one of the configured keys, or keylessly by one of the configured
+/// identities, returning who signed it. A refusal is a [`Violation`] saying
+/// why each signature found was turned down.
+pub async fn verify(
+    config: &SignatureConfig,
+    src: &Registry,
+    repo: &str,
+    reference: &str,
+) -> anyhow::Result<String> {
+    let digest = if reference.contains(':') {
+        reference.to_owned()
+    } else {
+        src.get_manifest(repo, reference).await?.digest
+    };
+    let trust = Trust::load(config)?;
+
+    // cosign stores the signatures of an image under a tag named after it
+    let tag = format!("{}.sig", digest.replace(':', "-"));
+    if src.head_manifest(repo, &tag).await?.is_none() {
+        return Err(Violation(vec![format!("{} is not signed", digest)]).into());
+    }
+    let signatures: Value = serde_json::from_slice(&src.get_manifest(repo, &tag).await?.bytes)
+        .context("invalid signature manifest")?;
+
+    let mut problems = Vec::new();
+    let layers = signatures["layers"].as_array().cloned().unwrap_or_default();
+    for (i, layer) in layers.iter().enumerate() {
+        if layer["mediaType"] != SIMPLE_SIGNING {
+            continue;
+        }
+        let payload_digest = layer["digest"].as_str().unwrap_or_default();
+        let payload = src.get_blob(repo, payload_digest).await?.bytes().await?;
+        let checked =
+            if format!("sha256:{}", hex::encode(Sha256::digest(&payload))) != payload_digest {
+                Err(anyhow::anyhow!("payload does not match its digest"))
+            } else {
+                check(config, &trust, &digest, &layer["annotations"], &payload)
+            };
+        match checked {
+            Ok(signer) => {
+                event!(Level::INFO, "{} is signed by {}", digest, signer);
+                return Ok(signer);
+            }
+            Err(e) => problems.push(format!("signature {}: {}", i + 1, e)),
+        }
+    }
+    if problems.is_empty() {
+        problems.push(format!("{} is not signed", digest));
+    } else {
+        problems.insert(0, format!("{} has no valid signature", digest));
+    }
+    Err(Violation(problems).into())
+}
+
+// What signatures are checked against, read afresh for each image so keys
+// can be rotated without a restart.
+struct Trust {
+    keys: Vec<(String, PublicKey)>,
+    roots: Vec<Vec<u8>>,
+    rekor: Option<PublicKey>,
+}
+
+impl Trust {
+    fn load(config: &SignatureConfig) -> anyhow::Result<Trust> {
+        let mut keys = Vec::new();
+        for path in &config.keys {
+            keys.push((path.display().to_string(), read_key(path)?));
+        }
+        let roots = match &config.fulcio_roots {
+            Some(path) => pem(&read(path)?, "CERTIFICATE")?,
+            None => Vec::new(),
+        };
+        let rekor = config.rekor_key.as_deref().map(read_key).transpose()?;
+        Ok(Trust { keys, roots, rekor })
+    }
+}
+
+fn read(path: &Path) -> anyhow::Result<String> {
+    std::fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn read_key(path: &Path) -> anyhow::Result<PublicKey> {
+    let der = pem(&read(path)?, "PUBLIC KEY")?;
+    let spki = der
+        .first()
+        .with_context(|| format!("no public key in {}", path.display()))?;
+    public_key(spki).with_context(|| format!("invalid public key in {}", path.display()))
+}
+
+// Check one signature of `digest` with its `payload`, returning who made it.
+fn check(
+    config: &SignatureConfig,
+    trust: &Trust,
+    digest: &str,
+    annotations: &Value,
+    payload: &[u8],
+) -> anyhow::Result<String> {
+    let claim: Value = serde_json::from_slice(payload).context("payload is not JSON")?;
+    let signed = &claim["critical"]["image"]["docker-manifest-digest"];
+    if signed != digest {
+        anyhow::bail!("payload is for {}", signed);
+    }
+    let signature = BASE64
+        .decode(
+            annotations[SIGNATURE_ANNOTATION]
+                .as_str()
+                .unwrap_or_default(),
+        )
+        .context("signature is not base64")?;
+    if signature.is_empty() {
+        anyhow::bail!("no signature annotation");
+    }
+
+    for (name, key) in &trust.keys {
+        if key.verify(payload, &signature).is_ok() {
+            return Ok(format!("key {}", name));
+        }
+    }
+    let certificate = match annotations[CERTIFICATE_ANNOTATION].as_str() {
+        Some(certificate) if !config.identities.is_empty() => certificate,
+        _ => anyhow::bail!("not made with any of the configured keys"),
+    };
+    keyless(config, trust, annotations, certificate, payload, &signature)
+}
+
+// Check a keyless signature: the Rekor log entry vouches for it and for when
+// it was made, the short-lived certificate was issued by Fulcio and valid
+// then, and names one of the configured identities.
+fn keyless(
+    config: &SignatureConfig,
+    trust: &Trust,
+    annotations: &Value,
+    certificate: &str,
+    payload: &[u8],
+    signature: &[u8],
+) -> anyhow::Result<String> {
+    let leaf = pem(certificate, "CERTIFICATE")?
+        .into_iter()
+        .next()
+        .context("no signing certificate")?;
+    let chain = pem(
+        annotations[CHAIN_ANNOTATION].as_str().unwrap_or_default(),
+        "CERTIFICATE",
+    )?;
+
+    let bundle: Value = serde_json::from_str(
+        annotations[BUNDLE_ANNOTATION]
+            .as_str()
+            .context("no Rekor bundle")?,
+    )
+    .context("invalid Rekor bundle")?;
+    let entry = &bundle["Payload"];
+    let timestamp = BASE64
+        .decode(bundle["SignedEntryTimestamp"].as_str().unwrap_or_default())
+        .context("invalid signed entry timestamp")?;
+    // the log signs its entry as canonical JSON, which has sorted keys
+    let rekor = trust.rekor.as_ref().context("no Rekor key configured")?;
+    rekor
+        .verify(&serde_json::to_vec(entry)?, &timestamp)
+        .map_err(|_| anyhow::anyhow!("Rekor entry timestamp does not verify"))?;
+
+    let body: Value = serde_json::from_slice(
+        &BASE64
+            .decode(entry["body"].as_str().unwrap_or_default())
+            .context("invalid Rekor entry body")?,
+    )
+    .context("invalid Rekor entry body")?;
+    let spec = &body["spec"];
+    if spec["data"]["hash"]["value"] != hex::encode(Sha256::digest(payload)) {
+        anyhow::bail!("Rekor entry is for another payload");
+    }
+    let logged_signature = BASE64
+        .decode(spec["signature"]["content"].as_str().unwrap_or_default())
+        .unwrap_or_default();
+    if logged_signature != signature {
+        anyhow::bail!("Rekor entry is for another signature");
+    }
+    let logged_certificate = BASE64
+        .decode(
+            spec["signature"]["publicKey"]["content"]
+                .as_str()
+                .unwrap_or_default(),
+        )
+        .unwrap_or_default();
+    let logged_certificate = pem(&String::from_utf8_lossy(&logged_certificate), "CERTIFICATE")?;
+    if logged_certificate.first() != Some(&leaf) {
+        anyhow::bail!("Rekor entry is for another certificate");
+    }
+    let integrated = entry["integratedTime"]
+        .as_u64()
+        .context("Rekor entry has no integratedTime")?;
+
+    let anchors = trust
+        .roots
+        .iter()
+        .map(|root| webpki::TrustAnchor::try_from_cert_der(root))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| anyhow::anyhow!("invalid Fulcio root: {:?}", e))?;
+    let intermediates: Vec<&[u8]> = chain.iter().map(Vec::as_slice).collect();
+    webpki::EndEntityCert::try_from(leaf.as_slice())
+        .and_then(|cert| {
+            cert.verify_for_usage(
+                CHAIN_ALGORITHMS,
+                &anchors,
+                &intermediates,
+                webpki::Time::from_seconds_since_unix_epoch(integrated),
+                webpki::KeyUsage::required(CODE_SIGNING),
+                &[],
+            )
+        })
+        .map_err(|e| anyhow::anyhow!("certificate does not verify: {:?}", e))?;
+
+    let cert = Certificate::parse(&leaf).context("invalid signing certificate")?;
+    public_key(cert.spki)?
+        .verify(payload, signature)
+        .map_err(|_| anyhow::anyhow!("signature does not verify with its certificate"))?;
+    let issuer = cert.issuer.unwrap_or_default();
+    for subject in &cert.subjects {
+        let allowed = config
+            .identities
+            .iter()
+            .any(|id| glob_match(&id.issuer, &issuer) && glob_match(&id.subject, subject));
+        if allowed {
+            return Ok(format!("{} ({})", subject, issuer));
+        }
+    }
+    anyhow::bail!(
+        "signed by {} ({}), not one of the configured identities",
+        cert.subjects.join(", "),
+        issuer
+    )
+}
+
+// What a signing certificate says about its holder.
+struct Certificate<'a> {
+    spki: &'a [u8],
+    subjects: Vec<String>,
+    issuer: Option<String>,
+}
+
+impl<'a> Certificate<'a> {
+    fn parse(data: &'a [u8]) -> anyhow::Result<Certificate<'a>> {
+        let (_, cert, _) = der(data)?;
+        let (_, mut tbs, _) = der(cert)?;
+        let mut fields = Vec::new();
+        while !tbs.is_empty() {
+            let (tag, contents, rest) = der(tbs)?;
+            fields.push((tag, contents, &tbs[..tbs.len() - rest.len()]));
+            tbs = rest;
+        }
+        // serial, signature, issuer, validity and subject come first, after
+        // the version when it is not the default
+        let start = usize::from(fields.first().map(|f| f.0) == Some(0xa0));
+        let spki = fields.get(start + 5).context("no public key")?.2;
+
+        let mut subjects = Vec::new();
+        let mut issuer = None;
+        if let Some((_, extensions, _)) = fields.iter().find(|f| f.0 == 0xa3) {
+            let (_, mut extensions, _) = der(extensions)?;
+            while !extensions.is_empty() {
+                let (_, extension, rest) = der(extensions)?;
+                extensions = rest;
+                let (_, oid, rest) = der(extension)?;
+                let (mut tag, mut value, rest) = der(rest)?;
+                // skip the critical flag
+                if tag == 0x01 {
+                    (tag, value, _) = der(rest)?;
+                }
+                if tag != 0x04 {
+                    continue;
+                }
+                match oid {
+                    SUBJECT_ALT_NAME => {
+                        let (_, mut names, _) = der(value)?;
+                        while !names.is_empty() {
+                            let (tag, name, rest) = der(names)?;
+                            // email addresses and URIs
+                            if tag == 0x81 || tag == 0x86 {
+                                subjects.push(String::from_utf8_lossy(name).into_owned());
+                            }
+                            names = rest;
+                        }
+                    }
+                    FULCIO_ISSUER_V2 => {
+                        let (_, name, _) = der(value)?;
+                        issuer = Some(String::from_utf8_lossy(name).into_owned());
+                    }
+                    FULCIO_ISSUER if issuer.is_none() => {
+                        issuer = Some(String::from_utf8_lossy(value).into_owned());
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(Certificate {
+            spki,
+            subjects,
+            issuer,
+        })
+    }
+}
+
+// ECDSA key of a DER SubjectPublicKeyInfo.
+fn public_key(spki: &[u8]) -> anyhow::Result<PublicKey> {
+    let (_, spki, _) = der(spki)?;
+    let (_, algorithm, rest) = der(spki)?;
+    let (_, key, _) = der(rest)?;
+    let (_, oid, parameters) = der(algorithm)?;
+    if oid != EC_PUBLIC_KEY {
+        anyhow::bail!("only ECDSA keys are supported");
+    }
+    let (_, curve, _) = der(parameters)?;
+    let algorithm = match curve {
+        P256 => &ring::signature::ECDSA_P256_SHA256_ASN1,
+        P384 => &ring::signature::ECDSA_P384_SHA384_ASN1,
+        _ => anyhow::bail!("only P-256 and P-384 keys are supported"),
+    };
+    // a bit string, led by its count of unused bits
+    let point = key.get(1..).context("empty public key")?;
+    Ok(UnparsedPublicKey::new(algorithm, point.to_vec()))
+}
+
+// Split the DER element at the start of `input` into its tag, its contents
+// and what follows it.
+fn der(input: &[u8]) -> anyhow::Result<(u8, &[u8], &[u8])> {
+    let (&tag, rest) = input.split_first().context("truncated DER")?;
+    let (&first, rest) = rest.split_first().context("truncated DER")?;
+    let (len, rest) = if first < 0x80 {
+        (first as usize, rest)
+    } else {
+        let n = (first & 0x7f) as usize;
+        if n == 0 || n > 4 || rest.len() < n {
+            anyhow::bail!("invalid DER length");
+        }
+        let len = rest[..n].iter().fold(0, |len, b| len << 8 | *b as usize);
+        (len, &rest[n..])
+    };
+    if rest.len() < len {
+        anyhow::bail!("truncated DER");
+    }
+    Ok((tag, &rest[..len], &rest[len..]))
+}
+
+// The DER contents of the PEM blocks labelled `label` in `text`.
+fn pem(text: &str, label: &str) -> anyhow::Result<Vec<Vec<u8>>> {
+    let begin = format!("-----BEGIN {}-----", label);
+    let end = format!("-----END {}-----", label);
+    let mut blocks = Vec::new();
+    let mut rest = text;
+    while let Some(start) = rest.find(&begin) {
+        let after = &rest[start + begin.len()..];
+        let stop = after
+            .find(&end)
+            .with_context(|| format!("unterminated {} block", label))?;
+        let base64: String = after[..stop]
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .collect();
+        blocks.push(
+            BASE64
+                .decode(base64)
+                .with_context(|| format!("invalid {} block", label))?,
+        );
+        rest = &after[stop + end.len()..];
+    }
+    Ok(blocks)
+}
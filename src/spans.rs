@@ -0,0 +1,66 @@
+use std::collections::HashMap;
+use tracing::field::Empty;
+use tracing::Span;
+
+/// Child spans of a pull, one per layer the Docker daemon reports on, each
+/// closed with the bytes it moved once the daemon is done with it. The
+/// parent gets the totals when finished.
+pub struct Layers {
+    parent: Span,
+    open: HashMap<String, (Span, u64)>,
+    bytes: u64,
+    layers: u64,
+}
+
+// statuses after which the daemon does no more with a layer
+const DONE: &[&str] = &["Pull complete", "Already exists"];
+
+impl Layers {
+    pub fn new(parent: &Span) -> Layers {
+        Layers {
+            parent: parent.clone(),
+            open: HashMap::new(),
+            bytes: 0,
+            layers: 0,
+        }
+    }
+
+    /// Note the daemon reporting `status`, out of `total` bytes when it
+    /// says, for the layer `id`.
+    pub fn update(&mut self, id: &str, status: &str, total: Option<i64>) {
+        // the tag being pulled is reported on as well
+        if status.starts_with("Pulling from") {
+            return;
+        }
+        // looked up before inserting, as most lines are about open layers
+        if !self.open.contains_key(id) {
+            let span =
+                tracing::info_span!(parent: &self.parent, "layer", layer = id, bytes = Empty);
+            self.open.insert(id.to_owned(), (span, 0));
+        }
+        let (_, bytes) = self.open.get_mut(id).expect("inserted above");
+        if let Some(total) = total.filter(|t| *t > 0) {
+            *bytes = total as u64;
+        }
+        if DONE.contains(&status) {
+            if let Some((span, bytes)) = self.open.remove(id) {
+                span.record("bytes", bytes);
+                self.bytes += bytes;
+                self.layers += 1;
+            }
+        }
+    }
+
+    /// Record the totals on the parent, closing the layers left open.
+    pub fn finish(self) {
+        self.parent.record("bytes", self.bytes);
+        self.parent.record("layers", self.layers);
+    }
+}
+
+/// Digest of a push, from the `<tag>: digest: <digest> size: <bytes>`
+/// status line the daemon ends it with.
+pub fn pushed(status: &str) -> Option<&str> {
+    let (_, rest) = status.split_once(": digest: ")?;
+    rest.split_whitespace().next()
+}
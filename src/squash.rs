@@ -0,0 +1,223 @@
+use crate::cache::BlobCache;
+use crate::registry::Manifest;
+use crate::registry::Registry;
+use anyhow::Context;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+use std::path::Path;
+use tracing::event;
+use tracing::Level;
+
+const OCI_MANIFEST: &str = "application/vnd.oci.image.manifest.v1+json";
+const OCI_LAYER: &str = "application/vnd.oci.image.layer.v1.tar+gzip";
+const DOCKER_LAYER: &str = "application/vnd.docker.image.rootfs.diff.tar.gzip";
+
+// overlay whiteouts, as layer tars record deletions
+const WHITEOUT: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// Image manifest pointing at a single layer holding the merged file system
+/// of all layers of `image`, and at a copy of its config describing that
+/// layer. The config is otherwise kept; its history stays, with the old
+/// steps marked as empty and one step added for the squash. The new blobs
+/// are only in the cache, so they are pushed like missing blobs.
+pub async fn squash(
+    cache: &BlobCache,
+    src: &Registry,
+    src_repo: &str,
+    image: &Manifest,
+) -> anyhow::Result<Manifest> {
+    let body = image.parse()?;
+    let config = body.config.context("manifest has no config")?;
+    let mut layers = Vec::new();
+    for layer in &body.layers {
+        layers.push(cache.fetch(src, src_repo, &layer.digest).await?);
+    }
+
+    let tmp_dir = cache.dir().join("tmp");
+    tokio::fs::create_dir_all(&tmp_dir).await?;
+    let tmp = tempfile::NamedTempFile::new_in(&tmp_dir)?;
+    let out = tmp.path().to_owned();
+    let (diff_id, digest, size) =
+        tokio::task::spawn_blocking(move || merge(&layers, &out)).await??;
+    let path = cache.path(&digest)?;
+    tokio::fs::create_dir_all(path.parent().unwrap()).await?;
+    tmp.persist(&path)?;
+    event!(
+        Level::INFO,
+        "squashed {} layers of {} into {}",
+        body.layers.len(),
+        image.digest,
+        digest
+    );
+
+    let path = cache.fetch(src, src_repo, &config.digest).await?;
+    let mut blob: serde_json::Value = serde_json::from_slice(&tokio::fs::read(&path).await?)?;
+    let blob_object = blob.as_object_mut().context("invalid image config")?;
+    blob_object.insert(
+        "rootfs".to_owned(),
+        serde_json::json!({ "type": "layers", "diff_ids": [diff_id] }),
+    );
+    let mut history = blob_object
+        .get("history")
+        .and_then(|h| h.as_array())
+        .cloned()
+        .unwrap_or_default();
+    for step in history.iter_mut().filter(|s| s.is_object()) {
+        step["empty_layer"] = true.into();
+    }
+    history.push(serde_json::json!({
+        "created": chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        "created_by": format!("image-sync squash of {} layers", body.layers.len()),
+    }));
+    blob_object.insert("history".to_owned(), history.into());
+    let config_bytes = serde_json::to_vec(&blob)?;
+    let config_digest = cache.put(&config_bytes).await?;
+
+    let layer_type = if image.media_type == OCI_MANIFEST {
+        OCI_LAYER
+    } else {
+        DOCKER_LAYER
+    };
+    let mut manifest: serde_json::Value = serde_json::from_slice(&image.bytes)?;
+    manifest["config"]["digest"] = config_digest.into();
+    manifest["config"]["size"] = config_bytes.len().into();
+    manifest["layers"] = serde_json::json!([{
+        "mediaType": layer_type,
+        "digest": digest,
+        "size": size,
+    }]);
+
+    Ok(Manifest::new(
+        image.media_type.clone(),
+        serde_json::to_vec(&manifest)?,
+    ))
+}
+
+// Write the file system of `layers`, lowest first, as one gzipped tar to
+// `out`, returning the tar's diff id and the blob's digest and size.
+fn merge(layers: &[impl AsRef<Path>], out: &Path) -> anyhow::Result<(String, String, u64)> {
+    // which layer has the final say for each path
+    let mut owners: BTreeMap<String, usize> = BTreeMap::new();
+    for (i, layer) in layers.iter().enumerate() {
+        let mut archive = tar::Archive::new(open(layer.as_ref())?);
+        for entry in archive.entries()? {
+            let path = normalize(&entry?.path()?);
+            let (dir, name) = match path.rsplit_once('/') {
+                Some((dir, name)) => (dir.to_owned(), name),
+                None => (String::new(), path.as_str()),
+            };
+            // deletions only hide what lower layers have
+            if name == OPAQUE {
+                let prefix = format!("{}/", dir);
+                owners.retain(|p, owner| *owner == i || !p.starts_with(&prefix));
+            } else if let Some(deleted) = name.strip_prefix(WHITEOUT) {
+                let deleted = if dir.is_empty() {
+                    deleted.to_owned()
+                } else {
+                    format!("{}/{}", dir, deleted)
+                };
+                let prefix = format!("{}/", deleted);
+                owners.retain(|p, owner| *owner == i || (*p != deleted && !p.starts_with(&prefix)));
+            } else {
+                owners.insert(path, i);
+            }
+        }
+    }
+
+    let file = io::BufWriter::new(fs::File::create(out)?);
+    let mut tar = tar::Builder::new(HashingWriter::new(GzEncoder::new(
+        HashingWriter::new(file),
+        flate2::Compression::default(),
+    )));
+    for (i, layer) in layers.iter().enumerate() {
+        let mut archive = tar::Archive::new(open(layer.as_ref())?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            let path = normalize(&entry.path()?);
+            if owners.get(&path) != Some(&i) {
+                continue;
+            }
+            let mut header = entry.header().clone();
+            match entry.link_name()? {
+                // hard links name archive paths, symlinks file system ones
+                Some(target) if header.entry_type().is_hard_link() => {
+                    let target = normalize(&target);
+                    tar.append_link(&mut header, &path, target)?
+                }
+                Some(target) => {
+                    let target = target.into_owned();
+                    tar.append_link(&mut header, &path, target)?
+                }
+                None => tar.append_data(&mut header, &path, &mut entry)?,
+            }
+        }
+    }
+    let gzip = tar.into_inner()?;
+    let diff_id = gzip.digest();
+    let mut blob = gzip.inner.finish()?;
+    let digest = blob.digest();
+    let size = blob.written;
+    blob.inner.flush()?;
+
+    Ok((diff_id, digest, size))
+}
+
+// A layer blob, gzipped or not.
+fn open(path: &Path) -> anyhow::Result<Box<dyn Read>> {
+    let mut magic = [0; 4];
+    let n = fs::File::open(path)?.read(&mut magic)?;
+    let file = io::BufReader::new(fs::File::open(path)?);
+    match &magic[..n] {
+        [0x1f, 0x8b, ..] => Ok(Box::new(GzDecoder::new(file))),
+        [0x28, 0xb5, 0x2f, 0xfd] => anyhow::bail!("cannot squash zstd compressed layers"),
+        _ => Ok(Box::new(file)),
+    }
+}
+
+// Layer tars write paths with or without a leading `./` and trailing `/`.
+fn normalize(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    let path = path.trim_start_matches("./").trim_end_matches('/');
+    path.trim_start_matches('/').to_owned()
+}
+
+// Writer keeping the digest and size of what went through it.
+struct HashingWriter<W> {
+    inner: W,
+    hasher: Sha256,
+    written: u64,
+}
+
+impl<W: Write> HashingWriter<W> {
+    fn new(inner: W) -> HashingWriter<W> {
+        HashingWriter {
+            inner,
+            hasher: Sha256::new(),
+            written: 0,
+        }
+    }
+
+    fn digest(&self) -> String {
+        format!("sha256:{}", hex::encode(self.hasher.clone().finalize()))
+    }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
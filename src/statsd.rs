@@ -0,0 +1,141 @@
+use crate::config::StatsdConfig;
+use prometheus::proto::MetricType;
+use std::collections::HashMap;
+use tokio::net::UdpSocket;
+use tracing::event;
+use tracing::Level;
+
+// keeps packets within a typical MTU
+const MAX_PACKET: usize = 1432;
+
+/// Send every registered metric to the StatsD agent of `config` each
+/// interval, after `refresh` has brought the gauges read at scrape time up
+/// to date. Gauges are sent as they are, counters as what they grew by
+/// since the last send, and histograms as the growth of their count and
+/// sum.
+pub async fn export(config: StatsdConfig, refresh: impl Fn()) {
+    let Some(addr) = config.addr.clone() else {
+        return;
+    };
+    let mut sent: HashMap<String, f64> = HashMap::new();
+    let mut interval = tokio::time::interval(config.interval);
+    loop {
+        interval.tick().await;
+        refresh();
+        let lines = lines(&config, &mut sent);
+        // the agent is looked up again each time, it may move
+        if let Err(e) = send(&addr, &lines).await {
+            event!(Level::WARN, "could not send metrics to {}: {:?}", addr, e);
+        }
+    }
+}
+
+async fn send(addr: &str, lines: &[String]) -> std::io::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(addr).await?;
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > MAX_PACKET {
+            socket.send(packet.as_bytes()).await?;
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+// StatsD lines of the current values, `sent` holding the counter values
+// last sent by metric and labels.
+fn lines(config: &StatsdConfig, sent: &mut HashMap<String, f64>) -> Vec<String> {
+    let mut lines = Vec::new();
+    for family in prometheus::gather() {
+        let name = format!("{}{}", config.prefix, family.get_name());
+        for metric in family.get_metric() {
+            let labels: Vec<(&str, &str)> = metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value()))
+                .collect();
+            let mut push = |suffix: &str, value: f64, kind: &str| {
+                lines.push(line(
+                    config,
+                    &format!("{}{}", name, suffix),
+                    &labels,
+                    value,
+                    kind,
+                ));
+            };
+            let mut delta = |suffix: &str, value: f64| {
+                let key = format!("{}{}{:?}", name, suffix, labels);
+                let last = sent.insert(key, value).unwrap_or(0.0);
+                // a counter only goes down when it was reset
+                (value - last).max(0.0)
+            };
+            match family.get_field_type() {
+                MetricType::GAUGE => push("", metric.get_gauge().get_value(), "g"),
+                MetricType::COUNTER => {
+                    let grown = delta("", metric.get_counter().get_value());
+                    if grown > 0.0 {
+                        push("", grown, "c");
+                    }
+                }
+                MetricType::HISTOGRAM => {
+                    let histogram = metric.get_histogram();
+                    let count = delta("_count", histogram.get_sample_count() as f64);
+                    let sum = delta("_sum", histogram.get_sample_sum());
+                    if count > 0.0 {
+                        push("_count", count, "c");
+                        push("_sum", sum, "c");
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    lines
+}
+
+fn line(
+    config: &StatsdConfig,
+    name: &str,
+    labels: &[(&str, &str)],
+    value: f64,
+    kind: &str,
+) -> String {
+    if !config.dogstatsd {
+        // plain StatsD has no tags, the labels become part of the name
+        let mut name = name.to_owned();
+        for (_, value) in labels {
+            name.push('.');
+            name.push_str(&sanitize(value));
+        }
+        return format!("{}:{}|{}", name, value, kind);
+    }
+    let tags: Vec<String> = config
+        .tags
+        .iter()
+        .cloned()
+        .chain(labels.iter().map(|(k, v)| format!("{}:{}", k, sanitize(v))))
+        .collect();
+    match tags.is_empty() {
+        true => format!("{}:{}|{}", name, value, kind),
+        false => format!("{}:{}|{}|#{}", name, value, kind, tags.join(",")),
+    }
+}
+
+// characters with a meaning in the StatsD line format
+fn sanitize(value: &str) -> String {
+    value
+        .chars()
+        .map(|c| match c {
+            ':' | '|' | '@' | '#' | ',' | '\n' => '_',
+            c => c,
+        })
+        .collect()
+}
@@ -0,0 +1,48 @@
+use crate::config::glob_match;
+use crate::config::StripConfig;
+use serde_json::Value;
+
+/// Remove the build metadata `rules` name from an image config blob.
+pub fn config(blob: &mut Value, rules: &StripConfig) {
+    let blob = match blob.as_object_mut() {
+        Some(blob) => blob,
+        None => return,
+    };
+    if rules.history {
+        blob.remove("history");
+        // legacy Docker fields describing the container of the last step
+        blob.remove("container");
+        blob.remove("container_config");
+    }
+    for key in ["config", "container_config"] {
+        if let Some(config) = blob.get_mut(key) {
+            if let Some(env) = config.get_mut("Env").and_then(Value::as_array_mut) {
+                env.retain(|var| {
+                    let name = var.as_str().unwrap_or_default();
+                    let name = name.split_once('=').map_or(name, |(name, _)| name);
+                    !any_match(&rules.env, name)
+                });
+            }
+            if let Some(labels) = config.get_mut("Labels").and_then(Value::as_object_mut) {
+                labels.retain(|name, _| !any_match(&rules.labels, name));
+            }
+        }
+    }
+}
+
+/// Remove the annotations `rules` name from a manifest or index.
+pub fn annotations(manifest: &mut Value, rules: &StripConfig) {
+    if let Some(annotations) = manifest
+        .get_mut("annotations")
+        .and_then(Value::as_object_mut)
+    {
+        annotations.retain(|name, _| !any_match(&rules.annotations, name));
+        if annotations.is_empty() {
+            manifest.as_object_mut().unwrap().remove("annotations");
+        }
+    }
+}
+
+fn any_match(patterns: &[String], name: &str) -> bool {
+    patterns.iter().any(|p| glob_match(p, name))
+}
@@ -0,0 +1,255 @@
+use crate::config::SyncLinkConfig;
+use crate::error::Error;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD as BASE64;
+use base64::Engine;
+use chrono::DateTime;
+use chrono::TimeZone;
+use chrono::Utc;
+use hmac::Hmac;
+use hmac::Mac;
+use ring::rand::SecureRandom;
+use ring::rand::SystemRandom;
+use serde::Deserialize;
+use serde::Serialize;
+use sha2::Sha256;
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::Duration;
+use tracing::event;
+use tracing::Level;
+
+type HmacSha256 = Hmac<Sha256>;
+
+// parameters a link cannot bake in, they belong to whoever follows it
+const RESERVED: &[&str] = &["wait", "traceparent", "tracestate"];
+
+/// Body of `POST /admin/sync-links`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct LinkReq {
+    /// Parameters of the sync, as `/imagesync` takes them: `image` and
+    /// optionally `destination`, `platforms` and so on.
+    pub request: BTreeMap<String, String>,
+    /// Seconds the link is valid, at most the configured maximum, which is
+    /// also the default.
+    pub ttl: Option<u64>,
+}
+
+// what a link token carries, signed
+#[derive(Serialize, Deserialize, Debug)]
+struct Claims {
+    request: BTreeMap<String, String>,
+    /// Unix seconds.
+    exp: i64,
+    nonce: String,
+}
+
+/// Signs sync links, URLs that run one predefined sync once until they
+/// expire, and redeems them. Redeemed links are kept in `sync-links.json`
+/// of the state directory until they expire, restarts do not make them
+/// usable again.
+pub struct Links {
+    secret: String,
+    max_ttl: Duration,
+    path: PathBuf,
+    // nonces of redeemed links, with their expiry
+    used: Mutex<HashMap<String, i64>>,
+}
+
+impl Links {
+    pub fn new(config: &SyncLinkConfig, state_dir: &Path) -> Option<Links> {
+        let path = state_dir.join("sync-links.json");
+        let used = match std::fs::read(&path) {
+            Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                event!(Level::WARN, "ignoring unreadable {}: {}", path.display(), e);
+                HashMap::new()
+            }),
+            Err(_) => HashMap::new(),
+        };
+        Some(Links {
+            secret: config.secret.clone()?,
+            max_ttl: config.max_ttl,
+            path,
+            used: Mutex::new(used),
+        })
+    }
+
+    /// A token for the sync `req` describes, and when it expires.
+    pub fn sign(&self, req: LinkReq) -> Result<(String, DateTime<Utc>), Error> {
+        let invalid = |message: String| Err(Error::Parse(message));
+        if !req.request.contains_key("image") {
+            return invalid("request has no image".to_owned());
+        }
+        if let Some(key) = RESERVED.iter().find(|key| req.request.contains_key(**key)) {
+            return invalid(format!("request cannot set {}", key));
+        }
+        let ttl = match req.ttl {
+            Some(0) => return invalid("ttl must be at least 1".to_owned()),
+            Some(secs) if secs > self.max_ttl.as_secs() => {
+                return invalid(format!(
+                    "ttl {} is over the maximum of {}",
+                    secs,
+                    self.max_ttl.as_secs()
+                ))
+            }
+            Some(secs) => secs,
+            None => self.max_ttl.as_secs(),
+        };
+        let exp = Utc::now().timestamp() + ttl as i64;
+        let mut nonce = [0; 16];
+        SystemRandom::new()
+            .fill(&mut nonce)
+            .expect("system random numbers");
+        let claims = Claims {
+            request: req.request,
+            exp,
+            nonce: hex::encode(nonce),
+        };
+        let payload = BASE64.encode(serde_json::to_vec(&claims).unwrap());
+        let signature = BASE64.encode(self.mac(&payload).finalize().into_bytes());
+        let expires = Utc.timestamp_opt(exp, 0).single().unwrap_or_default();
+        Ok((format!("{}.{}", payload, signature), expires))
+    }
+
+    /// The request parameters of the sync `token` stands for, if it is
+    /// signed with the secret, not expired and not redeemed before.
+    pub fn redeem(&self, token: &str) -> Result<HashMap<String, String>, Error> {
+        let rejected = |reason: &str| Err(Error::LinkRejected(reason.to_owned()));
+        let Some((payload, signature)) = token.split_once('.') else {
+            return rejected("malformed token");
+        };
+        let Ok(signature) = BASE64.decode(signature) else {
+            return rejected("malformed token");
+        };
+        if self.mac(payload).verify_slice(&signature).is_err() {
+            return rejected("signature does not match");
+        }
+        let Some(claims) = BASE64
+            .decode(payload)
+            .ok()
+            .and_then(|data| serde_json::from_slice::<Claims>(&data).ok())
+        else {
+            return rejected("malformed token");
+        };
+        let now = Utc::now().timestamp();
+        if claims.exp <= now {
+            return rejected("link has expired");
+        }
+
+        let mut used = self.used.lock().unwrap();
+        used.retain(|_, exp| *exp > now);
+        if used.insert(claims.nonce, claims.exp).is_some() {
+            return rejected("link was already used");
+        }
+        self.save(&used);
+        Ok(claims.request.into_iter().collect())
+    }
+
+    fn mac(&self, payload: &str) -> HmacSha256 {
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(payload.as_bytes());
+        mac
+    }
+
+    // Write the redeemed links, replacing the file atomically.
+    fn save(&self, used: &HashMap<String, i64>) {
+        let written = (|| -> anyhow::Result<()> {
+            let dir = self.path.parent().unwrap_or(Path::new("."));
+            std::fs::create_dir_all(dir)?;
+            let mut tmp = tempfile::NamedTempFile::new_in(dir)?;
+            serde_json::to_writer(&mut tmp, used)?;
+            tmp.persist(&self.path)?;
+            Ok(())
+        })();
+        if let Err(e) = written {
+            event!(Level::ERROR, "failed to save used sync links: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn open(dir: &Path) -> Links {
+        let config = SyncLinkConfig {
+            secret: Some("link secret".to_owned()),
+            max_ttl: Duration::from_secs(3600),
+        };
+        Links::new(&config, dir).unwrap()
+    }
+
+    fn request(image: &str) -> LinkReq {
+        LinkReq {
+            request: BTreeMap::from([("image".to_owned(), image.to_owned())]),
+            ttl: None,
+        }
+    }
+
+    fn rejection(result: Result<HashMap<String, String>, Error>) -> String {
+        match result {
+            Err(Error::LinkRejected(reason)) => reason,
+            other => panic!("expected a rejected link, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn redeems_signed_link_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let links = open(dir.path());
+        let (token, expires) = links.sign(request("nginx:1.25")).unwrap();
+        assert!(expires > Utc::now());
+        let request = links.redeem(&token).unwrap();
+        assert_eq!(request.get("image").map(String::as_str), Some("nginx:1.25"));
+        assert_eq!(rejection(links.redeem(&token)), "link was already used");
+        // nor after a restart
+        let links = open(dir.path());
+        assert_eq!(rejection(links.redeem(&token)), "link was already used");
+    }
+
+    #[test]
+    fn rejects_tampered_token() {
+        let dir = tempfile::tempdir().unwrap();
+        let links = open(dir.path());
+        let (token, _) = links.sign(request("nginx:1.25")).unwrap();
+        let (_, signature) = token.split_once('.').unwrap();
+        let (other, _) = links.sign(request("evil/image:latest")).unwrap();
+        let (payload, _) = other.split_once('.').unwrap();
+        let forged = format!("{}.{}", payload, signature);
+        assert_eq!(rejection(links.redeem(&forged)), "signature does not match");
+        assert_eq!(rejection(links.redeem("garbage")), "malformed token");
+    }
+
+    #[test]
+    fn rejects_link_of_other_secret() {
+        let dir = tempfile::tempdir().unwrap();
+        let (token, _) = open(dir.path()).sign(request("nginx:1.25")).unwrap();
+        let config = SyncLinkConfig {
+            secret: Some("another secret".to_owned()),
+            max_ttl: Duration::from_secs(3600),
+        };
+        let other = Links::new(&config, dir.path()).unwrap();
+        assert_eq!(rejection(other.redeem(&token)), "signature does not match");
+    }
+
+    #[test]
+    fn sign_checks_request() {
+        let dir = tempfile::tempdir().unwrap();
+        let links = open(dir.path());
+        let no_image = LinkReq {
+            request: BTreeMap::new(),
+            ttl: None,
+        };
+        assert!(matches!(links.sign(no_image), Err(Error::Parse(_))));
+        let mut wait = request("nginx");
+        wait.request.insert("wait".to_owned(), "true".to_owned());
+        assert!(matches!(links.sign(wait), Err(Error::Parse(_))));
+        let mut long = request("nginx");
+        long.ttl = Some(3601);
+        assert!(matches!(links.sign(long), Err(Error::Parse(_))));
+    }
+}
@@ -0,0 +1,235 @@
+use crate::jobs::Job;
+use anyhow::Context;
+use chrono::DateTime;
+use chrono::Utc;
+use std::fmt::Write;
+use std::time::Duration;
+
+// jobs shown of the queue and of the failures
+const QUEUED_SHOWN: usize = 10;
+const FAILURES_SHOWN: usize = 8;
+
+/// `image-sync top [--url <url>] [--interval <seconds>]`: a live view of
+/// the server at `url` (`IMAGESYNC_URL`, default `http://127.0.0.1:3030`),
+/// redrawn every interval from `/jobs`: the queue, running syncs with their
+/// progress, and the latest failures.
+pub async fn run(args: &[&str]) -> anyhow::Result<()> {
+    let mut url = std::env::var("IMAGESYNC_URL").unwrap_or("http://127.0.0.1:3030".to_owned());
+    let mut interval = Duration::from_secs(2);
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match *arg {
+            "--url" => url = args.next().context("--url needs a value")?.to_string(),
+            "--interval" => {
+                let secs: f64 = args
+                    .next()
+                    .context("--interval needs a value")?
+                    .parse()
+                    .context("--interval is in seconds")?;
+                interval = Duration::from_secs_f64(secs.max(0.1));
+            }
+            other => anyhow::bail!("unknown argument {}", other),
+        }
+    }
+    let url = url.trim_end_matches('/').to_owned();
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+
+    let mut tick = tokio::time::interval(interval);
+    loop {
+        tick.tick().await;
+        let screen = match Snapshot::fetch(&client, &url).await {
+            Ok(snapshot) => snapshot.render(&url, interval, width()),
+            Err(e) => format!(
+                "imagesync top - {}\n\nunreachable: {}\n",
+                url,
+                e.root_cause()
+            ),
+        };
+        // home and clear, then the frame, so it does not flicker line by line
+        print!("\x1b[H\x1b[2J{}", screen);
+        let _ = std::io::Write::flush(&mut std::io::stdout());
+    }
+}
+
+// what one frame shows
+struct Snapshot {
+    running: Vec<Job>,
+    queued: Vec<Job>,
+    queued_total: usize,
+    failed: Vec<Job>,
+    failed_total: usize,
+    succeeded_total: usize,
+}
+
+impl Snapshot {
+    async fn fetch(client: &reqwest::Client, url: &str) -> anyhow::Result<Snapshot> {
+        let (running, _) = jobs(client, url, "state=running&sort=started_at").await?;
+        let (queued, queued_total) = jobs(
+            client,
+            url,
+            &format!("state=queued&sort=created_at&limit={}", QUEUED_SHOWN),
+        )
+        .await?;
+        let (failed, failed_total) = jobs(
+            client,
+            url,
+            &format!("state=failed&sort=-finished_at&limit={}", FAILURES_SHOWN),
+        )
+        .await?;
+        let (_, succeeded_total) = jobs(client, url, "state=succeeded&limit=1&fields=id").await?;
+        Ok(Snapshot {
+            running,
+            queued,
+            queued_total,
+            failed,
+            failed_total,
+            succeeded_total,
+        })
+    }
+
+    fn render(&self, url: &str, interval: Duration, width: usize) -> String {
+        let now = Utc::now();
+        let mut out = String::new();
+        let _ = writeln!(
+            out,
+            "imagesync top - {} - {} (every {:?})",
+            url,
+            now.format("%Y-%m-%d %H:%M:%S"),
+            interval
+        );
+        let _ = writeln!(
+            out,
+            "running {}   queued {}   succeeded {}   failed {}\n",
+            self.running.len(),
+            self.queued_total,
+            self.succeeded_total,
+            self.failed_total
+        );
+
+        let _ = writeln!(out, "\x1b[1mRUNNING\x1b[0m");
+        if self.running.is_empty() {
+            let _ = writeln!(out, "  -");
+        }
+        // job id, phase, bar, percent and elapsed time take about 70 columns
+        let image_width = width.saturating_sub(70).max(20);
+        for job in &self.running {
+            let phase = job.phase.map_or("starting", |p| p.as_str());
+            let _ = writeln!(
+                out,
+                "  {:<20} {:<w$} {:<12} {} {:>5.1}% {:>8}",
+                job.id,
+                clip(&job.image, image_width),
+                phase,
+                bar(job.progress, 20),
+                job.progress,
+                since(job.started_at, now),
+                w = image_width
+            );
+        }
+
+        let _ = writeln!(out, "\n\x1b[1mQUEUED\x1b[0m");
+        if self.queued.is_empty() {
+            let _ = writeln!(out, "  -");
+        }
+        for job in &self.queued {
+            let held = match job.held_until {
+                Some(until) => format!("  held until {}", until.format("%H:%M")),
+                None => String::new(),
+            };
+            let _ = writeln!(
+                out,
+                "  {:<20} {:<6} {:<w$} {:>8}{}",
+                job.id,
+                format!("{:?}", job.priority).to_lowercase(),
+                clip(&job.image, image_width),
+                since(Some(job.created_at), now),
+                held,
+                w = image_width
+            );
+        }
+        if self.queued_total > self.queued.len() {
+            let _ = writeln!(out, "  ... {} more", self.queued_total - self.queued.len());
+        }
+
+        let _ = writeln!(out, "\n\x1b[1mRECENT FAILURES\x1b[0m");
+        if self.failed.is_empty() {
+            let _ = writeln!(out, "  -");
+        }
+        for job in &self.failed {
+            let line = format!(
+                "  {:<20} {} {:<20} {} {}",
+                job.id,
+                job.finished_at
+                    .map_or("--:--:--".to_owned(), |t| t.format("%H:%M:%S").to_string()),
+                job.error_code.as_deref().unwrap_or("failed"),
+                job.image,
+                job.error.as_deref().unwrap_or_default()
+            );
+            let _ = writeln!(out, "\x1b[31m{}\x1b[0m", clip(&line, width));
+        }
+        out
+    }
+}
+
+// Jobs `/jobs?<query>` lists, with the total matching.
+async fn jobs(
+    client: &reqwest::Client,
+    url: &str,
+    query: &str,
+) -> anyhow::Result<(Vec<Job>, usize)> {
+    let resp = client
+        .get(format!("{}/jobs?{}", url, query))
+        .send()
+        .await?
+        .error_for_status()?;
+    let total = resp
+        .headers()
+        .get("X-Total-Count")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok());
+    let jobs: Vec<Job> = match query.contains("fields=") {
+        // trimmed jobs only count
+        true => Vec::new(),
+        false => resp.json().await?,
+    };
+    let total = total.unwrap_or(jobs.len());
+    Ok((jobs, total))
+}
+
+fn bar(percent: f64, width: usize) -> String {
+    let filled = ((percent.clamp(0.0, 100.0) / 100.0) * width as f64).round() as usize;
+    format!("[{}{}]", "#".repeat(filled), "-".repeat(width - filled))
+}
+
+// `text` cut to `width` characters, marked when cut
+fn clip(text: &str, width: usize) -> String {
+    if text.chars().count() <= width {
+        return text.to_owned();
+    }
+    let mut clipped: String = text.chars().take(width.saturating_sub(1)).collect();
+    clipped.push('~');
+    clipped
+}
+
+// time since `t` as `1h02m`, `3m05s` or `12s`
+fn since(t: Option<DateTime<Utc>>, now: DateTime<Utc>) -> String {
+    let Some(t) = t else {
+        return "-".to_owned();
+    };
+    let secs = (now - t).num_seconds().max(0);
+    match secs {
+        s if s >= 3600 => format!("{}h{:02}m", s / 3600, s % 3600 / 60),
+        s if s >= 60 => format!("{}m{:02}s", s / 60, s % 60),
+        s => format!("{}s", s),
+    }
+}
+
+// columns of the terminal as the shell exports them, 100 otherwise
+fn width() -> usize {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|c| c.parse().ok())
+        .unwrap_or(100)
+}
@@ -0,0 +1,149 @@
+use ring::rand::SecureRandom;
+use ring::rand::SystemRandom;
+use std::collections::HashMap;
+use std::future::Future;
+use warp::Filter;
+use warp::Rejection;
+
+/// W3C trace context of a job: the trace it belongs to, the span it runs
+/// as, and the caller's span it is a child of.
+#[derive(Debug, Clone)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub span_id: String,
+    pub parent_id: Option<String>,
+    sampled: bool,
+    state: Option<String>,
+}
+
+tokio::task_local! {
+    static CURRENT: TraceContext;
+}
+
+impl TraceContext {
+    /// A new span in the trace `traceparent` names with `tracestate` kept
+    /// as is, or the root of a new trace when there is none or it is
+    /// malformed.
+    pub fn child_of(traceparent: Option<&str>, tracestate: Option<&str>) -> TraceContext {
+        match traceparent.and_then(parse) {
+            Some(parent) => TraceContext {
+                trace_id: parent.trace_id,
+                span_id: random_hex(8),
+                parent_id: Some(parent.parent_id),
+                sampled: parent.sampled,
+                state: tracestate.map(str::to_owned).filter(|s| !s.is_empty()),
+            },
+            None => TraceContext {
+                trace_id: random_hex(16),
+                span_id: random_hex(8),
+                parent_id: None,
+                sampled: true,
+                state: None,
+            },
+        }
+    }
+
+    /// The context of a job, from the headers its request came with.
+    pub fn of_request(map: &HashMap<String, String>) -> TraceContext {
+        TraceContext::child_of(
+            map.get("traceparent").map(String::as_str),
+            map.get("tracestate").map(String::as_str),
+        )
+    }
+
+    /// `traceparent` header naming this span as the parent.
+    pub fn traceparent(&self) -> String {
+        format!(
+            "00-{}-{}-{:02x}",
+            self.trace_id, self.span_id, self.sampled as u8
+        )
+    }
+
+    /// Run `future` within this context, so the requests it makes carry it.
+    pub async fn scope<F: Future>(self, future: F) -> F::Output {
+        CURRENT.scope(self, future).await
+    }
+}
+
+/// Add the trace context of the job being run, if any, to an outgoing
+/// request.
+pub fn inject(request: reqwest::RequestBuilder) -> reqwest::RequestBuilder {
+    match CURRENT.try_with(|ctx| (ctx.traceparent(), ctx.state.clone())) {
+        Ok((traceparent, Some(state))) => request
+            .header("traceparent", traceparent)
+            .header("tracestate", state),
+        Ok((traceparent, None)) => request.header("traceparent", traceparent),
+        Err(_) => request,
+    }
+}
+
+/// The `traceparent` and `tracestate` headers of a request, for keeping
+/// with the job it starts. Malformed ones are left out.
+pub fn headers() -> impl Filter<Extract = (HashMap<String, String>,), Error = Rejection> + Clone {
+    warp::header::optional::<String>("traceparent")
+        .and(warp::header::optional::<String>("tracestate"))
+        .map(|traceparent: Option<String>, tracestate: Option<String>| {
+            let mut headers = HashMap::new();
+            if let Some(traceparent) = traceparent.filter(|t| parse(t).is_some()) {
+                headers.insert("traceparent".to_owned(), traceparent);
+                if let Some(tracestate) = tracestate {
+                    headers.insert("tracestate".to_owned(), tracestate);
+                }
+            }
+            headers
+        })
+}
+
+/// Trace id of a `traceparent` header, if it is well formed.
+pub fn trace_id(traceparent: &str) -> Option<String> {
+    parse(traceparent).map(|p| p.trace_id)
+}
+
+struct Parent {
+    trace_id: String,
+    parent_id: String,
+    sampled: bool,
+}
+
+// `version-traceid-parentid-flags`; later versions may append fields
+fn parse(traceparent: &str) -> Option<Parent> {
+    let traceparent = traceparent.trim();
+    let mut parts = traceparent.split('-');
+    let version = parts.next()?;
+    let trace_id = parts.next()?;
+    let parent_id = parts.next()?;
+    let flags = parts.next()?;
+    let hex = |s: &str, len: usize| {
+        s.len() == len
+            && s.bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b))
+    };
+    let zero = |s: &str| s.bytes().all(|b| b == b'0');
+    if !hex(version, 2) || version == "ff" || (version == "00" && parts.next().is_some()) {
+        return None;
+    }
+    if !hex(trace_id, 32) || zero(trace_id) || !hex(parent_id, 16) || zero(parent_id) {
+        return None;
+    }
+    if !hex(flags, 2) {
+        return None;
+    }
+    Some(Parent {
+        trace_id: trace_id.to_owned(),
+        parent_id: parent_id.to_owned(),
+        sampled: u8::from_str_radix(flags, 16).ok()? & 1 == 1,
+    })
+}
+
+fn random_hex(bytes: usize) -> String {
+    let mut id = vec![0; bytes];
+    loop {
+        SystemRandom::new()
+            .fill(&mut id)
+            .expect("system random numbers");
+        // all zero ids are invalid
+        if id.iter().any(|&b| b != 0) {
+            return hex::encode(id);
+        }
+    }
+}
@@ -0,0 +1,99 @@
+use crate::config::Config;
+use crate::config::SyncMode;
+use chrono::TimeZone;
+use serde::Serialize;
+
+/// What `GET /version` reports: the build, and what this instance has
+/// turned on.
+#[derive(Serialize, Debug)]
+pub struct Version {
+    pub version: &'static str,
+    /// Commit built from, `-dirty` with uncommitted changes.
+    pub commit: &'static str,
+    /// RFC 3339 time of the build.
+    pub built: String,
+    pub rustc: &'static str,
+    pub target: &'static str,
+    pub features: Features,
+}
+
+#[derive(Serialize, Debug)]
+pub struct Features {
+    pub sync_mode: SyncMode,
+    /// Where synced images are exported: `dir`, `s3`.
+    pub export: Vec<&'static str>,
+    /// How registries are logged in to: `anonymous`, `basic` and `token`
+    /// always, `credentials` with named credentials configured.
+    pub auth: Vec<&'static str>,
+    /// How signatures are verified: `key`, `keyless`.
+    pub signatures: Vec<&'static str>,
+    /// Endpoints and background tasks turned on.
+    pub services: Vec<&'static str>,
+}
+
+pub fn version(config: &Config) -> Version {
+    let built = env!("IMAGESYNC_BUILT")
+        .parse()
+        .ok()
+        .and_then(|secs| chrono::Utc.timestamp_opt(secs, 0).single())
+        .map(|t| t.to_rfc3339())
+        .unwrap_or_default();
+    let signatures = &config.signatures;
+    let services = [
+        ("admin", config.admin_token.is_some()),
+        ("proxy", config.proxy.upstream.is_some()),
+        ("webhook", config.webhook.secret.is_some()),
+        ("sync_links", config.sync_links.secret.is_some()),
+        (
+            "mirror_list",
+            config.mirror_list.file.is_some() || config.mirror_list.git.is_some(),
+        ),
+        ("discovery", config.discovery.enabled),
+        ("retention", config.retention.is_enabled()),
+        ("gc", config.gc.registry.is_some()),
+        ("local_cache", config.local_cache.is_enabled()),
+        ("statsd", config.statsd.addr.is_some()),
+        ("daemon_events", config.daemon_events),
+        ("daemon_df", config.daemon_df_interval.is_some()),
+        ("dest_check", config.dest_check_interval.is_some()),
+    ];
+    Version {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("IMAGESYNC_COMMIT"),
+        built,
+        rustc: env!("IMAGESYNC_RUSTC"),
+        target: env!("IMAGESYNC_TARGET"),
+        features: Features {
+            sync_mode: config.sync_mode,
+            export: enabled(&[
+                ("dir", config.export.dir.is_some()),
+                ("s3", config.export.s3.is_some()),
+            ]),
+            auth: enabled(&[
+                ("anonymous", true),
+                ("basic", true),
+                ("token", true),
+                ("credentials", !config.credentials.is_empty()),
+            ]),
+            signatures: enabled(&[
+                (
+                    "key",
+                    signatures.is_enabled() && !signatures.keys.is_empty(),
+                ),
+                (
+                    "keyless",
+                    signatures.is_enabled() && !signatures.identities.is_empty(),
+                ),
+            ]),
+            services: enabled(&services),
+        },
+    }
+}
+
+fn enabled(features: &[(&'static str, bool)]) -> Vec<&'static str> {
+    features
+        .iter()
+        .filter(|(_, on)| *on)
+        .map(|(name, _)| *name)
+        .collect()
+}
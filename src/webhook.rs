@@ -0,0 +1,160 @@
+use crate::config::WebhookConfig;
+use crate::error::Error;
+use hmac::Hmac;
+use hmac::Mac;
+use serde::Deserialize;
+use sha2::Sha256;
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Body of `/webhooks/generic`.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct GenericReq {
+    pub images: Vec<String>,
+    #[serde(default)]
+    pub platforms: Vec<String>,
+    pub priority: Option<String>,
+}
+
+/// Checks the signature of webhook deliveries, accepting each one once.
+pub struct Verifier {
+    secret: String,
+    tolerance: i64,
+    // signatures accepted within the tolerance, by their timestamp
+    seen: Mutex<HashMap<String, i64>>,
+}
+
+impl Verifier {
+    pub fn new(config: &WebhookConfig) -> Option<Verifier> {
+        Some(Verifier {
+            secret: config.secret.clone()?,
+            tolerance: config.tolerance.as_secs() as i64,
+            seen: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Check that `signature` (`sha256=<hex>`) is the HMAC of
+    /// `<timestamp>.<body>` under the secret, that `timestamp` (Unix
+    /// seconds) is recent, and that the delivery was not accepted before.
+    pub fn verify(
+        &self,
+        timestamp: Option<&str>,
+        signature: Option<&str>,
+        body: &[u8],
+    ) -> Result<(), Error> {
+        let rejected = |reason: &str| Err(Error::Signature(reason.to_owned()));
+        let (Some(timestamp), Some(signature)) = (timestamp, signature) else {
+            return rejected("timestamp or signature header missing");
+        };
+        let Ok(sent) = timestamp.parse::<i64>() else {
+            return rejected("invalid timestamp");
+        };
+        let now = chrono::Utc::now().timestamp();
+        if (now - sent).abs() > self.tolerance {
+            return rejected("timestamp is too far off");
+        }
+        let Some(given) = signature
+            .strip_prefix("sha256=")
+            .and_then(|hex| hex::decode(hex).ok())
+        else {
+            return rejected("invalid signature");
+        };
+
+        let mut mac = HmacSha256::new_from_slice(self.secret.as_bytes())
+            .expect("HMAC accepts keys of any length");
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        if mac.verify_slice(&given).is_err() {
+            return rejected("signature does not match");
+        }
+
+        // a replay carries the same signature, and older ones fail the
+        // timestamp check anyway
+        let mut seen = self.seen.lock().unwrap();
+        seen.retain(|_, sent| (now - *sent).abs() <= self.tolerance);
+        if seen.insert(hex::encode(given), sent).is_some() {
+            return rejected("delivery was already accepted");
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    const SECRET: &str = "webhook secret";
+
+    fn verifier() -> Verifier {
+        Verifier::new(&WebhookConfig {
+            secret: Some(SECRET.to_owned()),
+            tolerance: Duration::from_secs(300),
+        })
+        .unwrap()
+    }
+
+    fn sign(timestamp: &str, body: &[u8]) -> String {
+        let mut mac = HmacSha256::new_from_slice(SECRET.as_bytes()).unwrap();
+        mac.update(timestamp.as_bytes());
+        mac.update(b".");
+        mac.update(body);
+        format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+    }
+
+    fn rejection(result: Result<(), Error>) -> String {
+        match result {
+            Err(Error::Signature(reason)) => reason,
+            other => panic!("expected a signature error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn no_secret_no_verifier() {
+        let config = WebhookConfig {
+            secret: None,
+            tolerance: Duration::from_secs(300),
+        };
+        assert!(Verifier::new(&config).is_none());
+    }
+
+    #[test]
+    fn accepts_signed_delivery_once() {
+        let verifier = verifier();
+        let body = br#"{"images":["nginx:1.25"]}"#;
+        let now = chrono::Utc::now().timestamp().to_string();
+        let signature = sign(&now, body);
+        assert!(verifier.verify(Some(&now), Some(&signature), body).is_ok());
+        let replay = verifier.verify(Some(&now), Some(&signature), body);
+        assert_eq!(rejection(replay), "delivery was already accepted");
+    }
+
+    #[test]
+    fn rejects_wrong_signature() {
+        let verifier = verifier();
+        let now = chrono::Utc::now().timestamp().to_string();
+        let signature = sign(&now, b"original body");
+        let tampered = verifier.verify(Some(&now), Some(&signature), b"other body");
+        assert_eq!(rejection(tampered), "signature does not match");
+        let unprefixed = signature.trim_start_matches("sha256=");
+        let result = verifier.verify(Some(&now), Some(unprefixed), b"original body");
+        assert_eq!(rejection(result), "invalid signature");
+    }
+
+    #[test]
+    fn rejects_old_or_missing_timestamp() {
+        let verifier = verifier();
+        let old = (chrono::Utc::now().timestamp() - 301).to_string();
+        let signature = sign(&old, b"body");
+        let result = verifier.verify(Some(&old), Some(&signature), b"body");
+        assert_eq!(rejection(result), "timestamp is too far off");
+        let result = verifier.verify(None, Some(&signature), b"body");
+        assert_eq!(rejection(result), "timestamp or signature header missing");
+        let result = verifier.verify(Some("yesterday"), Some(&signature), b"body");
+        assert_eq!(rejection(result), "invalid timestamp");
+    }
+}